@@ -0,0 +1,338 @@
+use std::fmt::Write;
+use std::ops::Range;
+
+use serde_json::{ json, Value };
+
+use crate::ast;
+
+
+/*
+ * Generic tree of a program for the dumps, each node named after what it
+ * is with its value if it has one, like the name of an identifier or an
+ * operator. As a parse tree, expressions nest in expression, term and
+ * factor nodes like the rules of the grammar, with the operators as
+ * leaves, instead of binary operations.
+ */
+#[derive(Clone, Debug, PartialEq)]
+pub struct Node {
+    pub kind: &'static str,
+    pub value: Option<String>,
+    pub span: Range<usize>,
+    pub children: Vec<Node>,
+}
+
+impl Node {
+    fn new(kind: &'static str, span: Range<usize>, children: Vec<Node>) -> Node {
+        Node { kind, value: None, span, children }
+    }
+
+    fn leaf(kind: &'static str, value: String, span: Range<usize>) -> Node {
+        Node { kind, value: Some(value), span, children: Vec::new() }
+    }
+}
+
+// Tree of the program, the content is the source it was parsed from
+pub fn tree(program: &ast::Program, content: &str, parse_tree: bool) -> Node {
+    let builder = Builder { content, comments: &program.comments, parse_tree };
+    Node::new("program", program.span.clone(), vec![builder.block(&program.block)])
+}
+
+struct Builder<'a> {
+    content: &'a str,
+    comments: &'a [Range<usize>],
+    parse_tree: bool,
+}
+
+impl Builder<'_> {
+    fn block(&self, block: &ast::Block) -> Node {
+        let mut children: Vec<Node> = block.declarations.iter().map(|declaration| self.declaration(declaration)).collect();
+        children.push(self.statement(&block.statement));
+        Node::new("block", block.span.clone(), children)
+    }
+
+    fn declaration(&self, declaration: &ast::Declaration) -> Node {
+        let span = declaration.span.clone();
+        match &declaration.kind {
+            ast::DeclarationKind::Const(constants) => {
+                let children = constants.iter()
+                    .map(|constant| Node::new("constant", constant.name.span.start..constant.value_span.end, vec![
+                        ident(&constant.name),
+                        Node::leaf("number", constant.value.to_string(), constant.value_span.clone()),
+                    ]))
+                    .collect();
+                Node::new("const", span, children)
+            },
+            ast::DeclarationKind::Var(variables) => Node::new("var", span, variables.iter().map(ident).collect()),
+            ast::DeclarationKind::Procedure(procedure) => {
+                Node::new("procedure", span, vec![ident(&procedure.name), self.block(&procedure.block)])
+            },
+        }
+    }
+
+    fn statement(&self, statement: &ast::Statement) -> Node {
+        let span = statement.span.clone();
+        match &statement.kind {
+            ast::StatementKind::Assign(name, value) => Node::new("assign", span, vec![ident(name), self.expression(value)]),
+            ast::StatementKind::Call(name) => Node::new("call", span, vec![ident(name)]),
+            ast::StatementKind::Read(names) => Node::new("read", span, names.iter().map(ident).collect()),
+            ast::StatementKind::Write(values) => {
+                Node::new("write", span, values.iter().map(|value| self.expression(value)).collect())
+            },
+            ast::StatementKind::Begin(statements) => {
+                Node::new("begin", span, statements.iter().map(|inner| self.statement(inner)).collect())
+            },
+            ast::StatementKind::If(condition, body) => {
+                Node::new("if", span, vec![self.condition(condition), self.statement(body)])
+            },
+            ast::StatementKind::While(condition, body) => {
+                Node::new("while", span, vec![self.condition(condition), self.statement(body)])
+            },
+            ast::StatementKind::Empty => Node::new("empty", span, Vec::new()),
+        }
+    }
+
+    fn condition(&self, condition: &ast::Condition) -> Node {
+        let span = condition.span.clone();
+        match &condition.kind {
+            ast::ConditionKind::Odd(value) => Node::new("odd", span, vec![self.expression(value)]),
+            ast::ConditionKind::Compare(left, relation, right) => {
+                let mut node = Node::new("compare", span, vec![self.expression(left), self.expression(right)]);
+                node.value = Some(relation.symbol().to_string());
+                node
+            },
+        }
+    }
+
+    fn expression(&self, expression: &ast::Expression) -> Node {
+        if self.parse_tree {
+            return self.rule_expression(expression);
+        }
+        let span = expression.span.clone();
+        match &expression.kind {
+            ast::ExpressionKind::Number(number) => Node::leaf("number", number.to_string(), span),
+            ast::ExpressionKind::Name(name) => ident(name),
+            ast::ExpressionKind::Negate(operand) => Node::new("negate", span, vec![self.expression(operand)]),
+            ast::ExpressionKind::Binary(left, operator, right) => {
+                let mut node = Node::new("binary", span, vec![self.expression(left), self.expression(right)]);
+                node.value = Some(operator.symbol().to_string());
+                node
+            },
+            ast::ExpressionKind::Paren(inner) => Node::new("paren", span, vec![self.expression(inner)]),
+        }
+    }
+
+    // expression = [ "+"|"-"] term { ("+"|"-") term}
+    fn rule_expression(&self, expression: &ast::Expression) -> Node {
+        let mut operations = Vec::new();
+        let mut first = expression;
+        while let ast::ExpressionKind::Binary(left, operator @ (ast::BinaryOperator::Add | ast::BinaryOperator::Sub), right) = &first.kind {
+            operations.push((*operator, right.as_ref(), left.span.end));
+            first = left;
+        }
+
+        let mut children = Vec::new();
+        match &first.kind {
+            // A leading minus negates the whole first term
+            ast::ExpressionKind::Negate(term) => {
+                children.push(self.operator("-", first.span.start));
+                children.push(self.rule_term(term));
+            },
+            _ => children.push(self.rule_term(first)),
+        }
+        for (operator, term, after) in operations.into_iter().rev() {
+            children.push(self.operator(operator.symbol(), after));
+            children.push(self.rule_term(term));
+        }
+        Node::new("expression", expression.span.clone(), children)
+    }
+
+    // term = factor {("*"|"/") factor}
+    fn rule_term(&self, term: &ast::Expression) -> Node {
+        let mut operations = Vec::new();
+        let mut first = term;
+        while let ast::ExpressionKind::Binary(left, operator @ (ast::BinaryOperator::Mul | ast::BinaryOperator::Div), right) = &first.kind {
+            operations.push((*operator, right.as_ref(), left.span.end));
+            first = left;
+        }
+
+        let mut children = vec![self.rule_factor(first)];
+        for (operator, factor, after) in operations.into_iter().rev() {
+            children.push(self.operator(operator.symbol(), after));
+            children.push(self.rule_factor(factor));
+        }
+        Node::new("term", term.span.clone(), children)
+    }
+
+    // factor = ident | number | "(" expression ")", the parser also takes a signed factor
+    fn rule_factor(&self, factor: &ast::Expression) -> Node {
+        let span = factor.span.clone();
+        let children = match &factor.kind {
+            ast::ExpressionKind::Number(number) => vec![Node::leaf("number", number.to_string(), span.clone())],
+            ast::ExpressionKind::Name(name) => vec![ident(name)],
+            ast::ExpressionKind::Paren(inner) => vec![self.rule_expression(inner)],
+            ast::ExpressionKind::Negate(operand) => {
+                let mut children = vec![self.operator("-", span.start)];
+                children.extend(self.rule_factor(operand).children);
+                children
+            },
+            // Only a parenthesized expression has a sum in a factor
+            ast::ExpressionKind::Binary(..) => vec![self.rule_expression(factor)],
+        };
+        Node::new("factor", span, children)
+    }
+
+    // Operator leaf, found at or after the position outside of comments
+    fn operator(&self, symbol: &str, from: usize) -> Node {
+        let mut position = from;
+        while position < self.content.len() {
+            match self.comments.iter().find(|comment| comment.contains(&position)) {
+                Some(comment) => position = comment.end,
+                None if self.content[position..].starts_with(symbol) => break,
+                None => position += 1,
+            }
+        }
+        Node::leaf("operator", symbol.to_string(), position..position + symbol.len())
+    }
+}
+
+fn ident(name: &ast::Ident) -> Node {
+    Node::leaf("ident", name.name.clone(), name.span.clone())
+}
+
+// {"kind": ..., "value": ..., "span": [start, end], "children": [...]}, value only if there is one
+pub fn json(node: &Node) -> Value {
+    let mut object = json!({
+        "kind": node.kind,
+        "span": [node.span.start, node.span.end],
+    });
+    if let Some(value) = &node.value {
+        object["value"] = json!(value);
+    }
+    if !node.children.is_empty() {
+        object["children"] = Value::Array(node.children.iter().map(json).collect());
+    }
+    object
+}
+
+// (kind value start..end children...), one child per line indented under its parent
+pub fn sexp(node: &Node) -> String {
+    fn write_node(node: &Node, indent: usize, output: &mut String) {
+        write!(output, "{}({}", "  ".repeat(indent), node.kind).unwrap();
+        if let Some(value) = &node.value {
+            write!(output, " {}", quote(value)).unwrap();
+        }
+        write!(output, " {}..{}", node.span.start, node.span.end).unwrap();
+        for child in &node.children {
+            output.push('\n');
+            write_node(child, indent + 1, output);
+        }
+        output.push(')');
+    }
+
+    let mut output = String::new();
+    write_node(node, 0, &mut output);
+    output.push('\n');
+    output
+}
+
+// Graphviz tree, each node labeled with its kind, value and span
+pub fn dot(node: &Node) -> String {
+    fn write_node(node: &Node, output: &mut String, count: &mut usize) -> usize {
+        let id = *count;
+        *count += 1;
+        let mut label = node.kind.to_string();
+        if let Some(value) = &node.value {
+            write!(label, "\\n{}", escape(value)).unwrap();
+        }
+        writeln!(output, "    n{} [label=\"{}\\n{}..{}\"];", id, label, node.span.start, node.span.end).unwrap();
+        for child in &node.children {
+            let child_id = write_node(child, output, count);
+            writeln!(output, "    n{} -> n{};", id, child_id).unwrap();
+        }
+        id
+    }
+
+    let mut output = String::from("digraph ast {\n    node [shape=box, fontname=\"monospace\"];\n");
+    write_node(node, &mut output, &mut 0);
+    output.push_str("}\n");
+    output
+}
+
+fn quote(value: &str) -> String {
+    format!("\"{}\"", escape(value))
+}
+
+// Values are names, numbers and operators, escaped only in case
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ast::dump;
+    use crate::ast::parser;
+
+    /* test binary operations nest as in the syntax tree */
+    #[test]
+    fn test_sexp() {
+        let content = "var x; x := -x + 2 * 3.";
+        let program = parser::parse(content).unwrap();
+
+        assert_eq!(dump::sexp(&dump::tree(&program, content, false)), "(program 0..23
+  (block 0..22
+    (var 0..6
+      (ident \"x\" 4..5))
+    (assign 7..22
+      (ident \"x\" 7..8)
+      (binary \"+\" 12..22
+        (negate 12..14
+          (ident \"x\" 13..14))
+        (binary \"*\" 17..22
+          (number \"2\" 17..18)
+          (number \"3\" 21..22))))))
+");
+    }
+
+    /* test the parse tree nests expression, term and factor like the grammar */
+    #[test]
+    fn test_parse_tree() {
+        let content = "write(1 (* - *) - (2) * 3).";
+        let program = parser::parse(content).unwrap();
+        let tree = dump::tree(&program, content, true);
+
+        assert_eq!(dump::sexp(&tree.children[0].children[0]), "(write 0..26
+  (expression 6..25
+    (term 6..7
+      (factor 6..7
+        (number \"1\" 6..7)))
+    (operator \"-\" 16..17)
+    (term 18..25
+      (factor 18..21
+        (expression 19..20
+          (term 19..20
+            (factor 19..20
+              (number \"2\" 19..20)))))
+      (operator \"*\" 22..23)
+      (factor 24..25
+        (number \"3\" 24..25)))))
+");
+    }
+
+    /* test the json and dot dumps carry the spans */
+    #[test]
+    fn test_json_dot() {
+        let content = "call p.";
+        let program = parser::parse(content).unwrap();
+        let tree = dump::tree(&program, content, false);
+
+        let json = dump::json(&tree);
+        assert_eq!(json["kind"], "program");
+        assert_eq!(json["children"][0]["children"][0]["children"][0], serde_json::json!({
+            "kind": "ident", "value": "p", "span": [5, 6],
+        }));
+
+        let dot = dump::dot(&tree);
+        assert!(dot.starts_with("digraph ast {\n"));
+        assert!(dot.contains("    n3 [label=\"ident\\np\\n5..6\"];\n    n2 -> n3;\n"));
+    }
+}
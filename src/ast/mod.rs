@@ -0,0 +1,140 @@
+pub mod dump;
+pub mod parser;
+
+use std::ops::Range;
+
+
+/* syntax tree of a whole program, every node keeps its span in the source */
+#[derive(Clone, Debug, PartialEq)]
+pub struct Program {
+    pub block: Block,
+    pub span: Range<usize>,
+    pub comments: Vec<Range<usize>>,    // spans of all comments, in order
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Block {
+    pub declarations: Vec<Declaration>,     // in source order
+    pub statement: Statement,
+    pub span: Range<usize>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Ident {
+    pub name: String,
+    pub span: Range<usize>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Declaration {
+    pub kind: DeclarationKind,
+    pub span: Range<usize>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum DeclarationKind {
+    Const(Vec<Constant>),
+    Var(Vec<Ident>),
+    Procedure(Box<Procedure>),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Constant {
+    pub name: Ident,
+    pub value: i64,
+    pub value_span: Range<usize>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Procedure {
+    pub name: Ident,
+    pub block: Block,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Statement {
+    pub kind: StatementKind,
+    pub span: Range<usize>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum StatementKind {
+    Assign(Ident, Expression),
+    Call(Ident),
+    Read(Vec<Ident>),
+    Write(Vec<Expression>),
+    Begin(Vec<Statement>),
+    If(Condition, Box<Statement>),
+    While(Condition, Box<Statement>),
+    Empty,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Condition {
+    pub kind: ConditionKind,
+    pub span: Range<usize>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum ConditionKind {
+    Odd(Expression),
+    Compare(Expression, Relation, Expression),
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Relation {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+impl Relation {
+    pub fn symbol(&self) -> &'static str {
+        match self {
+            Relation::Eq => "=",
+            Relation::Ne => "!=",
+            Relation::Lt => "<",
+            Relation::Le => "<=",
+            Relation::Gt => ">",
+            Relation::Ge => ">=",
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Expression {
+    pub kind: ExpressionKind,
+    pub span: Range<usize>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum ExpressionKind {
+    Number(i64),
+    Name(Ident),
+    // A leading minus negates the whole first term, like the code generator does
+    Negate(Box<Expression>),
+    Binary(Box<Expression>, BinaryOperator, Box<Expression>),
+    Paren(Box<Expression>),
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum BinaryOperator {
+    Add,
+    Sub,
+    Mul,
+    Div,
+}
+
+impl BinaryOperator {
+    pub fn symbol(&self) -> &'static str {
+        match self {
+            BinaryOperator::Add => "+",
+            BinaryOperator::Sub => "-",
+            BinaryOperator::Mul => "*",
+            BinaryOperator::Div => "/",
+        }
+    }
+}
@@ -0,0 +1,427 @@
+use crate::ast;
+use crate::symbol;
+use crate::compile::error;
+
+
+// Parse a whole program, stops at the first error
+pub fn parse(content: &str) -> Result<ast::Program, error::CompileError> {
+    let mut parser = Parser {
+        lexer: symbol::io::PL0Lexer::create_from_content(content),
+        last_end: 0,
+    };
+    parser.lexer.next();
+    parser.parse_program()
+}
+
+struct Parser<'a> {
+    lexer: symbol::io::PL0Lexer<'a>,
+    last_end: usize,    // end of the last consumed symbol
+}
+
+impl Parser<'_> {
+    fn parse_program(&mut self) -> Result<ast::Program, error::CompileError> {
+        let block = self.parse_block()?;
+        self.expect(symbol::Symbol::Period, "Program should end with .")?;
+        if *self.lexer.current() != symbol::Symbol::EOF {
+            return Err(self.error("Unexpected symbol after ."));
+        }
+
+        Ok(ast::Program {
+            span: block.span.start..self.last_end,
+            block,
+            comments: self.lexer.comments().clone(),
+        })
+    }
+
+    fn parse_block(&mut self) -> Result<ast::Block, error::CompileError> {
+        let start = self.start();
+        let mut declarations = Vec::new();
+
+        loop {
+            let declaration_start = self.start();
+            let kind = match self.lexer.current() {
+                symbol::Symbol::Constsym => {
+                    let mut constants = Vec::new();
+                    loop {
+                        self.advance();
+                        let name = self.parse_ident("Constant name expected")?;
+                        self.expect(symbol::Symbol::Eql, "Constant should be defined with =")?;
+                        if *self.lexer.current() != symbol::Symbol::Number {
+                            return Err(self.error("Constant should be defined with a number"));
+                        }
+                        let value_span = self.lexer.current_index();
+                        let value = self.parse_number()?;
+                        constants.push(ast::Constant { name, value, value_span });
+
+                        if *self.lexer.current() != symbol::Symbol::Comma {
+                            break;
+                        }
+                    }
+                    self.expect(symbol::Symbol::Semicolon, "Constant declaration should end with ;")?;
+                    ast::DeclarationKind::Const(constants)
+                },
+                symbol::Symbol::Varsym => {
+                    let mut variables = Vec::new();
+                    loop {
+                        self.advance();
+                        variables.push(self.parse_ident("Variable name expected")?);
+
+                        if *self.lexer.current() != symbol::Symbol::Comma {
+                            break;
+                        }
+                    }
+                    self.expect(symbol::Symbol::Semicolon, "Variable declaration should end with ;")?;
+                    ast::DeclarationKind::Var(variables)
+                },
+                symbol::Symbol::Procsym => {
+                    self.advance();
+                    let name = self.parse_ident("Procedure name expected")?;
+                    self.expect(symbol::Symbol::Semicolon, "Procedur is not ended with ;")?;
+                    let block = self.parse_block()?;
+                    self.expect(symbol::Symbol::Semicolon, "Block is not ended with ;")?;
+                    ast::DeclarationKind::Procedure(Box::new(ast::Procedure { name, block }))
+                },
+                _ => {
+                    // End of declaration
+                    break;
+                },
+            };
+            declarations.push(ast::Declaration {
+                kind,
+                span: declaration_start..self.last_end,
+            });
+        }
+
+        let statement = self.parse_statement()?;
+        Ok(ast::Block {
+            declarations,
+            span: start..self.last_end.max(start),
+            statement,
+        })
+    }
+
+    fn parse_statement(&mut self) -> Result<ast::Statement, error::CompileError> {
+        let start = self.start();
+        let kind = match self.lexer.current() {
+            symbol::Symbol::Ident => {
+                let name = self.parse_ident("Variable name expected")?;
+                self.expect(symbol::Symbol::Becomes, "Assignment should use :=")?;
+                ast::StatementKind::Assign(name, self.parse_expression()?)
+            },
+            symbol::Symbol::Callsym => {
+                self.advance();
+                ast::StatementKind::Call(self.parse_ident("Call statement should call a procedure name")?)
+            },
+            symbol::Symbol::Readsym => {
+                self.advance();
+                self.expect(symbol::Symbol::Lparen, "Read statement should begin with Lparen (")?;
+                let mut names = vec![self.parse_ident("Read statement should read into a variable")?];
+                while *self.lexer.current() == symbol::Symbol::Comma {
+                    self.advance();
+                    names.push(self.parse_ident("Read statement should read into a variable")?);
+                }
+                self.expect(symbol::Symbol::Rparen, "Read statement should end with Rparent )")?;
+                ast::StatementKind::Read(names)
+            },
+            symbol::Symbol::Writesym => {
+                self.advance();
+                self.expect(symbol::Symbol::Lparen, "Write statement should begin with Lparen (")?;
+                let mut expressions = vec![self.parse_expression()?];
+                while *self.lexer.current() == symbol::Symbol::Comma {
+                    self.advance();
+                    expressions.push(self.parse_expression()?);
+                }
+                self.expect(symbol::Symbol::Rparen, "Write statement should end with Rparent )")?;
+                ast::StatementKind::Write(expressions)
+            },
+            symbol::Symbol::Beginsym => {
+                self.advance();
+                let mut statements = vec![self.parse_statement()?];
+                loop {
+                    match self.lexer.current() {
+                        symbol::Symbol::Semicolon => {
+                            self.advance();
+                            statements.push(self.parse_statement()?);
+                        },
+                        symbol::Symbol::Endsym => {
+                            self.advance();
+                            break;
+                        },
+                        symbol::Symbol::Period | symbol::Symbol::EOF => {
+                            return Err(self.error("Begin should end with Endsym"));
+                        },
+                        _ => {
+                            return Err(self.error("Statement is not ended with ;"));
+                        },
+                    }
+                }
+                ast::StatementKind::Begin(statements)
+            },
+            symbol::Symbol::Ifsym => {
+                self.advance();
+                let condition = self.parse_condition()?;
+                self.expect(symbol::Symbol::Thensyn, "If condition should be ended with then")?;
+                ast::StatementKind::If(condition, Box::new(self.parse_statement()?))
+            },
+            symbol::Symbol::Whilesym => {
+                self.advance();
+                let condition = self.parse_condition()?;
+                self.expect(symbol::Symbol::Dosym, "While condition should be ended with do")?;
+                ast::StatementKind::While(condition, Box::new(self.parse_statement()?))
+            },
+            _ => {
+                // Empty statement
+                return Ok(ast::Statement {
+                    kind: ast::StatementKind::Empty,
+                    span: start..start,
+                });
+            },
+        };
+        Ok(ast::Statement {
+            kind,
+            span: start..self.last_end,
+        })
+    }
+
+    fn parse_condition(&mut self) -> Result<ast::Condition, error::CompileError> {
+        let start = self.start();
+        if *self.lexer.current() == symbol::Symbol::Oddsym {
+            self.advance();
+            let expression = self.parse_expression()?;
+            return Ok(ast::Condition {
+                span: start..expression.span.end,
+                kind: ast::ConditionKind::Odd(expression),
+            });
+        }
+
+        let left = self.parse_expression()?;
+        let relation = match self.lexer.current() {
+            symbol::Symbol::Eql => ast::Relation::Eq,
+            symbol::Symbol::Neq => ast::Relation::Ne,
+            symbol::Symbol::Lss => ast::Relation::Lt,
+            symbol::Symbol::Leq => ast::Relation::Le,
+            symbol::Symbol::Gtr => ast::Relation::Gt,
+            symbol::Symbol::Geq => ast::Relation::Ge,
+            _ => return Err(self.error("Relational operator expected")),
+        };
+        self.advance();
+        let right = self.parse_expression()?;
+        Ok(ast::Condition {
+            span: start..right.span.end,
+            kind: ast::ConditionKind::Compare(left, relation, right),
+        })
+    }
+
+    fn parse_expression(&mut self) -> Result<ast::Expression, error::CompileError> {
+        let start = self.start();
+        let negative = match self.lexer.current() {
+            symbol::Symbol::Minus => {
+                self.advance();
+                true
+            },
+            symbol::Symbol::Plus => {
+                self.advance();
+                false
+            },
+            _ => false,
+        };
+
+        // The sign is the one of the first factor
+        let mut expression = self.parse_term(false)?;
+        if negative {
+            expression = ast::Expression {
+                span: start..expression.span.end,
+                kind: ast::ExpressionKind::Negate(Box::new(expression)),
+            };
+        }
+
+        loop {
+            let operator = match self.lexer.current() {
+                symbol::Symbol::Plus => ast::BinaryOperator::Add,
+                symbol::Symbol::Minus => ast::BinaryOperator::Sub,
+                _ => break,
+            };
+            self.advance();
+            // Like the code generator, a factor after - takes that - as its sign and has none of its own
+            let right = self.parse_term(operator == ast::BinaryOperator::Add)?;
+            expression = ast::Expression {
+                span: start..right.span.end,
+                kind: ast::ExpressionKind::Binary(Box::new(expression), operator, Box::new(right)),
+            };
+        }
+        Ok(expression)
+    }
+
+    // Whether the first factor may have a sign
+    fn parse_term(&mut self, signed: bool) -> Result<ast::Expression, error::CompileError> {
+        let start = self.start();
+        let mut term = match signed {
+            true => self.parse_factor()?,
+            false => self.parse_primary()?,
+        };
+
+        loop {
+            let operator = match self.lexer.current() {
+                symbol::Symbol::Times => ast::BinaryOperator::Mul,
+                symbol::Symbol::Slash => ast::BinaryOperator::Div,
+                _ => break,
+            };
+            self.advance();
+            let right = self.parse_factor()?;
+            term = ast::Expression {
+                span: start..right.span.end,
+                kind: ast::ExpressionKind::Binary(Box::new(term), operator, Box::new(right)),
+            };
+        }
+        Ok(term)
+    }
+
+    fn parse_factor(&mut self) -> Result<ast::Expression, error::CompileError> {
+        let start = self.start();
+        match self.lexer.current() {
+            symbol::Symbol::Minus | symbol::Symbol::Plus => {
+                // Signed factor inside a term
+                let negative = *self.lexer.current() == symbol::Symbol::Minus;
+                self.advance();
+                let factor = self.parse_primary()?;
+                if !negative {
+                    return Ok(factor);
+                }
+                Ok(ast::Expression {
+                    span: start..factor.span.end,
+                    kind: ast::ExpressionKind::Negate(Box::new(factor)),
+                })
+            },
+            _ => self.parse_primary(),
+        }
+    }
+
+    fn parse_primary(&mut self) -> Result<ast::Expression, error::CompileError> {
+        let start = self.start();
+        let kind = match self.lexer.current() {
+            symbol::Symbol::Ident => ast::ExpressionKind::Name(self.parse_ident("Expression expected")?),
+            symbol::Symbol::Number => ast::ExpressionKind::Number(self.parse_number()?),
+            symbol::Symbol::Lparen => {
+                self.advance();
+                let expression = self.parse_expression()?;
+                self.expect(symbol::Symbol::Rparen, "Expression factor should be Rparen )")?;
+                ast::ExpressionKind::Paren(Box::new(expression))
+            },
+            _ => return Err(self.error("Expression expected")),
+        };
+        Ok(ast::Expression {
+            kind,
+            span: start..self.last_end,
+        })
+    }
+
+    fn parse_ident(&mut self, message: &str) -> Result<ast::Ident, error::CompileError> {
+        if *self.lexer.current() != symbol::Symbol::Ident {
+            return Err(self.error(message));
+        }
+        let ident = ast::Ident {
+            name: self.lexer.current_content().to_string(),
+            span: self.lexer.current_index(),
+        };
+        self.advance();
+        Ok(ident)
+    }
+
+    fn parse_number(&mut self) -> Result<i64, error::CompileError> {
+        match self.lexer.current_content().parse::<i64>() {
+            Ok(number) => {
+                self.advance();
+                Ok(number)
+            },
+            Err(_) => Err(self.error("Number is too large")),
+        }
+    }
+
+    fn expect(&mut self, sym: symbol::Symbol, message: &str) -> Result<(), error::CompileError> {
+        if *self.lexer.current() != sym {
+            return Err(self.error(message));
+        }
+        self.advance();
+        Ok(())
+    }
+
+    fn advance(&mut self) {
+        self.last_end = self.lexer.current_index().end;
+        self.lexer.next();
+    }
+
+    // Where the current symbol begins
+    fn start(&self) -> usize {
+        self.lexer.current_index().start
+    }
+
+    fn error(&self, message: &str) -> error::CompileError {
+        error::CompileError {
+            span: self.lexer.current_index(),
+            message: message.to_string(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ast;
+    use crate::ast::parser;
+
+    /* test declarations are kept in order with their spans */
+    #[test]
+    fn test_declarations() {
+        let program = parser::parse("var x; const a = 1, b = 2;\nprocedure p; ;\nx := a.").unwrap();
+        let declarations = &program.block.declarations;
+
+        assert_eq!(declarations.len(), 3);
+        assert_eq!(declarations[0].span, 0..6);
+        match &declarations[1].kind {
+            ast::DeclarationKind::Const(constants) => {
+                assert_eq!(constants[1].name.name, "b");
+                assert_eq!(constants[1].value, 2);
+            },
+            kind => panic!("unexpected declaration {:?}", kind),
+        }
+        match &declarations[2].kind {
+            ast::DeclarationKind::Procedure(procedure) => {
+                assert_eq!(procedure.name.name, "p");
+                assert_eq!(procedure.block.statement.kind, ast::StatementKind::Empty);
+            },
+            kind => panic!("unexpected declaration {:?}", kind),
+        }
+        assert_eq!(program.block.statement.span, 42..48);
+    }
+
+    /* test a leading minus negates the first term */
+    #[test]
+    fn test_expression_shape() {
+        let program = parser::parse("x := -a * b + (c - 1).").unwrap();
+        let expression = match program.block.statement.kind {
+            ast::StatementKind::Assign(_, expression) => expression,
+            kind => panic!("unexpected statement {:?}", kind),
+        };
+
+        match expression.kind {
+            ast::ExpressionKind::Binary(left, ast::BinaryOperator::Add, right) => {
+                assert!(matches!(left.kind, ast::ExpressionKind::Negate(_)));
+                assert_eq!(left.span, 5..11);
+                assert!(matches!(right.kind, ast::ExpressionKind::Paren(_)));
+            },
+            kind => panic!("unexpected expression {:?}", kind),
+        }
+    }
+
+    /* test comments are collected and errors located */
+    #[test]
+    fn test_comments_and_errors() {
+        let program = parser::parse("{ a } begin (* b *) end. { c }").unwrap();
+        assert_eq!(program.comments, vec![0..5, 12..19, 25..30]);
+
+        let error = parser::parse("begin x := 1 y := 2 end.").unwrap_err();
+        assert_eq!(error.message, "Statement is not ended with ;");
+        assert_eq!(error.span, 13..14);
+
+        assert_eq!(parser::parse("x := 1. y").unwrap_err().message, "Unexpected symbol after .");
+    }
+}
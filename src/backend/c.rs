@@ -0,0 +1,328 @@
+use std::fmt::Write;
+
+use crate::ast;
+use crate::backend;
+
+const INDENT: &str = "    ";
+
+const RESERVED: [&str; 38] = [
+    "auto", "break", "case", "char", "const", "continue", "default", "do", "double", "else",
+    "enum", "extern", "float", "for", "goto", "if", "inline", "int", "long", "register",
+    "restrict", "return", "short", "signed", "sizeof", "static", "struct", "switch", "typedef", "union",
+    "unsigned", "void", "volatile", "while", "_Bool", "_Complex", "_Imaginary",
+    "link",     // static link field of the frames
+];
+
+// Checked arithmetic and stdio based read and write, like the vm, inline
+// so that the unused ones are not reported
+const RUNTIME: &str = r#"#include <ctype.h>
+#include <errno.h>
+#include <inttypes.h>
+#include <stdint.h>
+#include <stdio.h>
+#include <stdlib.h>
+
+static inline void pl0_error(const char *message)
+{
+    fflush(stdout);
+    fprintf(stderr, "%s\n", message);
+    exit(1);
+}
+
+static inline int64_t pl0_add(int64_t a, int64_t b)
+{
+    if ((b > 0 && a > INT64_MAX - b) || (b < 0 && a < INT64_MIN - b))
+        pl0_error("Arithmetic overflow");
+    return a + b;
+}
+
+static inline int64_t pl0_sub(int64_t a, int64_t b)
+{
+    if ((b < 0 && a > INT64_MAX + b) || (b > 0 && a < INT64_MIN + b))
+        pl0_error("Arithmetic overflow");
+    return a - b;
+}
+
+static inline int64_t pl0_mul(int64_t a, int64_t b)
+{
+    if (a > 0 ? (b > 0 ? a > INT64_MAX / b : b < INT64_MIN / a)
+              : (b > 0 ? a < INT64_MIN / b : a != 0 && b < INT64_MAX / a))
+        pl0_error("Arithmetic overflow");
+    return a * b;
+}
+
+static inline int64_t pl0_div(int64_t a, int64_t b)
+{
+    if (b == 0)
+        pl0_error("Division by zero");
+    if (a == INT64_MIN && b == -1)
+        pl0_error("Arithmetic overflow");
+    return a / b;
+}
+
+static inline int64_t pl0_neg(int64_t a)
+{
+    if (a == INT64_MIN)
+        pl0_error("Arithmetic overflow");
+    return -a;
+}
+
+static inline void pl0_write(int64_t value)
+{
+    printf("%" PRId64 " ", value);
+}
+
+static inline void pl0_newline(void)
+{
+    putchar('\n');
+}
+
+static inline int64_t pl0_read(void)
+{
+    char line[256];
+    char *start = line;
+    char *end;
+    long long value;
+
+    printf("?");
+    fflush(stdout);
+    if (fgets(line, sizeof line, stdin) == NULL)
+        pl0_error("Invalid number");
+    while (isspace((unsigned char) *start))
+        start++;
+    errno = 0;
+    value = strtoll(start, &end, 10);
+    while (isspace((unsigned char) *end))
+        end++;
+    if (end == start || *end != '\0' || errno == ERANGE)
+        pl0_error("Invalid number");
+    return (int64_t) value;
+}
+"#;
+
+
+// Emit a standalone C99 program, each procedure is a function with its
+// variables in a frame structure linked to the frame of the enclosing one
+pub fn emit(program: &backend::Program) -> String {
+    let names: Vec<String> = program.procedures.iter().enumerate()
+        .map(|(index, procedure)| if index == 0 { String::from("main") } else { procedure.name.clone() })
+        .collect();
+    let functions: Vec<String> = backend::identifiers(&names, &[])
+        .iter()
+        .map(|name| format!("proc_{}", name))
+        .collect();
+    let fields: Vec<Vec<String>> = program.procedures.iter()
+        .map(|procedure| backend::identifiers(&procedure.variables, &RESERVED))
+        .collect();
+
+    let mut emitter = Emitter {
+        program,
+        functions: &functions,
+        fields: &fields,
+        output: String::new(),
+    };
+    emitter.output.push_str("/* Generated by pl0rust */\n");
+    emitter.output.push_str(RUNTIME);
+    emitter.frames();
+    emitter.prototypes();
+    for index in 0..program.procedures.len() {
+        emitter.function(index);
+    }
+    emitter.output.push_str("\nint main(void)\n{\n");
+    writeln!(emitter.output, "{}{}();", INDENT, functions[0]).unwrap();
+    writeln!(emitter.output, "{}return 0;\n}}", INDENT).unwrap();
+    emitter.output
+}
+
+struct Emitter<'a> {
+    program: &'a backend::Program,
+    functions: &'a [String],
+    fields: &'a [Vec<String>],
+    output: String,
+}
+
+impl Emitter<'_> {
+    fn frames(&mut self) {
+        for (index, procedure) in self.program.procedures.iter().enumerate() {
+            writeln!(self.output, "\nstruct frame_{} {{", self.functions[index]).unwrap();
+            if let Some(parent) = procedure.parent {
+                writeln!(self.output, "{}struct frame_{} *link;", INDENT, self.functions[parent]).unwrap();
+            } else if procedure.variables.is_empty() {
+                // A structure cannot be empty in C
+                writeln!(self.output, "{}char unused;", INDENT).unwrap();
+            }
+            for field in &self.fields[index] {
+                writeln!(self.output, "{}int64_t {};", INDENT, field).unwrap();
+            }
+            self.output.push_str("};\n");
+        }
+    }
+
+    fn prototypes(&mut self) {
+        self.output.push('\n');
+        for index in 0..self.program.procedures.len() {
+            writeln!(self.output, "static void {};", self.signature(index)).unwrap();
+        }
+    }
+
+    fn signature(&self, index: usize) -> String {
+        match self.program.procedures[index].parent {
+            Some(parent) => format!("{}(struct frame_{} *link)", self.functions[index], self.functions[parent]),
+            None => format!("{}(void)", self.functions[index]),
+        }
+    }
+
+    fn function(&mut self, index: usize) {
+        let procedure = &self.program.procedures[index];
+        match procedure.parent {
+            Some(_) => writeln!(self.output, "\n/* procedure {} */", procedure.name).unwrap(),
+            None => self.output.push_str("\n/* main program */\n"),
+        }
+        writeln!(self.output, "static void {}\n{{", self.signature(index)).unwrap();
+        let initializer = if procedure.parent.is_some() { "link" } else { "0" };
+        writeln!(self.output, "{}struct frame_{} frame = {{ {} }};", INDENT, self.functions[index], initializer).unwrap();
+        if procedure.parent.is_none() && self.fields[index].is_empty() {
+            writeln!(self.output, "{}(void) frame;", INDENT).unwrap();
+        }
+        self.statements(&procedure.body, 1);
+        self.output.push_str("}\n");
+    }
+
+    fn statements(&mut self, statement: &backend::Statement, indent: usize) {
+        let prefix = INDENT.repeat(indent);
+        match statement {
+            backend::Statement::Assign(variable, expression) => {
+                let expression = self.expression(expression);
+                writeln!(self.output, "{}{} = {};", prefix, self.variable(variable), expression).unwrap();
+            },
+            backend::Statement::Call { procedure: callee, depth } => {
+                let argument = match self.program.procedures[*callee].parent {
+                    Some(_) => self.frame(*depth, true),
+                    None => String::new(),
+                };
+                writeln!(self.output, "{}{}({});", prefix, self.functions[*callee], argument).unwrap();
+            },
+            backend::Statement::Read(variables) => {
+                for variable in variables {
+                    writeln!(self.output, "{}{} = pl0_read();", prefix, self.variable(variable)).unwrap();
+                }
+            },
+            backend::Statement::Write(expressions) => {
+                for expression in expressions {
+                    let expression = self.expression(expression);
+                    writeln!(self.output, "{}pl0_write({});", prefix, expression).unwrap();
+                }
+                writeln!(self.output, "{}pl0_newline();", prefix).unwrap();
+            },
+            backend::Statement::Begin(statements) => {
+                for statement in statements {
+                    self.statements(statement, indent);
+                }
+            },
+            backend::Statement::If(condition, body) => {
+                let condition = self.condition(condition);
+                writeln!(self.output, "{}if ({}) {{", prefix, condition).unwrap();
+                self.statements(body, indent + 1);
+                writeln!(self.output, "{}}}", prefix).unwrap();
+            },
+            backend::Statement::While(condition, body) => {
+                let condition = self.condition(condition);
+                writeln!(self.output, "{}while ({}) {{", prefix, condition).unwrap();
+                self.statements(body, indent + 1);
+                writeln!(self.output, "{}}}", prefix).unwrap();
+            },
+            backend::Statement::Empty => {},
+        }
+    }
+
+    fn condition(&self, condition: &backend::Condition) -> String {
+        match condition {
+            backend::Condition::Odd(expression) => format!("{} % 2 != 0", self.expression(expression)),
+            backend::Condition::Compare(left, relation, right) => {
+                let operator = match relation {
+                    ast::Relation::Eq => "==",
+                    relation => relation.symbol(),
+                };
+                format!("{} {} {}", self.expression(left), operator, self.expression(right))
+            },
+        }
+    }
+
+    fn expression(&self, expression: &backend::Expression) -> String {
+        match expression {
+            backend::Expression::Number(number) => number.to_string(),
+            backend::Expression::Variable(variable) => self.variable(variable),
+            backend::Expression::Negate(operand) => format!("pl0_neg({})", self.expression(operand)),
+            backend::Expression::Binary(left, operator, right) => {
+                let function = match operator {
+                    ast::BinaryOperator::Add => "pl0_add",
+                    ast::BinaryOperator::Sub => "pl0_sub",
+                    ast::BinaryOperator::Mul => "pl0_mul",
+                    ast::BinaryOperator::Div => "pl0_div",
+                };
+                format!("{}({}, {})", function, self.expression(left), self.expression(right))
+            },
+        }
+    }
+
+    fn variable(&self, variable: &backend::Variable) -> String {
+        format!("{}{}", self.frame(variable.depth, false), self.fields[variable.procedure][variable.index])
+    }
+
+    // Frame reached through depth static links, as a pointer or as the
+    // beginning of a member access
+    fn frame(&self, depth: usize, pointer: bool) -> String {
+        match (depth, pointer) {
+            (0, true) => String::from("&frame"),
+            (0, false) => String::from("frame."),
+            (depth, true) => format!("frame.link{}", "->link".repeat(depth - 1)),
+            (depth, false) => format!("frame.link{}->", "->link".repeat(depth - 1)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::process;
+
+    use crate::backend;
+
+    /* test frames are linked to the frame of the enclosing procedure */
+    #[test]
+    fn test_emit() {
+        let program = backend::compile("var x; procedure p; var int; begin int := x * 2; x := -int end;
+begin x := 3; call p; write(x) end.").unwrap();
+        let output = backend::c::emit(&program);
+
+        assert!(output.contains("struct frame_proc_p {\n    struct frame_proc_main *link;\n    int64_t int_;\n};"));
+        assert!(output.contains("    frame.int_ = pl0_mul(frame.link->x, 2);\n    frame.link->x = pl0_neg(frame.int_);\n"));
+        assert!(output.contains("    frame.x = 3;\n    proc_p(&frame);\n    pl0_write(frame.x);\n    pl0_newline();\n"));
+    }
+
+    /* test the compiled programs print what the vm prints */
+    #[test]
+    fn test_programs() {
+        if !backend::tests::available("c programs", &["cc"]) {
+            return;
+        }
+        let directory = backend::tests::temp_dir("c");
+        let source = directory.join("program.c");
+        let binary = directory.join("program");
+
+        for (name, content) in backend::tests::programs() {
+            fs::write(&source, backend::c::emit(&backend::compile(&content).unwrap())).unwrap();
+            let status = process::Command::new("cc")
+                .args(["-std=c99", "-Wall", "-Werror", "-o"])
+                .arg(&binary)
+                .arg(&source)
+                .status()
+                .unwrap();
+            assert!(status.success(), "{}", name);
+
+            let output = backend::tests::run(process::Command::new(&binary));
+            assert_eq!(output, backend::tests::vm_output(&content), "{}", name);
+        }
+        fs::remove_dir_all(&directory).unwrap();
+    }
+}
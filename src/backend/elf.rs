@@ -0,0 +1,358 @@
+use std::collections::HashMap;
+use std::convert::TryFrom;
+
+use crate::backend;
+use crate::backend::x86_64::{ Assembly, Condition, Instruction, Line, Register };
+
+
+// Where the file is mapped, the usual address of static executables
+const BASE_ADDRESS: u64 = 0x400000;
+const ELF_HEADER_SIZE: usize = 64;
+const PROGRAM_HEADER_SIZE: usize = 56;
+const PROGRAM_HEADERS: usize = 2;
+const HEADERS_SIZE: usize = ELF_HEADER_SIZE + PROGRAM_HEADERS * PROGRAM_HEADER_SIZE;
+
+/*
+ * Static Linux x86-64 executable of the program, made of the code of the
+ * assembly backend encoded here, without assembler nor linker. The whole
+ * file is one read only and executable segment: headers, code, then the
+ * data of the runtime.
+ */
+pub fn write(program: &backend::Program) -> Vec<u8> {
+    let assembly = backend::x86_64::assemble(program);
+    let code_address = BASE_ADDRESS + HEADERS_SIZE as u64;
+    let (code, labels) = encode(&assembly, code_address);
+    let entry = labels["_start"];
+
+    let mut file = Vec::with_capacity(HEADERS_SIZE + code.len());
+    elf_header(&mut file, entry);
+    // The code and its data
+    program_header(&mut file, PT_LOAD, PF_R | PF_X, (HEADERS_SIZE + code.len()) as u64);
+    // Ask for a stack which is not executable
+    program_header(&mut file, PT_GNU_STACK, PF_R | PF_W, 0);
+    file.extend_from_slice(&code);
+    file
+}
+
+const PT_LOAD: u32 = 1;
+const PT_GNU_STACK: u32 = 0x6474e551;
+const PF_X: u32 = 1;
+const PF_W: u32 = 2;
+const PF_R: u32 = 4;
+
+fn elf_header(file: &mut Vec<u8>, entry: u64) {
+    file.extend_from_slice(&[0x7f, b'E', b'L', b'F']);
+    file.push(2);   // 64 bits
+    file.push(1);   // little endian
+    file.push(1);   // version
+    file.push(0);   // System V ABI
+    file.extend_from_slice(&[0; 8]);
+    file.extend_from_slice(&2u16.to_le_bytes());    // executable
+    file.extend_from_slice(&62u16.to_le_bytes());   // x86-64
+    file.extend_from_slice(&1u32.to_le_bytes());
+    file.extend_from_slice(&entry.to_le_bytes());
+    file.extend_from_slice(&(ELF_HEADER_SIZE as u64).to_le_bytes());   // program headers
+    file.extend_from_slice(&0u64.to_le_bytes());                        // no section headers
+    file.extend_from_slice(&0u32.to_le_bytes());                        // flags
+    file.extend_from_slice(&(ELF_HEADER_SIZE as u16).to_le_bytes());
+    file.extend_from_slice(&(PROGRAM_HEADER_SIZE as u16).to_le_bytes());
+    file.extend_from_slice(&(PROGRAM_HEADERS as u16).to_le_bytes());
+    file.extend_from_slice(&64u16.to_le_bytes());   // size of a section header
+    file.extend_from_slice(&0u16.to_le_bytes());
+    file.extend_from_slice(&0u16.to_le_bytes());
+}
+
+fn program_header(file: &mut Vec<u8>, kind: u32, flags: u32, size: u64) {
+    file.extend_from_slice(&kind.to_le_bytes());
+    file.extend_from_slice(&flags.to_le_bytes());
+    let address = if kind == PT_LOAD { BASE_ADDRESS } else { 0 };
+    file.extend_from_slice(&0u64.to_le_bytes());         // offset in the file
+    file.extend_from_slice(&address.to_le_bytes());     // virtual address
+    file.extend_from_slice(&address.to_le_bytes());     // physical address
+    file.extend_from_slice(&size.to_le_bytes());        // in the file
+    file.extend_from_slice(&size.to_le_bytes());        // in memory
+    file.extend_from_slice(&0x1000u64.to_le_bytes());   // alignment
+}
+
+// Machine code of the text then the data, and the address of each label
+// when the code is loaded at address
+pub fn encode(assembly: &Assembly, address: u64) -> (Vec<u8>, HashMap<String, u64>) {
+    let mut encoder = Encoder {
+        code: Vec::new(),
+        fixups: Vec::new(),
+    };
+    let mut labels: HashMap<String, u64> = HashMap::new();
+    for line in &assembly.text {
+        match line {
+            Line::Label(label) => {
+                labels.insert(label.clone(), address + encoder.code.len() as u64);
+            },
+            Line::Instruction(instruction) => encoder.instruction(instruction),
+        }
+    }
+    for (label, bytes) in &assembly.data {
+        labels.insert(label.clone(), address + encoder.code.len() as u64);
+        encoder.code.extend_from_slice(bytes);
+    }
+
+    // Every reference to a label is 32 bits relative to the end of its instruction
+    for (position, label) in &encoder.fixups {
+        let target = labels[label] as i64;
+        let next = (address + *position as u64 + 4) as i64;
+        let relative = (target - next) as i32;
+        encoder.code[*position..*position + 4].copy_from_slice(&relative.to_le_bytes());
+    }
+    (encoder.code, labels)
+}
+
+struct Encoder {
+    code: Vec<u8>,
+    fixups: Vec<(usize, String)>,   // (position of a 32 bits displacement, label)
+}
+
+impl Encoder {
+    fn instruction(&mut self, instruction: &Instruction) {
+        match instruction {
+            Instruction::MovImmediate(register, value) => match i32::try_from(*value) {
+                Ok(value) => {
+                    self.register_operation(&[0xc7], 0, *register);
+                    self.code.extend_from_slice(&value.to_le_bytes());
+                },
+                Err(_) => {
+                    self.code.push(rex(true, 0, register.number()));
+                    self.code.push(0xb8 + (register.number() & 7));
+                    self.code.extend_from_slice(&value.to_le_bytes());
+                },
+            },
+            Instruction::Mov(destination, source) => self.registers(&[0x89], *source, *destination),
+            Instruction::Load(destination, base, displacement) => {
+                self.memory(true, &[0x8b], destination.number(), *base, *displacement);
+            },
+            Instruction::Store(base, displacement, source) => {
+                self.memory(true, &[0x89], source.number(), *base, *displacement);
+            },
+            Instruction::LoadByte(destination, base, displacement) => {
+                self.memory(true, &[0x0f, 0xb6], destination.number(), *base, *displacement);
+            },
+            Instruction::StoreByte(base, displacement, source) => {
+                // Without REX the low bytes of rsp, rbp, rsi and rdi are ah, ch, dh and bh
+                self.memory(false, &[0x88], source.number(), *base, *displacement);
+            },
+            Instruction::Lea(destination, base, displacement) => {
+                self.memory(true, &[0x8d], destination.number(), *base, *displacement);
+            },
+            Instruction::LeaLabel(destination, label) => {
+                self.code.push(rex(true, destination.number(), 0));
+                self.code.push(0x8d);
+                self.code.push(modrm(0, destination.number(), 5));
+                self.label(label);
+            },
+            Instruction::Push(register) => {
+                if register.number() >= 8 {
+                    self.code.push(0x41);
+                }
+                self.code.push(0x50 + (register.number() & 7));
+            },
+            Instruction::PushImmediate(value) => {
+                self.code.push(0x68);
+                self.code.extend_from_slice(&value.to_le_bytes());
+            },
+            Instruction::Pop(register) => {
+                if register.number() >= 8 {
+                    self.code.push(0x41);
+                }
+                self.code.push(0x58 + (register.number() & 7));
+            },
+            Instruction::Add(destination, source) => self.registers(&[0x01], *source, *destination),
+            Instruction::Sub(destination, source) => self.registers(&[0x29], *source, *destination),
+            Instruction::Imul(destination, source) => self.registers(&[0x0f, 0xaf], *destination, *source),
+            Instruction::Xor(destination, source) => self.registers(&[0x31], *source, *destination),
+            Instruction::Cmp(first, second) => self.registers(&[0x39], *second, *first),
+            Instruction::Test(first, second) => self.registers(&[0x85], *second, *first),
+            Instruction::AddImmediate(register, value) => self.immediate(0, *register, *value),
+            Instruction::SubImmediate(register, value) => self.immediate(5, *register, *value),
+            Instruction::CmpImmediate(register, value) => self.immediate(7, *register, *value),
+            Instruction::AndImmediate(register, value) => self.immediate(4, *register, *value),
+            Instruction::Neg(register) => self.register_operation(&[0xf7], 3, *register),
+            Instruction::Cqo => self.code.extend_from_slice(&[0x48, 0x99]),
+            Instruction::Idiv(register) => self.register_operation(&[0xf7], 7, *register),
+            Instruction::Set(condition, register) => {
+                self.code.push(rex(false, 0, register.number()));
+                self.code.extend_from_slice(&[0x0f, 0x90 + condition_code(*condition)]);
+                self.code.push(modrm(3, 0, register.number()));
+            },
+            Instruction::ZeroExtendByte(register) => self.registers(&[0x0f, 0xb6], *register, *register),
+            Instruction::Jump(label) => {
+                self.code.push(0xe9);
+                self.label(label);
+            },
+            Instruction::JumpIf(condition, label) => {
+                self.code.extend_from_slice(&[0x0f, 0x80 + condition_code(*condition)]);
+                self.label(label);
+            },
+            Instruction::Call(label) => {
+                self.code.push(0xe8);
+                self.label(label);
+            },
+            Instruction::Ret => self.code.push(0xc3),
+            Instruction::Leave => self.code.push(0xc9),
+            Instruction::Syscall => self.code.extend_from_slice(&[0x0f, 0x05]),
+        }
+    }
+
+    // 64 bits operation with the register field and a register operand
+    fn registers(&mut self, opcode: &[u8], field: Register, operand: Register) {
+        self.code.push(rex(true, field.number(), operand.number()));
+        self.code.extend_from_slice(opcode);
+        self.code.push(modrm(3, field.number(), operand.number()));
+    }
+
+    // 64 bits operation selected by the register field, on a register operand
+    fn register_operation(&mut self, opcode: &[u8], extension: u8, operand: Register) {
+        self.code.push(rex(true, 0, operand.number()));
+        self.code.extend_from_slice(opcode);
+        self.code.push(modrm(3, extension, operand.number()));
+    }
+
+    fn immediate(&mut self, extension: u8, operand: Register, value: i32) {
+        self.register_operation(&[0x81], extension, operand);
+        self.code.extend_from_slice(&value.to_le_bytes());
+    }
+
+    // Operation on the memory at base + displacement
+    fn memory(&mut self, wide: bool, opcode: &[u8], field: u8, base: Register, displacement: i32) {
+        self.code.push(rex(wide, field, base.number()));
+        self.code.extend_from_slice(opcode);
+
+        let short = i8::try_from(displacement);
+        // No displacement with rbp or r13 as base means rip relative
+        let mode = match short {
+            Ok(0) if base.number() & 7 != 5 => 0,
+            Ok(_) => 1,
+            Err(_) => 2,
+        };
+        self.code.push(modrm(mode, field, base.number()));
+        // rsp or r12 as base needs a scale index byte
+        if base.number() & 7 == 4 {
+            self.code.push(0x24);
+        }
+        match mode {
+            1 => self.code.push(displacement as u8),
+            2 => self.code.extend_from_slice(&displacement.to_le_bytes()),
+            _ => {},
+        }
+    }
+
+    fn label(&mut self, label: &str) {
+        self.fixups.push((self.code.len(), label.to_string()));
+        self.code.extend_from_slice(&[0; 4]);
+    }
+}
+
+fn rex(wide: bool, field: u8, operand: u8) -> u8 {
+    0x40 | (wide as u8) << 3 | (field >> 3) << 2 | operand >> 3
+}
+
+fn modrm(mode: u8, field: u8, operand: u8) -> u8 {
+    mode << 6 | (field & 7) << 3 | (operand & 7)
+}
+
+fn condition_code(condition: Condition) -> u8 {
+    match condition {
+        Condition::Overflow => 0x0,
+        Condition::Equal => 0x4,
+        Condition::NotEqual => 0x5,
+        Condition::BelowEqual => 0x6,
+        Condition::Above => 0x7,
+        Condition::Less => 0xc,
+        Condition::GreaterEqual => 0xd,
+        Condition::LessEqual => 0xe,
+        Condition::Greater => 0xf,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::process;
+
+    use crate::backend;
+    use crate::backend::elf;
+    use crate::backend::x86_64::{ Assembly, Condition, Instruction, Line, Register };
+
+    fn encode(instructions: Vec<Instruction>) -> Vec<u8> {
+        let assembly = Assembly {
+            text: instructions.into_iter().map(Line::Instruction).collect(),
+            data: Vec::new(),
+        };
+        elf::encode(&assembly, 0).0
+    }
+
+    /* test the encoding of the instructions, checked against GNU as */
+    #[test]
+    fn test_encode() {
+        assert_eq!(encode(vec![Instruction::MovImmediate(Register::Rax, -1)]), vec![0x48, 0xc7, 0xc0, 0xff, 0xff, 0xff, 0xff]);
+        assert_eq!(encode(vec![Instruction::MovImmediate(Register::R8, 1 << 40)]),
+            vec![0x49, 0xb8, 0, 0, 0, 0, 0, 1, 0, 0]);
+        assert_eq!(encode(vec![Instruction::Mov(Register::Rbp, Register::Rsp)]), vec![0x48, 0x89, 0xe5]);
+        assert_eq!(encode(vec![Instruction::Load(Register::Rax, Register::Rbp, -16)]), vec![0x48, 0x8b, 0x45, 0xf0]);
+        assert_eq!(encode(vec![Instruction::Store(Register::Rsi, 0, Register::Rcx)]), vec![0x48, 0x89, 0x0e]);
+        assert_eq!(encode(vec![Instruction::Store(Register::Rbp, -1000, Register::R10)]),
+            vec![0x4c, 0x89, 0x95, 0x18, 0xfc, 0xff, 0xff]);
+        assert_eq!(encode(vec![Instruction::StoreByte(Register::Rbp, -1, Register::Rdi)]), vec![0x40, 0x88, 0x7d, 0xff]);
+        assert_eq!(encode(vec![Instruction::LoadByte(Register::Rax, Register::Rsi, 0)]), vec![0x48, 0x0f, 0xb6, 0x06]);
+        assert_eq!(encode(vec![Instruction::Lea(Register::Rsi, Register::Rsp, 8)]), vec![0x48, 0x8d, 0x74, 0x24, 0x08]);
+        assert_eq!(encode(vec![Instruction::Push(Register::R9), Instruction::Pop(Register::Rcx)]), vec![0x41, 0x51, 0x59]);
+        assert_eq!(encode(vec![Instruction::PushImmediate(-2)]), vec![0x68, 0xfe, 0xff, 0xff, 0xff]);
+        assert_eq!(encode(vec![Instruction::Imul(Register::R10, Register::Rcx)]), vec![0x4c, 0x0f, 0xaf, 0xd1]);
+        assert_eq!(encode(vec![Instruction::Cmp(Register::Rsi, Register::R11)]), vec![0x4c, 0x39, 0xde]);
+        assert_eq!(encode(vec![Instruction::CmpImmediate(Register::Rcx, -1)]), vec![0x48, 0x81, 0xf9, 0xff, 0xff, 0xff, 0xff]);
+        assert_eq!(encode(vec![Instruction::Idiv(Register::R8), Instruction::Cqo]), vec![0x49, 0xf7, 0xf8, 0x48, 0x99]);
+        assert_eq!(encode(vec![Instruction::Set(Condition::LessEqual, Register::Rax), Instruction::ZeroExtendByte(Register::Rax)]),
+            vec![0x40, 0x0f, 0x9e, 0xc0, 0x48, 0x0f, 0xb6, 0xc0]);
+    }
+
+    /* test jumps, calls and data addresses are relative to the next instruction */
+    #[test]
+    fn test_labels() {
+        let assembly = Assembly {
+            text: vec![
+                Line::Label(String::from("start")),
+                Line::Instruction(Instruction::JumpIf(Condition::Overflow, String::from("end"))),
+                Line::Instruction(Instruction::LeaLabel(Register::Rsi, String::from("message"))),
+                Line::Label(String::from("end")),
+                Line::Instruction(Instruction::Call(String::from("start"))),
+            ],
+            data: vec![(String::from("message"), b"hi".to_vec())],
+        };
+        let (code, labels) = elf::encode(&assembly, 0x1000);
+
+        assert_eq!(code, vec![
+            0x0f, 0x80, 7, 0, 0, 0,
+            0x48, 0x8d, 0x35, 5, 0, 0, 0,
+            0xe8, 0xee, 0xff, 0xff, 0xff,
+            b'h', b'i',
+        ]);
+        assert_eq!(labels["end"], 0x100d);
+    }
+
+    /* test the executables print what the vm prints */
+    #[test]
+    fn test_programs() {
+        if !cfg!(all(target_os = "linux", target_arch = "x86_64")) {
+            backend::tests::skip("elf programs", "not an x86-64 Linux host");
+            return;
+        }
+        let directory = backend::tests::temp_dir("elf");
+        let binary = directory.join("program");
+
+        for (name, content) in backend::tests::programs() {
+            fs::write(&binary, elf::write(&backend::compile(&content).unwrap())).unwrap();
+            process::Command::new("chmod").arg("+x").arg(&binary).status().unwrap();
+
+            let output = backend::tests::run(process::Command::new(&binary));
+            assert_eq!(output, backend::tests::vm_output(&content), "{}", name);
+        }
+        fs::remove_dir_all(&directory).unwrap();
+    }
+}
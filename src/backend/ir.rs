@@ -0,0 +1,925 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::fmt::Write;
+
+use crate::ast;
+use crate::backend;
+
+
+/*
+ * Register based code of a procedure, made of basic blocks of instructions
+ * on virtual registers, each block ending with a jump, a branch or the
+ * return. The variables no nested procedure reaches live in registers in
+ * SSA form, with phi nodes where control flow joins; the others stay in
+ * the frame and are loaded and stored like the vm does.
+ */
+#[derive(Clone, Debug, PartialEq)]
+pub struct Function {
+    pub procedure: usize,
+    pub blocks: Vec<Block>,         // the entry block first
+    pub registers: usize,
+    pub promoted: Vec<bool>,        // by variable, whether it lives in registers
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Block {
+    pub phis: Vec<Phi>,
+    pub instructions: Vec<Instruction>,
+    pub terminator: Terminator,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Phi {
+    pub register: Register,
+    pub variable: usize,
+    pub sources: Vec<(usize, Register)>,    // value coming from each predecessor
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Register(pub usize);
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Instruction {
+    Constant(Register, i64),
+    Negate(Register, Register),     // stops on overflow like the vm
+    Binary(Register, ast::BinaryOperator, Register, Register),
+    Compare(Register, ast::Relation, Register, Register),  // 1 if true, 0 if false
+    Odd(Register, Register),
+    Load(Register, backend::Variable),
+    Store(backend::Variable, Register),
+    Read(Register),
+    Write(Register),
+    Newline,
+    Call { procedure: usize, depth: usize },
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Terminator {
+    Jump(usize),
+    Branch(Register, usize, usize), // to the first block if not 0, else to the second
+    Return,
+}
+
+impl fmt::Display for Register {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "%{}", self.0)
+    }
+}
+
+impl fmt::Display for Terminator {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Terminator::Jump(target) => write!(f, "jump b{}", target),
+            Terminator::Branch(condition, then, otherwise) => write!(f, "branch {} b{} b{}", condition, then, otherwise),
+            Terminator::Return => write!(f, "return"),
+        }
+    }
+}
+
+impl Instruction {
+    pub fn result(&self) -> Option<Register> {
+        match self {
+            Instruction::Constant(result, _)
+            | Instruction::Negate(result, _)
+            | Instruction::Binary(result, _, _, _)
+            | Instruction::Compare(result, _, _, _)
+            | Instruction::Odd(result, _)
+            | Instruction::Load(result, _)
+            | Instruction::Read(result) => Some(*result),
+            Instruction::Store(_, _) | Instruction::Write(_) | Instruction::Newline | Instruction::Call { .. } => None,
+        }
+    }
+
+    pub fn operands(&self) -> Vec<Register> {
+        match self {
+            Instruction::Negate(_, operand)
+            | Instruction::Odd(_, operand)
+            | Instruction::Store(_, operand)
+            | Instruction::Write(operand) => vec![*operand],
+            Instruction::Binary(_, _, left, right) | Instruction::Compare(_, _, left, right) => vec![*left, *right],
+            Instruction::Constant(_, _)
+            | Instruction::Load(_, _)
+            | Instruction::Read(_)
+            | Instruction::Newline
+            | Instruction::Call { .. } => Vec::new(),
+        }
+    }
+
+    fn rename(&mut self, from: Register, to: Register) {
+        let rename = |register: &mut Register| if *register == from { *register = to };
+        match self {
+            Instruction::Negate(_, operand)
+            | Instruction::Odd(_, operand)
+            | Instruction::Store(_, operand)
+            | Instruction::Write(operand) => rename(operand),
+            Instruction::Binary(_, _, left, right) | Instruction::Compare(_, _, left, right) => {
+                rename(left);
+                rename(right);
+            },
+            Instruction::Constant(_, _)
+            | Instruction::Load(_, _)
+            | Instruction::Read(_)
+            | Instruction::Newline
+            | Instruction::Call { .. } => {},
+        }
+    }
+}
+
+impl Terminator {
+    pub fn successors(&self) -> Vec<usize> {
+        match self {
+            Terminator::Jump(target) => vec![*target],
+            Terminator::Branch(_, then, otherwise) => vec![*then, *otherwise],
+            Terminator::Return => Vec::new(),
+        }
+    }
+}
+
+impl Function {
+    // Predecessors of every block, in the order of the blocks
+    pub fn predecessors(&self) -> Vec<Vec<usize>> {
+        let mut predecessors = vec![Vec::new(); self.blocks.len()];
+        for (index, block) in self.blocks.iter().enumerate() {
+            for successor in block.terminator.successors() {
+                if successor < self.blocks.len() && !predecessors[successor].contains(&index) {
+                    predecessors[successor].push(index);
+                }
+            }
+        }
+        predecessors
+    }
+}
+
+pub fn lower(program: &backend::Program) -> Vec<Function> {
+    let escaping = escaping(program);
+    program.procedures.iter().enumerate()
+        .map(|(index, procedure)| {
+            let promoted: Vec<bool> = escaping[index].iter().map(|escaping| !escaping).collect();
+            let mut builder = Builder {
+                procedure: index,
+                promoted: &promoted,
+                blocks: Vec::new(),
+                predecessors: Vec::new(),
+                sealed: Vec::new(),
+                incomplete: Vec::new(),
+                definitions: HashMap::new(),
+                registers: 0,
+                current: 0,
+            };
+            let entry = builder.block();
+            builder.seal(entry);
+            builder.statement(&procedure.body);
+            let mut function = Function {
+                procedure: index,
+                blocks: builder.blocks,
+                registers: builder.registers,
+                promoted: promoted.clone(),
+            };
+            simplify(&mut function);
+            function
+        })
+        .collect()
+}
+
+// Variables some nested procedure reaches through the static links, by procedure
+fn escaping(program: &backend::Program) -> Vec<Vec<bool>> {
+    fn variable(variable: &backend::Variable, escaping: &mut [Vec<bool>]) {
+        if variable.depth > 0 {
+            escaping[variable.procedure][variable.index] = true;
+        }
+    }
+
+    fn expression(value: &backend::Expression, escaping: &mut [Vec<bool>]) {
+        match value {
+            backend::Expression::Number(_) => {},
+            backend::Expression::Variable(used) => variable(used, escaping),
+            backend::Expression::Negate(operand) => expression(operand, escaping),
+            backend::Expression::Binary(left, _, right) => {
+                expression(left, escaping);
+                expression(right, escaping);
+            },
+        }
+    }
+
+    fn condition(test: &backend::Condition, escaping: &mut [Vec<bool>]) {
+        match test {
+            backend::Condition::Odd(value) => expression(value, escaping),
+            backend::Condition::Compare(left, _, right) => {
+                expression(left, escaping);
+                expression(right, escaping);
+            },
+        }
+    }
+
+    fn statement(current: &backend::Statement, escaping: &mut [Vec<bool>]) {
+        match current {
+            backend::Statement::Assign(target, value) => {
+                variable(target, escaping);
+                expression(value, escaping);
+            },
+            backend::Statement::Read(targets) => {
+                for target in targets {
+                    variable(target, escaping);
+                }
+            },
+            backend::Statement::Write(values) => {
+                for value in values {
+                    expression(value, escaping);
+                }
+            },
+            backend::Statement::Begin(statements) => {
+                for inner in statements {
+                    statement(inner, escaping);
+                }
+            },
+            backend::Statement::If(test, body) | backend::Statement::While(test, body) => {
+                condition(test, escaping);
+                statement(body, escaping);
+            },
+            backend::Statement::Call { .. } | backend::Statement::Empty => {},
+        }
+    }
+
+    let mut escaping: Vec<Vec<bool>> = program.procedures.iter()
+        .map(|procedure| vec![false; procedure.variables.len()])
+        .collect();
+    for procedure in &program.procedures {
+        statement(&procedure.body, &mut escaping);
+    }
+    escaping
+}
+
+/*
+ * Builds the SSA form while lowering, after Braun et al., "Simple and
+ * Efficient Construction of Static Single Assignment Form": a variable read
+ * in a block looks for its definition in the predecessors, and a block is
+ * sealed once all its predecessors are known, until then the reads get a
+ * phi completed at sealing.
+ */
+struct Builder<'a> {
+    procedure: usize,
+    promoted: &'a [bool],
+    blocks: Vec<Block>,
+    predecessors: Vec<Vec<usize>>,
+    sealed: Vec<bool>,
+    incomplete: Vec<Vec<(usize, Register)>>,    // phis waiting for the sealing, by block
+    definitions: HashMap<(usize, usize), Register>, // (variable, block) to its value at the end of the block
+    registers: usize,
+    current: usize,
+}
+
+impl Builder<'_> {
+    fn block(&mut self) -> usize {
+        self.blocks.push(Block { phis: Vec::new(), instructions: Vec::new(), terminator: Terminator::Return });
+        self.predecessors.push(Vec::new());
+        self.sealed.push(false);
+        self.incomplete.push(Vec::new());
+        self.blocks.len() - 1
+    }
+
+    fn register(&mut self) -> Register {
+        self.registers += 1;
+        Register(self.registers - 1)
+    }
+
+    fn emit(&mut self, instruction: Instruction) {
+        self.blocks[self.current].instructions.push(instruction);
+    }
+
+    // End the current block with the terminator
+    fn terminate(&mut self, terminator: Terminator) {
+        for successor in terminator.successors() {
+            self.predecessors[successor].push(self.current);
+        }
+        self.blocks[self.current].terminator = terminator;
+    }
+
+    fn seal(&mut self, block: usize) {
+        for (variable, phi) in std::mem::take(&mut self.incomplete[block]) {
+            self.phi_sources(variable, block, phi);
+        }
+        self.sealed[block] = true;
+    }
+
+    fn write_variable(&mut self, variable: usize, block: usize, value: Register) {
+        self.definitions.insert((variable, block), value);
+    }
+
+    fn read_variable(&mut self, variable: usize, block: usize) -> Register {
+        if let Some(value) = self.definitions.get(&(variable, block)) {
+            return *value;
+        }
+        let value = if !self.sealed[block] {
+            let phi = self.phi(variable, block);
+            self.incomplete[block].push((variable, phi));
+            phi
+        } else if self.predecessors[block].is_empty() {
+            // Variables start at 0 on entry
+            let value = self.register();
+            self.blocks[block].instructions.insert(0, Instruction::Constant(value, 0));
+            value
+        } else if self.predecessors[block].len() == 1 {
+            let predecessor = self.predecessors[block][0];
+            self.read_variable(variable, predecessor)
+        } else {
+            // Defined before looking at the predecessors to stop at loops
+            let phi = self.phi(variable, block);
+            self.write_variable(variable, block, phi);
+            self.phi_sources(variable, block, phi);
+            phi
+        };
+        self.write_variable(variable, block, value);
+        value
+    }
+
+    fn phi(&mut self, variable: usize, block: usize) -> Register {
+        let register = self.register();
+        self.blocks[block].phis.push(Phi { register, variable, sources: Vec::new() });
+        register
+    }
+
+    fn phi_sources(&mut self, variable: usize, block: usize, phi: Register) {
+        let mut sources = Vec::new();
+        for predecessor in self.predecessors[block].clone() {
+            sources.push((predecessor, self.read_variable(variable, predecessor)));
+        }
+        let phi = self.blocks[block].phis.iter_mut().find(|candidate| candidate.register == phi).unwrap();
+        phi.sources = sources;
+    }
+
+    fn in_register(&self, variable: &backend::Variable) -> bool {
+        variable.depth == 0 && variable.procedure == self.procedure && self.promoted[variable.index]
+    }
+
+    fn assign(&mut self, variable: &backend::Variable, value: Register) {
+        if self.in_register(variable) {
+            self.write_variable(variable.index, self.current, value);
+        } else {
+            self.emit(Instruction::Store(*variable, value));
+        }
+    }
+
+    fn statement(&mut self, current: &backend::Statement) {
+        match current {
+            backend::Statement::Assign(variable, value) => {
+                let value = self.expression(value);
+                self.assign(variable, value);
+            },
+            backend::Statement::Call { procedure, depth } => {
+                self.emit(Instruction::Call { procedure: *procedure, depth: *depth });
+            },
+            backend::Statement::Read(variables) => {
+                for variable in variables {
+                    let value = self.register();
+                    self.emit(Instruction::Read(value));
+                    self.assign(variable, value);
+                }
+            },
+            backend::Statement::Write(values) => {
+                for value in values {
+                    let value = self.expression(value);
+                    self.emit(Instruction::Write(value));
+                }
+                self.emit(Instruction::Newline);
+            },
+            backend::Statement::Begin(statements) => {
+                for inner in statements {
+                    self.statement(inner);
+                }
+            },
+            backend::Statement::If(test, body) => {
+                let test = self.condition(test);
+                let then = self.block();
+                let end = self.block();
+                self.terminate(Terminator::Branch(test, then, end));
+                self.seal(then);
+                self.current = then;
+                self.statement(body);
+                self.terminate(Terminator::Jump(end));
+                self.seal(end);
+                self.current = end;
+            },
+            backend::Statement::While(test, body) => {
+                let start = self.block();
+                self.terminate(Terminator::Jump(start));
+                self.current = start;
+                let test = self.condition(test);
+                let inner = self.block();
+                let end = self.block();
+                self.terminate(Terminator::Branch(test, inner, end));
+                self.seal(inner);
+                self.seal(end);
+                self.current = inner;
+                self.statement(body);
+                self.terminate(Terminator::Jump(start));
+                // The loop back is the last predecessor of the start
+                self.seal(start);
+                self.current = end;
+            },
+            backend::Statement::Empty => {},
+        }
+    }
+
+    fn condition(&mut self, test: &backend::Condition) -> Register {
+        match test {
+            backend::Condition::Odd(value) => {
+                let value = self.expression(value);
+                let result = self.register();
+                self.emit(Instruction::Odd(result, value));
+                result
+            },
+            backend::Condition::Compare(left, relation, right) => {
+                let left = self.expression(left);
+                let right = self.expression(right);
+                let result = self.register();
+                self.emit(Instruction::Compare(result, *relation, left, right));
+                result
+            },
+        }
+    }
+
+    fn expression(&mut self, value: &backend::Expression) -> Register {
+        match value {
+            backend::Expression::Number(number) => {
+                let result = self.register();
+                self.emit(Instruction::Constant(result, *number));
+                result
+            },
+            backend::Expression::Variable(variable) if self.in_register(variable) => {
+                self.read_variable(variable.index, self.current)
+            },
+            backend::Expression::Variable(variable) => {
+                let result = self.register();
+                self.emit(Instruction::Load(result, *variable));
+                result
+            },
+            backend::Expression::Negate(operand) => {
+                let operand = self.expression(operand);
+                let result = self.register();
+                self.emit(Instruction::Negate(result, operand));
+                result
+            },
+            backend::Expression::Binary(left, operator, right) => {
+                let left = self.expression(left);
+                let right = self.expression(right);
+                let result = self.register();
+                self.emit(Instruction::Binary(result, *operator, left, right));
+                result
+            },
+        }
+    }
+}
+
+/*
+ * Remove the phis which only merge one value besides themselves, which the
+ * construction leaves where a variable is not assigned in a loop or a
+ * branch, then number the registers again in the order of the blocks.
+ */
+fn simplify(function: &mut Function) {
+    loop {
+        let trivial = function.blocks.iter().enumerate()
+            .flat_map(|(block, current)| current.phis.iter().enumerate().map(move |(index, phi)| (block, index, phi)))
+            .find_map(|(block, index, phi)| {
+                let mut values = phi.sources.iter().map(|(_, value)| *value).filter(|value| *value != phi.register);
+                let first = values.next()?;
+                if values.all(|value| value == first) { Some((block, index, phi.register, first)) } else { None }
+            });
+        let (block, index, from, to) = match trivial {
+            Some(trivial) => trivial,
+            None => break,
+        };
+        function.blocks[block].phis.remove(index);
+        for current in &mut function.blocks {
+            for phi in &mut current.phis {
+                for (_, value) in &mut phi.sources {
+                    if *value == from {
+                        *value = to;
+                    }
+                }
+            }
+            for instruction in &mut current.instructions {
+                instruction.rename(from, to);
+            }
+            if let Terminator::Branch(condition, _, _) = &mut current.terminator {
+                if *condition == from {
+                    *condition = to;
+                }
+            }
+        }
+    }
+
+    let mut numbers = HashMap::new();
+    for block in &function.blocks {
+        let results = block.phis.iter().map(|phi| phi.register)
+            .chain(block.instructions.iter().filter_map(|instruction| instruction.result()));
+        for register in results {
+            let number = numbers.len();
+            numbers.entry(register).or_insert(Register(number));
+        }
+    }
+    let number = |register: &mut Register| *register = numbers[register];
+    for block in &mut function.blocks {
+        for phi in &mut block.phis {
+            number(&mut phi.register);
+            for (_, value) in &mut phi.sources {
+                number(value);
+            }
+        }
+        for instruction in &mut block.instructions {
+            match instruction {
+                Instruction::Constant(result, _) | Instruction::Load(result, _) | Instruction::Read(result) => number(result),
+                Instruction::Negate(result, operand) | Instruction::Odd(result, operand) => {
+                    number(result);
+                    number(operand);
+                },
+                Instruction::Binary(result, _, left, right) | Instruction::Compare(result, _, left, right) => {
+                    number(result);
+                    number(left);
+                    number(right);
+                },
+                Instruction::Store(_, operand) | Instruction::Write(operand) => number(operand),
+                Instruction::Newline | Instruction::Call { .. } => {},
+            }
+        }
+        if let Terminator::Branch(condition, _, _) = &mut block.terminator {
+            number(condition);
+        }
+    }
+    function.registers = numbers.len();
+}
+
+/*
+ * Check the function is in SSA form: every register is defined once and
+ * its definition dominates its uses, every phi has a value for each
+ * predecessor of its block, and the variables in registers are never
+ * loaded or stored.
+ */
+pub fn verify(function: &Function) -> Result<(), String> {
+    let blocks = function.blocks.len();
+    for (index, block) in function.blocks.iter().enumerate() {
+        if let Some(target) = block.terminator.successors().into_iter().find(|target| *target >= blocks) {
+            return Err(format!("b{}: jump to the missing block b{}", index, target));
+        }
+    }
+
+    // Where each register is defined, as (block, position), phis before the instructions
+    let mut definitions: HashMap<Register, (usize, usize)> = HashMap::new();
+    for (index, block) in function.blocks.iter().enumerate() {
+        let results = block.phis.iter().map(|phi| phi.register)
+            .chain(block.instructions.iter().filter_map(|instruction| instruction.result()));
+        for (position, register) in results.enumerate() {
+            if register.0 >= function.registers {
+                return Err(format!("b{}: {} out of the {} registers", index, register, function.registers));
+            }
+            if definitions.insert(register, (index, position)).is_some() {
+                return Err(format!("b{}: {} defined twice", index, register));
+            }
+        }
+    }
+
+    let predecessors = function.predecessors();
+    let dominators = dominators(function, &predecessors);
+    let dominates = |register: Register, block: usize, position: usize| -> Result<(), String> {
+        match definitions.get(&register) {
+            None => Err(format!("b{}: {} used but never defined", block, register)),
+            Some(&(definition, at)) if definition == block && at < position => Ok(()),
+            Some(&(definition, _)) if definition != block && dominators[block].contains(&definition) => Ok(()),
+            // Unreachable blocks have no dominators
+            Some(_) if dominators[block].is_empty() => Ok(()),
+            Some(_) => Err(format!("b{}: {} used where its definition does not dominate", block, register)),
+        }
+    };
+
+    for (index, block) in function.blocks.iter().enumerate() {
+        for phi in &block.phis {
+            let mut sources: Vec<usize> = phi.sources.iter().map(|(predecessor, _)| *predecessor).collect();
+            sources.sort_unstable();
+            let mut expected = predecessors[index].clone();
+            expected.sort_unstable();
+            if sources != expected {
+                return Err(format!("b{}: phi {} does not merge exactly the predecessors", index, phi.register));
+            }
+            if !function.promoted.get(phi.variable).copied().unwrap_or(false) {
+                return Err(format!("b{}: phi {} of a variable kept in the frame", index, phi.register));
+            }
+            for (predecessor, value) in &phi.sources {
+                // The value has to be there at the end of the predecessor
+                dominates(*value, *predecessor, usize::MAX)?;
+            }
+        }
+        for (offset, instruction) in block.instructions.iter().enumerate() {
+            let position = block.phis.len() + offset;
+            for operand in instruction.operands() {
+                dominates(operand, index, position)?;
+            }
+            if let Instruction::Load(_, variable) | Instruction::Store(variable, _) = instruction {
+                if variable.depth == 0 && variable.procedure == function.procedure
+                    && function.promoted.get(variable.index).copied().unwrap_or(false) {
+                    return Err(format!("b{}: variable {} is in a register but accessed in the frame", index, variable.index));
+                }
+            }
+        }
+        if let Terminator::Branch(condition, _, _) = block.terminator {
+            dominates(condition, index, usize::MAX)?;
+        }
+    }
+    Ok(())
+}
+
+// Dominators of every block including itself, empty for the unreachable blocks
+fn dominators(function: &Function, predecessors: &[Vec<usize>]) -> Vec<Vec<usize>> {
+    let blocks = function.blocks.len();
+    let mut reachable = vec![false; blocks];
+    let mut pending = vec![0];
+    while let Some(block) = pending.pop() {
+        if block < blocks && !reachable[block] {
+            reachable[block] = true;
+            pending.extend(function.blocks[block].terminator.successors());
+        }
+    }
+
+    let all: Vec<usize> = (0..blocks).filter(|block| reachable[*block]).collect();
+    let mut dominators: Vec<Vec<usize>> = (0..blocks)
+        .map(|block| match block {
+            0 => vec![0],
+            block if reachable[block] => all.clone(),
+            _ => Vec::new(),
+        })
+        .collect();
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for block in 1..blocks {
+            if !reachable[block] {
+                continue;
+            }
+            let mut common: Option<Vec<usize>> = None;
+            for predecessor in predecessors[block].iter().filter(|predecessor| reachable[**predecessor]) {
+                common = Some(match common {
+                    None => dominators[*predecessor].clone(),
+                    Some(common) => common.into_iter().filter(|block| dominators[*predecessor].contains(block)).collect(),
+                });
+            }
+            let mut updated = common.unwrap_or_default();
+            if !updated.contains(&block) {
+                updated.push(block);
+            }
+            updated.sort_unstable();
+            if updated != dominators[block] {
+                dominators[block] = updated;
+                changed = true;
+            }
+        }
+    }
+    dominators
+}
+
+// Text of the functions, with the names of the procedures and variables
+pub fn print(program: &backend::Program, functions: &[Function]) -> String {
+    let variable = |variable: &backend::Variable| {
+        format!("{}@{}", program.procedures[variable.procedure].variables[variable.index], variable.depth)
+    };
+    let mut output = String::new();
+    for function in functions {
+        let procedure = &program.procedures[function.procedure];
+        if !output.is_empty() {
+            output.push('\n');
+        }
+        writeln!(output, "function {}", procedure.name).unwrap();
+        let predecessors = function.predecessors();
+        for (index, block) in function.blocks.iter().enumerate() {
+            let from: Vec<String> = predecessors[index].iter().map(|predecessor| format!("b{}", predecessor)).collect();
+            if from.is_empty() {
+                writeln!(output, "b{}:", index).unwrap();
+            } else {
+                writeln!(output, "b{}:    ; from {}", index, from.join(", ")).unwrap();
+            }
+            for phi in &block.phis {
+                let sources: Vec<String> = phi.sources.iter()
+                    .map(|(predecessor, value)| format!("[b{} {}]", predecessor, value))
+                    .collect();
+                writeln!(output, "    {} = phi {} {}", phi.register, procedure.variables[phi.variable], sources.join(" ")).unwrap();
+            }
+            for instruction in &block.instructions {
+                let text = match instruction {
+                    Instruction::Constant(result, value) => format!("{} = {}", result, value),
+                    Instruction::Negate(result, operand) => format!("{} = -{}", result, operand),
+                    Instruction::Binary(result, operator, left, right) => {
+                        format!("{} = {} {} {}", result, left, operator.symbol(), right)
+                    },
+                    Instruction::Compare(result, relation, left, right) => {
+                        format!("{} = {} {} {}", result, left, relation.symbol(), right)
+                    },
+                    Instruction::Odd(result, operand) => format!("{} = odd {}", result, operand),
+                    Instruction::Load(result, used) => format!("{} = load {}", result, variable(used)),
+                    Instruction::Store(target, value) => format!("store {} {}", variable(target), value),
+                    Instruction::Read(result) => format!("{} = read", result),
+                    Instruction::Write(value) => format!("write {}", value),
+                    Instruction::Newline => String::from("newline"),
+                    Instruction::Call { procedure, depth } => {
+                        format!("call {}@{}", program.procedures[*procedure].name, depth)
+                    },
+                };
+                writeln!(output, "    {}", text).unwrap();
+            }
+            writeln!(output, "    {}", block.terminator).unwrap();
+        }
+    }
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ast;
+    use crate::backend;
+    use crate::backend::ir;
+    use crate::backend::ir::{Instruction, Register, Terminator};
+
+    /* test a variable assigned in a loop gets a phi at the start of the loop */
+    #[test]
+    fn test_lower() {
+        let program = backend::compile("var x; begin while x < 3 do x := x + 1; write(x) end.").unwrap();
+        let functions = ir::lower(&program);
+
+        assert_eq!(ir::print(&program, &functions), "function _main
+b0:
+    %0 = 0
+    jump b1
+b1:    ; from b0, b2
+    %1 = phi x [b0 %0] [b2 %5]
+    %2 = 3
+    %3 = %1 < %2
+    branch %3 b2 b3
+b2:    ; from b1
+    %4 = 1
+    %5 = %1 + %4
+    jump b1
+b3:    ; from b1
+    write %1
+    newline
+    return
+");
+        assert_eq!(ir::verify(&functions[0]), Ok(()));
+    }
+
+    /* test the phi of a variable assigned in a branch merges both values */
+    #[test]
+    fn test_branch() {
+        let program = backend::compile("var x; begin read(x); if odd x then x := 0; write(x) end.").unwrap();
+        let functions = ir::lower(&program);
+
+        let end = &functions[0].blocks[2];
+        assert_eq!(end.phis.len(), 1);
+        assert_eq!(end.phis[0].sources, vec![(0, Register(0)), (1, Register(2))]);
+        assert_eq!(end.instructions[0], Instruction::Write(end.phis[0].register));
+        assert_eq!(ir::verify(&functions[0]), Ok(()));
+    }
+
+    /* test variables a nested procedure reaches stay in the frame */
+    #[test]
+    fn test_escaping() {
+        let program = backend::compile("var x, y; procedure p; x := 1; begin y := 2; call p; write(x, y) end.").unwrap();
+        let functions = ir::lower(&program);
+        let x = backend::Variable { procedure: 0, index: 0, depth: 0 };
+
+        assert_eq!(functions[0].promoted, vec![false, true]);
+        assert_eq!(functions[0].blocks[0].instructions, vec![
+            Instruction::Constant(Register(0), 2),
+            Instruction::Call { procedure: 1, depth: 0 },
+            Instruction::Load(Register(1), x),
+            Instruction::Write(Register(1)),
+            Instruction::Write(Register(0)),
+            Instruction::Newline,
+        ]);
+        assert_eq!(functions[1].blocks[0].instructions[1],
+            Instruction::Store(backend::Variable { depth: 1, ..x }, Register(0)));
+    }
+
+    /* test the verifier rejects a use the definition does not dominate */
+    #[test]
+    fn test_verify() {
+        let program = backend::compile("var x; begin if x = 0 then x := 5; write(x) end.").unwrap();
+        let mut functions = ir::lower(&program);
+        assert_eq!(ir::verify(&functions[0]), Ok(()));
+
+        // Use the value of the branch directly after the join
+        let five = functions[0].blocks[1].instructions[0].result().unwrap();
+        functions[0].blocks[2].instructions[0] = Instruction::Write(five);
+        assert!(ir::verify(&functions[0]).unwrap_err().contains("does not dominate"));
+
+        functions[0].blocks[2].phis[0].sources.pop();
+        assert!(ir::verify(&functions[0]).unwrap_err().contains("predecessors"));
+
+        functions[0].blocks[0].terminator = Terminator::Jump(9);
+        assert!(ir::verify(&functions[0]).unwrap_err().contains("missing block"));
+    }
+
+    /* test running the functions prints what the vm prints */
+    #[test]
+    fn test_programs() {
+        for (name, content) in backend::tests::programs() {
+            let program = backend::compile(&content).unwrap();
+            let functions = ir::lower(&program);
+            for function in &functions {
+                assert_eq!(ir::verify(function), Ok(()), "{}", name);
+            }
+
+            let mut machine = Machine {
+                functions: &functions,
+                frames: Vec::new(),
+                input: backend::tests::INPUT.lines().map(|line| line.trim().parse().unwrap()).collect(),
+                output: String::new(),
+            };
+            machine.call(0, None);
+            assert_eq!(machine.output, backend::tests::vm_output(&content), "{}", name);
+        }
+    }
+
+    // Interpreter of the functions, with the frames of the variables in memory
+    struct Machine<'a> {
+        functions: &'a [ir::Function],
+        frames: Vec<(Option<usize>, Vec<i64>)>,     // (static link, variables)
+        input: Vec<i64>,
+        output: String,
+    }
+
+    impl Machine<'_> {
+        fn frame(&self, depth: usize) -> usize {
+            let mut frame = self.frames.len() - 1;
+            for _ in 0..depth {
+                frame = self.frames[frame].0.unwrap();
+            }
+            frame
+        }
+
+        fn call(&mut self, index: usize, link: Option<usize>) {
+            let function = &self.functions[index];
+            self.frames.push((link, vec![0; function.promoted.len()]));
+            let mut registers = vec![0i64; function.registers];
+            let (mut block, mut previous) = (0, 0);
+            loop {
+                let current = &function.blocks[block];
+                let values: Vec<i64> = current.phis.iter()
+                    .map(|phi| registers[phi.sources.iter().find(|(from, _)| *from == previous).unwrap().1 .0])
+                    .collect();
+                for (phi, value) in current.phis.iter().zip(values) {
+                    registers[phi.register.0] = value;
+                }
+                for instruction in &current.instructions {
+                    match *instruction {
+                        Instruction::Constant(result, value) => registers[result.0] = value,
+                        Instruction::Negate(result, operand) => registers[result.0] = registers[operand.0].checked_neg().unwrap(),
+                        Instruction::Binary(result, operator, left, right) => {
+                            let (left, right) = (registers[left.0], registers[right.0]);
+                            registers[result.0] = match operator {
+                                ast::BinaryOperator::Add => left.checked_add(right),
+                                ast::BinaryOperator::Sub => left.checked_sub(right),
+                                ast::BinaryOperator::Mul => left.checked_mul(right),
+                                ast::BinaryOperator::Div => left.checked_div(right),
+                            }.unwrap();
+                        },
+                        Instruction::Compare(result, relation, left, right) => {
+                            let (left, right) = (registers[left.0], registers[right.0]);
+                            registers[result.0] = i64::from(match relation {
+                                ast::Relation::Eq => left == right,
+                                ast::Relation::Ne => left != right,
+                                ast::Relation::Lt => left < right,
+                                ast::Relation::Le => left <= right,
+                                ast::Relation::Gt => left > right,
+                                ast::Relation::Ge => left >= right,
+                            });
+                        },
+                        Instruction::Odd(result, operand) => registers[result.0] = registers[operand.0] % 2,
+                        Instruction::Load(result, variable) => {
+                            let frame = self.frame(variable.depth);
+                            registers[result.0] = self.frames[frame].1[variable.index];
+                        },
+                        Instruction::Store(variable, value) => {
+                            let frame = self.frame(variable.depth);
+                            self.frames[frame].1[variable.index] = registers[value.0];
+                        },
+                        Instruction::Read(result) => {
+                            self.output.push('?');
+                            registers[result.0] = self.input.remove(0);
+                        },
+                        Instruction::Write(value) => self.output.push_str(&format!("{} ", registers[value.0])),
+                        Instruction::Newline => self.output.push('\n'),
+                        Instruction::Call { procedure, depth } => {
+                            let link = self.frame(depth);
+                            self.call(procedure, Some(link));
+                        },
+                    }
+                }
+                previous = block;
+                block = match current.terminator {
+                    Terminator::Jump(target) => target,
+                    Terminator::Branch(condition, then, otherwise) => {
+                        if registers[condition.0] != 0 { then } else { otherwise }
+                    },
+                    Terminator::Return => break,
+                };
+            }
+            self.frames.pop();
+        }
+    }
+}
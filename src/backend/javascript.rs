@@ -0,0 +1,237 @@
+use std::fmt::Write;
+
+use crate::ast;
+use crate::backend;
+
+const INDENT: &str = "    ";
+
+const RESERVED: [&str; 60] = [
+    "await", "break", "case", "catch", "class", "const", "continue", "debugger", "default", "delete",
+    "do", "else", "enum", "export", "extends", "false", "finally", "for", "function", "if",
+    "implements", "import", "in", "instanceof", "interface", "let", "new", "null", "package", "private",
+    "protected", "public", "return", "static", "super", "switch", "this", "throw", "true", "try",
+    "typeof", "var", "void", "while", "with", "yield", "arguments", "eval", "console", "process",
+    "require", "BigInt",
+    // names of the runtime
+    "fs", "MIN", "MAX", "input", "fail", "check", "div", "read",
+];
+
+// Numbers are BigInt, which never overflow, so results are checked against
+// the range of the vm; the division of BigInt truncates like the one of the vm
+const RUNTIME: &str = r#""use strict";
+const fs = require("fs");
+
+const MIN = -(2n ** 63n);
+const MAX = 2n ** 63n - 1n;
+let input = null;
+
+function fail(message) {
+    console.error(message);
+    process.exit(1);
+}
+
+function check(value) {
+    if (value < MIN || value > MAX) {
+        fail("Arithmetic overflow");
+    }
+    return value;
+}
+
+function div(a, b) {
+    if (b === 0n) {
+        fail("Division by zero");
+    }
+    return check(a / b);
+}
+
+function read() {
+    process.stdout.write("?");
+    if (input === null) {
+        input = fs.readFileSync(0, "utf8").split("\n");
+    }
+    const text = input.length > 0 ? input.shift().trim() : "";
+    if (!/^[+-]?[0-9]+$/.test(text) || BigInt(text) < MIN || BigInt(text) > MAX) {
+        fail("Invalid number");
+    }
+    return BigInt(text);
+}
+"#;
+
+
+// Emit a JavaScript program for node, each procedure is a function nested
+// in the function of the enclosing one, so it reaches the variables of the
+// enclosing procedures through its closure
+pub fn emit(program: &backend::Program) -> String {
+    let names = backend::names(program, "main", &RESERVED);
+
+    let mut emitter = Emitter {
+        program,
+        names: &names,
+        output: String::new(),
+    };
+    emitter.output.push_str("// Generated by pl0rust\n");
+    emitter.output.push_str(RUNTIME);
+    emitter.output.push('\n');
+    emitter.function(0, 0);
+    writeln!(emitter.output, "\n{}();", names.procedures[0]).unwrap();
+    emitter.output
+}
+
+struct Emitter<'a> {
+    program: &'a backend::Program,
+    names: &'a backend::Names,
+    output: String,
+}
+
+impl Emitter<'_> {
+    fn function(&mut self, index: usize, indent: usize) {
+        let prefix = INDENT.repeat(indent);
+        let inner = INDENT.repeat(indent + 1);
+        let procedure = &self.program.procedures[index];
+        writeln!(self.output, "{}function {}() {{", prefix, self.names.procedures[index]).unwrap();
+        for variable in &self.names.variables[index] {
+            writeln!(self.output, "{}let {} = 0n;", inner, variable).unwrap();
+        }
+        for (nested, child) in self.program.procedures.iter().enumerate() {
+            if child.parent == Some(index) {
+                self.output.push('\n');
+                self.function(nested, indent + 1);
+                self.output.push('\n');
+            }
+        }
+        self.statement(&procedure.body, indent + 1);
+        writeln!(self.output, "{}}}", prefix).unwrap();
+    }
+
+    fn statement(&mut self, statement: &backend::Statement, indent: usize) {
+        let prefix = INDENT.repeat(indent);
+        match statement {
+            backend::Statement::Assign(variable, expression) => {
+                writeln!(self.output, "{}{} = {};", prefix, self.variable(variable), self.expression(expression)).unwrap();
+            },
+            backend::Statement::Call { procedure, .. } => {
+                writeln!(self.output, "{}{}();", prefix, self.names.procedures[*procedure]).unwrap();
+            },
+            backend::Statement::Read(variables) => {
+                for variable in variables {
+                    writeln!(self.output, "{}{} = read();", prefix, self.variable(variable)).unwrap();
+                }
+            },
+            backend::Statement::Write(expressions) => {
+                // A space after every number, like the vm
+                let line: String = expressions.iter()
+                    .map(|expression| format!("${{{}}} ", self.expression(expression)))
+                    .collect();
+                writeln!(self.output, "{}console.log(`{}`);", prefix, line).unwrap();
+            },
+            backend::Statement::Begin(statements) => {
+                for statement in statements {
+                    self.statement(statement, indent);
+                }
+            },
+            backend::Statement::If(condition, body) => {
+                writeln!(self.output, "{}if ({}) {{", prefix, self.condition(condition)).unwrap();
+                self.statement(body, indent + 1);
+                writeln!(self.output, "{}}}", prefix).unwrap();
+            },
+            backend::Statement::While(condition, body) => {
+                writeln!(self.output, "{}while ({}) {{", prefix, self.condition(condition)).unwrap();
+                self.statement(body, indent + 1);
+                writeln!(self.output, "{}}}", prefix).unwrap();
+            },
+            backend::Statement::Empty => {},
+        }
+    }
+
+    fn condition(&self, condition: &backend::Condition) -> String {
+        match condition {
+            backend::Condition::Odd(expression) => format!("{} % 2n !== 0n", self.expression(expression)),
+            backend::Condition::Compare(left, relation, right) => {
+                let operator = match relation {
+                    ast::Relation::Eq => "===",
+                    ast::Relation::Ne => "!==",
+                    relation => relation.symbol(),
+                };
+                format!("{} {} {}", self.expression(left), operator, self.expression(right))
+            },
+        }
+    }
+
+    fn expression(&self, expression: &backend::Expression) -> String {
+        match expression {
+            backend::Expression::Number(number) if *number < 0 => format!("({}n)", number),
+            backend::Expression::Number(number) => format!("{}n", number),
+            backend::Expression::Variable(variable) => self.variable(variable),
+            backend::Expression::Negate(operand) => format!("check(-{})", self.expression(operand)),
+            backend::Expression::Binary(left, ast::BinaryOperator::Div, right) => {
+                format!("div({}, {})", self.expression(left), self.expression(right))
+            },
+            backend::Expression::Binary(left, operator, right) => {
+                let operator = match operator {
+                    ast::BinaryOperator::Add => "+",
+                    ast::BinaryOperator::Sub => "-",
+                    _ => "*",
+                };
+                format!("check({} {} {})", self.expression(left), operator, self.expression(right))
+            },
+        }
+    }
+
+    fn variable(&self, variable: &backend::Variable) -> String {
+        self.names.variables[variable.procedure][variable.index].clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::process;
+
+    use crate::backend;
+
+    /* test nested functions reach the variables of the enclosing ones */
+    #[test]
+    fn test_emit() {
+        let program = backend::compile("var x, y; procedure p; var x; begin x := y; y := x - (-1) end;
+begin y := 3; call p; write(x, y / 2) end.").unwrap();
+        let output = backend::javascript::emit(&program);
+
+        assert!(output.contains("function main() {
+    let x = 0n;
+    let y = 0n;
+
+    function p() {
+        let x_ = 0n;
+        x_ = y;
+        y = check(x_ - check(-1n));
+    }
+
+    y = 3n;
+    p();
+    console.log(`${x} ${div(y, 2n)} `);
+}
+
+main();
+"));
+    }
+
+    /* test the scripts print what the vm prints */
+    #[test]
+    fn test_programs() {
+        if !backend::tests::available("javascript programs", &["node"]) {
+            return;
+        }
+        let directory = backend::tests::temp_dir("javascript");
+        let source = directory.join("program.js");
+
+        for (name, content) in backend::tests::programs() {
+            fs::write(&source, backend::javascript::emit(&backend::compile(&content).unwrap())).unwrap();
+            let mut command = process::Command::new("node");
+            command.arg(&source);
+
+            let output = backend::tests::run(command);
+            assert_eq!(output, backend::tests::vm_output(&content), "{}", name);
+        }
+        fs::remove_dir_all(&directory).unwrap();
+    }
+}
@@ -0,0 +1,435 @@
+use std::fmt::Write;
+
+use crate::ast;
+use crate::backend;
+
+const INDENT: &str = "  ";
+
+// Checked arithmetic with the overflow intrinsics, and stdio based read
+// and write like the vm, on top of the C library
+const RUNTIME: &str = r#"@pl0_write_format = private constant [6 x i8] c"%lld \00"
+@pl0_prompt = private constant [2 x i8] c"?\00"
+@pl0_read_format = private constant [8 x i8] c"%lld %c\00"
+@pl0_error_format = private constant [4 x i8] c"%s\0A\00"
+@pl0_overflow_message = private constant [20 x i8] c"Arithmetic overflow\00"
+@pl0_division_by_zero_message = private constant [17 x i8] c"Division by zero\00"
+@pl0_invalid_message = private constant [15 x i8] c"Invalid number\00"
+
+declare i32 @printf(ptr, ...)
+declare i32 @dprintf(i32, ptr, ...)
+declare i32 @sscanf(ptr, ptr, ...)
+declare i32 @putchar(i32)
+declare i32 @getchar()
+declare i32 @fflush(ptr)
+declare void @exit(i32) noreturn
+declare { i64, i1 } @llvm.sadd.with.overflow.i64(i64, i64)
+declare { i64, i1 } @llvm.ssub.with.overflow.i64(i64, i64)
+declare { i64, i1 } @llvm.smul.with.overflow.i64(i64, i64)
+
+define internal void @pl0_error(ptr %message) noreturn {
+  call i32 @fflush(ptr null)
+  call i32 (i32, ptr, ...) @dprintf(i32 2, ptr @pl0_error_format, ptr %message)
+  call void @exit(i32 1)
+  unreachable
+}
+
+define internal i64 @pl0_checked({ i64, i1 } %result) {
+  %overflow = extractvalue { i64, i1 } %result, 1
+  br i1 %overflow, label %error, label %done
+error:
+  call void @pl0_error(ptr @pl0_overflow_message)
+  unreachable
+done:
+  %value = extractvalue { i64, i1 } %result, 0
+  ret i64 %value
+}
+
+define internal i64 @pl0_add(i64 %a, i64 %b) {
+  %result = call { i64, i1 } @llvm.sadd.with.overflow.i64(i64 %a, i64 %b)
+  %value = call i64 @pl0_checked({ i64, i1 } %result)
+  ret i64 %value
+}
+
+define internal i64 @pl0_sub(i64 %a, i64 %b) {
+  %result = call { i64, i1 } @llvm.ssub.with.overflow.i64(i64 %a, i64 %b)
+  %value = call i64 @pl0_checked({ i64, i1 } %result)
+  ret i64 %value
+}
+
+define internal i64 @pl0_mul(i64 %a, i64 %b) {
+  %result = call { i64, i1 } @llvm.smul.with.overflow.i64(i64 %a, i64 %b)
+  %value = call i64 @pl0_checked({ i64, i1 } %result)
+  ret i64 %value
+}
+
+define internal i64 @pl0_neg(i64 %a) {
+  %value = call i64 @pl0_sub(i64 0, i64 %a)
+  ret i64 %value
+}
+
+define internal i64 @pl0_div(i64 %a, i64 %b) {
+  %zero = icmp eq i64 %b, 0
+  br i1 %zero, label %division_by_zero, label %nonzero
+division_by_zero:
+  call void @pl0_error(ptr @pl0_division_by_zero_message)
+  unreachable
+nonzero:
+  %minimum = icmp eq i64 %a, -9223372036854775808
+  %minus_one = icmp eq i64 %b, -1
+  %overflow = and i1 %minimum, %minus_one
+  br i1 %overflow, label %error, label %divide
+error:
+  call void @pl0_error(ptr @pl0_overflow_message)
+  unreachable
+divide:
+  %value = sdiv i64 %a, %b
+  ret i64 %value
+}
+
+define internal void @pl0_write(i64 %value) {
+  call i32 (ptr, ...) @printf(ptr @pl0_write_format, i64 %value)
+  ret void
+}
+
+define internal void @pl0_newline() {
+  call i32 @putchar(i32 10)
+  ret void
+}
+
+define internal i64 @pl0_read() {
+entry:
+  %line = alloca [256 x i8]
+  %value = alloca i64
+  %rest = alloca i8
+  call i32 (ptr, ...) @printf(ptr @pl0_prompt)
+  call i32 @fflush(ptr null)
+  br label %next
+next:
+  %length = phi i64 [ 0, %entry ], [ %longer, %append ]
+  %character = call i32 @getchar()
+  %end_of_file = icmp eq i32 %character, -1
+  %end_of_line = icmp eq i32 %character, 10
+  %full = icmp eq i64 %length, 255
+  %end_of_input = or i1 %end_of_file, %end_of_line
+  %end = or i1 %end_of_input, %full
+  br i1 %end, label %parse, label %append
+append:
+  %slot = getelementptr inbounds [256 x i8], ptr %line, i64 0, i64 %length
+  %byte = trunc i32 %character to i8
+  store i8 %byte, ptr %slot
+  %longer = add i64 %length, 1
+  br label %next
+parse:
+  %terminator = getelementptr inbounds [256 x i8], ptr %line, i64 0, i64 %length
+  store i8 0, ptr %terminator
+  %converted = call i32 (ptr, ptr, ...) @sscanf(ptr %line, ptr @pl0_read_format, ptr %value, ptr %rest)
+  %number = icmp eq i32 %converted, 1
+  br i1 %number, label %done, label %invalid
+invalid:
+  call void @pl0_error(ptr @pl0_invalid_message)
+  unreachable
+done:
+  %result = load i64, ptr %value
+  ret i64 %result
+}
+"#;
+
+
+/*
+ * Emit LLVM IR text (opaque pointers, LLVM 15 and later), each procedure
+ * is a function with its variables in a frame structure allocated on the
+ * stack, the first field is the static link:
+ *   llc -filetype=obj program.ll && cc -o program program.o
+ */
+pub fn emit(program: &backend::Program) -> String {
+    let names: Vec<String> = program.procedures.iter().enumerate()
+        .map(|(index, procedure)| if index == 0 { String::from("main") } else { procedure.name.clone() })
+        .collect();
+    let functions: Vec<String> = backend::identifiers(&names, &[])
+        .iter()
+        .map(|name| format!("proc_{}", name))
+        .collect();
+
+    let mut output = String::from("; Generated by pl0rust\n");
+    output.push_str(RUNTIME);
+    output.push('\n');
+    for (index, procedure) in program.procedures.iter().enumerate() {
+        let mut fields = vec!["ptr"];
+        fields.extend(procedure.variables.iter().map(|_| "i64"));
+        let mut names = vec!["link"];
+        names.extend(procedure.variables.iter().map(String::as_str));
+        writeln!(output, "%frame.{} = type {{ {} }}    ; {}", functions[index], fields.join(", "), names.join(", ")).unwrap();
+    }
+
+    for index in 0..program.procedures.len() {
+        let mut emitter = Emitter {
+            program,
+            functions: &functions,
+            index,
+            output: String::new(),
+            temporaries: 0,
+            labels: 0,
+        };
+        emitter.function();
+        output.push_str(&emitter.output);
+    }
+
+    output.push_str("\ndefine i32 @main() {\n");
+    writeln!(output, "{}call void @{}()", INDENT, functions[0]).unwrap();
+    writeln!(output, "{}ret i32 0\n}}", INDENT).unwrap();
+    output
+}
+
+struct Emitter<'a> {
+    program: &'a backend::Program,
+    functions: &'a [String],
+    index: usize,           // procedure of the function
+    output: String,
+    temporaries: usize,     // %t1, %t2...
+    labels: usize,          // L1, L2...
+}
+
+impl Emitter<'_> {
+    fn function(&mut self) {
+        let procedure = &self.program.procedures[self.index];
+        let frame_type = format!("%frame.{}", self.functions[self.index]);
+        match procedure.parent {
+            Some(_) => {
+                writeln!(self.output, "\n; procedure {}", procedure.name).unwrap();
+                writeln!(self.output, "define internal void @{}(ptr %link) {{", self.functions[self.index]).unwrap();
+            },
+            None => {
+                self.output.push_str("\n; main program\n");
+                writeln!(self.output, "define internal void @{}() {{", self.functions[self.index]).unwrap();
+            },
+        }
+        writeln!(self.output, "{}%frame = alloca {}", INDENT, frame_type).unwrap();
+        writeln!(self.output, "{}store {} zeroinitializer, ptr %frame", INDENT, frame_type).unwrap();
+        if procedure.parent.is_some() {
+            writeln!(self.output, "{}store ptr %link, ptr %frame", INDENT).unwrap();
+        }
+        self.statement(&procedure.body);
+        writeln!(self.output, "{}ret void\n}}", INDENT).unwrap();
+    }
+
+    fn statement(&mut self, statement: &backend::Statement) {
+        match statement {
+            backend::Statement::Assign(variable, expression) => {
+                let value = self.expression(expression);
+                let address = self.variable(variable);
+                self.instruction(format!("store i64 {}, ptr {}", value, address));
+            },
+            backend::Statement::Call { procedure, depth } => {
+                let argument = match self.program.procedures[*procedure].parent {
+                    Some(_) => format!("ptr {}", self.frame(*depth)),
+                    None => String::new(),
+                };
+                self.instruction(format!("call void @{}({})", self.functions[*procedure], argument));
+            },
+            backend::Statement::Read(variables) => {
+                for variable in variables {
+                    let value = self.value("call i64 @pl0_read()");
+                    let address = self.variable(variable);
+                    self.instruction(format!("store i64 {}, ptr {}", value, address));
+                }
+            },
+            backend::Statement::Write(expressions) => {
+                for expression in expressions {
+                    let value = self.expression(expression);
+                    self.instruction(format!("call void @pl0_write(i64 {})", value));
+                }
+                self.instruction(String::from("call void @pl0_newline()"));
+            },
+            backend::Statement::Begin(statements) => {
+                for statement in statements {
+                    self.statement(statement);
+                }
+            },
+            backend::Statement::If(condition, body) => {
+                let then = self.label();
+                let end = self.label();
+                let condition = self.condition(condition);
+                self.instruction(format!("br i1 {}, label %{}, label %{}", condition, then, end));
+                self.block(&then);
+                self.statement(body);
+                self.instruction(format!("br label %{}", end));
+                self.block(&end);
+            },
+            backend::Statement::While(condition, body) => {
+                let test = self.label();
+                let repeat = self.label();
+                let end = self.label();
+                self.instruction(format!("br label %{}", test));
+                self.block(&test);
+                let condition = self.condition(condition);
+                self.instruction(format!("br i1 {}, label %{}, label %{}", condition, repeat, end));
+                self.block(&repeat);
+                self.statement(body);
+                self.instruction(format!("br label %{}", test));
+                self.block(&end);
+            },
+            backend::Statement::Empty => {},
+        }
+    }
+
+    // An i1 value
+    fn condition(&mut self, condition: &backend::Condition) -> String {
+        match condition {
+            backend::Condition::Odd(expression) => {
+                let value = self.expression(expression);
+                let bit = self.value(&format!("and i64 {}, 1", value));
+                self.value(&format!("icmp ne i64 {}, 0", bit))
+            },
+            backend::Condition::Compare(left, relation, right) => {
+                let left = self.expression(left);
+                let right = self.expression(right);
+                let predicate = match relation {
+                    ast::Relation::Eq => "eq",
+                    ast::Relation::Ne => "ne",
+                    ast::Relation::Lt => "slt",
+                    ast::Relation::Le => "sle",
+                    ast::Relation::Gt => "sgt",
+                    ast::Relation::Ge => "sge",
+                };
+                self.value(&format!("icmp {} i64 {}, {}", predicate, left, right))
+            },
+        }
+    }
+
+    // An i64 constant or temporary
+    fn expression(&mut self, expression: &backend::Expression) -> String {
+        match expression {
+            backend::Expression::Number(number) => number.to_string(),
+            backend::Expression::Variable(variable) => {
+                let address = self.variable(variable);
+                self.value(&format!("load i64, ptr {}", address))
+            },
+            backend::Expression::Negate(operand) => {
+                let operand = self.expression(operand);
+                self.value(&format!("call i64 @pl0_neg(i64 {})", operand))
+            },
+            backend::Expression::Binary(left, operator, right) => {
+                let left = self.expression(left);
+                let right = self.expression(right);
+                let function = match operator {
+                    ast::BinaryOperator::Add => "pl0_add",
+                    ast::BinaryOperator::Sub => "pl0_sub",
+                    ast::BinaryOperator::Mul => "pl0_mul",
+                    ast::BinaryOperator::Div => "pl0_div",
+                };
+                self.value(&format!("call i64 @{}(i64 {}, i64 {})", function, left, right))
+            },
+        }
+    }
+
+    // Address of the variable in its frame
+    fn variable(&mut self, variable: &backend::Variable) -> String {
+        let frame = self.frame(variable.depth);
+        self.value(&format!("getelementptr inbounds %frame.{}, ptr {}, i32 0, i32 {}",
+            self.functions[variable.procedure], frame, variable.index + 1))
+    }
+
+    // Frame reached through depth static links
+    fn frame(&mut self, depth: usize) -> String {
+        let mut frame = String::from("%frame");
+        for _ in 0..depth {
+            frame = self.value(&format!("load ptr, ptr {}", frame));
+        }
+        frame
+    }
+
+    fn value(&mut self, instruction: &str) -> String {
+        self.temporaries += 1;
+        let name = format!("%t{}", self.temporaries);
+        self.instruction(format!("{} = {}", name, instruction));
+        name
+    }
+
+    fn instruction(&mut self, instruction: String) {
+        writeln!(self.output, "{}{}", INDENT, instruction).unwrap();
+    }
+
+    fn label(&mut self) -> String {
+        self.labels += 1;
+        format!("L{}", self.labels)
+    }
+
+    fn block(&mut self, label: &str) {
+        writeln!(self.output, "{}:", label).unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::process;
+
+    use crate::backend;
+
+    /* test variables are reached through the static links of the frames */
+    #[test]
+    fn test_emit() {
+        let program = backend::compile("var x; procedure p; var y; begin y := x * 2; x := -y end;
+begin x := 3; while x < 10 do call p end.").unwrap();
+        let output = backend::llvm::emit(&program);
+
+        assert!(output.contains("%frame.proc_p = type { ptr, i64 }    ; link, y\n"));
+        assert!(output.contains("define internal void @proc_p(ptr %link) {
+  %frame = alloca %frame.proc_p
+  store %frame.proc_p zeroinitializer, ptr %frame
+  store ptr %link, ptr %frame
+  %t1 = load ptr, ptr %frame
+  %t2 = getelementptr inbounds %frame.proc_main, ptr %t1, i32 0, i32 1
+  %t3 = load i64, ptr %t2
+  %t4 = call i64 @pl0_mul(i64 %t3, i64 2)
+"));
+        assert!(output.contains("  br label %L1
+L1:
+  %t2 = getelementptr inbounds %frame.proc_main, ptr %frame, i32 0, i32 1
+  %t3 = load i64, ptr %t2
+  %t4 = icmp slt i64 %t3, 10
+  br i1 %t4, label %L2, label %L3
+L2:
+  call void @proc_p(ptr %frame)
+  br label %L1
+L3:
+  ret void
+"));
+    }
+
+    /* test the compiled programs print what the vm prints */
+    #[test]
+    fn test_programs() {
+        if !backend::tests::available("llvm programs", &["llc", "cc"]) {
+            return;
+        }
+        // Opaque pointers are only the default since LLVM 15
+        let output = process::Command::new("llc").arg("--version").output().unwrap();
+        let version = String::from_utf8_lossy(&output.stdout);
+        let major: u32 = version.split("version ").nth(1)
+            .and_then(|rest| rest.split('.').next())
+            .and_then(|major| major.parse().ok())
+            .unwrap_or(15);
+
+        let directory = backend::tests::temp_dir("llvm");
+        let source = directory.join("program.ll");
+        let object = directory.join("program.o");
+        let binary = directory.join("program");
+
+        for (name, content) in backend::tests::programs() {
+            fs::write(&source, backend::llvm::emit(&backend::compile(&content).unwrap())).unwrap();
+            let mut llc = process::Command::new("llc");
+            if major < 15 {
+                llc.arg("-opaque-pointers");
+            }
+            let status = llc.args(["-filetype=obj", "-relocation-model=pic", "-o"]).arg(&object).arg(&source).status().unwrap();
+            assert!(status.success(), "{}", name);
+            let status = process::Command::new("cc").arg("-o").arg(&binary).arg(&object).status().unwrap();
+            assert!(status.success(), "{}", name);
+
+            let output = backend::tests::run(process::Command::new(&binary));
+            assert_eq!(output, backend::tests::vm_output(&content), "{}", name);
+        }
+        fs::remove_dir_all(&directory).unwrap();
+    }
+}
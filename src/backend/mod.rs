@@ -0,0 +1,464 @@
+pub mod c;
+pub mod elf;
+pub mod javascript;
+pub mod ir;
+pub mod llvm;
+pub mod native;
+pub mod python;
+pub mod riscv64;
+pub mod rust;
+pub mod wasm;
+pub mod x86_64;
+
+use crate::ast;
+use crate::ast::parser;
+use crate::compile::codegen;
+use crate::compile::error;
+use crate::symbol;
+
+
+/*
+ * Program with every name resolved, the common input of the backends.
+ * Constants are replaced by their value, and every use of a variable or
+ * procedure knows how many static links to follow to reach the frame it
+ * belongs to, the same count the vm uses as the level of Lod, Sto and Cal.
+ */
+#[derive(Clone, Debug, PartialEq)]
+pub struct Program {
+    pub procedures: Vec<Procedure>,     // the main procedure first, then in declaration order
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Procedure {
+    pub name: String,
+    pub parent: Option<usize>,      // procedure declaring this one, none for the main procedure
+    pub level: usize,               // nesting depth, 0 for the main procedure
+    pub variables: Vec<String>,     // in declaration order, names may repeat
+    pub body: Statement,
+}
+
+/* variable of a procedure, reached from the current frame through depth static links */
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Variable {
+    pub procedure: usize,
+    pub index: usize,
+    pub depth: usize,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Statement {
+    Assign(Variable, Expression),
+    // The callee gets as static link the frame reached through depth static links
+    Call { procedure: usize, depth: usize },
+    Read(Vec<Variable>),
+    Write(Vec<Expression>),
+    Begin(Vec<Statement>),
+    If(Condition, Box<Statement>),
+    While(Condition, Box<Statement>),
+    Empty,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Condition {
+    Odd(Expression),
+    Compare(Expression, ast::Relation, Expression),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Expression {
+    Number(i64),
+    Variable(Variable),
+    Negate(Box<Expression>),
+    Binary(Box<Expression>, ast::BinaryOperator, Box<Expression>),
+}
+
+// Check the program with the code generator, then resolve it for the backends
+pub fn compile(content: &str) -> Result<Program, Vec<error::CompileError>> {
+    let mut lexer = symbol::io::PL0Lexer::create_from_content(content);
+    let mut generator = codegen::CodeGenerator::new();
+    if !generator.build_block(0, &mut lexer) {
+        return Err(generator.get_errors().clone());
+    }
+
+    let program = parser::parse(content).map_err(|error| vec![error])?;
+    lower(&program).map_err(|error| vec![error])
+}
+
+// Resolve the names of a parsed program the way the code generator does
+pub fn lower(program: &ast::Program) -> Result<Program, error::CompileError> {
+    let mut lowering = Lowering {
+        procedures: Vec::new(),
+        // The main procedure can be called too, like in the name table
+        scopes: vec![vec![(String::from("_main"), Entry::Procedure(0))]],
+    };
+    lowering.procedures.push(Procedure {
+        name: String::from("_main"),
+        parent: None,
+        level: 0,
+        variables: Vec::new(),
+        body: Statement::Empty,
+    });
+    lowering.block(0, &program.block)?;
+    Ok(Program { procedures: lowering.procedures })
+}
+
+/*
+ * Give every name an identifier valid in a target language: reserved words
+ * and repeated names get underscores appended until they are unique.
+ */
+pub fn identifiers(names: &[String], reserved: &[&str]) -> Vec<String> {
+    let mut identifiers: Vec<String> = Vec::with_capacity(names.len());
+    for name in names {
+        let mut identifier = name.clone();
+        while reserved.contains(&identifier.as_str()) || identifiers.contains(&identifier) {
+            identifier.push('_');
+        }
+        identifiers.push(identifier);
+    }
+    identifiers
+}
+
+/* identifiers for a target where procedures and variables share one namespace */
+#[derive(Clone, Debug, PartialEq)]
+pub struct Names {
+    pub procedures: Vec<String>,
+    pub variables: Vec<Vec<String>>,    // by procedure, then by index
+}
+
+// Every procedure and variable gets an identifier of its own in the whole
+// program, so nothing shadows anything, main names the main procedure
+pub fn names(program: &Program, main: &str, reserved: &[&str]) -> Names {
+    let mut all: Vec<String> = program.procedures.iter().enumerate()
+        .map(|(index, procedure)| if index == 0 { main.to_string() } else { procedure.name.clone() })
+        .collect();
+    for procedure in &program.procedures {
+        all.extend(procedure.variables.iter().cloned());
+    }
+    let mut unique = identifiers(&all, reserved).into_iter();
+    let procedures = unique.by_ref().take(program.procedures.len()).collect();
+    let variables = program.procedures.iter()
+        .map(|procedure| unique.by_ref().take(procedure.variables.len()).collect())
+        .collect();
+    Names { procedures, variables }
+}
+
+#[derive(Copy, Clone)]
+enum Entry {
+    Constant(i64),
+    Variable(usize, usize),     // (procedure, index)
+    Procedure(usize),
+}
+
+struct Lowering {
+    procedures: Vec<Procedure>,
+    scopes: Vec<Vec<(String, Entry)>>,  // declarations visible so far, one list per nesting level
+}
+
+impl Lowering {
+    fn block(&mut self, procedure: usize, block: &ast::Block) -> Result<(), error::CompileError> {
+        for declaration in &block.declarations {
+            match &declaration.kind {
+                ast::DeclarationKind::Const(constants) => {
+                    for constant in constants {
+                        self.declare(&constant.name.name, Entry::Constant(constant.value));
+                    }
+                },
+                ast::DeclarationKind::Var(variables) => {
+                    for variable in variables {
+                        let index = self.procedures[procedure].variables.len();
+                        self.procedures[procedure].variables.push(variable.name.clone());
+                        self.declare(&variable.name, Entry::Variable(procedure, index));
+                    }
+                },
+                ast::DeclarationKind::Procedure(declared) => {
+                    let index = self.procedures.len();
+                    self.procedures.push(Procedure {
+                        name: declared.name.name.clone(),
+                        parent: Some(procedure),
+                        level: self.procedures[procedure].level + 1,
+                        variables: Vec::new(),
+                        body: Statement::Empty,
+                    });
+                    // Declared before its block so that it can call itself
+                    self.declare(&declared.name.name, Entry::Procedure(index));
+
+                    self.scopes.push(Vec::new());
+                    let result = self.block(index, &declared.block);
+                    self.scopes.pop();
+                    result?;
+                },
+            }
+        }
+
+        self.procedures[procedure].body = self.statement(&block.statement)?;
+        Ok(())
+    }
+
+    fn declare(&mut self, name: &str, entry: Entry) {
+        if let Some(scope) = self.scopes.last_mut() {
+            scope.push((name.to_string(), entry));
+        }
+    }
+
+    // Entry of a name and the level of the block declaring it
+    fn lookup(&self, name: &ast::Ident) -> Result<(Entry, usize), error::CompileError> {
+        for (level, scope) in self.scopes.iter().enumerate().rev() {
+            if let Some((_, entry)) = scope.iter().rev().find(|(declared, _)| *declared == name.name) {
+                return Ok((*entry, level));
+            }
+        }
+        Err(error::CompileError {
+            span: name.span.clone(),
+            message: format!("Undeclared identifier {}", name.name),
+        })
+    }
+
+    fn variable(&self, name: &ast::Ident, message: &str) -> Result<Variable, error::CompileError> {
+        match self.lookup(name)? {
+            (Entry::Variable(procedure, index), level) => Ok(Variable {
+                procedure,
+                index,
+                depth: self.scopes.len() - 1 - level,
+            }),
+            _ => Err(error::CompileError {
+                span: name.span.clone(),
+                message: message.to_string(),
+            }),
+        }
+    }
+
+    fn statement(&self, statement: &ast::Statement) -> Result<Statement, error::CompileError> {
+        Ok(match &statement.kind {
+            ast::StatementKind::Assign(name, expression) => {
+                Statement::Assign(self.variable(name, "Only a variable can be assigned")?, self.expression(expression)?)
+            },
+            ast::StatementKind::Call(name) => match self.lookup(name)? {
+                (Entry::Procedure(procedure), level) => Statement::Call {
+                    procedure,
+                    depth: self.scopes.len() - 1 - level,
+                },
+                _ => return Err(error::CompileError {
+                    span: name.span.clone(),
+                    message: String::from("Only a procedure can be called"),
+                }),
+            },
+            ast::StatementKind::Read(names) => Statement::Read(names.iter()
+                .map(|name| self.variable(name, "Read statement should read into a variable"))
+                .collect::<Result<_, _>>()?),
+            ast::StatementKind::Write(expressions) => Statement::Write(expressions.iter()
+                .map(|expression| self.expression(expression))
+                .collect::<Result<_, _>>()?),
+            ast::StatementKind::Begin(statements) => Statement::Begin(statements.iter()
+                .map(|statement| self.statement(statement))
+                .collect::<Result<_, _>>()?),
+            ast::StatementKind::If(condition, body) => {
+                Statement::If(self.condition(condition)?, Box::new(self.statement(body)?))
+            },
+            ast::StatementKind::While(condition, body) => {
+                Statement::While(self.condition(condition)?, Box::new(self.statement(body)?))
+            },
+            ast::StatementKind::Empty => Statement::Empty,
+        })
+    }
+
+    fn condition(&self, condition: &ast::Condition) -> Result<Condition, error::CompileError> {
+        Ok(match &condition.kind {
+            ast::ConditionKind::Odd(expression) => Condition::Odd(self.expression(expression)?),
+            ast::ConditionKind::Compare(left, relation, right) => {
+                Condition::Compare(self.expression(left)?, *relation, self.expression(right)?)
+            },
+        })
+    }
+
+    fn expression(&self, expression: &ast::Expression) -> Result<Expression, error::CompileError> {
+        Ok(match &expression.kind {
+            ast::ExpressionKind::Number(number) => Expression::Number(*number),
+            ast::ExpressionKind::Name(name) => match self.lookup(name)? {
+                (Entry::Constant(value), _) => Expression::Number(value),
+                (Entry::Variable(procedure, index), level) => Expression::Variable(Variable {
+                    procedure,
+                    index,
+                    depth: self.scopes.len() - 1 - level,
+                }),
+                (Entry::Procedure(_), _) => return Err(error::CompileError {
+                    span: name.span.clone(),
+                    message: String::from("Procedure cannot be used in expression"),
+                }),
+            },
+            ast::ExpressionKind::Negate(operand) => Expression::Negate(Box::new(self.expression(operand)?)),
+            ast::ExpressionKind::Binary(left, operator, right) => Expression::Binary(
+                Box::new(self.expression(left)?),
+                *operator,
+                Box::new(self.expression(right)?),
+            ),
+            ast::ExpressionKind::Paren(inner) => self.expression(inner)?,
+        })
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::fs;
+    use std::io::Write;
+    use std::path;
+    use std::process;
+
+    use crate::ast;
+    use crate::backend;
+    use crate::compile::codegen;
+    use crate::symbol;
+    use crate::vm;
+
+    pub(crate) const INPUT: &str = "5\n-7\n";
+
+    // Directory for the files of a test, runs of the tests at the same time each have their own
+    pub(crate) fn temp_dir(name: &str) -> path::PathBuf {
+        let directory = std::env::temp_dir().join(format!("pl0rust-{}-{}", name, process::id()));
+        fs::create_dir_all(&directory).unwrap();
+        directory
+    }
+
+    // Tools which do not run with --version
+    pub(crate) fn missing<'a>(tools: &[&'a str]) -> Vec<&'a str> {
+        tools.iter()
+            .copied()
+            .filter(|tool| process::Command::new(tool).arg("--version").output().is_err())
+            .collect()
+    }
+
+    // Whether the tools of a test are there, the test is reported as skipped if not
+    pub(crate) fn available(test: &str, tools: &[&str]) -> bool {
+        let missing = missing(tools);
+        if !missing.is_empty() {
+            skip(test, &format!("{} not found", missing.join(", ")));
+        }
+        missing.is_empty()
+    }
+
+    // The harness has no skipped state, written past its output capture to be seen
+    pub(crate) fn skip(test: &str, reason: &str) {
+        writeln!(std::io::stderr(), "{} skipped: {}", test, reason).unwrap();
+    }
+
+    // Samples and a few programs for what they do not cover, as (name, source)
+    pub(crate) fn programs() -> Vec<(String, String)> {
+        let mut programs: Vec<(String, String)> = fs::read_dir("sample").unwrap()
+            .map(|entry| {
+                let path = entry.unwrap().path();
+                (path.display().to_string(), fs::read_to_string(&path).unwrap())
+            })
+            .collect();
+        programs.sort();
+        programs.push((String::from("recursion"), String::from("var n, result;
+procedure factorial;
+    var k;
+    procedure multiply;
+        result := result * k;
+    begin
+        k := n;
+        if k > 1 then begin n := n - 1; call factorial; call multiply end
+    end;
+begin read(n); result := 1; call factorial; write(result) end.")));
+        programs.push((String::from("arithmetic"), String::from("const big = 9223372036854775807;
+var a, b;
+begin
+    read(a, b);
+    write(a / 2, b / 2, -b / 2, a * b, a - b, -a + b, big - 1, -big);
+    if odd b then write(1);
+    if odd a then write(2);
+    while a > b do begin a := a - 3; write(a) end;
+    if a != b then write(3)
+end.")));
+        programs
+    }
+
+    // Output of the vm given INPUT
+    pub(crate) fn vm_output(content: &str) -> String {
+        let mut lexer = symbol::io::PL0Lexer::create_from_content(content);
+        let mut generator = codegen::CodeGenerator::new();
+        assert!(generator.build_block(0, &mut lexer));
+        let output = vm::SharedBuffer::default();
+        let mut machine = vm::PL0VirtualMachine::load(generator.get_vm_code().to_vec());
+        machine.redirect(Box::new(INPUT.as_bytes()), Box::new(output.clone()));
+        machine.execute();
+        output.take()
+    }
+
+    // Output of a compiled program given INPUT
+    pub(crate) fn run(mut command: process::Command) -> String {
+        let mut child = command
+            .stdin(process::Stdio::piped())
+            .stdout(process::Stdio::piped())
+            .spawn()
+            .unwrap();
+        // A program which does not read may exit before getting its input
+        if let Err(error) = child.stdin.take().unwrap().write_all(INPUT.as_bytes()) {
+            assert_eq!(error.kind(), std::io::ErrorKind::BrokenPipe);
+        }
+        let output = child.wait_with_output().unwrap();
+        assert!(output.status.success());
+        String::from_utf8(output.stdout).unwrap()
+    }
+
+    /* test names resolve to the innermost declaration with the static link count */
+    #[test]
+    fn test_lower() {
+        let program = backend::compile("const c = 7; var x;
+procedure p;
+    var x, y;
+    procedure q;
+        begin x := c; y := 1; call p; call q end;
+    call q;
+begin x := 2; call p end.").unwrap();
+
+        assert_eq!(program.procedures.len(), 3);
+        assert_eq!(program.procedures[1].variables, vec!["x", "y"]);
+        assert_eq!(program.procedures[2].parent, Some(1));
+        assert_eq!(program.procedures[2].level, 2);
+
+        let x = backend::Variable { procedure: 1, index: 0, depth: 1 };
+        let y = backend::Variable { procedure: 1, index: 1, depth: 1 };
+        assert_eq!(program.procedures[2].body, backend::Statement::Begin(vec![
+            backend::Statement::Assign(x, backend::Expression::Number(7)),
+            backend::Statement::Assign(y, backend::Expression::Number(1)),
+            backend::Statement::Call { procedure: 1, depth: 2 },
+            backend::Statement::Call { procedure: 2, depth: 1 },
+        ]));
+        assert_eq!(program.procedures[0].body, backend::Statement::Begin(vec![
+            backend::Statement::Assign(
+                backend::Variable { procedure: 0, index: 0, depth: 0 },
+                backend::Expression::Number(2),
+            ),
+            backend::Statement::Call { procedure: 1, depth: 0 },
+        ]));
+    }
+
+    /* test a declaration is only visible after it, and a repeated name hides the first one */
+    #[test]
+    fn test_declaration_order() {
+        let program = backend::compile("var x; procedure p; x := 1; var x; begin x := 2; call _main end.").unwrap();
+
+        assert_eq!(program.procedures[1].body, backend::Statement::Assign(
+            backend::Variable { procedure: 0, index: 0, depth: 1 },
+            backend::Expression::Number(1),
+        ));
+        assert_eq!(program.procedures[0].body, backend::Statement::Begin(vec![
+            backend::Statement::Assign(
+                backend::Variable { procedure: 0, index: 1, depth: 0 },
+                backend::Expression::Number(2),
+            ),
+            backend::Statement::Call { procedure: 0, depth: 0 },
+        ]));
+        assert_eq!(backend::identifiers(&program.procedures[0].variables, &["int"]), vec!["x", "x_"]);
+    }
+
+    /* test errors of the code generator are reported */
+    #[test]
+    fn test_errors() {
+        let errors = backend::compile("begin x := 1 end.").unwrap_err();
+        assert_eq!(errors[0].message, "Undeclared identifier x");
+
+        let program = ast::parser::parse("procedure p; ; begin p := 1 end.").unwrap();
+        assert_eq!(backend::lower(&program).unwrap_err().message, "Only a variable can be assigned");
+    }
+}
@@ -0,0 +1,161 @@
+use crate::ast;
+use crate::backend;
+
+
+/*
+ * Code of a procedure for a machine with an operand stack, the common
+ * lowering of the native backends. Each backend only has to say how to
+ * run each operation with its own registers and frame layout.
+ */
+#[derive(Clone, Debug, PartialEq)]
+pub struct Function {
+    pub procedure: usize,
+    pub variables: usize,
+    pub code: Vec<Operation>,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Operation {
+    Push(i64),
+    Load(backend::Variable),
+    Store(backend::Variable),   // pops the value
+    Negate,
+    Binary(ast::BinaryOperator),
+    Compare(ast::Relation),     // replaces both operands by 1 if true, 0 if false
+    Odd,                        // replaces the operand by something else than 0 if odd
+    Write,                      // pops the value
+    Newline,
+    Read,                       // pushes the value
+    Call { procedure: usize, depth: usize },
+    Label(usize),               // labels are unique in the whole program
+    Jump(usize),
+    JumpIfZero(usize),          // pops the condition
+}
+
+pub fn lower(program: &backend::Program) -> Vec<Function> {
+    let mut labels = 0;
+    program.procedures.iter().enumerate()
+        .map(|(index, procedure)| {
+            let mut code = Vec::new();
+            statement(&procedure.body, &mut code, &mut labels);
+            Function {
+                procedure: index,
+                variables: procedure.variables.len(),
+                code,
+            }
+        })
+        .collect()
+}
+
+fn statement(current: &backend::Statement, code: &mut Vec<Operation>, labels: &mut usize) {
+    match current {
+        backend::Statement::Assign(variable, value) => {
+            expression(value, code);
+            code.push(Operation::Store(*variable));
+        },
+        backend::Statement::Call { procedure, depth } => {
+            code.push(Operation::Call { procedure: *procedure, depth: *depth });
+        },
+        backend::Statement::Read(variables) => {
+            for variable in variables {
+                code.push(Operation::Read);
+                code.push(Operation::Store(*variable));
+            }
+        },
+        backend::Statement::Write(expressions) => {
+            for value in expressions {
+                expression(value, code);
+                code.push(Operation::Write);
+            }
+            code.push(Operation::Newline);
+        },
+        backend::Statement::Begin(statements) => {
+            for inner in statements {
+                statement(inner, code, labels);
+            }
+        },
+        backend::Statement::If(test, body) => {
+            let end = label(labels);
+            condition(test, code);
+            code.push(Operation::JumpIfZero(end));
+            statement(body, code, labels);
+            code.push(Operation::Label(end));
+        },
+        backend::Statement::While(test, body) => {
+            let start = label(labels);
+            let end = label(labels);
+            code.push(Operation::Label(start));
+            condition(test, code);
+            code.push(Operation::JumpIfZero(end));
+            statement(body, code, labels);
+            code.push(Operation::Jump(start));
+            code.push(Operation::Label(end));
+        },
+        backend::Statement::Empty => {},
+    }
+}
+
+fn condition(condition: &backend::Condition, code: &mut Vec<Operation>) {
+    match condition {
+        backend::Condition::Odd(value) => {
+            expression(value, code);
+            code.push(Operation::Odd);
+        },
+        backend::Condition::Compare(left, relation, right) => {
+            expression(left, code);
+            expression(right, code);
+            code.push(Operation::Compare(*relation));
+        },
+    }
+}
+
+fn expression(value: &backend::Expression, code: &mut Vec<Operation>) {
+    match value {
+        backend::Expression::Number(number) => code.push(Operation::Push(*number)),
+        backend::Expression::Variable(variable) => code.push(Operation::Load(*variable)),
+        backend::Expression::Negate(operand) => {
+            expression(operand, code);
+            code.push(Operation::Negate);
+        },
+        backend::Expression::Binary(left, operator, right) => {
+            expression(left, code);
+            expression(right, code);
+            code.push(Operation::Binary(*operator));
+        },
+    }
+}
+
+fn label(labels: &mut usize) -> usize {
+    *labels += 1;
+    *labels - 1
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ast;
+    use crate::backend;
+    use crate::backend::native;
+    use crate::backend::native::Operation;
+
+    /* test control flow becomes labels and jumps */
+    #[test]
+    fn test_lower() {
+        let program = backend::compile("var x; begin while x < 3 do x := x + 1; if odd x then write(x) end.").unwrap();
+        let functions = native::lower(&program);
+        let x = backend::Variable { procedure: 0, index: 0, depth: 0 };
+
+        assert_eq!(functions[0].variables, 1);
+        assert_eq!(functions[0].code, vec![
+            Operation::Label(0),
+            Operation::Load(x), Operation::Push(3), Operation::Compare(ast::Relation::Lt),
+            Operation::JumpIfZero(1),
+            Operation::Load(x), Operation::Push(1), Operation::Binary(ast::BinaryOperator::Add), Operation::Store(x),
+            Operation::Jump(0),
+            Operation::Label(1),
+            Operation::Load(x), Operation::Odd,
+            Operation::JumpIfZero(2),
+            Operation::Load(x), Operation::Write, Operation::Newline,
+            Operation::Label(2),
+        ]);
+    }
+}
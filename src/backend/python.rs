@@ -0,0 +1,283 @@
+use std::collections::BTreeSet;
+use std::fmt::Write;
+
+use crate::ast;
+use crate::backend;
+
+const INDENT: &str = "    ";
+
+const RESERVED: [&str; 46] = [
+    "False", "None", "True", "and", "as", "assert", "async", "await", "break", "class",
+    "continue", "def", "del", "elif", "else", "except", "finally", "for", "from", "global",
+    "if", "import", "in", "is", "lambda", "nonlocal", "not", "or", "pass", "raise",
+    "return", "try", "while", "with", "yield", "print", "input", "abs", "int",
+    // names of the runtime
+    "re", "sys", "MIN", "MAX", "fail", "check", "div",
+];
+
+// Integers of Python never overflow, so results are checked against the
+// range of the vm, and the division truncates like the one of the vm
+const RUNTIME: &str = r#"import re
+import sys
+
+MIN = -2 ** 63
+MAX = 2 ** 63 - 1
+
+
+def fail(message):
+    sys.stdout.flush()
+    print(message, file=sys.stderr)
+    sys.exit(1)
+
+
+def check(value):
+    if not MIN <= value <= MAX:
+        fail("Arithmetic overflow")
+    return value
+
+
+def div(a, b):
+    if b == 0:
+        fail("Division by zero")
+    quotient = abs(a) // abs(b)
+    return check(quotient if (a < 0) == (b < 0) else -quotient)
+
+
+def read():
+    try:
+        text = input("?").strip()
+    except EOFError:
+        fail("Invalid number")
+    if not re.fullmatch(r"[+-]?[0-9]+", text) or not MIN <= int(text) <= MAX:
+        fail("Invalid number")
+    return int(text)
+"#;
+
+
+// Emit a Python 3 script, each procedure is a function nested in the
+// function of the enclosing one, and captures the variables it assigns
+// with nonlocal
+pub fn emit(program: &backend::Program) -> String {
+    let names = backend::names(program, "main", &RESERVED);
+
+    let mut emitter = Emitter {
+        program,
+        names: &names,
+        output: String::new(),
+    };
+    emitter.output.push_str("# Generated by pl0rust\n");
+    emitter.output.push_str(RUNTIME);
+    emitter.output.push_str("\n\n");
+    emitter.function(0, 0);
+    emitter.output.push_str("\n\nsys.setrecursionlimit(100000)\n");
+    writeln!(emitter.output, "{}()", names.procedures[0]).unwrap();
+    emitter.output
+}
+
+struct Emitter<'a> {
+    program: &'a backend::Program,
+    names: &'a backend::Names,
+    output: String,
+}
+
+impl Emitter<'_> {
+    fn function(&mut self, index: usize, indent: usize) {
+        let prefix = INDENT.repeat(indent);
+        let inner = INDENT.repeat(indent + 1);
+        let procedure = &self.program.procedures[index];
+        writeln!(self.output, "{}def {}():", prefix, self.names.procedures[index]).unwrap();
+
+        let mut captured = BTreeSet::new();
+        assigned(&procedure.body, &mut captured);
+        let captured: Vec<&str> = captured.into_iter()
+            .filter(|&(owner, _)| owner != index)
+            .map(|(owner, variable)| self.names.variables[owner][variable].as_str())
+            .collect();
+        if !captured.is_empty() {
+            writeln!(self.output, "{}nonlocal {}", inner, captured.join(", ")).unwrap();
+        }
+        for variable in &self.names.variables[index] {
+            writeln!(self.output, "{}{} = 0", inner, variable).unwrap();
+        }
+        for (nested, child) in self.program.procedures.iter().enumerate() {
+            if child.parent == Some(index) {
+                self.output.push('\n');
+                self.function(nested, indent + 1);
+                self.output.push('\n');
+            }
+        }
+
+        let length = self.output.len();
+        self.statement(&procedure.body, indent + 1);
+        if self.output.len() == length {
+            writeln!(self.output, "{}pass", inner).unwrap();
+        }
+    }
+
+    fn statement(&mut self, statement: &backend::Statement, indent: usize) {
+        let prefix = INDENT.repeat(indent);
+        match statement {
+            backend::Statement::Assign(variable, expression) => {
+                writeln!(self.output, "{}{} = {}", prefix, self.variable(variable), self.expression(expression)).unwrap();
+            },
+            backend::Statement::Call { procedure, .. } => {
+                writeln!(self.output, "{}{}()", prefix, self.names.procedures[*procedure]).unwrap();
+            },
+            backend::Statement::Read(variables) => {
+                for variable in variables {
+                    writeln!(self.output, "{}{} = read()", prefix, self.variable(variable)).unwrap();
+                }
+            },
+            backend::Statement::Write(expressions) => {
+                let mut arguments: Vec<String> = expressions.iter().map(|expression| self.expression(expression)).collect();
+                // A space after every number, like the vm
+                arguments.push(String::from("end=\" \\n\""));
+                if expressions.is_empty() {
+                    writeln!(self.output, "{}print()", prefix).unwrap();
+                } else {
+                    writeln!(self.output, "{}print({})", prefix, arguments.join(", ")).unwrap();
+                }
+            },
+            backend::Statement::Begin(statements) => {
+                for statement in statements {
+                    self.statement(statement, indent);
+                }
+            },
+            backend::Statement::If(condition, body) => {
+                writeln!(self.output, "{}if {}:", prefix, self.condition(condition)).unwrap();
+                self.block(body, indent + 1);
+            },
+            backend::Statement::While(condition, body) => {
+                writeln!(self.output, "{}while {}:", prefix, self.condition(condition)).unwrap();
+                self.block(body, indent + 1);
+            },
+            backend::Statement::Empty => {},
+        }
+    }
+
+    // Statement as the body of a compound statement, which cannot be empty
+    fn block(&mut self, statement: &backend::Statement, indent: usize) {
+        let length = self.output.len();
+        self.statement(statement, indent);
+        if self.output.len() == length {
+            writeln!(self.output, "{}pass", INDENT.repeat(indent)).unwrap();
+        }
+    }
+
+    fn condition(&self, condition: &backend::Condition) -> String {
+        match condition {
+            backend::Condition::Odd(expression) => format!("{} % 2 != 0", self.expression(expression)),
+            backend::Condition::Compare(left, relation, right) => {
+                let operator = match relation {
+                    ast::Relation::Eq => "==",
+                    relation => relation.symbol(),
+                };
+                format!("{} {} {}", self.expression(left), operator, self.expression(right))
+            },
+        }
+    }
+
+    fn expression(&self, expression: &backend::Expression) -> String {
+        match expression {
+            backend::Expression::Number(number) => number.to_string(),
+            backend::Expression::Variable(variable) => self.variable(variable),
+            backend::Expression::Negate(operand) => format!("check(-{})", self.operand(operand)),
+            backend::Expression::Binary(left, ast::BinaryOperator::Div, right) => {
+                format!("div({}, {})", self.expression(left), self.expression(right))
+            },
+            backend::Expression::Binary(left, operator, right) => {
+                let operator = match operator {
+                    ast::BinaryOperator::Add => "+",
+                    ast::BinaryOperator::Sub => "-",
+                    _ => "*",
+                };
+                format!("check({} {} {})", self.operand(left), operator, self.operand(right))
+            },
+        }
+    }
+
+    // Expression as the operand of an operator, negative numbers are
+    // parenthesized so that - -1 does not read as a decrement
+    fn operand(&self, expression: &backend::Expression) -> String {
+        match expression {
+            backend::Expression::Number(number) if *number < 0 => format!("({})", number),
+            expression => self.expression(expression),
+        }
+    }
+
+    fn variable(&self, variable: &backend::Variable) -> String {
+        self.names.variables[variable.procedure][variable.index].clone()
+    }
+}
+
+// Variables assigned or read into by the statement, as (procedure, index)
+fn assigned(statement: &backend::Statement, variables: &mut BTreeSet<(usize, usize)>) {
+    match statement {
+        backend::Statement::Assign(variable, _) => {
+            variables.insert((variable.procedure, variable.index));
+        },
+        backend::Statement::Read(targets) => {
+            for variable in targets {
+                variables.insert((variable.procedure, variable.index));
+            }
+        },
+        backend::Statement::Begin(statements) => {
+            for statement in statements {
+                assigned(statement, variables);
+            }
+        },
+        backend::Statement::If(_, body) | backend::Statement::While(_, body) => assigned(body, variables),
+        backend::Statement::Call { .. } | backend::Statement::Write(_) | backend::Statement::Empty => {},
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::process;
+
+    use crate::backend;
+
+    /* test nested functions declare the variables they assign as nonlocal */
+    #[test]
+    fn test_emit() {
+        let program = backend::compile("var x, y; procedure p; var x; begin x := y; y := x - (-1) end;
+begin y := 3; call p; write(x, y / 2) end.").unwrap();
+        let output = backend::python::emit(&program);
+
+        assert!(output.contains("def main():
+    x = 0
+    y = 0
+
+    def p():
+        nonlocal y
+        x_ = 0
+        x_ = y
+        y = check(x_ - check(-1))
+
+    y = 3
+    p()
+    print(x, div(y, 2), end=\" \\n\")
+"));
+    }
+
+    /* test the scripts print what the vm prints */
+    #[test]
+    fn test_programs() {
+        if !backend::tests::available("python programs", &["python3"]) {
+            return;
+        }
+        let directory = backend::tests::temp_dir("python");
+        let source = directory.join("program.py");
+
+        for (name, content) in backend::tests::programs() {
+            fs::write(&source, backend::python::emit(&backend::compile(&content).unwrap())).unwrap();
+            let mut command = process::Command::new("python3");
+            command.arg(&source);
+
+            let output = backend::tests::run(command);
+            assert_eq!(output, backend::tests::vm_output(&content), "{}", name);
+        }
+        fs::remove_dir_all(&directory).unwrap();
+    }
+}
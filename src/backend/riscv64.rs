@@ -0,0 +1,789 @@
+use std::fmt;
+
+use crate::ast;
+use crate::backend;
+use crate::backend::native;
+
+
+/*
+ * Frame of a procedure, the static link comes in a0 like the first
+ * argument of the standard calling convention, s0 is the frame pointer:
+ *   -8(s0)             return address
+ *   -16(s0)            frame pointer of the caller (dynamic link)
+ *   -24(s0)            static link
+ *   -32 - 8 * i(s0)    variable i
+ * Operands live on the stack below, so between statements sp is aligned
+ * on 16 bytes when calling another procedure.
+ */
+const LINK_OFFSET: i32 = -24;
+
+fn variable_offset(index: usize) -> i32 {
+    -32 - 8 * index as i32
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Register {
+    Zero,
+    Ra,
+    Sp,
+    S0,
+    A0,
+    A1,
+    A2,
+    A7,
+    T0,
+    T1,
+    T2,
+    T3,
+    T4,
+    T5,
+    T6,
+}
+
+impl Register {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Register::Zero => "zero",
+            Register::Ra => "ra",
+            Register::Sp => "sp",
+            Register::S0 => "s0",
+            Register::A0 => "a0",
+            Register::A1 => "a1",
+            Register::A2 => "a2",
+            Register::A7 => "a7",
+            Register::T0 => "t0",
+            Register::T1 => "t1",
+            Register::T2 => "t2",
+            Register::T3 => "t3",
+            Register::T4 => "t4",
+            Register::T5 => "t5",
+            Register::T6 => "t6",
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Condition {
+    Equal,
+    NotEqual,
+    Less,
+    GreaterEqual,
+    LessUnsigned,
+    GreaterEqualUnsigned,
+}
+
+impl Condition {
+    pub fn suffix(&self) -> &'static str {
+        match self {
+            Condition::Equal => "eq",
+            Condition::NotEqual => "ne",
+            Condition::Less => "lt",
+            Condition::GreaterEqual => "ge",
+            Condition::LessUnsigned => "ltu",
+            Condition::GreaterEqualUnsigned => "geu",
+        }
+    }
+}
+
+/* the RV64IM instructions the backend needs, with the usual pseudo instructions */
+#[derive(Clone, Debug, PartialEq)]
+pub enum Instruction {
+    Li(Register, i64),
+    Mv(Register, Register),                         // (destination, source)
+    La(Register, String),
+    Ld(Register, Register, i32),                    // (destination, base, offset)
+    Sd(Register, Register, i32),                    // (source, base, offset)
+    Lbu(Register, Register, i32),
+    Sb(Register, Register, i32),
+    Addi(Register, Register, i32),                  // (destination, source, immediate)
+    Andi(Register, Register, i32),
+    Xori(Register, Register, i32),
+    Srai(Register, Register, i32),
+    Add(Register, Register, Register),              // (destination, first, second)
+    Sub(Register, Register, Register),
+    Mul(Register, Register, Register),
+    Mulh(Register, Register, Register),             // high 64 bits of the signed product
+    Div(Register, Register, Register),
+    Rem(Register, Register, Register),
+    Xor(Register, Register, Register),
+    Or(Register, Register, Register),
+    Slt(Register, Register, Register),              // 1 if the first is less than the second
+    Seqz(Register, Register),
+    Snez(Register, Register),
+    Neg(Register, Register),
+    Branch(Condition, Register, Register, String),  // only within 4 KiB
+    J(String),
+    Call(String),
+    Ret,
+    Ecall,
+}
+
+impl fmt::Display for Instruction {
+    // GNU assembler syntax, the destination comes first
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Instruction::Li(register, value) => write!(f, "li {}, {}", register.name(), value),
+            Instruction::Mv(destination, source) => write!(f, "mv {}, {}", destination.name(), source.name()),
+            Instruction::La(register, label) => write!(f, "la {}, {}", register.name(), label),
+            Instruction::Ld(destination, base, offset) => {
+                write!(f, "ld {}, {}({})", destination.name(), offset, base.name())
+            },
+            Instruction::Sd(source, base, offset) => write!(f, "sd {}, {}({})", source.name(), offset, base.name()),
+            Instruction::Lbu(destination, base, offset) => {
+                write!(f, "lbu {}, {}({})", destination.name(), offset, base.name())
+            },
+            Instruction::Sb(source, base, offset) => write!(f, "sb {}, {}({})", source.name(), offset, base.name()),
+            Instruction::Addi(destination, source, value) => {
+                write!(f, "addi {}, {}, {}", destination.name(), source.name(), value)
+            },
+            Instruction::Andi(destination, source, value) => {
+                write!(f, "andi {}, {}, {}", destination.name(), source.name(), value)
+            },
+            Instruction::Xori(destination, source, value) => {
+                write!(f, "xori {}, {}, {}", destination.name(), source.name(), value)
+            },
+            Instruction::Srai(destination, source, value) => {
+                write!(f, "srai {}, {}, {}", destination.name(), source.name(), value)
+            },
+            Instruction::Add(destination, first, second) => write_operation(f, "add", destination, first, second),
+            Instruction::Sub(destination, first, second) => write_operation(f, "sub", destination, first, second),
+            Instruction::Mul(destination, first, second) => write_operation(f, "mul", destination, first, second),
+            Instruction::Mulh(destination, first, second) => write_operation(f, "mulh", destination, first, second),
+            Instruction::Div(destination, first, second) => write_operation(f, "div", destination, first, second),
+            Instruction::Rem(destination, first, second) => write_operation(f, "rem", destination, first, second),
+            Instruction::Xor(destination, first, second) => write_operation(f, "xor", destination, first, second),
+            Instruction::Or(destination, first, second) => write_operation(f, "or", destination, first, second),
+            Instruction::Slt(destination, first, second) => write_operation(f, "slt", destination, first, second),
+            Instruction::Seqz(destination, source) => write!(f, "seqz {}, {}", destination.name(), source.name()),
+            Instruction::Snez(destination, source) => write!(f, "snez {}, {}", destination.name(), source.name()),
+            Instruction::Neg(destination, source) => write!(f, "neg {}, {}", destination.name(), source.name()),
+            Instruction::Branch(condition, first, second, label) => {
+                write!(f, "b{} {}, {}, {}", condition.suffix(), first.name(), second.name(), label)
+            },
+            Instruction::J(label) => write!(f, "j {}", label),
+            Instruction::Call(label) => write!(f, "call {}", label),
+            Instruction::Ret => write!(f, "ret"),
+            Instruction::Ecall => write!(f, "ecall"),
+        }
+    }
+}
+
+fn write_operation(f: &mut fmt::Formatter, mnemonic: &str, destination: &Register, first: &Register,
+    second: &Register) -> fmt::Result {
+    write!(f, "{} {}, {}, {}", mnemonic, destination.name(), first.name(), second.name())
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Line {
+    Label(String),
+    Instruction(Instruction),
+}
+
+/* whole program: code starting at _start, then read only data */
+#[derive(Clone, Debug, PartialEq)]
+pub struct Assembly {
+    pub text: Vec<Line>,
+    pub data: Vec<(String, Vec<u8>)>,
+}
+
+impl fmt::Display for Assembly {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "# Generated by pl0rust")?;
+        writeln!(f, "    .text")?;
+        writeln!(f, "    .globl _start")?;
+        for line in &self.text {
+            match line {
+                Line::Label(label) => writeln!(f, "{}:", label)?,
+                Line::Instruction(instruction) => writeln!(f, "    {}", instruction)?,
+            }
+        }
+        writeln!(f, "\n    .section .rodata")?;
+        for (label, bytes) in &self.data {
+            let bytes: Vec<String> = bytes.iter().map(|byte| byte.to_string()).collect();
+            writeln!(f, "{}:\n    .byte {}", label, bytes.join(", "))?;
+        }
+        writeln!(f, "\n    .section .note.GNU-stack, \"\", @progbits")
+    }
+}
+
+// Emit GNU assembler text for Linux on RV64IM, to be linked without libc
+// and run on a board or under a user mode emulator:
+// riscv64-linux-gnu-as -o program.o program.s && riscv64-linux-gnu-ld -o program program.o
+// qemu-riscv64 program
+pub fn emit(program: &backend::Program) -> String {
+    assemble(program).to_string()
+}
+
+pub fn assemble(program: &backend::Program) -> Assembly {
+    let names: Vec<String> = program.procedures.iter().enumerate()
+        .map(|(index, procedure)| if index == 0 { String::from("main") } else { procedure.name.clone() })
+        .collect();
+    let functions: Vec<String> = backend::identifiers(&names, &[])
+        .iter()
+        .map(|name| format!("proc_{}", name))
+        .collect();
+
+    let mut emitter = Emitter {
+        functions: &functions,
+        text: Vec::new(),
+        checks: 0,
+    };
+    emitter.label("_start");
+    emitter.emit(Instruction::Li(Register::A0, 0));
+    emitter.emit(Instruction::Call(functions[0].clone()));
+    emitter.exit(0);
+
+    for function in native::lower(program) {
+        emitter.function(&function);
+    }
+    emitter.runtime();
+
+    Assembly {
+        text: emitter.text,
+        data: MESSAGES.iter()
+            .map(|(label, message)| (label.to_string(), message.as_bytes().to_vec()))
+            .collect(),
+    }
+}
+
+const MESSAGES: [(&str, &str); 3] = [
+    ("pl0_overflow_message", "Arithmetic overflow\n"),
+    ("pl0_division_by_zero_message", "Division by zero\n"),
+    ("pl0_invalid_message", "Invalid number\n"),
+];
+
+// System calls of the generic Linux table
+const SYS_READ: i64 = 63;
+const SYS_WRITE: i64 = 64;
+const SYS_EXIT: i64 = 93;
+
+struct Emitter<'a> {
+    functions: &'a [String],
+    text: Vec<Line>,
+    checks: usize,      // local labels after the checks
+}
+
+impl Emitter<'_> {
+    fn function(&mut self, function: &native::Function) {
+        let frame = (24 + 8 * function.variables as i32 + 15) / 16 * 16;
+        self.label(&self.functions[function.procedure].clone());
+        self.emit(Instruction::Addi(Register::Sp, Register::Sp, -16));
+        self.emit(Instruction::Sd(Register::Ra, Register::Sp, 8));
+        self.emit(Instruction::Sd(Register::S0, Register::Sp, 0));
+        self.emit(Instruction::Addi(Register::S0, Register::Sp, 16));
+        self.add_immediate(Register::Sp, Register::Sp, 16 - frame);
+        self.emit(Instruction::Sd(Register::A0, Register::S0, LINK_OFFSET));
+        for index in 0..function.variables {
+            let (base, offset) = self.address(Register::S0, variable_offset(index));
+            self.emit(Instruction::Sd(Register::Zero, base, offset));
+        }
+
+        for operation in &function.code {
+            self.operation(operation);
+        }
+
+        self.emit(Instruction::Mv(Register::Sp, Register::S0));
+        self.emit(Instruction::Ld(Register::Ra, Register::Sp, -8));
+        self.emit(Instruction::Ld(Register::S0, Register::Sp, -16));
+        self.emit(Instruction::Ret);
+    }
+
+    fn operation(&mut self, operation: &native::Operation) {
+        use Register::*;
+
+        match *operation {
+            native::Operation::Push(value) => {
+                self.emit(Instruction::Li(T0, value));
+                self.push(T0);
+            },
+            native::Operation::Load(variable) => {
+                let base = self.frame(variable.depth, T1);
+                let (base, offset) = self.address(base, variable_offset(variable.index));
+                self.emit(Instruction::Ld(T0, base, offset));
+                self.push(T0);
+            },
+            native::Operation::Store(variable) => {
+                self.pop(T0);
+                let base = self.frame(variable.depth, T1);
+                let (base, offset) = self.address(base, variable_offset(variable.index));
+                self.emit(Instruction::Sd(T0, base, offset));
+            },
+            native::Operation::Negate => {
+                self.pop(T0);
+                self.emit(Instruction::Li(T3, i64::MIN));
+                self.check(Condition::NotEqual, T0, T3, "pl0_overflow");
+                self.emit(Instruction::Neg(T0, T0));
+                self.push(T0);
+            },
+            native::Operation::Binary(operator) => {
+                self.pop(T1);
+                self.pop(T0);
+                match operator {
+                    // Overflowed when adding a negative number does not make it smaller
+                    ast::BinaryOperator::Add => {
+                        self.emit(Instruction::Add(T2, T0, T1));
+                        self.emit(Instruction::Slt(T3, T1, Zero));
+                        self.emit(Instruction::Slt(T4, T2, T0));
+                        self.check(Condition::Equal, T3, T4, "pl0_overflow");
+                    },
+                    ast::BinaryOperator::Sub => {
+                        self.emit(Instruction::Sub(T2, T0, T1));
+                        self.emit(Instruction::Slt(T3, Zero, T1));
+                        self.emit(Instruction::Slt(T4, T2, T0));
+                        self.check(Condition::Equal, T3, T4, "pl0_overflow");
+                    },
+                    // The high half is only the sign of the low half without overflow
+                    ast::BinaryOperator::Mul => {
+                        self.emit(Instruction::Mul(T2, T0, T1));
+                        self.emit(Instruction::Mulh(T3, T0, T1));
+                        self.emit(Instruction::Srai(T4, T2, 63));
+                        self.check(Condition::Equal, T3, T4, "pl0_overflow");
+                    },
+                    ast::BinaryOperator::Div => {
+                        self.check(Condition::NotEqual, T1, Zero, "pl0_division_by_zero");
+                        // Only the smallest number divided by -1 overflows
+                        self.emit(Instruction::Li(T3, i64::MIN));
+                        self.emit(Instruction::Xor(T3, T0, T3));
+                        self.emit(Instruction::Addi(T4, T1, 1));
+                        self.emit(Instruction::Or(T3, T3, T4));
+                        self.check(Condition::NotEqual, T3, Zero, "pl0_overflow");
+                        self.emit(Instruction::Div(T2, T0, T1));
+                    },
+                }
+                self.push(T2);
+            },
+            native::Operation::Compare(relation) => {
+                self.pop(T1);
+                self.pop(T0);
+                match relation {
+                    ast::Relation::Eq | ast::Relation::Ne => {
+                        self.emit(Instruction::Sub(T2, T0, T1));
+                        if relation == ast::Relation::Eq {
+                            self.emit(Instruction::Seqz(T2, T2));
+                        } else {
+                            self.emit(Instruction::Snez(T2, T2));
+                        }
+                    },
+                    ast::Relation::Lt => self.emit(Instruction::Slt(T2, T0, T1)),
+                    ast::Relation::Gt => self.emit(Instruction::Slt(T2, T1, T0)),
+                    ast::Relation::Le => {
+                        self.emit(Instruction::Slt(T2, T1, T0));
+                        self.emit(Instruction::Xori(T2, T2, 1));
+                    },
+                    ast::Relation::Ge => {
+                        self.emit(Instruction::Slt(T2, T0, T1));
+                        self.emit(Instruction::Xori(T2, T2, 1));
+                    },
+                }
+                self.push(T2);
+            },
+            native::Operation::Odd => {
+                self.pop(T0);
+                self.emit(Instruction::Andi(T0, T0, 1));
+                self.push(T0);
+            },
+            native::Operation::Write => {
+                self.pop(A0);
+                self.emit(Instruction::Call(String::from("pl0_write")));
+            },
+            native::Operation::Newline => {
+                self.emit(Instruction::Call(String::from("pl0_newline")));
+            },
+            native::Operation::Read => {
+                self.emit(Instruction::Call(String::from("pl0_read")));
+                self.push(A0);
+            },
+            native::Operation::Call { procedure, depth } => {
+                if self.frame(depth, A0) == S0 {
+                    self.emit(Instruction::Mv(A0, S0));
+                }
+                self.emit(Instruction::Call(self.functions[procedure].clone()));
+            },
+            native::Operation::Label(label) => self.label(&format!(".L{}", label)),
+            native::Operation::Jump(label) => self.emit(Instruction::J(format!(".L{}", label))),
+            native::Operation::JumpIfZero(label) => {
+                self.pop(T0);
+                self.check(Condition::NotEqual, T0, Zero, &format!(".L{}", label));
+            },
+        }
+    }
+
+    // Goes on if the condition holds, jumps to label otherwise; conditional
+    // branches are short so they skip a jump which reaches anywhere
+    fn check(&mut self, condition: Condition, first: Register, second: Register, label: &str) {
+        let next = format!(".Lok{}", self.checks);
+        self.checks += 1;
+        self.emit(Instruction::Branch(condition, first, second, next.clone()));
+        self.emit(Instruction::J(label.to_string()));
+        self.label(&next);
+    }
+
+    fn push(&mut self, register: Register) {
+        self.emit(Instruction::Addi(Register::Sp, Register::Sp, -8));
+        self.emit(Instruction::Sd(register, Register::Sp, 0));
+    }
+
+    fn pop(&mut self, register: Register) {
+        self.emit(Instruction::Ld(register, Register::Sp, 0));
+        self.emit(Instruction::Addi(Register::Sp, Register::Sp, 8));
+    }
+
+    // Register holding the frame reached through depth static links
+    fn frame(&mut self, depth: usize, register: Register) -> Register {
+        if depth == 0 {
+            return Register::S0;
+        }
+        self.emit(Instruction::Ld(register, Register::S0, LINK_OFFSET));
+        for _ in 1..depth {
+            self.emit(Instruction::Ld(register, register, LINK_OFFSET));
+        }
+        register
+    }
+
+    // Base and offset within the 12 bits of the immediates, t6 holds the
+    // address when the offset is larger
+    fn address(&mut self, base: Register, offset: i32) -> (Register, i32) {
+        if (-2048..2048).contains(&offset) {
+            return (base, offset);
+        }
+        self.emit(Instruction::Li(Register::T6, offset as i64));
+        self.emit(Instruction::Add(Register::T6, base, Register::T6));
+        (Register::T6, 0)
+    }
+
+    fn add_immediate(&mut self, destination: Register, source: Register, value: i32) {
+        if (-2048..2048).contains(&value) {
+            self.emit(Instruction::Addi(destination, source, value));
+        } else {
+            self.emit(Instruction::Li(Register::T6, value as i64));
+            self.emit(Instruction::Add(destination, source, Register::T6));
+        }
+    }
+
+    /*
+     * write prints the number in a0 and a space, newline prints a new line,
+     * read prompts with ? and returns in a0 the number on the next line of
+     * stdin. Errors print their message on stderr and exit with 1. The
+     * kernel keeps every register but a0 across a system call.
+     */
+    fn runtime(&mut self) {
+        use Condition::*;
+        use Instruction::*;
+        use Register::*;
+
+        // Digits are written backwards from the end of a buffer on the
+        // stack, the number is made negative first so that the smallest one
+        // works too
+        self.label("pl0_write");
+        self.emit(Addi(Sp, Sp, -32));
+        self.emit(Addi(A1, Sp, 31));
+        self.emit(Li(T0, b' ' as i64));
+        self.emit(Sb(T0, A1, 0));
+        self.emit(Mv(T0, A0));
+        self.emit(Branch(GreaterEqual, Zero, T0, String::from(".Lwrite_digits")));
+        self.emit(Neg(T0, T0));
+        self.label(".Lwrite_digits");
+        self.emit(Li(T1, 10));
+        self.emit(Rem(T2, T0, T1));
+        self.emit(Div(T0, T0, T1));
+        self.emit(Neg(T2, T2));
+        self.emit(Addi(T2, T2, b'0' as i32));
+        self.emit(Addi(A1, A1, -1));
+        self.emit(Sb(T2, A1, 0));
+        self.emit(Branch(NotEqual, T0, Zero, String::from(".Lwrite_digits")));
+        self.emit(Branch(GreaterEqual, A0, Zero, String::from(".Lwrite_output")));
+        self.emit(Addi(A1, A1, -1));
+        self.emit(Li(T0, b'-' as i64));
+        self.emit(Sb(T0, A1, 0));
+        self.label(".Lwrite_output");
+        self.emit(Addi(A2, Sp, 32));
+        self.emit(Sub(A2, A2, A1));
+        self.emit(Li(A0, 1));
+        self.emit(Li(A7, SYS_WRITE));
+        self.emit(Ecall);
+        self.emit(Addi(Sp, Sp, 32));
+        self.emit(Ret);
+
+        self.label("pl0_newline");
+        self.emit(Li(A0, b'\n' as i64));
+        self.emit(J(String::from("pl0_put")));
+
+        // Write the byte in a0 to stdout
+        self.label("pl0_put");
+        self.emit(Addi(Sp, Sp, -16));
+        self.emit(Sb(A0, Sp, 0));
+        self.emit(Mv(A1, Sp));
+        self.emit(Li(A2, 1));
+        self.emit(Li(A0, 1));
+        self.emit(Li(A7, SYS_WRITE));
+        self.emit(Ecall);
+        self.emit(Addi(Sp, Sp, 16));
+        self.emit(Ret);
+
+        // The line is read byte by byte into a buffer on the stack, t1
+        // counts its bytes even past the end of the buffer
+        self.label("pl0_read");
+        self.emit(Addi(Sp, Sp, -272));
+        self.emit(Sd(Ra, Sp, 264));
+        self.emit(Li(A0, b'?' as i64));
+        self.emit(Call(String::from("pl0_put")));
+        self.emit(Li(T1, 0));
+        self.emit(Li(T2, 256));
+        self.label(".Lread_byte");
+        self.emit(Li(A0, 0));
+        self.emit(Addi(A1, Sp, 256));
+        self.emit(Li(A2, 1));
+        self.emit(Li(A7, SYS_READ));
+        self.emit(Ecall);
+        self.emit(Li(T0, 1));
+        self.emit(Branch(NotEqual, A0, T0, String::from(".Lread_parse")));
+        self.emit(Lbu(T0, Sp, 256));
+        self.emit(Li(T3, b'\n' as i64));
+        self.emit(Branch(Equal, T0, T3, String::from(".Lread_parse")));
+        self.emit(Branch(GreaterEqual, T1, T2, String::from(".Lread_count")));
+        self.emit(Add(T3, Sp, T1));
+        self.emit(Sb(T0, T3, 0));
+        self.label(".Lread_count");
+        self.emit(Addi(T1, T1, 1));
+        self.emit(J(String::from(".Lread_byte")));
+
+        // a1 goes through the buffer up to a2, the number is accumulated
+        // negatively in t4, t5 tells whether it has a minus sign
+        self.label(".Lread_parse");
+        self.emit(Branch(Less, T2, T1, String::from("pl0_invalid")));
+        self.emit(Mv(A1, Sp));
+        self.emit(Add(A2, Sp, T1));
+        self.label(".Lread_leading");
+        self.emit(Branch(GreaterEqual, A1, A2, String::from("pl0_invalid")));
+        self.emit(Lbu(T0, A1, 0));
+        self.whitespace(".Lread_leading_space");
+        self.emit(J(String::from(".Lread_sign")));
+        self.label(".Lread_leading_space");
+        self.emit(Addi(A1, A1, 1));
+        self.emit(J(String::from(".Lread_leading")));
+
+        self.label(".Lread_sign");
+        self.emit(Li(T5, 0));
+        self.emit(Li(T3, b'+' as i64));
+        self.emit(Branch(Equal, T0, T3, String::from(".Lread_skip_sign")));
+        self.emit(Li(T3, b'-' as i64));
+        self.emit(Branch(NotEqual, T0, T3, String::from(".Lread_number")));
+        self.emit(Li(T5, 1));
+        self.label(".Lread_skip_sign");
+        self.emit(Addi(A1, A1, 1));
+        self.label(".Lread_number");
+        self.emit(Li(T4, 0));
+        self.emit(Mv(T6, A1));
+        self.emit(Li(T1, 10));
+        self.label(".Lread_digit");
+        self.emit(Branch(GreaterEqual, A1, A2, String::from(".Lread_digits_end")));
+        self.emit(Lbu(T0, A1, 0));
+        self.emit(Addi(T0, T0, -(b'0' as i32)));
+        self.emit(Branch(GreaterEqualUnsigned, T0, T1, String::from(".Lread_digits_end")));
+        self.emit(Mul(T2, T4, T1));
+        self.emit(Mulh(T3, T4, T1));
+        self.emit(Srai(A0, T2, 63));
+        self.emit(Branch(NotEqual, T3, A0, String::from("pl0_invalid")));
+        self.emit(Sub(T4, T2, T0));
+        self.emit(Branch(Less, T2, T4, String::from("pl0_invalid")));
+        self.emit(Addi(A1, A1, 1));
+        self.emit(J(String::from(".Lread_digit")));
+        self.label(".Lread_digits_end");
+        self.emit(Branch(Equal, A1, T6, String::from("pl0_invalid")));
+
+        self.label(".Lread_trailing");
+        self.emit(Branch(GreaterEqual, A1, A2, String::from(".Lread_done")));
+        self.emit(Lbu(T0, A1, 0));
+        self.whitespace(".Lread_trailing_space");
+        self.emit(J(String::from("pl0_invalid")));
+        self.label(".Lread_trailing_space");
+        self.emit(Addi(A1, A1, 1));
+        self.emit(J(String::from(".Lread_trailing")));
+
+        self.label(".Lread_done");
+        self.emit(Branch(NotEqual, T5, Zero, String::from(".Lread_return")));
+        self.emit(Li(T3, i64::MIN));
+        self.emit(Branch(Equal, T4, T3, String::from("pl0_invalid")));
+        self.emit(Neg(T4, T4));
+        self.label(".Lread_return");
+        self.emit(Mv(A0, T4));
+        self.emit(Ld(Ra, Sp, 264));
+        self.emit(Addi(Sp, Sp, 272));
+        self.emit(Ret);
+
+        for (label, message) in MESSAGES.iter() {
+            self.label(label.trim_end_matches("_message"));
+            self.emit(La(A1, label.to_string()));
+            self.emit(Li(A2, message.len() as i64));
+            self.emit(J(String::from("pl0_error")));
+        }
+        self.label("pl0_error");
+        self.emit(Li(A0, 2));
+        self.emit(Li(A7, SYS_WRITE));
+        self.emit(Ecall);
+        self.exit(1);
+    }
+
+    // Jump to label if the byte in t0 is a white space
+    fn whitespace(&mut self, label: &str) {
+        self.emit(Instruction::Li(Register::T3, b' ' as i64));
+        self.emit(Instruction::Branch(Condition::Equal, Register::T0, Register::T3, label.to_string()));
+        self.emit(Instruction::Addi(Register::T3, Register::T0, -9));
+        self.emit(Instruction::Li(Register::A7, 5));
+        self.emit(Instruction::Branch(Condition::LessUnsigned, Register::T3, Register::A7, label.to_string()));
+    }
+
+    fn exit(&mut self, code: i64) {
+        self.emit(Instruction::Li(Register::A0, code));
+        self.emit(Instruction::Li(Register::A7, SYS_EXIT));
+        self.emit(Instruction::Ecall);
+    }
+
+    fn label(&mut self, label: &str) {
+        self.text.push(Line::Label(label.to_string()));
+    }
+
+    fn emit(&mut self, instruction: Instruction) {
+        self.text.push(Line::Instruction(instruction));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::process;
+
+    use crate::backend;
+
+    /* test variables are reached through the static links */
+    #[test]
+    fn test_emit() {
+        let program = backend::compile("var x; procedure p; procedure q; x := 2; call q; begin call p; write(x) end.").unwrap();
+        let output = backend::riscv64::emit(&program);
+
+        assert!(output.contains("proc_q:
+    addi sp, sp, -16
+    sd ra, 8(sp)
+    sd s0, 0(sp)
+    addi s0, sp, 16
+    addi sp, sp, -16
+    sd a0, -24(s0)
+    li t0, 2
+    addi sp, sp, -8
+    sd t0, 0(sp)
+    ld t0, 0(sp)
+    addi sp, sp, 8
+    ld t1, -24(s0)
+    ld t1, -24(t1)
+    sd t0, -32(t1)
+    mv sp, s0
+    ld ra, -8(sp)
+    ld s0, -16(sp)
+    ret
+"));
+        assert!(output.contains("proc_main:
+    addi sp, sp, -16
+    sd ra, 8(sp)
+    sd s0, 0(sp)
+    addi s0, sp, 16
+    addi sp, sp, -16
+    sd a0, -24(s0)
+    sd zero, -32(s0)
+    mv a0, s0
+    call proc_p
+"));
+    }
+
+    /* test the listing of a small program, the code of main before the runtime routines */
+    #[test]
+    fn test_listing() {
+        let program = backend::compile("var x; x := -x / 3.").unwrap();
+        let output = backend::riscv64::emit(&program);
+
+        let main = output.split("pl0_write:").next().unwrap();
+        assert_eq!(main, "# Generated by pl0rust
+    .text
+    .globl _start
+_start:
+    li a0, 0
+    call proc_main
+    li a0, 0
+    li a7, 93
+    ecall
+proc_main:
+    addi sp, sp, -16
+    sd ra, 8(sp)
+    sd s0, 0(sp)
+    addi s0, sp, 16
+    addi sp, sp, -16
+    sd a0, -24(s0)
+    sd zero, -32(s0)
+    ld t0, -32(s0)
+    addi sp, sp, -8
+    sd t0, 0(sp)
+    li t0, 3
+    addi sp, sp, -8
+    sd t0, 0(sp)
+    ld t1, 0(sp)
+    addi sp, sp, 8
+    ld t0, 0(sp)
+    addi sp, sp, 8
+    bne t1, zero, .Lok0
+    j pl0_division_by_zero
+.Lok0:
+    li t3, -9223372036854775808
+    xor t3, t0, t3
+    addi t4, t1, 1
+    or t3, t3, t4
+    bne t3, zero, .Lok1
+    j pl0_overflow
+.Lok1:
+    div t2, t0, t1
+    addi sp, sp, -8
+    sd t2, 0(sp)
+    ld t0, 0(sp)
+    addi sp, sp, 8
+    li t3, -9223372036854775808
+    bne t0, t3, .Lok2
+    j pl0_overflow
+.Lok2:
+    neg t0, t0
+    addi sp, sp, -8
+    sd t0, 0(sp)
+    ld t0, 0(sp)
+    addi sp, sp, 8
+    sd t0, -32(s0)
+    mv sp, s0
+    ld ra, -8(sp)
+    ld s0, -16(sp)
+    ret
+");
+        assert!(output.ends_with("    .section .note.GNU-stack, \"\", @progbits\n"));
+    }
+
+    /* test the assembled programs print what the vm prints under an emulator */
+    #[test]
+    #[ignore = "needs riscv64 toolchain and qemu"]
+    fn test_programs() {
+        let missing = backend::tests::missing(&["riscv64-linux-gnu-as", "riscv64-linux-gnu-ld", "qemu-riscv64"]);
+        assert!(missing.is_empty(), "{} not found", missing.join(", "));
+        let directory = backend::tests::temp_dir("riscv64");
+        let source = directory.join("program.s");
+        let object = directory.join("program.o");
+        let binary = directory.join("program");
+
+        for (name, content) in backend::tests::programs() {
+            fs::write(&source, backend::riscv64::emit(&backend::compile(&content).unwrap())).unwrap();
+            let assembled = process::Command::new("riscv64-linux-gnu-as").arg("-o").arg(&object).arg(&source).status().unwrap();
+            assert!(assembled.success(), "{}", name);
+            let linked = process::Command::new("riscv64-linux-gnu-ld").arg("-o").arg(&binary).arg(&object).status().unwrap();
+            assert!(linked.success(), "{}", name);
+
+            let mut command = process::Command::new("qemu-riscv64");
+            command.arg(&binary);
+            let output = backend::tests::run(command);
+            assert_eq!(output, backend::tests::vm_output(&content), "{}", name);
+        }
+        fs::remove_dir_all(&directory).unwrap();
+    }
+}
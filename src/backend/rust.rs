@@ -0,0 +1,287 @@
+use std::fmt::Write;
+
+use crate::ast;
+use crate::backend;
+
+const INDENT: &str = "    ";
+
+const RESERVED: [&str; 50] = [
+    "as", "break", "const", "continue", "crate", "else", "enum", "extern", "false", "fn",
+    "for", "if", "impl", "in", "let", "loop", "match", "mod", "move", "mut",
+    "pub", "ref", "return", "self", "Self", "static", "struct", "super", "trait", "true",
+    "type", "unsafe", "use", "where", "while", "async", "await", "dyn", "abstract", "become",
+    "box", "do", "final", "macro", "override", "priv", "typeof", "unsized", "virtual", "yield",
+];
+
+// Checked arithmetic and stdin based read like the vm, which parses the
+// line the same way
+const RUNTIME: &str = r#"#![allow(dead_code, non_camel_case_types, non_snake_case, unused_assignments, unused_mut, unused_variables)]
+
+use std::io::{self, Write};
+use std::process;
+
+fn fail(message: &str) -> ! {
+    let _ = io::stdout().flush();
+    eprintln!("{}", message);
+    process::exit(1);
+}
+
+fn add(a: i64, b: i64) -> i64 {
+    a.checked_add(b).unwrap_or_else(|| fail("Arithmetic overflow"))
+}
+
+fn sub(a: i64, b: i64) -> i64 {
+    a.checked_sub(b).unwrap_or_else(|| fail("Arithmetic overflow"))
+}
+
+fn mul(a: i64, b: i64) -> i64 {
+    a.checked_mul(b).unwrap_or_else(|| fail("Arithmetic overflow"))
+}
+
+fn div(a: i64, b: i64) -> i64 {
+    if b == 0 {
+        fail("Division by zero");
+    }
+    a.checked_div(b).unwrap_or_else(|| fail("Arithmetic overflow"))
+}
+
+fn neg(a: i64) -> i64 {
+    a.checked_neg().unwrap_or_else(|| fail("Arithmetic overflow"))
+}
+
+fn read() -> i64 {
+    print!("?");
+    let _ = io::stdout().flush();
+    let mut line = String::new();
+    if io::stdin().read_line(&mut line).is_err() {
+        fail("Invalid number");
+    }
+    line.trim().parse().unwrap_or_else(|_| fail("Invalid number"))
+}
+"#;
+
+
+/*
+ * Emit a Rust program, each procedure is a function with its variables in
+ * a frame structure. Instead of a static link, a procedure takes mutable
+ * references to the frames of all the enclosing procedures, innermost
+ * first, so the borrow checker sees they never alias.
+ */
+pub fn emit(program: &backend::Program) -> String {
+    let names: Vec<String> = program.procedures.iter().enumerate()
+        .map(|(index, procedure)| if index == 0 { String::from("main") } else { procedure.name.clone() })
+        .collect();
+    let names = backend::identifiers(&names, &[]);
+    let fields: Vec<Vec<String>> = program.procedures.iter()
+        .map(|procedure| backend::identifiers(&procedure.variables, &RESERVED))
+        .collect();
+
+    let mut emitter = Emitter {
+        program,
+        names: &names,
+        fields: &fields,
+        output: String::new(),
+    };
+    emitter.output.push_str("// Generated by pl0rust\n");
+    emitter.output.push_str(RUNTIME);
+    emitter.frames();
+    for index in 0..program.procedures.len() {
+        emitter.function(index);
+    }
+    writeln!(emitter.output, "\nfn main() {{\n{}proc_{}();\n}}", INDENT, names[0]).unwrap();
+    emitter.output
+}
+
+struct Emitter<'a> {
+    program: &'a backend::Program,
+    names: &'a [String],
+    fields: &'a [Vec<String>],
+    output: String,
+}
+
+impl Emitter<'_> {
+    fn frames(&mut self) {
+        for (index, fields) in self.fields.iter().enumerate() {
+            writeln!(self.output, "\nstruct frame_{} {{", self.names[index]).unwrap();
+            for field in fields {
+                writeln!(self.output, "{}{}: i64,", INDENT, field).unwrap();
+            }
+            self.output.push_str("}\n");
+        }
+    }
+
+    // Enclosing procedures, innermost first
+    fn ancestors(&self, index: usize) -> Vec<usize> {
+        let mut ancestors = Vec::new();
+        let mut current = self.program.procedures[index].parent;
+        while let Some(parent) = current {
+            ancestors.push(parent);
+            current = self.program.procedures[parent].parent;
+        }
+        ancestors
+    }
+
+    fn function(&mut self, index: usize) {
+        let procedure = &self.program.procedures[index];
+        match procedure.parent {
+            Some(_) => writeln!(self.output, "\n// procedure {}", procedure.name).unwrap(),
+            None => self.output.push_str("\n// main program\n"),
+        }
+        let parameters: Vec<String> = self.ancestors(index).iter()
+            .map(|&ancestor| format!("{}_frame: &mut frame_{}", self.names[ancestor], self.names[ancestor]))
+            .collect();
+        writeln!(self.output, "fn proc_{}({}) {{", self.names[index], parameters.join(", ")).unwrap();
+        let initializers: Vec<String> = self.fields[index].iter().map(|field| format!("{}: 0", field)).collect();
+        if initializers.is_empty() {
+            writeln!(self.output, "{}let mut frame = frame_{} {{}};", INDENT, self.names[index]).unwrap();
+        } else {
+            writeln!(self.output, "{}let mut frame = frame_{} {{ {} }};", INDENT, self.names[index],
+                initializers.join(", ")).unwrap();
+        }
+        let ancestors = self.ancestors(index);
+        self.statement(&procedure.body, &ancestors, 1);
+        self.output.push_str("}\n");
+    }
+
+    fn statement(&mut self, statement: &backend::Statement, ancestors: &[usize], indent: usize) {
+        let prefix = INDENT.repeat(indent);
+        match statement {
+            backend::Statement::Assign(variable, expression) => {
+                let expression = self.expression(expression, ancestors);
+                writeln!(self.output, "{}{} = {};", prefix, self.variable(variable, ancestors), expression).unwrap();
+            },
+            backend::Statement::Call { procedure, depth } => {
+                // The frames the callee encloses in are those from depth on
+                let level = self.program.procedures[*procedure].level;
+                let arguments: Vec<String> = (*depth..*depth + level)
+                    .map(|depth| match depth {
+                        0 => String::from("&mut frame"),
+                        depth => format!("{}_frame", self.names[ancestors[depth - 1]]),
+                    })
+                    .collect();
+                writeln!(self.output, "{}proc_{}({});", prefix, self.names[*procedure], arguments.join(", ")).unwrap();
+            },
+            backend::Statement::Read(variables) => {
+                for variable in variables {
+                    writeln!(self.output, "{}{} = read();", prefix, self.variable(variable, ancestors)).unwrap();
+                }
+            },
+            backend::Statement::Write(expressions) => {
+                // A space after every number, like the vm
+                let arguments: Vec<String> = expressions.iter()
+                    .map(|expression| self.expression(expression, ancestors))
+                    .collect();
+                let format = "{} ".repeat(arguments.len());
+                if arguments.is_empty() {
+                    writeln!(self.output, "{}println!();", prefix).unwrap();
+                } else {
+                    writeln!(self.output, "{}println!(\"{}\", {});", prefix, format, arguments.join(", ")).unwrap();
+                }
+            },
+            backend::Statement::Begin(statements) => {
+                for statement in statements {
+                    self.statement(statement, ancestors, indent);
+                }
+            },
+            backend::Statement::If(condition, body) => {
+                writeln!(self.output, "{}if {} {{", prefix, self.condition(condition, ancestors)).unwrap();
+                self.statement(body, ancestors, indent + 1);
+                writeln!(self.output, "{}}}", prefix).unwrap();
+            },
+            backend::Statement::While(condition, body) => {
+                writeln!(self.output, "{}while {} {{", prefix, self.condition(condition, ancestors)).unwrap();
+                self.statement(body, ancestors, indent + 1);
+                writeln!(self.output, "{}}}", prefix).unwrap();
+            },
+            backend::Statement::Empty => {},
+        }
+    }
+
+    fn condition(&self, condition: &backend::Condition, ancestors: &[usize]) -> String {
+        match condition {
+            backend::Condition::Odd(expression) => format!("{} % 2 != 0", self.expression(expression, ancestors)),
+            backend::Condition::Compare(left, relation, right) => {
+                let operator = match relation {
+                    ast::Relation::Eq => "==",
+                    relation => relation.symbol(),
+                };
+                format!("{} {} {}", self.expression(left, ancestors), operator, self.expression(right, ancestors))
+            },
+        }
+    }
+
+    fn expression(&self, expression: &backend::Expression, ancestors: &[usize]) -> String {
+        match expression {
+            backend::Expression::Number(number) => number.to_string(),
+            backend::Expression::Variable(variable) => self.variable(variable, ancestors),
+            backend::Expression::Negate(operand) => format!("neg({})", self.expression(operand, ancestors)),
+            backend::Expression::Binary(left, operator, right) => {
+                let function = match operator {
+                    ast::BinaryOperator::Add => "add",
+                    ast::BinaryOperator::Sub => "sub",
+                    ast::BinaryOperator::Mul => "mul",
+                    ast::BinaryOperator::Div => "div",
+                };
+                format!("{}({}, {})", function, self.expression(left, ancestors), self.expression(right, ancestors))
+            },
+        }
+    }
+
+    fn variable(&self, variable: &backend::Variable, ancestors: &[usize]) -> String {
+        let field = &self.fields[variable.procedure][variable.index];
+        match variable.depth {
+            0 => format!("frame.{}", field),
+            depth => format!("{}_frame.{}", self.names[ancestors[depth - 1]], field),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::process;
+
+    use crate::backend;
+
+    /* test procedures take the frames of the enclosing procedures */
+    #[test]
+    fn test_emit() {
+        let program = backend::compile("var x; procedure p; var type; procedure q; begin x := type * 2; call p end;
+call q; begin x := 3; call p; write(x, -x) end.").unwrap();
+        let output = backend::rust::emit(&program);
+
+        assert!(output.contains("struct frame_p {\n    type_: i64,\n}"));
+        assert!(output.contains("fn proc_q(p_frame: &mut frame_p, main_frame: &mut frame_main) {
+    let mut frame = frame_q {};
+    main_frame.x = mul(p_frame.type_, 2);
+    proc_p(main_frame);
+}"));
+        assert!(output.contains("    frame.x = 3;\n    proc_p(&mut frame);\n    println!(\"{} {} \", frame.x, neg(frame.x));\n"));
+    }
+
+    /* test the compiled programs print what the vm prints */
+    #[test]
+    fn test_programs() {
+        if !backend::tests::available("rust programs", &["rustc"]) {
+            return;
+        }
+        let directory = backend::tests::temp_dir("rust");
+        let source = directory.join("program.rs");
+        let binary = directory.join("program");
+
+        for (name, content) in backend::tests::programs() {
+            fs::write(&source, backend::rust::emit(&backend::compile(&content).unwrap())).unwrap();
+            let status = process::Command::new("rustc")
+                .args(["--edition", "2018", "-D", "warnings", "-o"])
+                .arg(&binary)
+                .arg(&source)
+                .status()
+                .unwrap();
+            assert!(status.success(), "{}", name);
+
+            let output = backend::tests::run(process::Command::new(&binary));
+            assert_eq!(output, backend::tests::vm_output(&content), "{}", name);
+        }
+        fs::remove_dir_all(&directory).unwrap();
+    }
+}
@@ -0,0 +1,568 @@
+use crate::ast;
+use crate::backend;
+
+
+/*
+ * WebAssembly module of the program. The host provides the imports of the
+ * "pl0" module:
+ *   write(i64)     prints a value followed by a space
+ *   newline()      ends the line of a write statement
+ *   read() -> i64  prompts with "?" and reads a number
+ *   error(i32)     reports ERRORS[code] and stops the program
+ * and runs the exported "main" function, "memory" is exported too.
+ *
+ * Activation records live in the linear memory, from address 0 upwards,
+ * the global stack pointer is the address of the next one:
+ *   0(frame)           static link, the frame of the enclosing procedure
+ *   8 + 8 * i(frame)   variable i
+ * Each procedure is a function taking the static link, except the main
+ * procedure which has none.
+ */
+pub const ERRORS: [&str; 3] = [
+    "Arithmetic overflow",
+    "Division by zero",
+    "Stack overflow",
+];
+
+const OVERFLOW: i32 = 0;
+const DIVISION_BY_ZERO: i32 = 1;
+const STACK_OVERFLOW: i32 = 2;
+
+const PAGE_SIZE: usize = 65536;
+
+// Types
+const I32: u8 = 0x7f;
+const I64: u8 = 0x7e;
+const FUNCTION: u8 = 0x60;
+const EMPTY: u8 = 0x40;
+
+const TYPES: [(&[u8], &[u8]); 6] = [
+    (&[I64], &[]),          // write
+    (&[], &[]),             // newline and the main procedure
+    (&[], &[I64]),          // read
+    (&[I32], &[]),          // error and the other procedures
+    (&[I64, I64], &[I64]),  // checked binary operations
+    (&[I64], &[I64]),       // checked negation
+];
+const TYPE_PROCEDURE: u32 = 3;
+const TYPE_MAIN: u32 = 1;
+
+// Functions, the imports come first
+const IMPORTS: [(&str, u32); 4] = [("write", 0), ("newline", 1), ("read", 2), ("error", 3)];
+const WRITE: u32 = 0;
+const NEWLINE: u32 = 1;
+const READ: u32 = 2;
+const ERROR: u32 = 3;
+const ADD: u32 = 4;
+const SUB: u32 = 5;
+const MUL: u32 = 6;
+const DIV: u32 = 7;
+const NEG: u32 = 8;
+const PROCEDURES: u32 = 9;
+
+const STACK_POINTER: u32 = 0;
+
+// Instructions
+const UNREACHABLE: u8 = 0x00;
+const BLOCK: u8 = 0x02;
+const LOOP: u8 = 0x03;
+const IF: u8 = 0x04;
+const END: u8 = 0x0b;
+const BR: u8 = 0x0c;
+const BR_IF: u8 = 0x0d;
+const RETURN: u8 = 0x0f;
+const CALL: u8 = 0x10;
+const LOCAL_GET: u8 = 0x20;
+const LOCAL_SET: u8 = 0x21;
+const LOCAL_TEE: u8 = 0x22;
+const GLOBAL_GET: u8 = 0x23;
+const GLOBAL_SET: u8 = 0x24;
+const I32_LOAD: u8 = 0x28;
+const I64_LOAD: u8 = 0x29;
+const I32_STORE: u8 = 0x36;
+const I64_STORE: u8 = 0x37;
+const MEMORY_SIZE: u8 = 0x3f;
+const MEMORY_GROW: u8 = 0x40;
+const I32_CONST: u8 = 0x41;
+const I64_CONST: u8 = 0x42;
+const I32_EQZ: u8 = 0x45;
+const I32_EQ: u8 = 0x46;
+const I32_GT_U: u8 = 0x4b;
+const I64_EQZ: u8 = 0x50;
+const I64_EQ: u8 = 0x51;
+const I64_NE: u8 = 0x52;
+const I64_LT_S: u8 = 0x53;
+const I64_GT_S: u8 = 0x55;
+const I64_LE_S: u8 = 0x57;
+const I64_GE_S: u8 = 0x59;
+const I32_ADD: u8 = 0x6a;
+const I32_AND: u8 = 0x71;
+const I32_SHL: u8 = 0x74;
+const I64_ADD: u8 = 0x7c;
+const I64_SUB: u8 = 0x7d;
+const I64_MUL: u8 = 0x7e;
+const I64_DIV_S: u8 = 0x7f;
+const I64_AND: u8 = 0x83;
+const I64_XOR: u8 = 0x85;
+const I32_WRAP_I64: u8 = 0xa7;
+
+pub fn emit(program: &backend::Program) -> Vec<u8> {
+    let mut module = vec![0x00, b'a', b's', b'm', 1, 0, 0, 0];
+
+    let mut types = Vec::new();
+    unsigned(&mut types, TYPES.len() as u64);
+    for (parameters, results) in TYPES.iter() {
+        types.push(FUNCTION);
+        unsigned(&mut types, parameters.len() as u64);
+        types.extend_from_slice(parameters);
+        unsigned(&mut types, results.len() as u64);
+        types.extend_from_slice(results);
+    }
+    section(&mut module, 1, &types);
+
+    let mut imports = Vec::new();
+    unsigned(&mut imports, IMPORTS.len() as u64);
+    for (name, type_index) in IMPORTS.iter() {
+        string(&mut imports, "pl0");
+        string(&mut imports, name);
+        imports.push(0x00);
+        unsigned(&mut imports, *type_index as u64);
+    }
+    section(&mut module, 2, &imports);
+
+    let mut functions = Vec::new();
+    unsigned(&mut functions, (PROCEDURES - ADD) as u64 + program.procedures.len() as u64);
+    functions.extend_from_slice(&[4, 4, 4, 4, 5]);
+    for procedure in &program.procedures {
+        let type_index = if procedure.parent.is_some() { TYPE_PROCEDURE } else { TYPE_MAIN };
+        unsigned(&mut functions, type_index as u64);
+    }
+    section(&mut module, 3, &functions);
+
+    // One page to begin with, grown along with the stack
+    section(&mut module, 5, &[1, 0x00, 1]);
+
+    let mut globals = vec![1, I32, 1];
+    globals.push(I32_CONST);
+    signed(&mut globals, 0);
+    globals.push(END);
+    section(&mut module, 6, &globals);
+
+    let mut exports = Vec::new();
+    unsigned(&mut exports, 2);
+    string(&mut exports, "main");
+    exports.push(0x00);
+    unsigned(&mut exports, PROCEDURES as u64);
+    string(&mut exports, "memory");
+    exports.push(0x02);
+    unsigned(&mut exports, 0);
+    section(&mut module, 7, &exports);
+
+    let mut bodies: Vec<Vec<u8>> = vec![add(), sub(), mul(), div(), neg()];
+    for index in 0..program.procedures.len() {
+        bodies.push(procedure(program, index));
+    }
+    let mut code = Vec::new();
+    unsigned(&mut code, bodies.len() as u64);
+    for body in bodies {
+        unsigned(&mut code, body.len() as u64);
+        code.extend_from_slice(&body);
+    }
+    section(&mut module, 10, &code);
+    module
+}
+
+fn section(module: &mut Vec<u8>, id: u8, contents: &[u8]) {
+    module.push(id);
+    unsigned(module, contents.len() as u64);
+    module.extend_from_slice(contents);
+}
+
+fn string(output: &mut Vec<u8>, text: &str) {
+    unsigned(output, text.len() as u64);
+    output.extend_from_slice(text.as_bytes());
+}
+
+// LEB128 encodings
+pub fn unsigned(output: &mut Vec<u8>, mut value: u64) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            output.push(byte);
+            return;
+        }
+        output.push(byte | 0x80);
+    }
+}
+
+pub fn signed(output: &mut Vec<u8>, mut value: i64) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        // Done once the rest is only the sign, the sign bit of the byte included
+        if (value == 0 && byte & 0x40 == 0) || (value == -1 && byte & 0x40 != 0) {
+            output.push(byte);
+            return;
+        }
+        output.push(byte | 0x80);
+    }
+}
+
+/* body of a function: locals, instructions and the final end */
+struct Function {
+    code: Vec<u8>,
+}
+
+impl Function {
+    // locals declared besides the parameters
+    fn new(locals: &[(u32, u8)]) -> Function {
+        let mut code = Vec::new();
+        unsigned(&mut code, locals.len() as u64);
+        for (count, value_type) in locals {
+            unsigned(&mut code, *count as u64);
+            code.push(*value_type);
+        }
+        Function { code }
+    }
+
+    fn op(&mut self, opcode: u8) -> &mut Function {
+        self.code.push(opcode);
+        self
+    }
+
+    fn index(&mut self, opcode: u8, index: u32) -> &mut Function {
+        self.code.push(opcode);
+        unsigned(&mut self.code, index as u64);
+        self
+    }
+
+    fn i32_const(&mut self, value: i32) -> &mut Function {
+        self.code.push(I32_CONST);
+        signed(&mut self.code, value as i64);
+        self
+    }
+
+    fn i64_const(&mut self, value: i64) -> &mut Function {
+        self.code.push(I64_CONST);
+        signed(&mut self.code, value);
+        self
+    }
+
+    // Load or store with the alignment of the value as a power of 2
+    fn memory(&mut self, opcode: u8, alignment: u32, offset: u32) -> &mut Function {
+        self.code.push(opcode);
+        unsigned(&mut self.code, alignment as u64);
+        unsigned(&mut self.code, offset as u64);
+        self
+    }
+
+    // Stops the program with an error when the condition on the stack is true
+    fn error_if(&mut self, error: i32) -> &mut Function {
+        self.op(IF).op(EMPTY).i32_const(error).index(CALL, ERROR).op(UNREACHABLE).op(END)
+    }
+
+    fn end(&mut self) -> Vec<u8> {
+        self.code.push(END);
+        std::mem::take(&mut self.code)
+    }
+}
+
+// add(a, b), the result has not the sign of any operand on overflow
+fn add() -> Vec<u8> {
+    Function::new(&[(1, I64)])
+        .index(LOCAL_GET, 0).index(LOCAL_GET, 1).op(I64_ADD).index(LOCAL_SET, 2)
+        .index(LOCAL_GET, 0).index(LOCAL_GET, 2).op(I64_XOR)
+        .index(LOCAL_GET, 1).index(LOCAL_GET, 2).op(I64_XOR)
+        .op(I64_AND).i64_const(0).op(I64_LT_S).error_if(OVERFLOW)
+        .index(LOCAL_GET, 2)
+        .end()
+}
+
+// sub(a, b), overflows when the operands have different signs and the
+// result has not the sign of a
+fn sub() -> Vec<u8> {
+    Function::new(&[(1, I64)])
+        .index(LOCAL_GET, 0).index(LOCAL_GET, 1).op(I64_SUB).index(LOCAL_SET, 2)
+        .index(LOCAL_GET, 0).index(LOCAL_GET, 1).op(I64_XOR)
+        .index(LOCAL_GET, 0).index(LOCAL_GET, 2).op(I64_XOR)
+        .op(I64_AND).i64_const(0).op(I64_LT_S).error_if(OVERFLOW)
+        .index(LOCAL_GET, 2)
+        .end()
+}
+
+// mul(a, b), the product divided by a gives back b unless it overflowed
+fn mul() -> Vec<u8> {
+    Function::new(&[(1, I64)])
+        .index(LOCAL_GET, 0).op(I64_EQZ)
+        .op(IF).op(EMPTY).i64_const(0).op(RETURN).op(END)
+        .index(LOCAL_GET, 0).i64_const(-1).op(I64_EQ)
+        .index(LOCAL_GET, 1).i64_const(i64::MIN).op(I64_EQ)
+        .op(I32_AND).error_if(OVERFLOW)
+        .index(LOCAL_GET, 0).index(LOCAL_GET, 1).op(I64_MUL).index(LOCAL_TEE, 2)
+        .index(LOCAL_GET, 0).op(I64_DIV_S).index(LOCAL_GET, 1).op(I64_NE).error_if(OVERFLOW)
+        .index(LOCAL_GET, 2)
+        .end()
+}
+
+// div(a, b), checked before wasm traps
+fn div() -> Vec<u8> {
+    Function::new(&[])
+        .index(LOCAL_GET, 1).op(I64_EQZ).error_if(DIVISION_BY_ZERO)
+        .index(LOCAL_GET, 0).i64_const(i64::MIN).op(I64_EQ)
+        .index(LOCAL_GET, 1).i64_const(-1).op(I64_EQ)
+        .op(I32_AND).error_if(OVERFLOW)
+        .index(LOCAL_GET, 0).index(LOCAL_GET, 1).op(I64_DIV_S)
+        .end()
+}
+
+fn neg() -> Vec<u8> {
+    Function::new(&[])
+        .index(LOCAL_GET, 0).i64_const(i64::MIN).op(I64_EQ).error_if(OVERFLOW)
+        .i64_const(0).index(LOCAL_GET, 0).op(I64_SUB)
+        .end()
+}
+
+fn procedure(program: &backend::Program, index: usize) -> Vec<u8> {
+    let procedure = &program.procedures[index];
+    // The frame address follows the static link parameter
+    let frame = if procedure.parent.is_some() { 1 } else { 0 };
+    let size = 8 + 8 * procedure.variables.len();
+
+    let mut emitter = Emitter {
+        program,
+        frame,
+        function: Function::new(&[(1, I32)]),
+    };
+    let function = &mut emitter.function;
+    function.index(GLOBAL_GET, STACK_POINTER).index(LOCAL_TEE, frame)
+        .i32_const(size as i32).op(I32_ADD).index(GLOBAL_SET, STACK_POINTER);
+    // Grow the memory when the frame goes past its end
+    function.index(GLOBAL_GET, STACK_POINTER).op(MEMORY_SIZE).op(0x00).i32_const(16).op(I32_SHL).op(I32_GT_U)
+        .op(IF).op(EMPTY)
+        .i32_const((size / PAGE_SIZE + 1) as i32).op(MEMORY_GROW).op(0x00).i32_const(-1).op(I32_EQ).error_if(STACK_OVERFLOW)
+        .op(END);
+    if procedure.parent.is_some() {
+        function.index(LOCAL_GET, frame).index(LOCAL_GET, 0).memory(I32_STORE, 2, 0);
+    }
+    // Frames are reused, the variables start from 0 like in the vm
+    for variable in 0..procedure.variables.len() {
+        function.index(LOCAL_GET, frame).i64_const(0).memory(I64_STORE, 3, variable_offset(variable));
+    }
+
+    emitter.statement(&procedure.body);
+    emitter.function.index(LOCAL_GET, frame).index(GLOBAL_SET, STACK_POINTER);
+    emitter.function.end()
+}
+
+fn variable_offset(index: usize) -> u32 {
+    8 + 8 * index as u32
+}
+
+struct Emitter<'a> {
+    program: &'a backend::Program,
+    frame: u32,     // local holding the address of the frame
+    function: Function,
+}
+
+impl Emitter<'_> {
+    fn statement(&mut self, statement: &backend::Statement) {
+        match statement {
+            backend::Statement::Assign(variable, value) => {
+                self.frame(variable.depth);
+                self.expression(value);
+                self.function.memory(I64_STORE, 3, variable_offset(variable.index));
+            },
+            backend::Statement::Call { procedure, depth } => {
+                if self.program.procedures[*procedure].parent.is_some() {
+                    self.frame(*depth);
+                }
+                self.function.index(CALL, PROCEDURES + *procedure as u32);
+            },
+            backend::Statement::Read(variables) => {
+                for variable in variables {
+                    self.frame(variable.depth);
+                    self.function.index(CALL, READ).memory(I64_STORE, 3, variable_offset(variable.index));
+                }
+            },
+            backend::Statement::Write(expressions) => {
+                for value in expressions {
+                    self.expression(value);
+                    self.function.index(CALL, WRITE);
+                }
+                self.function.index(CALL, NEWLINE);
+            },
+            backend::Statement::Begin(statements) => {
+                for inner in statements {
+                    self.statement(inner);
+                }
+            },
+            backend::Statement::If(test, body) => {
+                self.condition(test);
+                self.function.op(IF).op(EMPTY);
+                self.statement(body);
+                self.function.op(END);
+            },
+            backend::Statement::While(test, body) => {
+                self.function.op(BLOCK).op(EMPTY).op(LOOP).op(EMPTY);
+                self.condition(test);
+                self.function.op(I32_EQZ).index(BR_IF, 1);
+                self.statement(body);
+                self.function.index(BR, 0).op(END).op(END);
+            },
+            backend::Statement::Empty => {},
+        }
+    }
+
+    // Leaves an i32, 0 when false
+    fn condition(&mut self, condition: &backend::Condition) {
+        match condition {
+            backend::Condition::Odd(value) => {
+                self.expression(value);
+                self.function.i64_const(1).op(I64_AND).op(I32_WRAP_I64);
+            },
+            backend::Condition::Compare(left, relation, right) => {
+                self.expression(left);
+                self.expression(right);
+                self.function.op(match relation {
+                    ast::Relation::Eq => I64_EQ,
+                    ast::Relation::Ne => I64_NE,
+                    ast::Relation::Lt => I64_LT_S,
+                    ast::Relation::Le => I64_LE_S,
+                    ast::Relation::Gt => I64_GT_S,
+                    ast::Relation::Ge => I64_GE_S,
+                });
+            },
+        }
+    }
+
+    fn expression(&mut self, value: &backend::Expression) {
+        match value {
+            backend::Expression::Number(number) => {
+                self.function.i64_const(*number);
+            },
+            backend::Expression::Variable(variable) => {
+                self.frame(variable.depth);
+                self.function.memory(I64_LOAD, 3, variable_offset(variable.index));
+            },
+            backend::Expression::Negate(operand) => {
+                self.expression(operand);
+                self.function.index(CALL, NEG);
+            },
+            backend::Expression::Binary(left, operator, right) => {
+                self.expression(left);
+                self.expression(right);
+                self.function.index(CALL, match operator {
+                    ast::BinaryOperator::Add => ADD,
+                    ast::BinaryOperator::Sub => SUB,
+                    ast::BinaryOperator::Mul => MUL,
+                    ast::BinaryOperator::Div => DIV,
+                });
+            },
+        }
+    }
+
+    // Pushes the address of the frame reached through depth static links
+    fn frame(&mut self, depth: usize) {
+        self.function.index(LOCAL_GET, self.frame);
+        for _ in 0..depth {
+            self.function.memory(I32_LOAD, 2, 0);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::process;
+
+    use crate::backend;
+    use crate::backend::wasm;
+
+    // Host of the modules for node, with the input on stdin
+    const HOST: &str = r#"const fs = require('fs');
+const bytes = fs.readFileSync(process.argv[2]);
+const errors = process.argv.slice(3);
+const lines = fs.readFileSync(0, 'utf8').split('\n');
+let line = 0;
+function fail(message) {
+    fs.writeSync(2, message + '\n');
+    process.exit(1);
+}
+if (!WebAssembly.validate(bytes)) {
+    fail('Invalid module');
+}
+const imports = {
+    pl0: {
+        write: value => fs.writeSync(1, value + ' '),
+        newline: () => fs.writeSync(1, '\n'),
+        read: () => {
+            fs.writeSync(1, '?');
+            const text = (line < lines.length ? lines[line++] : '').trim();
+            if (!/^[+-]?[0-9]+$/.test(text)) {
+                fail('Invalid number');
+            }
+            const value = BigInt(text);
+            if (value !== BigInt.asIntN(64, value)) {
+                fail('Invalid number');
+            }
+            return value;
+        },
+        error: code => fail(errors[code]),
+    },
+};
+new WebAssembly.Instance(new WebAssembly.Module(bytes), imports).exports.main();
+"#;
+
+    /* test the LEB128 encodings */
+    #[test]
+    fn test_leb128() {
+        let mut output = Vec::new();
+        wasm::unsigned(&mut output, 624485);
+        assert_eq!(output, vec![0xe5, 0x8e, 0x26]);
+
+        let mut output = Vec::new();
+        wasm::signed(&mut output, -123456);
+        assert_eq!(output, vec![0xc0, 0xbb, 0x78]);
+
+        let mut output = Vec::new();
+        wasm::signed(&mut output, 64);
+        wasm::signed(&mut output, -64);
+        wasm::signed(&mut output, i64::MIN);
+        assert_eq!(output, vec![0xc0, 0x00, 0x40, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x7f]);
+    }
+
+    /* test the procedures follow the runtime functions */
+    #[test]
+    fn test_emit() {
+        let program = backend::compile("var x; procedure p; x := x + 1; call p.").unwrap();
+        let module = wasm::emit(&program);
+
+        assert_eq!(&module[..8], b"\0asm\x01\0\0\0");
+        // The main procedure passes its frame to p then pops it
+        let call = [0x20, 0x00, 0x10, wasm::PROCEDURES as u8 + 1, 0x20, 0x00, 0x24, 0x00, 0x0b];
+        assert!(module.windows(call.len()).any(|window| window == call));
+    }
+
+    /* test the modules are valid and print what the vm prints */
+    #[test]
+    fn test_programs() {
+        if !backend::tests::available("wasm programs", &["node"]) {
+            return;
+        }
+        let directory = backend::tests::temp_dir("wasm");
+        let host = directory.join("host.js");
+        let module = directory.join("program.wasm");
+        fs::write(&host, HOST).unwrap();
+
+        for (name, content) in backend::tests::programs() {
+            fs::write(&module, wasm::emit(&backend::compile(&content).unwrap())).unwrap();
+            let mut command = process::Command::new("node");
+            command.arg(&host).arg(&module).args(wasm::ERRORS.iter());
+
+            let output = backend::tests::run(command);
+            assert_eq!(output, backend::tests::vm_output(&content), "{}", name);
+        }
+        fs::remove_dir_all(&directory).unwrap();
+    }
+}
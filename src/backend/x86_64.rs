@@ -0,0 +1,694 @@
+use std::convert::TryFrom;
+use std::fmt;
+
+use crate::ast;
+use crate::backend;
+use crate::backend::native;
+
+
+/*
+ * Frame of a procedure, the static link comes in %rdi like the first
+ * argument of a System V call:
+ *   8(%rbp)            return address
+ *   0(%rbp)            frame pointer of the caller (dynamic link)
+ *   -8(%rbp)           static link
+ *   -16 - 8 * i(%rbp)  variable i
+ * Operands live on the machine stack, so between statements %rsp is
+ * aligned on 16 bytes when calling another procedure or the runtime.
+ */
+const LINK_OFFSET: i32 = -8;
+
+fn variable_offset(index: usize) -> i32 {
+    -16 - 8 * index as i32
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Register {
+    Rax,
+    Rcx,
+    Rdx,
+    Rbx,
+    Rsp,
+    Rbp,
+    Rsi,
+    Rdi,
+    R8,
+    R9,
+    R10,
+    R11,
+}
+
+impl Register {
+    // Number in the encoding of the instructions
+    pub fn number(&self) -> u8 {
+        *self as u8
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Register::Rax => "rax",
+            Register::Rcx => "rcx",
+            Register::Rdx => "rdx",
+            Register::Rbx => "rbx",
+            Register::Rsp => "rsp",
+            Register::Rbp => "rbp",
+            Register::Rsi => "rsi",
+            Register::Rdi => "rdi",
+            Register::R8 => "r8",
+            Register::R9 => "r9",
+            Register::R10 => "r10",
+            Register::R11 => "r11",
+        }
+    }
+
+    // Name of the lowest byte
+    pub fn byte_name(&self) -> &'static str {
+        match self {
+            Register::Rax => "al",
+            Register::Rcx => "cl",
+            Register::Rdx => "dl",
+            Register::Rbx => "bl",
+            Register::Rsp => "spl",
+            Register::Rbp => "bpl",
+            Register::Rsi => "sil",
+            Register::Rdi => "dil",
+            Register::R8 => "r8b",
+            Register::R9 => "r9b",
+            Register::R10 => "r10b",
+            Register::R11 => "r11b",
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Condition {
+    Equal,
+    NotEqual,
+    Less,
+    LessEqual,
+    Greater,
+    GreaterEqual,
+    BelowEqual,     // unsigned
+    Above,          // unsigned
+    Overflow,
+}
+
+impl Condition {
+    pub fn suffix(&self) -> &'static str {
+        match self {
+            Condition::Equal => "e",
+            Condition::NotEqual => "ne",
+            Condition::Less => "l",
+            Condition::LessEqual => "le",
+            Condition::Greater => "g",
+            Condition::GreaterEqual => "ge",
+            Condition::BelowEqual => "be",
+            Condition::Above => "a",
+            Condition::Overflow => "o",
+        }
+    }
+}
+
+/* the few instructions the backend needs, registers are all 64 bits wide */
+#[derive(Clone, Debug, PartialEq)]
+pub enum Instruction {
+    MovImmediate(Register, i64),
+    Mov(Register, Register),                // (destination, source)
+    Load(Register, Register, i32),          // (destination, base, displacement)
+    Store(Register, i32, Register),         // (base, displacement, source)
+    LoadByte(Register, Register, i32),      // zero extended
+    StoreByte(Register, i32, Register),     // lowest byte of the source
+    Lea(Register, Register, i32),
+    LeaLabel(Register, String),             // rip relative
+    Push(Register),
+    PushImmediate(i32),
+    Pop(Register),
+    Add(Register, Register),                // (destination, source)
+    Sub(Register, Register),
+    Imul(Register, Register),
+    Xor(Register, Register),
+    Cmp(Register, Register),                // flags of the first minus the second
+    Test(Register, Register),
+    AddImmediate(Register, i32),
+    SubImmediate(Register, i32),
+    CmpImmediate(Register, i32),
+    AndImmediate(Register, i32),
+    Neg(Register),
+    Cqo,
+    Idiv(Register),
+    Set(Condition, Register),               // lowest byte
+    ZeroExtendByte(Register),               // from the lowest byte of the same register
+    Jump(String),
+    JumpIf(Condition, String),
+    Call(String),
+    Ret,
+    Leave,
+    Syscall,
+}
+
+impl fmt::Display for Instruction {
+    // GNU assembler syntax, the source comes first
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Instruction::MovImmediate(register, value) if i32::try_from(*value).is_ok() => {
+                write!(f, "movq ${}, %{}", value, register.name())
+            },
+            Instruction::MovImmediate(register, value) => write!(f, "movabsq ${}, %{}", value, register.name()),
+            Instruction::Mov(destination, source) => write!(f, "movq %{}, %{}", source.name(), destination.name()),
+            Instruction::Load(destination, base, displacement) => {
+                write!(f, "movq {}(%{}), %{}", displacement, base.name(), destination.name())
+            },
+            Instruction::Store(base, displacement, source) => {
+                write!(f, "movq %{}, {}(%{})", source.name(), displacement, base.name())
+            },
+            Instruction::LoadByte(destination, base, displacement) => {
+                write!(f, "movzbq {}(%{}), %{}", displacement, base.name(), destination.name())
+            },
+            Instruction::StoreByte(base, displacement, source) => {
+                write!(f, "movb %{}, {}(%{})", source.byte_name(), displacement, base.name())
+            },
+            Instruction::Lea(destination, base, displacement) => {
+                write!(f, "leaq {}(%{}), %{}", displacement, base.name(), destination.name())
+            },
+            Instruction::LeaLabel(destination, label) => write!(f, "leaq {}(%rip), %{}", label, destination.name()),
+            Instruction::Push(register) => write!(f, "pushq %{}", register.name()),
+            Instruction::PushImmediate(value) => write!(f, "pushq ${}", value),
+            Instruction::Pop(register) => write!(f, "popq %{}", register.name()),
+            Instruction::Add(destination, source) => write!(f, "addq %{}, %{}", source.name(), destination.name()),
+            Instruction::Sub(destination, source) => write!(f, "subq %{}, %{}", source.name(), destination.name()),
+            Instruction::Imul(destination, source) => write!(f, "imulq %{}, %{}", source.name(), destination.name()),
+            Instruction::Xor(destination, source) => write!(f, "xorq %{}, %{}", source.name(), destination.name()),
+            Instruction::Cmp(first, second) => write!(f, "cmpq %{}, %{}", second.name(), first.name()),
+            Instruction::Test(first, second) => write!(f, "testq %{}, %{}", second.name(), first.name()),
+            Instruction::AddImmediate(register, value) => write!(f, "addq ${}, %{}", value, register.name()),
+            Instruction::SubImmediate(register, value) => write!(f, "subq ${}, %{}", value, register.name()),
+            Instruction::CmpImmediate(register, value) => write!(f, "cmpq ${}, %{}", value, register.name()),
+            Instruction::AndImmediate(register, value) => write!(f, "andq ${}, %{}", value, register.name()),
+            Instruction::Neg(register) => write!(f, "negq %{}", register.name()),
+            Instruction::Cqo => write!(f, "cqto"),
+            Instruction::Idiv(register) => write!(f, "idivq %{}", register.name()),
+            Instruction::Set(condition, register) => write!(f, "set{} %{}", condition.suffix(), register.byte_name()),
+            Instruction::ZeroExtendByte(register) => {
+                write!(f, "movzbq %{}, %{}", register.byte_name(), register.name())
+            },
+            Instruction::Jump(label) => write!(f, "jmp {}", label),
+            Instruction::JumpIf(condition, label) => write!(f, "j{} {}", condition.suffix(), label),
+            Instruction::Call(label) => write!(f, "call {}", label),
+            Instruction::Ret => write!(f, "ret"),
+            Instruction::Leave => write!(f, "leave"),
+            Instruction::Syscall => write!(f, "syscall"),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Line {
+    Label(String),
+    Instruction(Instruction),
+}
+
+/* whole program: code starting at _start, then read only data */
+#[derive(Clone, Debug, PartialEq)]
+pub struct Assembly {
+    pub text: Vec<Line>,
+    pub data: Vec<(String, Vec<u8>)>,
+}
+
+impl fmt::Display for Assembly {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "# Generated by pl0rust")?;
+        writeln!(f, "    .text")?;
+        writeln!(f, "    .globl _start")?;
+        for line in &self.text {
+            match line {
+                Line::Label(label) => writeln!(f, "{}:", label)?,
+                Line::Instruction(instruction) => writeln!(f, "    {}", instruction)?,
+            }
+        }
+        writeln!(f, "\n    .section .rodata")?;
+        for (label, bytes) in &self.data {
+            let bytes: Vec<String> = bytes.iter().map(|byte| byte.to_string()).collect();
+            writeln!(f, "{}:\n    .byte {}", label, bytes.join(", "))?;
+        }
+        writeln!(f, "\n    .section .note.GNU-stack, \"\", @progbits")
+    }
+}
+
+// Emit GNU assembler text for Linux, to be linked without libc:
+// as -o program.o program.s && ld -o program program.o
+pub fn emit(program: &backend::Program) -> String {
+    assemble(program).to_string()
+}
+
+pub fn assemble(program: &backend::Program) -> Assembly {
+    let names: Vec<String> = program.procedures.iter().enumerate()
+        .map(|(index, procedure)| if index == 0 { String::from("main") } else { procedure.name.clone() })
+        .collect();
+    let functions: Vec<String> = backend::identifiers(&names, &[])
+        .iter()
+        .map(|name| format!("proc_{}", name))
+        .collect();
+
+    let mut emitter = Emitter {
+        functions: &functions,
+        text: Vec::new(),
+        divisions: 0,
+    };
+    emitter.label("_start");
+    emitter.emit(Instruction::Xor(Register::Rdi, Register::Rdi));
+    emitter.emit(Instruction::Call(functions[0].clone()));
+    emitter.exit(0);
+
+    for function in native::lower(program) {
+        emitter.function(&function);
+    }
+    emitter.runtime();
+
+    Assembly {
+        text: emitter.text,
+        data: MESSAGES.iter()
+            .map(|(label, message)| (label.to_string(), message.as_bytes().to_vec()))
+            .collect(),
+    }
+}
+
+const MESSAGES: [(&str, &str); 3] = [
+    ("pl0_overflow_message", "Arithmetic overflow\n"),
+    ("pl0_division_by_zero_message", "Division by zero\n"),
+    ("pl0_invalid_message", "Invalid number\n"),
+];
+
+const SYS_READ: i64 = 0;
+const SYS_WRITE: i64 = 1;
+const SYS_EXIT: i64 = 60;
+
+struct Emitter<'a> {
+    functions: &'a [String],
+    text: Vec<Line>,
+    divisions: usize,   // local labels of the divisions
+}
+
+impl Emitter<'_> {
+    fn function(&mut self, function: &native::Function) {
+        let frame = (8 + 8 * function.variables as i32 + 15) / 16 * 16;
+        self.label(&self.functions[function.procedure].clone());
+        self.emit(Instruction::Push(Register::Rbp));
+        self.emit(Instruction::Mov(Register::Rbp, Register::Rsp));
+        self.emit(Instruction::SubImmediate(Register::Rsp, frame));
+        self.emit(Instruction::Store(Register::Rbp, LINK_OFFSET, Register::Rdi));
+        if function.variables > 0 {
+            self.emit(Instruction::Xor(Register::Rax, Register::Rax));
+        }
+        for index in 0..function.variables {
+            self.emit(Instruction::Store(Register::Rbp, variable_offset(index), Register::Rax));
+        }
+
+        for operation in &function.code {
+            self.operation(operation);
+        }
+
+        self.emit(Instruction::Leave);
+        self.emit(Instruction::Ret);
+    }
+
+    fn operation(&mut self, operation: &native::Operation) {
+        match *operation {
+            native::Operation::Push(value) => match i32::try_from(value) {
+                Ok(value) => self.emit(Instruction::PushImmediate(value)),
+                Err(_) => {
+                    self.emit(Instruction::MovImmediate(Register::Rax, value));
+                    self.emit(Instruction::Push(Register::Rax));
+                },
+            },
+            native::Operation::Load(variable) => {
+                let base = self.frame(variable.depth, Register::Rax);
+                self.emit(Instruction::Load(Register::Rax, base, variable_offset(variable.index)));
+                self.emit(Instruction::Push(Register::Rax));
+            },
+            native::Operation::Store(variable) => {
+                let base = self.frame(variable.depth, Register::Rcx);
+                self.emit(Instruction::Pop(Register::Rax));
+                self.emit(Instruction::Store(base, variable_offset(variable.index), Register::Rax));
+            },
+            native::Operation::Negate => {
+                self.emit(Instruction::Pop(Register::Rax));
+                self.emit(Instruction::Neg(Register::Rax));
+                self.emit(Instruction::JumpIf(Condition::Overflow, String::from("pl0_overflow")));
+                self.emit(Instruction::Push(Register::Rax));
+            },
+            native::Operation::Binary(operator) => {
+                self.emit(Instruction::Pop(Register::Rcx));
+                self.emit(Instruction::Pop(Register::Rax));
+                match operator {
+                    ast::BinaryOperator::Add => self.emit(Instruction::Add(Register::Rax, Register::Rcx)),
+                    ast::BinaryOperator::Sub => self.emit(Instruction::Sub(Register::Rax, Register::Rcx)),
+                    ast::BinaryOperator::Mul => self.emit(Instruction::Imul(Register::Rax, Register::Rcx)),
+                    ast::BinaryOperator::Div => self.division(),
+                }
+                if operator != ast::BinaryOperator::Div {
+                    self.emit(Instruction::JumpIf(Condition::Overflow, String::from("pl0_overflow")));
+                }
+                self.emit(Instruction::Push(Register::Rax));
+            },
+            native::Operation::Compare(relation) => {
+                let condition = match relation {
+                    ast::Relation::Eq => Condition::Equal,
+                    ast::Relation::Ne => Condition::NotEqual,
+                    ast::Relation::Lt => Condition::Less,
+                    ast::Relation::Le => Condition::LessEqual,
+                    ast::Relation::Gt => Condition::Greater,
+                    ast::Relation::Ge => Condition::GreaterEqual,
+                };
+                self.emit(Instruction::Pop(Register::Rcx));
+                self.emit(Instruction::Pop(Register::Rax));
+                self.emit(Instruction::Cmp(Register::Rax, Register::Rcx));
+                self.emit(Instruction::Set(condition, Register::Rax));
+                self.emit(Instruction::ZeroExtendByte(Register::Rax));
+                self.emit(Instruction::Push(Register::Rax));
+            },
+            native::Operation::Odd => {
+                self.emit(Instruction::Pop(Register::Rax));
+                self.emit(Instruction::AndImmediate(Register::Rax, 1));
+                self.emit(Instruction::Push(Register::Rax));
+            },
+            native::Operation::Write => {
+                self.emit(Instruction::Pop(Register::Rdi));
+                self.emit(Instruction::Call(String::from("pl0_write")));
+            },
+            native::Operation::Newline => {
+                self.emit(Instruction::Call(String::from("pl0_newline")));
+            },
+            native::Operation::Read => {
+                self.emit(Instruction::Call(String::from("pl0_read")));
+                self.emit(Instruction::Push(Register::Rax));
+            },
+            native::Operation::Call { procedure, depth } => {
+                if self.frame(depth, Register::Rdi) == Register::Rbp {
+                    self.emit(Instruction::Mov(Register::Rdi, Register::Rbp));
+                }
+                self.emit(Instruction::Call(self.functions[procedure].clone()));
+            },
+            native::Operation::Label(label) => self.label(&format!(".L{}", label)),
+            native::Operation::Jump(label) => self.emit(Instruction::Jump(format!(".L{}", label))),
+            native::Operation::JumpIfZero(label) => {
+                self.emit(Instruction::Pop(Register::Rax));
+                self.emit(Instruction::Test(Register::Rax, Register::Rax));
+                self.emit(Instruction::JumpIf(Condition::Equal, format!(".L{}", label)));
+            },
+        }
+    }
+
+    // %rax divided by %rcx, idiv traps instead of overflowing so dividing
+    // by -1 is a negation
+    fn division(&mut self) {
+        let divide = format!(".Ldivide{}", self.divisions);
+        let done = format!(".Ldivided{}", self.divisions);
+        self.divisions += 1;
+
+        self.emit(Instruction::Test(Register::Rcx, Register::Rcx));
+        self.emit(Instruction::JumpIf(Condition::Equal, String::from("pl0_division_by_zero")));
+        self.emit(Instruction::CmpImmediate(Register::Rcx, -1));
+        self.emit(Instruction::JumpIf(Condition::NotEqual, divide.clone()));
+        self.emit(Instruction::Neg(Register::Rax));
+        self.emit(Instruction::JumpIf(Condition::Overflow, String::from("pl0_overflow")));
+        self.emit(Instruction::Jump(done.clone()));
+        self.label(&divide);
+        self.emit(Instruction::Cqo);
+        self.emit(Instruction::Idiv(Register::Rcx));
+        self.label(&done);
+    }
+
+    // Register holding the frame reached through depth static links
+    fn frame(&mut self, depth: usize, register: Register) -> Register {
+        if depth == 0 {
+            return Register::Rbp;
+        }
+        self.emit(Instruction::Load(register, Register::Rbp, LINK_OFFSET));
+        for _ in 1..depth {
+            self.emit(Instruction::Load(register, register, LINK_OFFSET));
+        }
+        register
+    }
+
+    /*
+     * write prints the number in %rdi and a space, newline prints a new line,
+     * read prompts with ? and returns in %rax the number on the next line of
+     * stdin. Errors print their message on stderr and exit with 1.
+     */
+    fn runtime(&mut self) {
+        use Instruction::*;
+        use Register::*;
+
+        // Digits are written backwards from the end of a buffer on the
+        // stack, the number is made negative first so that the smallest one
+        // works too
+        self.label("pl0_write");
+        self.emit(Push(Rbp));
+        self.emit(Mov(Rbp, Rsp));
+        self.emit(SubImmediate(Rsp, 32));
+        self.emit(Lea(Rsi, Rbp, -1));
+        self.emit(MovImmediate(Rcx, b' ' as i64));
+        self.emit(StoreByte(Rsi, 0, Rcx));
+        self.emit(Mov(Rax, Rdi));
+        self.emit(Test(Rax, Rax));
+        self.emit(JumpIf(Condition::LessEqual, String::from(".Lwrite_digits")));
+        self.emit(Neg(Rax));
+        self.label(".Lwrite_digits");
+        self.emit(MovImmediate(R8, 10));
+        self.emit(Cqo);
+        self.emit(Idiv(R8));
+        self.emit(Neg(Rdx));
+        self.emit(AddImmediate(Rdx, b'0' as i32));
+        self.emit(SubImmediate(Rsi, 1));
+        self.emit(StoreByte(Rsi, 0, Rdx));
+        self.emit(Test(Rax, Rax));
+        self.emit(JumpIf(Condition::NotEqual, String::from(".Lwrite_digits")));
+        self.emit(Test(Rdi, Rdi));
+        self.emit(JumpIf(Condition::GreaterEqual, String::from(".Lwrite_output")));
+        self.emit(SubImmediate(Rsi, 1));
+        self.emit(MovImmediate(Rcx, b'-' as i64));
+        self.emit(StoreByte(Rsi, 0, Rcx));
+        self.label(".Lwrite_output");
+        self.emit(Mov(Rdx, Rbp));
+        self.emit(Sub(Rdx, Rsi));
+        self.emit(MovImmediate(Rdi, 1));
+        self.emit(MovImmediate(Rax, SYS_WRITE));
+        self.emit(Syscall);
+        self.emit(Leave);
+        self.emit(Ret);
+
+        self.label("pl0_newline");
+        self.emit(MovImmediate(Rdi, b'\n' as i64));
+        self.emit(Jump(String::from("pl0_put")));
+
+        // Write the byte in %rdi to stdout
+        self.label("pl0_put");
+        self.emit(Push(Rbp));
+        self.emit(Mov(Rbp, Rsp));
+        self.emit(SubImmediate(Rsp, 16));
+        self.emit(StoreByte(Rbp, -1, Rdi));
+        self.emit(Lea(Rsi, Rbp, -1));
+        self.emit(MovImmediate(Rdx, 1));
+        self.emit(MovImmediate(Rdi, 1));
+        self.emit(MovImmediate(Rax, SYS_WRITE));
+        self.emit(Syscall);
+        self.emit(Leave);
+        self.emit(Ret);
+
+        // The line is read byte by byte into a buffer on the stack, %r8
+        // counts its bytes even past the end of the buffer
+        self.label("pl0_read");
+        self.emit(Push(Rbp));
+        self.emit(Mov(Rbp, Rsp));
+        self.emit(SubImmediate(Rsp, 272));
+        self.emit(MovImmediate(Rdi, b'?' as i64));
+        self.emit(Call(String::from("pl0_put")));
+        self.emit(Xor(R8, R8));
+        self.label(".Lread_byte");
+        self.emit(Lea(Rsi, Rbp, -264));
+        self.emit(MovImmediate(Rdx, 1));
+        self.emit(Xor(Rdi, Rdi));
+        self.emit(MovImmediate(Rax, SYS_READ));
+        self.emit(Syscall);
+        self.emit(CmpImmediate(Rax, 1));
+        self.emit(JumpIf(Condition::NotEqual, String::from(".Lread_parse")));
+        self.emit(LoadByte(Rax, Rbp, -264));
+        self.emit(CmpImmediate(Rax, b'\n' as i32));
+        self.emit(JumpIf(Condition::Equal, String::from(".Lread_parse")));
+        self.emit(CmpImmediate(R8, 256));
+        self.emit(JumpIf(Condition::GreaterEqual, String::from(".Lread_count")));
+        self.emit(Lea(Rsi, Rbp, -256));
+        self.emit(Add(Rsi, R8));
+        self.emit(StoreByte(Rsi, 0, Rax));
+        self.label(".Lread_count");
+        self.emit(AddImmediate(R8, 1));
+        self.emit(Jump(String::from(".Lread_byte")));
+
+        // %rsi goes through the buffer up to %rdi, the number is accumulated
+        // negatively in %r10, %r9 tells whether it has a minus sign
+        self.label(".Lread_parse");
+        self.emit(CmpImmediate(R8, 256));
+        self.emit(JumpIf(Condition::Greater, String::from("pl0_invalid")));
+        self.emit(Lea(Rsi, Rbp, -256));
+        self.emit(Mov(Rdi, Rsi));
+        self.emit(Add(Rdi, R8));
+        self.label(".Lread_leading");
+        self.emit(Cmp(Rsi, Rdi));
+        self.emit(JumpIf(Condition::GreaterEqual, String::from("pl0_invalid")));
+        self.emit(LoadByte(Rax, Rsi, 0));
+        self.whitespace(".Lread_leading_space");
+        self.emit(Jump(String::from(".Lread_sign")));
+        self.label(".Lread_leading_space");
+        self.emit(AddImmediate(Rsi, 1));
+        self.emit(Jump(String::from(".Lread_leading")));
+
+        self.label(".Lread_sign");
+        self.emit(Xor(R9, R9));
+        self.emit(CmpImmediate(Rax, b'+' as i32));
+        self.emit(JumpIf(Condition::Equal, String::from(".Lread_skip_sign")));
+        self.emit(CmpImmediate(Rax, b'-' as i32));
+        self.emit(JumpIf(Condition::NotEqual, String::from(".Lread_number")));
+        self.emit(MovImmediate(R9, 1));
+        self.label(".Lread_skip_sign");
+        self.emit(AddImmediate(Rsi, 1));
+        self.label(".Lread_number");
+        self.emit(Xor(R10, R10));
+        self.emit(Mov(R11, Rsi));
+        self.emit(MovImmediate(Rcx, 10));
+        self.label(".Lread_digit");
+        self.emit(Cmp(Rsi, Rdi));
+        self.emit(JumpIf(Condition::GreaterEqual, String::from(".Lread_digits_end")));
+        self.emit(LoadByte(Rax, Rsi, 0));
+        self.emit(SubImmediate(Rax, b'0' as i32));
+        self.emit(CmpImmediate(Rax, 9));
+        self.emit(JumpIf(Condition::Above, String::from(".Lread_digits_end")));
+        self.emit(Imul(R10, Rcx));
+        self.emit(JumpIf(Condition::Overflow, String::from("pl0_invalid")));
+        self.emit(Sub(R10, Rax));
+        self.emit(JumpIf(Condition::Overflow, String::from("pl0_invalid")));
+        self.emit(AddImmediate(Rsi, 1));
+        self.emit(Jump(String::from(".Lread_digit")));
+        self.label(".Lread_digits_end");
+        self.emit(Cmp(Rsi, R11));
+        self.emit(JumpIf(Condition::Equal, String::from("pl0_invalid")));
+
+        self.label(".Lread_trailing");
+        self.emit(Cmp(Rsi, Rdi));
+        self.emit(JumpIf(Condition::GreaterEqual, String::from(".Lread_done")));
+        self.emit(LoadByte(Rax, Rsi, 0));
+        self.whitespace(".Lread_trailing_space");
+        self.emit(Jump(String::from("pl0_invalid")));
+        self.label(".Lread_trailing_space");
+        self.emit(AddImmediate(Rsi, 1));
+        self.emit(Jump(String::from(".Lread_trailing")));
+
+        self.label(".Lread_done");
+        self.emit(Test(R9, R9));
+        self.emit(JumpIf(Condition::NotEqual, String::from(".Lread_return")));
+        self.emit(Neg(R10));
+        self.emit(JumpIf(Condition::Overflow, String::from("pl0_invalid")));
+        self.label(".Lread_return");
+        self.emit(Mov(Rax, R10));
+        self.emit(Leave);
+        self.emit(Ret);
+
+        for (label, message) in MESSAGES.iter() {
+            self.label(label.trim_end_matches("_message"));
+            self.emit(LeaLabel(Rsi, label.to_string()));
+            self.emit(MovImmediate(Rdx, message.len() as i64));
+            self.emit(Jump(String::from("pl0_error")));
+        }
+        self.label("pl0_error");
+        self.emit(MovImmediate(Rdi, 2));
+        self.emit(MovImmediate(Rax, SYS_WRITE));
+        self.emit(Syscall);
+        self.exit(1);
+    }
+
+    // Jump to label if the byte in %rax is a white space
+    fn whitespace(&mut self, label: &str) {
+        self.emit(Instruction::CmpImmediate(Register::Rax, b' ' as i32));
+        self.emit(Instruction::JumpIf(Condition::Equal, label.to_string()));
+        self.emit(Instruction::Mov(Register::Rcx, Register::Rax));
+        self.emit(Instruction::SubImmediate(Register::Rcx, 9));
+        self.emit(Instruction::CmpImmediate(Register::Rcx, 4));
+        self.emit(Instruction::JumpIf(Condition::BelowEqual, label.to_string()));
+    }
+
+    fn exit(&mut self, code: i64) {
+        self.emit(Instruction::MovImmediate(Register::Rdi, code));
+        self.emit(Instruction::MovImmediate(Register::Rax, SYS_EXIT));
+        self.emit(Instruction::Syscall);
+    }
+
+    fn label(&mut self, label: &str) {
+        self.text.push(Line::Label(label.to_string()));
+    }
+
+    fn emit(&mut self, instruction: Instruction) {
+        self.text.push(Line::Instruction(instruction));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::process;
+
+    use crate::backend;
+
+    /* test variables are reached through the static links */
+    #[test]
+    fn test_emit() {
+        let program = backend::compile("var x; procedure p; procedure q; x := 2; call q; begin call p; write(x) end.").unwrap();
+        let output = backend::x86_64::emit(&program);
+
+        assert!(output.contains("proc_q:
+    pushq %rbp
+    movq %rsp, %rbp
+    subq $16, %rsp
+    movq %rdi, -8(%rbp)
+    pushq $2
+    movq -8(%rbp), %rcx
+    movq -8(%rcx), %rcx
+    popq %rax
+    movq %rax, -16(%rcx)
+    leave
+    ret
+"));
+        assert!(output.contains("proc_main:
+    pushq %rbp
+    movq %rsp, %rbp
+    subq $16, %rsp
+    movq %rdi, -8(%rbp)
+    xorq %rax, %rax
+    movq %rax, -16(%rbp)
+    movq %rbp, %rdi
+    call proc_p
+"));
+    }
+
+    /* test the assembled programs print what the vm prints */
+    #[test]
+    fn test_programs() {
+        if !backend::tests::available("x86-64 programs", &["as", "ld"]) {
+            return;
+        }
+        let directory = backend::tests::temp_dir("x86_64");
+        let source = directory.join("program.s");
+        let object = directory.join("program.o");
+        let binary = directory.join("program");
+
+        for (name, content) in backend::tests::programs() {
+            fs::write(&source, backend::x86_64::emit(&backend::compile(&content).unwrap())).unwrap();
+            let assembled = process::Command::new("as").arg("-o").arg(&object).arg(&source).status().unwrap();
+            assert!(assembled.success(), "{}", name);
+            let linked = process::Command::new("ld").arg("-o").arg(&binary).arg(&object).status().unwrap();
+            assert!(linked.success(), "{}", name);
+
+            let output = backend::tests::run(process::Command::new(&binary));
+            assert_eq!(output, backend::tests::vm_output(&content), "{}", name);
+        }
+        fs::remove_dir_all(&directory).unwrap();
+    }
+}
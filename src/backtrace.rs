@@ -0,0 +1,252 @@
+use std::fmt::Write;
+
+use crate::vm;
+use crate::compile::cfg;
+use crate::compile::nametab;
+
+
+/* activation of a procedure on the stack of a stopped vm */
+#[derive(Clone, Debug, PartialEq)]
+pub struct Frame {
+    pub procedure: Option<usize>,   // index in name table
+    pub name: String,
+    pub pc: usize,                  // the faulting instruction, or the Cal of a caller
+    pub bp: usize,
+    pub line: Option<usize>,
+    pub column: Option<usize>,      // with the debug info of the vm
+    pub source: Option<String>,     // text of the line
+}
+
+/* what the program was doing when a runtime error stopped it */
+#[derive(Clone, Debug, PartialEq)]
+pub struct Report {
+    pub error: vm::RuntimeError,
+    pub file: Option<String>,
+    pub frames: Vec<Frame>,         // the faulting frame first
+    pub locals: Vec<(String, i64)>, // variables of the faulting frame
+}
+
+/*
+ * Report of the runtime error of the vm, none if it has not failed. The
+ * procedure of a frame is the one its caller's Cal went to, found from the
+ * return address on the stack, the main procedure is the frame at the bottom.
+ */
+pub fn capture(machine: &vm::PL0VirtualMachine, name_table: &[nametab::NameTableItem],
+    line_table: &[(usize, usize)]) -> Option<Report> {
+    let error = machine.error()?.clone();
+    let frames = frames(machine, name_table, line_table);
+
+    let stack = machine.stack();
+    let locals = match frames.first().and_then(|frame| frame.procedure.map(|procedure| (procedure, frame.bp))) {
+        Some((procedure, bp)) => nametab::declarations(name_table, procedure).into_iter()
+            .filter(|item| item.kind == nametab::NameTableObject::Variable)
+            // Left out when the frame faulted before allocating them
+            .filter_map(|item| stack.get(bp + item.adr).map(|value| (item.name.clone(), *value)))
+            .collect(),
+        None => Vec::new(),
+    };
+    Some(Report { error, file: machine.debug_info().map(|debug_info| debug_info.file.clone()), frames, locals })
+}
+
+// Walk the dynamic links, the innermost frame first
+pub fn frames(machine: &vm::PL0VirtualMachine, name_table: &[nametab::NameTableItem],
+    line_table: &[(usize, usize)]) -> Vec<Frame> {
+    let code = machine.instructions();
+    let stack = machine.stack();
+    let debug_info = machine.debug_info();
+
+    let mut frames = Vec::new();
+    let mut pc = machine.pc();
+    let mut bp = machine.bp();
+    loop {
+        let (procedure, name) = if bp <= 3 {
+            (Some(0), name_table.first().map_or_else(|| String::from("_main"), |item| item.name.clone()))
+        } else {
+            let call = (stack[bp - 1] as usize).checked_sub(1)
+                .and_then(|call| code.get(call))
+                .filter(|instruction| instruction.f == vm::Fct::Cal);
+            match call {
+                Some(call) => {
+                    let entry = cfg::callee(code, call.a);
+                    let procedure = name_table.iter()
+                        .position(|item| item.kind == nametab::NameTableObject::Procedur && item.adr == entry);
+                    (procedure, procedure.map_or_else(|| format!("@{}", entry), |index| name_table[index].name.clone()))
+                },
+                None => (None, String::from("?")),
+            }
+        };
+
+        let span = debug_info.and_then(|debug_info| debug_info.span(pc));
+        let line = match (debug_info, &span) {
+            (Some(debug_info), Some(span)) => Some(debug_info.line_of(span.start)),
+            // The line of the statement the instruction belongs to
+            _ => line_table.iter().filter(|(start, _)| *start <= pc).max_by_key(|(start, _)| *start).map(|(_, line)| *line),
+        };
+        frames.push(Frame {
+            procedure,
+            name,
+            pc,
+            bp,
+            line,
+            column: debug_info.zip(span).map(|(debug_info, span)| debug_info.column_of(span.start)),
+            source: debug_info.zip(line).map(|(debug_info, line)| debug_info.source_line(line).trim().to_string()),
+        });
+
+        if bp <= 3 || bp > stack.len() {
+            break;
+        }
+        // The caller is positioned on its Cal instruction
+        pc = (stack[bp - 1] as usize).saturating_sub(1);
+        bp = stack[bp - 2] as usize;
+    }
+    frames
+}
+
+impl Report {
+    /*
+     * Text of the report: the error, then every frame from the faulting one
+     * to the main procedure with where it is in the source, then the
+     * variables of the faulting frame.
+     */
+    pub fn render(&self) -> String {
+        let mut output = format!("runtime error: {}\n", self.error);
+        for (index, frame) in self.frames.iter().enumerate() {
+            let location = match (&self.file, frame.line, frame.column) {
+                (Some(file), Some(line), Some(column)) => format!("{}:{}:{}", file, line, column),
+                (_, Some(line), _) => format!("line {}", line),
+                _ => format!("pc {}", frame.pc),
+            };
+            let prefix = if index == 0 { "in" } else { "called from" };
+            write!(output, "  {} {} at {}", prefix, frame.name, location).unwrap();
+            match &frame.source {
+                Some(source) => writeln!(output, "\n      {}", source).unwrap(),
+                None => writeln!(output).unwrap(),
+            }
+        }
+        if let Some(frame) = self.frames.first() {
+            if !self.locals.is_empty() {
+                writeln!(output, "locals of {}:", frame.name).unwrap();
+            }
+        }
+        for (name, value) in &self.locals {
+            writeln!(output, "  {} = {}", name, value).unwrap();
+        }
+        output
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::backtrace;
+    use crate::bytecode;
+    use crate::compile::codegen;
+    use crate::symbol;
+    use crate::vm;
+
+    fn run(content: &str) -> (bytecode::Module, vm::PL0VirtualMachine) {
+        let mut lexer = symbol::io::PL0Lexer::create_from_content(content);
+        let mut generator = codegen::CodeGenerator::new();
+        assert!(generator.build_block(0, &mut lexer));
+
+        let module = bytecode::Module::compiled(&generator, "test.pl0", content);
+        let mut machine = module.load();
+        machine.redirect(Box::new(std::io::empty()), Box::new(std::io::sink()));
+        machine.execute();
+        (module, machine)
+    }
+
+    const PROGRAM: &str = "var d;
+procedure outer;
+  var n;
+  procedure inner;
+    var q;
+  begin
+    q := n;
+    write(q / d)
+  end;
+begin
+  n := 7;
+  call inner
+end;
+begin
+  d := 0;
+  call outer
+end.";
+
+    /* test the frames of a division by zero with the locals of the faulting one */
+    #[test]
+    fn test_division_by_zero() {
+        let (module, machine) = run(PROGRAM);
+        assert_eq!(machine.error(), Some(&vm::RuntimeError::DivisionByZero));
+
+        let report = backtrace::capture(&machine, &module.name_table, &module.line_table).unwrap();
+        let frames: Vec<(&str, Option<usize>, Option<usize>)> = report.frames.iter()
+            .map(|frame| (frame.name.as_str(), frame.line, frame.column))
+            .collect();
+        assert_eq!(frames, vec![("inner", Some(8), Some(11)), ("outer", Some(12), Some(3)), ("_main", Some(16), Some(3))]);
+        assert_eq!(report.locals, vec![(String::from("q"), 7)]);
+
+        assert_eq!(report.render(), "runtime error: division by zero
+  in inner at test.pl0:8:11
+      write(q / d)
+  called from outer at test.pl0:12:3
+      call inner
+  called from _main at test.pl0:16:3
+      call outer
+locals of inner:
+  q = 7
+");
+    }
+
+    /* test the frame after a removed procedure is named by its own procedure */
+    #[test]
+    fn test_removed_procedure() {
+        let content = "var x; procedure dead; x := 1; procedure live; x := 10 / x; begin x := 0; call live end.";
+        let mut lexer = symbol::io::PL0Lexer::create_from_content(content);
+        let mut generator = codegen::CodeGenerator::new();
+        assert!(generator.build_block(0, &mut lexer));
+        assert_eq!(generator.eliminate_dead_code().procedures, vec!["dead"]);
+        generator.optimize();
+
+        let module = bytecode::Module::compiled(&generator, "test.pl0", content);
+        let mut machine = module.load();
+        machine.redirect(Box::new(std::io::empty()), Box::new(std::io::sink()));
+        machine.execute();
+        let report = backtrace::capture(&machine, &module.name_table, &module.line_table).unwrap();
+        let names: Vec<&str> = report.frames.iter().map(|frame| frame.name.as_str()).collect();
+        assert_eq!(names, vec!["live", "_main"]);
+    }
+
+    /* test the limits stop the program and a finished one has no report */
+    #[test]
+    fn test_limits() {
+        let content = "var n;
+procedure r; begin n := n + 1; call r end;
+call r.";
+        let (module, machine) = run(content);
+        assert_eq!(machine.error(), Some(&vm::RuntimeError::StackOverflow(vm::STACK_LIMIT)));
+        let report = backtrace::capture(&machine, &module.name_table, &module.line_table).unwrap();
+        assert!(report.frames.len() > vm::STACK_LIMIT / 4);
+        assert!(report.frames[..report.frames.len() - 1].iter().all(|frame| frame.name == "r"));
+        assert_eq!(report.frames.last().unwrap().name, "_main");
+
+        let mut machine = module.load();
+        machine.redirect(Box::new(std::io::empty()), Box::new(std::io::sink()));
+        machine.set_stack_limit(10);
+        machine.execute();
+        assert_eq!(machine.error(), Some(&vm::RuntimeError::StackOverflow(10)));
+        assert!(machine.stack().len() <= 10);
+        let report = backtrace::capture(&machine, &module.name_table, &module.line_table).unwrap();
+        assert_eq!(report.frames[0].name, "r");
+        assert_eq!(report.locals, vec![]);
+
+        let mut machine = module.load();
+        machine.redirect(Box::new(std::io::empty()), Box::new(std::io::sink()));
+        machine.set_step_limit(Some(100));
+        machine.execute();
+        assert_eq!(machine.error(), Some(&vm::RuntimeError::StepLimit(100)));
+
+        let (module, machine) = run("var x; x := 1.");
+        assert_eq!(backtrace::capture(&machine, &module.name_table, &module.line_table), None);
+    }
+}
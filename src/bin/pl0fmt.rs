@@ -0,0 +1,91 @@
+use std::env;
+use std::fs;
+use std::io;
+use std::io::{ Read, Write };
+use std::process;
+
+use pl0rust::symbol;
+use pl0rust::formatter;
+
+const USAGE: &str = "Usage: pl0fmt [--check] [file...]
+
+Formats the files in place, or stdin to stdout when no file is given.
+--check  only report the files that are not formatted, exits with 1 if any";
+
+
+fn main() {
+    let mut check = false;
+    let mut files = Vec::new();
+    for arg in env::args().skip(1) {
+        match arg.as_str() {
+            "--check" => check = true,
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                return;
+            },
+            _ => files.push(arg),
+        }
+    }
+
+    let mut unformatted = false;
+    let mut failed = false;
+
+    if files.is_empty() {
+        let mut content = String::new();
+        io::stdin().read_to_string(&mut content).expect("Something went wrong reading stdin");
+        match format("<stdin>", &content) {
+            Some(formatted) if check => unformatted = formatted != content,
+            Some(formatted) => {
+                io::stdout().write_all(formatted.as_bytes()).expect("Something went wrong writing stdout");
+            },
+            None => failed = true,
+        }
+    }
+
+    for file in &files {
+        let content = match fs::read_to_string(file) {
+            Ok(content) => content,
+            Err(error) => {
+                eprintln!("{}: {}", file, error);
+                failed = true;
+                continue;
+            },
+        };
+        let formatted = match format(file, &content) {
+            Some(formatted) => formatted,
+            None => {
+                failed = true;
+                continue;
+            },
+        };
+        if formatted == content {
+            continue;
+        }
+
+        if check {
+            println!("Would reformat {}", file);
+            unformatted = true;
+        } else if let Err(error) = fs::write(file, formatted) {
+            eprintln!("{}: {}", file, error);
+            failed = true;
+        }
+    }
+
+    if failed {
+        process::exit(2);
+    }
+    if unformatted {
+        process::exit(1);
+    }
+}
+
+fn format(name: &str, content: &str) -> Option<String> {
+    match formatter::format(content) {
+        Ok(formatted) => Some(formatted),
+        Err(error) => {
+            let lex = symbol::io::PL0Lexer::create_from_content(content);
+            eprintln!("{}:{}: {}", name, lex.line_of(error.span.start), error);
+            None
+        },
+    }
+}
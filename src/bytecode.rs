@@ -0,0 +1,376 @@
+use std::convert::TryFrom;
+use std::ops::Range;
+
+use crate::vm;
+use crate::symbol;
+use crate::compile::codegen;
+use crate::compile::nametab;
+
+
+const MAGIC: &[u8; 4] = b"PL0B";
+const VERSION: u32 = 2;
+
+/* source of a compiled program and the span of the construct each instruction comes from */
+#[derive(Clone, Debug, PartialEq)]
+pub struct DebugInfo {
+    pub file: String,
+    pub source: String,
+    pub spans: Vec<Range<usize>>,
+    lines: symbol::lines::LineIndex,
+}
+
+impl DebugInfo {
+    pub fn new(file: &str, source: &str, spans: Vec<Range<usize>>) -> DebugInfo {
+        DebugInfo {
+            file: file.to_string(),
+            source: source.to_string(),
+            spans,
+            lines: symbol::lines::LineIndex::new(source),
+        }
+    }
+
+    // Span of the instruction at pc, none for code without a source
+    pub fn span(&self, pc: usize) -> Option<Range<usize>> {
+        self.spans.get(pc).filter(|span| !span.is_empty()).cloned()
+    }
+
+    // Line containing the byte offset, starting from 1
+    pub fn line_of(&self, offset: usize) -> usize {
+        self.lines.line_of(offset)
+    }
+
+    // Column of the byte offset in its line, starting from 1
+    pub fn column_of(&self, offset: usize) -> usize {
+        self.lines.column_of(&self.source, offset)
+    }
+
+    pub fn line(&self, pc: usize) -> Option<usize> {
+        self.span(pc).map(|span| self.line_of(span.start))
+    }
+
+    // Source of the construct of the instruction at pc
+    pub fn text(&self, pc: usize) -> Option<&str> {
+        self.span(pc).and_then(|span| self.source.get(span))
+    }
+
+    // Text of a line, starting from 1, without its end of line
+    pub fn source_line(&self, line: usize) -> &str {
+        self.lines.text(&self.source, line)
+    }
+}
+
+/* a compiled program with what the tools need to relate it to its source */
+#[derive(Clone, Debug)]
+pub struct Module {
+    pub arithmetic: vm::Arithmetic,     // the code was compiled for
+    pub code: Vec<vm::Instruction>,
+    pub name_table: Vec<nametab::NameTableItem>,
+    pub line_table: Vec<(usize, usize)>,
+    pub debug_info: DebugInfo,
+}
+
+impl Module {
+    // Program of the code generator, compiled from the source in file
+    pub fn compiled(generator: &codegen::CodeGenerator, file: &str, source: &str) -> Module {
+        Module {
+            arithmetic: generator.get_arithmetic(),
+            code: generator.get_vm_code().to_vec(),
+            name_table: generator.get_name_table().to_vec(),
+            line_table: generator.get_line_table().to_vec(),
+            debug_info: DebugInfo::new(file, source, generator.get_spans().to_vec()),
+        }
+    }
+
+    // Virtual machine loaded with the code, runtime errors and traces refer to the source
+    pub fn load(&self) -> vm::PL0VirtualMachine {
+        let mut machine = vm::PL0VirtualMachine::load(self.code.clone());
+        machine.set_arithmetic(self.arithmetic);
+        machine.set_debug_info(self.debug_info.clone());
+        machine
+    }
+}
+
+// Whether the content is a bytecode file rather than a source
+pub fn is_bytecode(content: &[u8]) -> bool {
+    content.starts_with(MAGIC)
+}
+
+/*
+ * Bytecode file of the module. After the magic number and the version come
+ * the arithmetic as its overflow behavior, a byte, and its word size, then
+ * the instructions, the name table, the line table of the statements, then
+ * the debug info: the file name, the source and the span of every
+ * instruction. Integers are little endian, counts and lengths are u32, a
+ * string is its length in bytes then its UTF-8 content, a span two u64.
+ */
+pub fn write(module: &Module) -> Vec<u8> {
+    let mut output = Vec::new();
+    output.extend_from_slice(MAGIC);
+    write_u32(&mut output, VERSION);
+    output.push(match module.arithmetic.overflow {
+        vm::Overflow::Checked => 0,
+        vm::Overflow::Wrapping => 1,
+        vm::Overflow::Saturating => 2,
+    });
+    write_u32(&mut output, module.arithmetic.bits);
+
+    write_u32(&mut output, module.code.len() as u32);
+    for instruction in &module.code {
+        output.push(opcode(instruction.f));
+        write_u32(&mut output, instruction.l as u32);
+        write_u64(&mut output, instruction.a as u64);
+    }
+
+    write_u32(&mut output, module.name_table.len() as u32);
+    for item in &module.name_table {
+        write_string(&mut output, &item.name);
+        output.push(match item.kind {
+            nametab::NameTableObject::Constant => 0,
+            nametab::NameTableObject::Variable => 1,
+            nametab::NameTableObject::Procedur => 2,
+        });
+        write_u64(&mut output, item.val as u64);
+        write_u32(&mut output, item.level as u32);
+        write_u64(&mut output, item.adr as u64);
+        write_u64(&mut output, item.size as u64);
+        write_span(&mut output, &item.span);
+    }
+
+    write_u32(&mut output, module.line_table.len() as u32);
+    for (pc, line) in &module.line_table {
+        write_u64(&mut output, *pc as u64);
+        write_u64(&mut output, *line as u64);
+    }
+
+    let debug_info = &module.debug_info;
+    write_string(&mut output, &debug_info.file);
+    write_string(&mut output, &debug_info.source);
+    write_u32(&mut output, debug_info.spans.len() as u32);
+    for span in &debug_info.spans {
+        write_span(&mut output, span);
+    }
+    output
+}
+
+// Module of a bytecode file written by write
+pub fn read(content: &[u8]) -> Result<Module, String> {
+    if !is_bytecode(content) {
+        return Err(String::from("Not a pl/0 bytecode file"));
+    }
+    let mut reader = Reader { content, position: MAGIC.len() };
+    let version = reader.u32()?;
+    if version == 0 || version > VERSION {
+        return Err(format!("Unsupported bytecode version {}", version));
+    }
+    // The first version ran with the default arithmetic
+    let arithmetic = match version {
+        1 => vm::Arithmetic::default(),
+        _ => {
+            let overflow = match reader.u8()? {
+                0 => vm::Overflow::Checked,
+                1 => vm::Overflow::Wrapping,
+                2 => vm::Overflow::Saturating,
+                overflow => return Err(format!("Unknown overflow behavior {}", overflow)),
+            };
+            let bits = reader.u32()?;
+            vm::Arithmetic::new(overflow, bits).ok_or(format!("Unsupported word size {}", bits))?
+        },
+    };
+
+    let mut code = Vec::new();
+    for _ in 0..reader.u32()? {
+        let f = match reader.u8()? {
+            0 => vm::Fct::Lit,
+            1 => vm::Fct::Opr,
+            2 => vm::Fct::Lod,
+            3 => vm::Fct::Sto,
+            4 => vm::Fct::Cal,
+            5 => vm::Fct::Inte,
+            6 => vm::Fct::Jmp,
+            7 => vm::Fct::Jpc,
+            8 => vm::Fct::Hlt,
+            f => return Err(format!("Unknown instruction {}", f)),
+        };
+        let l = reader.u32()? as usize;
+        let a = reader.usize()?;
+        code.push(vm::Instruction { f, l, a });
+    }
+
+    let mut name_table = Vec::new();
+    for _ in 0..reader.u32()? {
+        let name = reader.string()?;
+        let kind = match reader.u8()? {
+            0 => nametab::NameTableObject::Constant,
+            1 => nametab::NameTableObject::Variable,
+            2 => nametab::NameTableObject::Procedur,
+            kind => return Err(format!("Unknown name kind {}", kind)),
+        };
+        name_table.push(nametab::NameTableItem {
+            name,
+            kind,
+            val: reader.u64()? as i64,
+            level: reader.u32()? as usize,
+            adr: reader.usize()?,
+            size: reader.usize()?,
+            span: reader.span()?,
+        });
+    }
+
+    let mut line_table = Vec::new();
+    for _ in 0..reader.u32()? {
+        line_table.push((reader.usize()?, reader.usize()?));
+    }
+
+    let file = reader.string()?;
+    let source = reader.string()?;
+    let mut spans = Vec::new();
+    for _ in 0..reader.u32()? {
+        spans.push(reader.span()?);
+    }
+    if spans.len() != code.len() || spans.iter().any(|span| span.end > source.len() || span.start > span.end) {
+        return Err(String::from("Debug info does not match the code"));
+    }
+    if reader.position != content.len() {
+        return Err(String::from("Unexpected data after the debug info"));
+    }
+
+    Ok(Module { arithmetic, code, name_table, line_table, debug_info: DebugInfo::new(&file, &source, spans) })
+}
+
+fn opcode(f: vm::Fct) -> u8 {
+    match f {
+        vm::Fct::Lit => 0,
+        vm::Fct::Opr => 1,
+        vm::Fct::Lod => 2,
+        vm::Fct::Sto => 3,
+        vm::Fct::Cal => 4,
+        vm::Fct::Inte => 5,
+        vm::Fct::Jmp => 6,
+        vm::Fct::Jpc => 7,
+        vm::Fct::Hlt => 8,
+    }
+}
+
+fn write_u32(output: &mut Vec<u8>, value: u32) {
+    output.extend_from_slice(&value.to_le_bytes());
+}
+
+fn write_u64(output: &mut Vec<u8>, value: u64) {
+    output.extend_from_slice(&value.to_le_bytes());
+}
+
+fn write_string(output: &mut Vec<u8>, value: &str) {
+    write_u32(output, value.len() as u32);
+    output.extend_from_slice(value.as_bytes());
+}
+
+fn write_span(output: &mut Vec<u8>, span: &Range<usize>) {
+    write_u64(output, span.start as u64);
+    write_u64(output, span.end as u64);
+}
+
+struct Reader<'a> {
+    content: &'a [u8],
+    position: usize,
+}
+
+impl Reader<'_> {
+    fn bytes(&mut self, count: usize) -> Result<&[u8], String> {
+        let end = self.position.checked_add(count).filter(|end| *end <= self.content.len())
+            .ok_or("Unexpected end of the bytecode file")?;
+        let bytes = &self.content[self.position..end];
+        self.position = end;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, String> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<u32, String> {
+        Ok(u32::from_le_bytes(<[u8; 4]>::try_from(self.bytes(4)?).unwrap()))
+    }
+
+    fn u64(&mut self) -> Result<u64, String> {
+        Ok(u64::from_le_bytes(<[u8; 8]>::try_from(self.bytes(8)?).unwrap()))
+    }
+
+    fn usize(&mut self) -> Result<usize, String> {
+        usize::try_from(self.u64()?).map_err(|_| String::from("Address out of range"))
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        let length = self.u32()? as usize;
+        String::from_utf8(self.bytes(length)?.to_vec()).map_err(|_| String::from("Invalid UTF-8 string"))
+    }
+
+    fn span(&mut self) -> Result<Range<usize>, String> {
+        Ok(self.usize()?..self.usize()?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::bytecode;
+    use crate::compile::codegen;
+    use crate::symbol;
+    use crate::vm;
+
+    fn module(content: &str) -> bytecode::Module {
+        let mut lexer = symbol::io::PL0Lexer::create_from_content(content);
+        let mut generator = codegen::CodeGenerator::new();
+        assert!(generator.build_block(0, &mut lexer));
+        bytecode::Module::compiled(&generator, "test.pl0", content)
+    }
+
+    /* test a module reads back as it was written */
+    #[test]
+    fn test_round_trip() {
+        let mut module = module("const c = 3; var x;\nprocedure p; x := x * c;\nbegin x := 2; call p; write(x) end.");
+        module.arithmetic = vm::Arithmetic::new(vm::Overflow::Wrapping, 16).unwrap();
+        let content = bytecode::write(&module);
+        assert!(bytecode::is_bytecode(&content));
+
+        let read = bytecode::read(&content).unwrap();
+        assert_eq!(format!("{:?}", read), format!("{:?}", module));
+        assert_eq!(read.load().arithmetic(), module.arithmetic);
+        assert_eq!(read.code[5].to_string(), "Opr  0 4");
+        assert_eq!(read.debug_info.text(5), Some("x * c"));
+        assert_eq!(read.debug_info.line(5), Some(2));
+        assert_eq!(read.debug_info.column_of(read.debug_info.spans[5].start), 19);
+    }
+
+    /* test damaged files are rejected */
+    #[test]
+    fn test_invalid() {
+        let content = bytecode::write(&module("write(1)."));
+
+        assert_eq!(bytecode::read(b"var x;").unwrap_err(), "Not a pl/0 bytecode file");
+        assert_eq!(bytecode::read(&content[..content.len() - 1]).unwrap_err(), "Unexpected end of the bytecode file");
+        let mut version = content.clone();
+        version[4] = 9;
+        assert_eq!(bytecode::read(&version).unwrap_err(), "Unsupported bytecode version 9");
+        let mut longer = content.clone();
+        longer.push(0);
+        assert_eq!(bytecode::read(&longer).unwrap_err(), "Unexpected data after the debug info");
+
+        // The first version has no arithmetic
+        let mut first = content;
+        first[4] = 1;
+        first.drain(8..13);
+        assert_eq!(bytecode::read(&first).unwrap().arithmetic, vm::Arithmetic::default());
+    }
+
+    /* test lines and columns of the instructions */
+    #[test]
+    fn test_debug_info() {
+        let debug_info = bytecode::DebugInfo::new("test.pl0", "var x;\nbegin\n  x := 1\nend.", vec![0..0, 15..21]);
+
+        assert_eq!(debug_info.span(0), None);
+        assert_eq!(debug_info.line(1), Some(3));
+        assert_eq!(debug_info.column_of(15), 3);
+        assert_eq!(debug_info.text(1), Some("x := 1"));
+        assert_eq!(debug_info.source_line(3), "  x := 1");
+        assert_eq!(debug_info.source_line(5), "");
+    }
+}
@@ -0,0 +1,374 @@
+use std::collections::HashMap;
+use std::fmt::Write;
+
+use crate::vm;
+use crate::compile::cfg;
+use crate::compile::nametab;
+
+
+/* procedure of the call graph, the main procedure is the first one */
+#[derive(Clone, Debug, PartialEq)]
+pub struct Procedure {
+    pub name: String,
+    pub level: usize,       // static nesting depth, 0 for the main procedure
+    pub frame: usize,       // cells of its activation record, links and data
+    pub operands: usize,    // most cells its expressions need on top of the frame
+    pub calls: Vec<usize>,  // procedures it calls, in the order of the first call
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct CallGraph {
+    pub procedures: Vec<Procedure>,
+}
+
+/*
+ * Build the static call graph from the Cal instructions of every procedure,
+ * which the code generator emits for the call statements. Every frame
+ * has the three links below the data, the main procedure's too.
+ */
+pub fn build(code: &[vm::Instruction], name_table: &[nametab::NameTableItem]) -> CallGraph {
+    let blocks = cfg::build(code, name_table);
+    let indexes: HashMap<usize, usize> = blocks.iter().enumerate()
+        .map(|(index, procedure)| (procedure.entry, index))
+        .collect();
+    let items = name_table.iter()
+        .filter(|item| item.kind == nametab::NameTableObject::Procedur && item.adr < code.len());
+
+    let procedures = blocks.iter().zip(items).enumerate()
+        .map(|(index, (procedure, item))| {
+            let mut calls = Vec::new();
+            for block in &procedure.blocks {
+                for (edge, target) in &block.edges {
+                    if let (cfg::Edge::Call, Some(callee)) = (edge, indexes.get(target)) {
+                        if !calls.contains(callee) {
+                            calls.push(*callee);
+                        }
+                    }
+                }
+            }
+            let data = match code[procedure.entry].f {
+                vm::Fct::Inte => code[procedure.entry].a,
+                _ => 0,
+            };
+            Procedure {
+                name: procedure.name.clone(),
+                // The name table has the level the procedure is declared at
+                level: if index == 0 { 0 } else { item.level + 1 },
+                frame: data + 3,
+                operands: operands(code, procedure),
+                calls,
+            }
+        })
+        .collect();
+    CallGraph { procedures }
+}
+
+// Highest operand stack of a procedure over all the paths through its blocks
+fn operands(code: &[vm::Instruction], procedure: &cfg::Procedure) -> usize {
+    let starts: HashMap<usize, &cfg::Block> = procedure.blocks.iter().map(|block| (block.start, block)).collect();
+    let mut heights: HashMap<usize, usize> = HashMap::new();
+    let mut pending = vec![(procedure.entry, 0)];
+    let mut highest = 0;
+    while let Some((start, height)) = pending.pop() {
+        let block = match starts.get(&start) {
+            Some(block) if heights.get(&start).is_none_or(|seen| *seen < height) => block,
+            _ => continue,
+        };
+        heights.insert(start, height);
+
+        let mut current = height;
+        for instruction in &code[block.start..block.end] {
+            current = match instruction.f {
+                vm::Fct::Lit | vm::Fct::Lod => current + 1,
+                vm::Fct::Sto | vm::Fct::Jpc => current.saturating_sub(1),
+                vm::Fct::Opr => match instruction.a {
+                    2..=5 | 8..=14 => current.saturating_sub(1),
+                    16 => current + 1,
+                    _ => current,
+                },
+                vm::Fct::Cal | vm::Fct::Inte | vm::Fct::Jmp | vm::Fct::Hlt => current,
+            };
+            highest = highest.max(current);
+        }
+        for (edge, target) in &block.edges {
+            if *edge != cfg::Edge::Call {
+                pending.push((*target, current));
+            }
+        }
+    }
+    highest
+}
+
+impl CallGraph {
+    // Procedures calling each other in a cycle, each group sorted, in the order of their first procedure
+    pub fn recursion(&self) -> Vec<Vec<usize>> {
+        let mut groups: Vec<Vec<usize>> = components(self).into_iter()
+            .filter(|group| group.len() > 1 || self.procedures[group[0]].calls.contains(&group[0]))
+            .map(|mut group| {
+                group.sort_unstable();
+                group
+            })
+            .collect();
+        groups.sort();
+        groups
+    }
+
+    // Deepest static nesting of a procedure declaration
+    pub fn depth(&self) -> usize {
+        self.procedures.iter().map(|procedure| procedure.level).max().unwrap_or(0)
+    }
+
+    // Procedures the main procedure can reach through calls, itself included
+    pub fn reachable(&self) -> Vec<bool> {
+        let mut reached = vec![false; self.procedures.len()];
+        let mut pending = vec![0];
+        while let Some(index) = pending.pop() {
+            if index < reached.len() && !reached[index] {
+                reached[index] = true;
+                pending.extend(self.procedures[index].calls.iter().copied());
+            }
+        }
+        reached
+    }
+
+    /*
+     * Most cells of the vm stack the program can use, none if a recursion
+     * is reachable from the main procedure. A call is a statement, so the
+     * operand stack of the caller is empty at that point and the callee
+     * starts right above its frame.
+     */
+    pub fn stack_bound(&self) -> Option<usize> {
+        let reached = self.reachable();
+        if self.recursion().iter().flatten().any(|index| reached[*index]) {
+            return None;
+        }
+
+        fn usage(graph: &CallGraph, index: usize, memo: &mut HashMap<usize, usize>) -> usize {
+            if let Some(cells) = memo.get(&index) {
+                return *cells;
+            }
+            let procedure = &graph.procedures[index];
+            let callees = procedure.calls.iter().map(|callee| usage(graph, *callee, memo)).max().unwrap_or(0);
+            let cells = procedure.frame + procedure.operands.max(callees);
+            memo.insert(index, cells);
+            cells
+        }
+        self.procedures.first().map(|_| usage(self, 0, &mut HashMap::new()))
+    }
+
+    // Readable report of the graph and its analysis
+    pub fn text(&self) -> String {
+        let mut output = String::new();
+        let recursion = self.recursion();
+        for procedure in &self.procedures {
+            write!(output, "{} (level {}, frame {}, operands {})", procedure.name, procedure.level,
+                procedure.frame, procedure.operands).unwrap();
+            if procedure.calls.is_empty() {
+                output.push('\n');
+            } else {
+                let callees: Vec<&str> = procedure.calls.iter().map(|callee| self.procedures[*callee].name.as_str()).collect();
+                writeln!(output, " calls {}", callees.join(", ")).unwrap();
+            }
+        }
+
+        output.push('\n');
+        for group in &recursion {
+            let names: Vec<&str> = group.iter().map(|index| self.procedures[*index].name.as_str()).collect();
+            match group.len() {
+                1 => writeln!(output, "direct recursion: {}", names[0]).unwrap(),
+                _ => writeln!(output, "mutual recursion: {}", names.join(", ")).unwrap(),
+            }
+        }
+        if recursion.is_empty() {
+            output.push_str("no recursion\n");
+        }
+        writeln!(output, "maximum nesting depth: {}", self.depth()).unwrap();
+        match self.stack_bound() {
+            Some(cells) => writeln!(output, "stack bound: {} cells", cells).unwrap(),
+            None => output.push_str("stack bound: none, the program is recursive\n"),
+        }
+        output
+    }
+
+    // Graphviz graph of the calls, recursive procedures in bold
+    pub fn dot(&self) -> String {
+        let recursive: Vec<usize> = self.recursion().into_iter().flatten().collect();
+        let mut output = String::from("digraph callgraph {\n    node [shape=box, fontname=\"monospace\"];\n");
+        for (index, procedure) in self.procedures.iter().enumerate() {
+            let style = if recursive.contains(&index) { ", style=bold" } else { "" };
+            writeln!(output, "    p{} [label=\"{}\\nlevel {}\"{}];", index, procedure.name, procedure.level, style).unwrap();
+        }
+        for (index, procedure) in self.procedures.iter().enumerate() {
+            for callee in &procedure.calls {
+                writeln!(output, "    p{} -> p{};", index, callee).unwrap();
+            }
+        }
+        output.push_str("}\n");
+        output
+    }
+}
+
+// Strongly connected components with the algorithm of Tarjan
+fn components(graph: &CallGraph) -> Vec<Vec<usize>> {
+    struct Search<'a> {
+        graph: &'a CallGraph,
+        index: Vec<Option<usize>>,
+        low: Vec<usize>,
+        stack: Vec<usize>,
+        on_stack: Vec<bool>,
+        next: usize,
+        components: Vec<Vec<usize>>,
+    }
+
+    impl Search<'_> {
+        fn visit(&mut self, node: usize) {
+            self.index[node] = Some(self.next);
+            self.low[node] = self.next;
+            self.next += 1;
+            self.stack.push(node);
+            self.on_stack[node] = true;
+
+            for callee in self.graph.procedures[node].calls.clone() {
+                match self.index[callee] {
+                    None => {
+                        self.visit(callee);
+                        self.low[node] = self.low[node].min(self.low[callee]);
+                    },
+                    Some(index) if self.on_stack[callee] => self.low[node] = self.low[node].min(index),
+                    Some(_) => {},
+                }
+            }
+
+            if Some(self.low[node]) == self.index[node] {
+                let mut component = Vec::new();
+                while let Some(member) = self.stack.pop() {
+                    self.on_stack[member] = false;
+                    component.push(member);
+                    if member == node {
+                        break;
+                    }
+                }
+                self.components.push(component);
+            }
+        }
+    }
+
+    let count = graph.procedures.len();
+    let mut search = Search {
+        graph,
+        index: vec![None; count],
+        low: vec![0; count],
+        stack: Vec::new(),
+        on_stack: vec![false; count],
+        next: 0,
+        components: Vec::new(),
+    };
+    for node in 0..count {
+        if search.index[node].is_none() {
+            search.visit(node);
+        }
+    }
+    search.components
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::backend;
+    use crate::compile::callgraph;
+    use crate::compile::codegen;
+    use crate::symbol;
+    use crate::vm;
+
+    fn graph(content: &str) -> callgraph::CallGraph {
+        let mut lexer = symbol::io::PL0Lexer::create_from_content(content);
+        let mut generator = codegen::CodeGenerator::new();
+        assert!(generator.build_block(0, &mut lexer));
+        callgraph::build(generator.get_vm_code(), generator.get_name_table())
+    }
+
+    /* test direct and mutual recursion are told apart */
+    #[test]
+    fn test_recursion() {
+        let graph = graph("procedure a; call a;
+procedure b;
+    procedure c; call b;
+    call c;
+procedure d; call a;
+call d.");
+
+        let names: Vec<&str> = graph.procedures.iter().map(|procedure| procedure.name.as_str()).collect();
+        assert_eq!(names, vec!["_main", "a", "b", "c", "d"]);
+        assert_eq!(graph.procedures[0].calls, vec![4]);
+        assert_eq!(graph.recursion(), vec![vec![1], vec![2, 3]]);
+        assert_eq!(graph.depth(), 2);
+        assert_eq!(graph.stack_bound(), None);
+        assert!(graph.text().contains("direct recursion: a\nmutual recursion: b, c\n"));
+    }
+
+    /* test the stack bound adds the frames along the deepest chain of calls */
+    #[test]
+    fn test_stack_bound() {
+        let graph = graph("var x, y;
+procedure p; var z; z := x * (y + 1);
+procedure q; begin call p; call p end;
+begin call q; write(x + y) end.");
+
+        // Every declaration takes a data cell, the procedures too
+        assert_eq!(graph.procedures[0].frame, 3 + 4);
+        assert_eq!(graph.procedures[1].frame, 3 + 1);
+        assert_eq!(graph.procedures[1].operands, 3);
+        assert_eq!(graph.procedures[2].calls, vec![1]);
+        assert_eq!(graph.stack_bound(), Some(7 + 3 + 4 + 3));
+        assert_eq!(graph.text().lines().last(), Some("stack bound: 17 cells"));
+
+        let dot = graph.dot();
+        assert!(dot.contains("    p2 [label=\"q\\nlevel 1\"];\n"));
+        assert!(dot.contains("    p2 -> p1;\n"));
+    }
+
+    /* test the edges are the call statements and the bound is what the vm reaches */
+    #[test]
+    fn test_programs() {
+        for (name, content) in backend::tests::programs() {
+            let graph = graph(&content);
+            let program = backend::compile(&content).unwrap();
+            assert_eq!(graph.procedures.len(), program.procedures.len(), "{}", name);
+            for (procedure, lowered) in graph.procedures.iter().zip(&program.procedures) {
+                let mut calls = Vec::new();
+                calls_of(&lowered.body, &mut calls);
+                assert_eq!(procedure.calls, calls, "{}", name);
+            }
+
+            if let Some(bound) = graph.stack_bound() {
+                let mut lexer = symbol::io::PL0Lexer::create_from_content(&content);
+                let mut generator = codegen::CodeGenerator::new();
+                assert!(generator.build_block(0, &mut lexer));
+                let mut machine = vm::PL0VirtualMachine::load(generator.get_vm_code().to_vec());
+                machine.redirect(Box::new(backend::tests::INPUT.as_bytes()), Box::new(vm::SharedBuffer::default()));
+                machine.prepare();
+                let mut highest = 0;
+                loop {
+                    machine.single_step_execute();
+                    highest = highest.max(machine.stack().len());
+                    if machine.pc() == 0 {
+                        break;
+                    }
+                }
+                assert!(highest <= bound, "{}: {} above {}", name, highest, bound);
+            }
+        }
+    }
+
+    fn calls_of(statement: &backend::Statement, calls: &mut Vec<usize>) {
+        match statement {
+            backend::Statement::Call { procedure, .. } if !calls.contains(procedure) => calls.push(*procedure),
+            backend::Statement::Begin(statements) => {
+                for inner in statements {
+                    calls_of(inner, calls);
+                }
+            },
+            backend::Statement::If(_, body) | backend::Statement::While(_, body) => calls_of(body, calls),
+            _ => {},
+        }
+    }
+}
@@ -0,0 +1,193 @@
+use std::fmt::Write;
+
+use crate::vm;
+use crate::compile::nametab;
+
+
+/* instructions from start to end excluded, entered only at start and left only at the end */
+#[derive(Clone, Debug, PartialEq)]
+pub struct Block {
+    pub start: usize,
+    pub end: usize,
+    pub edges: Vec<(Edge, usize)>,  // (kind, address of the target block)
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Edge {
+    Next,       // falls through or jumps unconditionally
+    True,       // the condition of Jpc holds, it falls through
+    False,      // the condition of Jpc fails, it jumps
+    Call,       // to the entry of the called procedure
+}
+
+/* code of a procedure from its Inte to its return */
+#[derive(Clone, Debug, PartialEq)]
+pub struct Procedure {
+    pub name: String,
+    pub entry: usize,
+    pub blocks: Vec<Block>,
+}
+
+/*
+ * Split the code of every procedure into basic blocks. A procedure starts
+ * at the address of the name table and ends with its first return, the
+ * code of nested procedures comes before it. Blocks start at the entry,
+ * at the targets of Jmp and Jpc, and after them.
+ */
+pub fn build(code: &[vm::Instruction], name_table: &[nametab::NameTableItem]) -> Vec<Procedure> {
+    name_table.iter()
+        .filter(|item| item.kind == nametab::NameTableObject::Procedur && item.adr < code.len())
+        .map(|item| {
+            let entry = item.adr;
+            let end = (entry..code.len())
+                .find(|pc| code[*pc].f == vm::Fct::Opr && code[*pc].a == 0)
+                .map_or(code.len(), |pc| pc + 1);
+
+            let mut leaders = vec![entry];
+            for (pc, instruction) in code.iter().enumerate().take(end).skip(entry) {
+                if let vm::Fct::Jmp | vm::Fct::Jpc = instruction.f {
+                    leaders.push(instruction.a);
+                    leaders.push(pc + 1);
+                }
+            }
+            leaders.retain(|leader| (entry..end).contains(leader));
+            leaders.sort_unstable();
+            leaders.dedup();
+
+            let blocks = leaders.iter().enumerate()
+                .map(|(index, start)| {
+                    let block_end = leaders.get(index + 1).copied().unwrap_or(end);
+                    let mut edges: Vec<(Edge, usize)> = (*start..block_end)
+                        .filter(|pc| code[*pc].f == vm::Fct::Cal)
+                        .map(|pc| (Edge::Call, callee(code, code[pc].a)))
+                        .collect();
+                    let last = code[block_end - 1];
+                    match last.f {
+                        vm::Fct::Jmp => edges.push((Edge::Next, last.a)),
+                        vm::Fct::Jpc => {
+                            edges.push((Edge::True, block_end));
+                            edges.push((Edge::False, last.a));
+                        },
+                        vm::Fct::Opr if last.a == 0 => {},
+                        vm::Fct::Hlt => {},
+                        _ if block_end < end => edges.push((Edge::Next, block_end)),
+                        _ => {},
+                    }
+                    Block { start: *start, end: block_end, edges }
+                })
+                .collect();
+            Procedure { name: item.name.clone(), entry, blocks }
+        })
+        .collect()
+}
+
+// Entry of a called procedure, a call compiled before the body of the
+// callee was known goes to the jump at the start of its block
+pub fn callee(code: &[vm::Instruction], mut address: usize) -> usize {
+    let mut jumps = 0;
+    while address < code.len() && code[address].f == vm::Fct::Jmp && jumps < code.len() {
+        address = code[address].a;
+        jumps += 1;
+    }
+    address
+}
+
+// Graphviz graph of the blocks, one cluster per procedure, calls dashed
+pub fn dot(code: &[vm::Instruction], procedures: &[Procedure]) -> String {
+    let mut output = String::from("digraph cfg {\n    node [shape=box, fontname=\"monospace\"];\n");
+    let mut calls = String::new();
+    if let (Some(first), Some(main)) = (code.first(), procedures.first()) {
+        // Execution starts with the jump at 0 into the main procedure
+        if first.f == vm::Fct::Jmp {
+            writeln!(output, "    start [shape=point];\n    start -> b{};", main.entry).unwrap();
+        }
+    }
+    for procedure in procedures {
+        writeln!(output, "    subgraph cluster_{} {{", procedure.entry).unwrap();
+        writeln!(output, "        label=\"{}\";", procedure.name).unwrap();
+        for block in &procedure.blocks {
+            let listing: String = (block.start..block.end)
+                .map(|pc| format!("{}: {}\\l", pc, code[pc]))
+                .collect();
+            writeln!(output, "        b{} [label=\"{}\"];", block.start, listing).unwrap();
+            for (edge, target) in &block.edges {
+                let attributes = match edge {
+                    Edge::Next => "",
+                    Edge::True => " [label=\"true\"]",
+                    Edge::False => " [label=\"false\"]",
+                    Edge::Call => {
+                        // Outside of the cluster, else graphviz draws the callee in it
+                        writeln!(calls, "    b{} -> b{} [style=dashed, label=\"call\"];", block.start, target).unwrap();
+                        continue;
+                    },
+                };
+                writeln!(output, "        b{} -> b{}{};", block.start, target, attributes).unwrap();
+            }
+        }
+        output.push_str("    }\n");
+    }
+    output.push_str(&calls);
+    output.push_str("}\n");
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::compile::cfg;
+    use crate::compile::cfg::Edge;
+    use crate::compile::codegen;
+    use crate::symbol;
+    use crate::vm;
+
+    fn procedures(content: &str) -> (codegen::CodeGenerator, Vec<cfg::Procedure>) {
+        let mut lexer = symbol::io::PL0Lexer::create_from_content(content);
+        let mut generator = codegen::CodeGenerator::new();
+        assert!(generator.build_block(0, &mut lexer));
+        let procedures = cfg::build(generator.get_vm_code(), generator.get_name_table());
+        (generator, procedures)
+    }
+
+    /* test a while loop splits into the test, the body and the exit */
+    #[test]
+    fn test_while() {
+        let (generator, procedures) = procedures("var x; begin while x < 3 do x := x + 1; write(x) end.");
+        let listing: Vec<String> = generator.get_vm_code().iter().map(|instruction| instruction.to_string()).collect();
+        assert_eq!(listing[2..6], ["Lod  0 0", "Lit  0 3", "Opr  0 10", "Jpc  0 11"]);
+
+        assert_eq!(procedures.len(), 1);
+        assert_eq!(procedures[0].blocks, vec![
+            cfg::Block { start: 1, end: 2, edges: vec![(Edge::Next, 2)] },
+            cfg::Block { start: 2, end: 6, edges: vec![(Edge::True, 6), (Edge::False, 11)] },
+            cfg::Block { start: 6, end: 11, edges: vec![(Edge::Next, 2)] },
+            cfg::Block { start: 11, end: 15, edges: vec![] },
+        ]);
+    }
+
+    /* test calls link the caller block to the entry of the callee */
+    #[test]
+    fn test_dot() {
+        let (generator, procedures) = procedures("var x; procedure p; if odd x then x := 0; begin call p end.");
+        let output = cfg::dot(generator.get_vm_code(), &procedures);
+
+        assert_eq!(procedures[1].name, "p");
+        assert!(output.starts_with("digraph cfg {\n"));
+        assert!(output.contains("    start -> b9;\n"));
+        assert!(output.contains("    subgraph cluster_2 {\n        label=\"p\";\n"));
+        assert!(output.contains("        b2 [label=\"2: Inte 0 0\\l3: Lod  1 0\\l4: Opr  0 6\\l5: Jpc  0 8\\l\"];\n"));
+        assert!(output.contains("        b2 -> b6 [label=\"true\"];\n        b2 -> b8 [label=\"false\"];\n"));
+        assert!(output.contains("        b6 -> b8;\n        b8 [label=\"8: Opr  0 0\\l\"];\n"));
+        assert!(output.contains("    b9 -> b2 [style=dashed, label=\"call\"];\n"));
+    }
+
+    /* test a call compiled before the body of the callee goes to its entry */
+    #[test]
+    fn test_forward_call() {
+        let (generator, procedures) = procedures("procedure b; procedure c; call b; call c; call b.");
+        let code = generator.get_vm_code();
+
+        assert_eq!(procedures[2].name, "c");
+        let call = procedures[2].blocks[0].edges[0];
+        assert_eq!(call, (Edge::Call, procedures[1].entry));
+        assert_eq!(code[procedures[1].entry].f, vm::Fct::Inte);
+    }
+}
@@ -1,6 +1,11 @@
+use std::ops::Range;
+
 use crate::vm;
 use crate::symbol;
 use crate::compile::nametab;
+use crate::compile::error;
+use crate::compile::peephole;
+use crate::compile::deadcode;
 
 
 pub struct CodeGenerator {
@@ -9,6 +14,23 @@ pub struct CodeGenerator {
 
     code_pointer: usize,
     table_pointer: usize,
+
+    line_table: Vec<(usize, usize)>,    // (code pointer, source line) of each statement
+    spans: Vec<Range<usize>>,           // source span of the construct that produced each instruction
+
+    errors: Vec<error::CompileError>,
+    references: Vec<(usize, Range<usize>)>,         // (name table index, span) of each identifier use
+    procedure_extents: Vec<(usize, Range<usize>)>,  // (name table index, span) of each procedure declaration
+    closed: Vec<Range<usize>>,  // name table entries of finished blocks
+
+    constant_folding: bool,     // evaluate constant expressions at compile time
+    arithmetic: vm::Arithmetic, // of the machine the code is for
+}
+
+impl Default for CodeGenerator {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl CodeGenerator {
@@ -18,9 +40,30 @@ impl CodeGenerator {
             name_table: Vec::new(),
             code_pointer: 0,
             table_pointer: 0,
+            line_table: Vec::new(),
+            spans: Vec::new(),
+            errors: Vec::new(),
+            references: Vec::new(),
+            procedure_extents: Vec::new(),
+            closed: Vec::new(),
+            constant_folding: true,
+            arithmetic: vm::Arithmetic::default(),
         }
     }
 
+    pub fn set_constant_folding(&mut self, enabled: bool) {
+        self.constant_folding = enabled;
+    }
+
+    // Numbers must fit in its words and constant expressions are folded with it
+    pub fn set_arithmetic(&mut self, arithmetic: vm::Arithmetic) {
+        self.arithmetic = arithmetic;
+    }
+
+    pub fn get_arithmetic(&self) -> vm::Arithmetic {
+        self.arithmetic
+    }
+
     pub fn gen(&self, opcode: vm::Fct, level: usize, extra: usize) -> vm::Instruction {
         vm::Instruction {
             f: opcode,
@@ -29,7 +72,8 @@ impl CodeGenerator {
         }
     }
 
-    pub fn build_block(&mut self, level: usize, lexer: &mut symbol::io::PL0Lexer) {
+    // Returns whether the program is correctly ended with a period and has no error
+    pub fn build_block(&mut self, _level: usize, lexer: &mut symbol::io::PL0Lexer) -> bool {
         // Create anonymous main procedure
         self.add_into_name_table("_main", 0, nametab::NameTableObject::Procedur, 0, 0);
 
@@ -37,28 +81,174 @@ impl CodeGenerator {
             self.block(0, lexer);
         }
 
-        let sym = lexer.current();
-        if *sym == symbol::Symbol::Period {
-            println!("Parsing finished");
-        } else {
-            println!("Parsing Failed {:?}", sym);
+        if *lexer.current() != symbol::Symbol::Period {
+            self.error(lexer, "Program should end with .");
         }
+        self.errors.is_empty()
     }
 
     pub fn block(&mut self, level: usize, lexer: &mut symbol::io::PL0Lexer) {
         let table_pointer_0 = self.table_pointer;
 
-        // Add jump to code
-        self.code_pointer += 1;
-        self.code.push(self.gen(vm::Fct::Jmp, 0, 0));
+        // Add jump to code, it gets the span of the statement of the block once it is known
+        self.emit(self.gen(vm::Fct::Jmp, 0, 0), 0..0);
 
         // Set procedur begin pos
         self.name_table[self.table_pointer - 1].adr = self.code_pointer - 1;
+        let jump = self.code_pointer - 1;
 
         let mut data_pointer: usize = 0;    // Count data size in this block (single level, no deeper)
         {
             lexer.next();
         }
+        self.parse_declarations(level, lexer, &mut data_pointer);
+
+        // Generate current block
+        self.code[self.name_table[table_pointer_0 - 1].adr].a = self.code_pointer;
+        self.name_table[table_pointer_0 - 1].adr = self.code_pointer;
+        self.name_table[table_pointer_0 - 1].size = data_pointer;
+
+        // Record current code pointer pos
+        // let code_pointer_0 = self.code_pointer;
+
+        // Begin statement
+        self.emit(self.gen(vm::Fct::Inte, 0, data_pointer), 0..0);
+        let entry = self.code_pointer - 1;
+
+        // Statement
+        let span = self.parse_statement(level, lexer);
+        // Should end with end/semicolon
+        self.emit(self.gen(vm::Fct::Opr, 0, 0), span.clone());
+        // End statement
+        self.spans[jump] = span.clone();
+        self.spans[entry] = span;
+
+        // Declarations of this block are out of scope from now on
+        self.closed.push(table_pointer_0..self.table_pointer);
+    }
+
+    // Run the peephole optimizer on the whole program, procedure addresses
+    // and statement positions follow the instructions they point to
+    pub fn optimize(&mut self) {
+        let (code, relocation) = peephole::optimize(&self.code);
+        self.code = code;
+        self.code_pointer = self.code.len();
+
+        for item in self.name_table.iter_mut() {
+            if item.kind == nametab::NameTableObject::Procedur && item.adr != nametab::REMOVED {
+                item.adr = relocation[item.adr];
+            }
+        }
+        self.relocate_lines(&relocation);
+        self.relocate_spans(&relocation);
+    }
+
+    // Remove the procedures never reached from the main procedure and the code
+    // after unconditional jumps, returns what was removed
+    pub fn eliminate_dead_code(&mut self) -> deadcode::Removed {
+        let (code, relocation, reached) = deadcode::eliminate(&self.code);
+        let removed = deadcode::Removed {
+            procedures: self.name_table.iter()
+                .skip(1)
+                .filter(|item| item.kind == nametab::NameTableObject::Procedur && reached.get(item.adr) == Some(&false))
+                .map(|item| item.name.clone())
+                .collect(),
+            instructions: self.code.len() - code.len(),
+        };
+        self.code = code;
+        self.code_pointer = self.code.len();
+
+        for item in self.name_table.iter_mut() {
+            if item.kind == nametab::NameTableObject::Procedur && item.adr != nametab::REMOVED {
+                // A removed procedure keeps no address, the next kept instruction is another one's
+                item.adr = match reached.get(item.adr) {
+                    Some(false) => nametab::REMOVED,
+                    _ => relocation[item.adr],
+                };
+            }
+        }
+        // Statements that were removed have no line any more
+        self.line_table.retain(|(pc, _)| reached.get(*pc) != Some(&false));
+        self.relocate_lines(&relocation);
+        self.relocate_spans(&relocation);
+        removed
+    }
+
+    fn relocate_lines(&mut self, relocation: &[usize]) {
+        let mut line_table: Vec<(usize, usize)> = Vec::new();
+        for (pc, line) in &self.line_table {
+            let pc = relocation[*pc];
+            match line_table.last_mut() {
+                // The first statement left no code, like mark_line does
+                Some(last) if last.0 == pc => last.1 = *line,
+                _ => line_table.push((pc, *line)),
+            }
+        }
+        self.line_table = line_table;
+    }
+
+    fn relocate_spans(&mut self, relocation: &[usize]) {
+        let mut spans = vec![0..0; self.code.len()];
+        for (pc, span) in self.spans.iter().enumerate() {
+            // A removed instruction goes to the next kept one, which keeps its own span
+            if relocation[pc + 1] > relocation[pc] {
+                spans[relocation[pc]] = span.clone();
+            }
+        }
+        self.spans = spans;
+    }
+
+    // Compile one input of the interactive mode into the main procedure,
+    // returns where to start executing it
+    pub fn build_incremental(&mut self, lexer: &mut symbol::io::PL0Lexer) -> Option<usize> {
+        if self.name_table.is_empty() {
+            // Create anonymous main procedure
+            self.add_into_name_table("_main", 0, nametab::NameTableObject::Procedur, 0, 0);
+        }
+
+        // Keep the previous inputs if this one is wrong
+        let code_pointer_0 = self.code_pointer;
+        let table_pointer_0 = self.table_pointer;
+        let line_table_0 = self.line_table.len();
+        let references_0 = self.references.len();
+        let procedure_extents_0 = self.procedure_extents.len();
+        let closed_0 = self.closed.len();
+        self.errors.clear();
+
+        let data_pointer_0 = self.name_table[0].size;
+        let mut data_pointer = data_pointer_0;
+        {
+            lexer.next();
+        }
+        self.parse_declarations(0, lexer, &mut data_pointer);
+
+        // Allocate the new variables on the main activation record
+        let entry = self.code_pointer;
+        self.emit(self.gen(vm::Fct::Inte, 0, data_pointer - data_pointer_0), 0..0);
+
+        self.spans[entry] = self.parse_statement(0, lexer);
+        if *lexer.current() == symbol::Symbol::Semicolon || *lexer.current() == symbol::Symbol::Period {
+            lexer.next();
+        }
+        if *lexer.current() != symbol::Symbol::EOF {
+            self.error(lexer, "Unexpected symbol after statement");
+        }
+
+        if !self.errors.is_empty() {
+            self.truncate(code_pointer_0);
+            self.name_table.truncate(table_pointer_0);
+            self.table_pointer = table_pointer_0;
+            self.line_table.truncate(line_table_0);
+            self.references.truncate(references_0);
+            self.procedure_extents.truncate(procedure_extents_0);
+            self.closed.truncate(closed_0);
+            return None;
+        }
+        self.name_table[0].size = data_pointer;
+        Some(entry)
+    }
+
+    fn parse_declarations(&mut self, level: usize, lexer: &mut symbol::io::PL0Lexer, data_pointer: &mut usize) {
         loop {
             match lexer.current() {
                 symbol::Symbol::Constsym => {
@@ -66,36 +256,42 @@ impl CodeGenerator {
                     loop {
                         // const gen, at least the first one
                         let mut should_continue = true;
-                        let mut identity = String::new();
-                        let mut number: i64 = 0;
+                        let identity: String;
+                        let identity_span: Range<usize>;
                         {
                             // Detect identity
                             let symbol = lexer.next();
                             if *symbol != symbol::Symbol::Ident {
                                 should_continue = false;
+                                self.error(lexer, "Constant name expected");
                             }
                             identity = lexer.current_content().to_string();
+                            identity_span = lexer.current_index();
                         }
                         if should_continue {
                             // Detect =
                             let symbol = lexer.next();
-                            if *symbol != symbol::Symbol::Eql && *symbol != symbol::Symbol::Becomes {
-                                should_continue = false;
-                            }
-                            if *symbol == symbol::Symbol::Becomes {
+                            if *symbol != symbol::Symbol::Eql {
                                 should_continue = false;
+                                self.error(lexer, "Constant should be defined with =");
                             }
                         }
                         if should_continue {
                             // Detect a number
                             let symbol = lexer.next();
-                            if *symbol != symbol::Symbol::Number {
-                                should_continue = false;
+                            if *symbol == symbol::Symbol::Number {
+                                match self.number(lexer) {
+                                    Some(number) => {
+                                        self.add_into_name_table(&identity, number, nametab::NameTableObject::Constant, level, *data_pointer);
+                                        self.name_table[self.table_pointer - 1].span = identity_span;
+                                        *data_pointer += 1;
+                                    },
+                                    None => {
+                                        self.error(lexer, "Number is too large");
+                                    },
+                                }
                             } else {
-                                number = lexer.current_content().parse::<i64>()
-                                    .expect("Cannot parse i64 constant from current token");
-                                self.add_into_name_table(&identity, number, nametab::NameTableObject::Constant, level, data_pointer);
-                                data_pointer += 1;
+                                self.error(lexer, "Constant should be defined with a number");
                             }
                         }
                         let symbol = lexer.next();
@@ -103,60 +299,73 @@ impl CodeGenerator {
                             // break
                             break;
                         }
+                        if *symbol != symbol::Symbol::Comma {
+                            self.error(lexer, "Constant declaration should end with ;");
+                            break;
+                        }
                     }
                 },
                 symbol::Symbol::Varsym => {
                     // Variable declaration
                     loop {
                         // var gen, at least the first one
-                        let mut should_continue = true;
-                        let mut identity = String::new();
                         {
                             // Detect identity
                             let symbol = lexer.next();
-                            if *symbol != symbol::Symbol::Ident {
-                                should_continue = false;
+                            if *symbol == symbol::Symbol::Ident {
+                                let identity = lexer.current_content().to_string();
+                                self.add_into_name_table(&identity, 0, nametab::NameTableObject::Variable, level, *data_pointer);
+                                self.name_table[self.table_pointer - 1].span = lexer.current_index();
+                                *data_pointer += 1;
+                            } else {
+                                self.error(lexer, "Variable name expected");
                             }
-                            identity = lexer.current_content().to_string();
-                            self.add_into_name_table(&identity, 0, nametab::NameTableObject::Variable, level, data_pointer);
-                            data_pointer += 1;
                         }
                         let symbol = lexer.next();
                         if *symbol == symbol::Symbol::Semicolon {
                             // break
                             break;
                         }
+                        if *symbol != symbol::Symbol::Comma {
+                            self.error(lexer, "Variable declaration should end with ;");
+                            break;
+                        }
                     }
                 },
                 symbol::Symbol::Procsym => {
                     // Proc declaration
                     let mut should_continue = true;
-                    let mut identity = String::new();
+                    let procedure_begin = lexer.current_index().start;
 
                     {
                         // Detect identity
                         let symbol = lexer.next();
                         if *symbol != symbol::Symbol::Ident {
                             should_continue = false;
+                            self.error(lexer, "Procedure name expected");
                         }
-                        identity = lexer.current_content().to_string();
-                        self.add_into_name_table(&identity, 0, nametab::NameTableObject::Procedur, level, data_pointer);
-                        data_pointer += 1;
+                        let identity = lexer.current_content().to_string();
+                        self.add_into_name_table(&identity, 0, nametab::NameTableObject::Procedur, level, *data_pointer);
+                        self.name_table[self.table_pointer - 1].span = lexer.current_index();
+                        *data_pointer += 1;
                     }
+                    let procedure_index = self.table_pointer - 1;
                     if should_continue {
                         // semicolon
                         let symbol = lexer.next();
 
                         if *symbol != symbol::Symbol::Semicolon {
-                            panic!("Procedur is not ended with ;");
+                            self.error(lexer, "Procedur is not ended with ;");
+                            lexer.keep_once();
                         }
                     }
                     // Enter the next level
                     self.block(level + 1, lexer);
    
                     if *lexer.current() != symbol::Symbol::Semicolon {
-                        panic!("Block is not ended with ;");
+                        self.error(lexer, "Block is not ended with ;");
                     }
+                    self.procedure_extents.push((procedure_index, procedure_begin..lexer.current_index().end));
                     // TODO: add some rescue solution
                 },
                 _ => {
@@ -176,25 +385,6 @@ impl CodeGenerator {
                 break;
             }
         }
-
-        // Generate current block
-        self.code[self.name_table[table_pointer_0 - 1].adr].a = self.code_pointer;
-        self.name_table[table_pointer_0 - 1].adr = self.code_pointer;
-        self.name_table[table_pointer_0 - 1].size = data_pointer;
-
-        // Record current code pointer pos
-        // let code_pointer_0 = self.code_pointer;
-
-        // Begin statement
-        self.code_pointer += 1;
-        self.code.push(self.gen(vm::Fct::Inte, 0, data_pointer));
-
-        // Statement
-        self.parse_statement(level, lexer);
-        // Should end with end/semicolon
-        self.code_pointer += 1;
-        self.code.push(self.gen(vm::Fct::Opr, 0, 0));
-        // End statement
     }
 
     fn add_into_name_table(&mut self, identity: &str, num: i64, k: nametab::NameTableObject, level: usize, pdx: usize) {
@@ -205,9 +395,10 @@ impl CodeGenerator {
                     name: String::from(identity),
                     kind: k,
                     val: num,
-                    level: 0,
+                    level,
                     adr: 0,
                     size: 0,
+                    span: 0..0,
                 }
             },
             nametab::NameTableObject::Variable => {
@@ -215,9 +406,10 @@ impl CodeGenerator {
                     name: String::from(identity),
                     kind: k,
                     val: 0,
-                    level: level,
+                    level,
                     adr: pdx,
                     size: 0,
+                    span: 0..0,
                 }
             },
             nametab::NameTableObject::Procedur => {
@@ -225,50 +417,62 @@ impl CodeGenerator {
                     name: String::from(identity),
                     kind: k,
                     val: 0,
-                    level: level,
+                    level,
                     adr: 0,
                     size: 0,
+                    span: 0..0,
                 }
             },
         });
         
     }
 
-    fn parse_statement(&mut self, level: usize, lexer: &mut symbol::io::PL0Lexer) {
+    // Returns the span of the statement
+    fn parse_statement(&mut self, level: usize, lexer: &mut symbol::io::PL0Lexer) -> Range<usize> {
         if *lexer.current() == symbol::Symbol::Semicolon {
             // Get the next symbol if upper level doesn't do that
             lexer.next();
         }
+        match *lexer.current() {
+            symbol::Symbol::Ident | symbol::Symbol::Readsym | symbol::Symbol::Writesym
+            | symbol::Symbol::Callsym | symbol::Symbol::Ifsym | symbol::Symbol::Whilesym => {
+                // Remember where the statement begins
+                self.mark_line(lexer.current_line());
+            },
+            _ => {},
+        }
+        let start = lexer.current_index().start;
         match *lexer.current() {
             symbol::Symbol::Ident => {
                 // Handle as a assignment statement
 
                 // Get the index of identifier
-                let identifier_index: usize = self.find_variable(lexer.current_content(), self.table_pointer);
-                let mut should_continue = true;
-                
-                if identifier_index == 0 {
-                    should_continue = false;
+                let identifier_index: usize = self.lookup(lexer);
+
+                if identifier_index != 0
+                    && self.name_table[identifier_index - 1].kind != nametab::NameTableObject::Variable {
+                    self.error(lexer, "Only a variable can be assigned");
                 }
 
-                if should_continue {
-                    // Detect Becomes symbol
-                    if *lexer.next() != symbol::Symbol::Becomes {
-                        should_continue = false;
-                    }
+                // Detect Becomes symbol
+                if *lexer.next() != symbol::Symbol::Becomes {
+                    self.error(lexer, "Assignment should use :=");
+                    lexer.keep_once();
                 }
 
-                if should_continue {
+                {
                     // Expression
                     self.parse_expression(level, lexer);
+                }
 
+                if identifier_index != 0
+                    && self.name_table[identifier_index - 1].kind == nametab::NameTableObject::Variable {
                     // Store the result in the variable
-                    self.code_pointer += 1;
-                    self.code.push(self.gen(
+                    self.emit(self.gen(
                         vm::Fct::Sto,
                         level - self.name_table[identifier_index - 1].level,
                         self.name_table[identifier_index - 1].adr
-                    ));
+                    ), start..lexer.previous_end());
                 }
             },
             symbol::Symbol::Readsym => {
@@ -278,6 +482,7 @@ impl CodeGenerator {
                 {
                     if *lexer.next() != symbol::Symbol::Lparen {
                         should_continue = false;
+                        self.error(lexer, "Read statement should begin with Lparen (");
                     }
                 }
 
@@ -289,40 +494,40 @@ impl CodeGenerator {
                             lexer.next();
                         }
 
-                        if *lexer.current() != symbol::Symbol::Ident {
-                            should_continue = false;
-                        }
-
-                        if should_continue {
+                        if *lexer.current() == symbol::Symbol::Ident {
                             // Get the index of identifier
-                            identifier_index = self.find_variable(lexer.current_content(), self.table_pointer);
+                            identifier_index = self.lookup(lexer);
+                        } else {
+                            self.error(lexer, "Read statement should read into a variable");
                         }
 
-                        if identifier_index == 0{
-                            should_continue = false;
+                        if identifier_index != 0
+                            && self.name_table[identifier_index - 1].kind != nametab::NameTableObject::Variable {
+                            self.error(lexer, "Read statement should read into a variable");
+                            identifier_index = 0;
                         }
 
-                        if should_continue {
-                            self.code_pointer += 2;
+                        if identifier_index != 0 {
                             // Read content to the stack top
-                            self.code.push(self.gen(vm::Fct::Opr, 0, 16));
+                            self.emit(self.gen(vm::Fct::Opr, 0, 16), lexer.current_index());
                             // Store the result in the variable
-                            self.code.push(self.gen(
+                            self.emit(self.gen(
                                 vm::Fct::Sto,
-                                level - self.name_table[identifier_index].level,
-                                self.name_table[identifier_index].adr
-                            ));
+                                level - self.name_table[identifier_index - 1].level,
+                                self.name_table[identifier_index - 1].adr
+                            ), lexer.current_index());
                         }
 
                         if *lexer.next() != symbol::Symbol::Comma {
                             break;
                         }
                     }
-                }
 
-                if *lexer.next() != symbol::Symbol::Rparen {
-                    panic!("Read statement should end with Rparent )");
+                    if *lexer.current() != symbol::Symbol::Rparen {
+                        self.error(lexer, "Read statement should end with Rparent )");
+                    }
                 }
+                lexer.next();
             },
             symbol::Symbol::Writesym => {
                 // write()
@@ -331,92 +536,81 @@ impl CodeGenerator {
                 {
                     if *lexer.next() != symbol::Symbol::Lparen {
                         should_continue = false;
+                        self.error(lexer, "Write statement should begin with Lparen (");
                     }
                 }
 
                 if should_continue {
                     loop {
-                        if should_continue {
-                            self.parse_expression(level, lexer);
-                        }
+                        let argument = self.parse_expression(level, lexer);
 
-                        if should_continue {
-                            self.code_pointer += 1;
-                            // Write content on the stack top
-                            self.code.push(self.gen(vm::Fct::Opr, 0, 14));
-                        }
+                        // Write content on the stack top
+                        self.emit(self.gen(vm::Fct::Opr, 0, 14), argument..lexer.previous_end().max(argument));
 
                         if *lexer.current() != symbol::Symbol::Comma {
                             break;
                         }
                     }
-                }
 
-                if *lexer.current() != symbol::Symbol::Rparen {
-                    panic!("Write statement should end with Rparent )")
-                }
+                    if *lexer.current() != symbol::Symbol::Rparen {
+                        self.error(lexer, "Write statement should end with Rparent )");
+                    }
 
-                if should_continue {
-                    self.code_pointer += 1;
                     // New line
-                    self.code.push(self.gen(vm::Fct::Opr, 0, 15));
+                    self.emit(self.gen(vm::Fct::Opr, 0, 15), start..lexer.current_index().end);
                 }
+                lexer.next();
             },
             symbol::Symbol::Callsym => {
                 // Call a function
-                let mut should_continue = true;
                 let mut index = 0;
                 {
                     // Get the next symbol
                     lexer.next();
                 }
 
-                if *lexer.current() != symbol::Symbol::Ident {
-                    should_continue = false;
-                }
-
-                if should_continue {
+                if *lexer.current() == symbol::Symbol::Ident {
                     // Get the index of identifier
-                    index = self.find_variable(lexer.current_content(), self.table_pointer);
+                    index = self.lookup(lexer);
+                } else {
+                    self.error(lexer, "Call statement should call a procedure name");
                 }
 
-                if index == 0 {
-                    should_continue = false;
-                }
-
-                if self.name_table[index - 1].kind == nametab::NameTableObject::Procedur {
-                    self.code_pointer += 1;
-                    self.code.push(self.gen(vm::Fct::Cal, 
-                        level - self.name_table[index - 1].level, self.name_table[index - 1].adr));
+                if index != 0 && self.name_table[index - 1].kind == nametab::NameTableObject::Procedur {
+                    self.emit(self.gen(vm::Fct::Cal,
+                        level - self.name_table[index - 1].level, self.name_table[index - 1].adr),
+                        start..lexer.current_index().end);
+                } else if index != 0 {
+                    self.error(lexer, "Only a procedure can be called");
                 }
+                lexer.next();
             },
             symbol::Symbol::Ifsym => {
-                let mut should_continue = true;
-                self.parse_condition(level, lexer);
+                let condition_start = self.code_pointer;
+                let condition = self.parse_condition(level, lexer);
                 {
                     if *lexer.current() != symbol::Symbol::Thensyn {
-                        panic!("If condition should be ended with then")
+                        self.error(lexer, "If condition should be ended with then");
+                        lexer.keep_once();
                     }
                 }
 
-                if should_continue {
+                {
                     {
                         lexer.next();
                     }
-                    let cx1 = self.code_pointer;
-
                     // Generate Jump before parse statement
-                    self.code_pointer += 1;
-                    self.code.push(self.gen(vm::Fct::Jpc, 0, 0));
+                    let cx1 = self.condition_jump(condition_start, condition);
 
                     self.parse_statement(level, lexer);
 
                     // Modify the jump address
-                    self.code[cx1].a = self.code_pointer;
+                    if let Some(cx1) = cx1 {
+                        self.code[cx1].a = self.code_pointer;
+                    }
                 }
             },
             symbol::Symbol::Beginsym => {
-                let should_continue = false;
                 {
                     lexer.next();
                 }
@@ -424,56 +618,112 @@ impl CodeGenerator {
                 self.parse_statement(level, lexer);
 
                 loop {
-                    if *lexer.current() != symbol::Symbol::Semicolon && *lexer.current() != symbol::Symbol::Endsym {
+                    if *lexer.current() == symbol::Symbol::Endsym {
                         lexer.next();
+                        break;
                     }
-                    if *lexer.current() == symbol::Symbol::Endsym || *lexer.current() == symbol::Symbol::Period {
-                        if *lexer.current() == symbol::Symbol::Endsym {
-                            lexer.next();
-                        }
+                    if *lexer.current() == symbol::Symbol::Period || *lexer.current() == symbol::Symbol::EOF {
+                        self.error(lexer, "Begin should end with Endsym");
                         break;
                     }
                     if *lexer.current() != symbol::Symbol::Semicolon {
-                        panic!("Statement is not ended with ;, {:?}", lexer.current());
+                        self.error(lexer, "Statement is not ended with ;");
+                        if !self.is_statement_begin(lexer.current()) {
+                            // Skip the unexpected symbol
+                            lexer.next();
+                            continue;
+                        }
                     }
 
                     self.parse_statement(level, lexer);
                 }
-                if lexer.previous() != symbol::Symbol::Endsym &&
-                    *lexer.current() != symbol::Symbol::Period {
-                    panic!("Begin should end with Endsym");
-                }
             },
             symbol::Symbol::Whilesym => {
-                let mut should_continue = true;
-
                 let cx1 = self.code_pointer;    // condition pos
 
-                self.parse_condition(level, lexer);
-
-                let cx2 = self.code_pointer;    // loop begin pos
+                let condition = self.parse_condition(level, lexer);
 
                 // Generate Jump before parse statement
-                self.code_pointer += 1;
-                self.code.push(self.gen(vm::Fct::Jpc, 0, 0));
+                let cx2 = self.condition_jump(cx1, condition);     // loop exit
                 if *lexer.current() != symbol::Symbol::Dosym {
-                    panic!("While condition should be ended with Do symbol, but get {:?}", lexer.current());
+                    self.error(lexer, "While condition should be ended with do");
+                    lexer.keep_once();
                 }
 
-                if should_continue {
+                {
                     {
                         lexer.next();
                     }
                     self.parse_statement(level, lexer);
-                    self.code_pointer += 1;
-                    self.code.push(self.gen(vm::Fct::Jmp, 0, cx1));  // Jump to condition
-                    self.code[cx2].a = self.code_pointer;
+                    self.emit(self.gen(vm::Fct::Jmp, 0, cx1), start..lexer.previous_end());  // Jump to condition
+                    if let Some(cx2) = cx2 {
+                        self.code[cx2].a = self.code_pointer;
+                    }
                 }
             },
             _ => {
-                // I cannot handle the sym
+                // Empty statement
             },
         }
+        start..lexer.previous_end().max(start)
+    }
+
+    fn is_statement_begin(&self, sym: &symbol::Symbol) -> bool {
+        matches!(sym, symbol::Symbol::Ident | symbol::Symbol::Readsym | symbol::Symbol::Writesym
+            | symbol::Symbol::Callsym | symbol::Symbol::Ifsym | symbol::Symbol::Whilesym
+            | symbol::Symbol::Beginsym)
+    }
+
+    // Resolve the identifier under the lexer, 0 if it is not declared
+    fn lookup(&mut self, lexer: &symbol::io::PL0Lexer) -> usize {
+        let index = self.find_variable(lexer.current_content(), self.table_pointer);
+        if index == 0 {
+            let message = format!("Undeclared identifier {}", lexer.current_content());
+            self.error(lexer, &message);
+        } else {
+            self.references.push((index - 1, lexer.current_index()));
+        }
+        index
+    }
+
+    fn error(&mut self, lexer: &symbol::io::PL0Lexer, message: &str) {
+        self.error_at(lexer.current_index(), message);
+    }
+
+    fn error_at(&mut self, span: Range<usize>, message: &str) {
+        // Report only the first error on the same symbol
+        if self.errors.iter().any(|error| error.span == span) {
+            return;
+        }
+        self.errors.push(error::CompileError {
+            span,
+            message: message.to_string(),
+        });
+    }
+
+    fn mark_line(&mut self, line: usize) {
+        match self.line_table.last_mut() {
+            Some(last) if last.0 == self.code_pointer => {
+                last.1 = line;
+            },
+            _ => {
+                self.line_table.push((self.code_pointer, line));
+            },
+        }
+    }
+
+    // Append an instruction produced by the construct at span
+    fn emit(&mut self, instruction: vm::Instruction, span: Range<usize>) {
+        self.code_pointer += 1;
+        self.code.push(instruction);
+        self.spans.push(span);
+    }
+
+    // Drop the instructions from start on
+    fn truncate(&mut self, start: usize) {
+        self.code.truncate(start);
+        self.spans.truncate(start);
+        self.code_pointer = start;
     }
 
     fn find_variable(&self, name: &str, tail: usize) -> usize {
@@ -482,7 +732,7 @@ impl CodeGenerator {
             return 0;
         }
         loop {
-            if pointer <= 0 || self.name_table[pointer - 1].name == name {
+            if pointer == 0 || (self.name_table[pointer - 1].name == name && !self.is_closed(pointer - 1)) {
                 break;
             }
             pointer -= 1;
@@ -490,18 +740,26 @@ impl CodeGenerator {
         pointer
     }
 
-    fn parse_expression(&mut self, level: usize, lexer: &mut symbol::io::PL0Lexer) {
-        let mut is_positive = true;
+    fn is_closed(&self, index: usize) -> bool {
+        self.closed.iter().any(|range| range.contains(&index))
+    }
+
+    // Returns where the expression begins in the source
+    fn parse_expression(&mut self, level: usize, lexer: &mut symbol::io::PL0Lexer) -> usize {
+        let mut is_positive;
+        let start = self.code_pointer;
+        let source_start;
 
         {
             // Parse a term
-            is_positive = self.parse_term(level, lexer);
+            let (positive, term_start) = self.parse_term(level, lexer);
+            is_positive = positive;
+            source_start = term_start;
         }
 
         if !is_positive {
             // Negative
-            self.code_pointer += 1;
-            self.code.push(self.gen(vm::Fct::Opr, 0, 1));
+            self.negate(start, lexer.current_index(), source_start..lexer.previous_end());
         }
 
         loop {
@@ -513,22 +771,27 @@ impl CodeGenerator {
             if *lexer.current() != symbol::Symbol::Minus && *lexer.current() != symbol::Symbol::Plus {
                 break;
             }
+            let middle = self.code_pointer;
+            let operator = lexer.current_index();
 
-            is_positive = self.parse_term(level, lexer);
+            is_positive = self.parse_term(level, lexer).0;
 
-            self.code_pointer += 1;
+            let span = source_start..lexer.previous_end();
             if is_positive {
-                self.code.push(self.gen(vm::Fct::Opr, 0, 2));
+                self.operation(start, middle, 2, operator, span);
             } else {
-                self.code.push(self.gen(vm::Fct::Opr, 0, 3));
+                self.operation(start, middle, 3, operator, span);
             }
         }
 
-
+        source_start
     }
 
-    fn parse_term(&mut self, level: usize, lexer: &mut symbol::io::PL0Lexer) -> bool {
-        let is_positive = self.parse_factor(level, lexer);
+    // Returns whether the first factor is positive and where the term begins in the source
+    fn parse_term(&mut self, level: usize, lexer: &mut symbol::io::PL0Lexer) -> (bool, usize) {
+        let start = self.code_pointer;
+        let (is_positive, factor) = self.parse_factor(level, lexer);
+        let source_start = factor.start;
         loop {
             let mut is_time = false;
             let mut is_slash = false;
@@ -551,147 +814,270 @@ impl CodeGenerator {
                 },
             }
 
-            self.parse_factor(level, lexer);
+            let middle = self.code_pointer;
+            let operator = lexer.current_index();
+            let (factor_positive, factor) = self.parse_factor(level, lexer);
+            if !factor_positive {
+                // Signed factor such as a * -b
+                self.negate(middle, operator.clone(), factor.clone());
+            }
 
+            let span = source_start..factor.end;
             if is_time {
-                self.code_pointer += 1;
-                self.code.push(self.gen(vm::Fct::Opr, 0, 4));
+                self.operation(start, middle, 4, operator, span);
             } else if is_slash {
-                self.code_pointer += 1;
-                self.code.push(self.gen(vm::Fct::Opr, 0, 5));
+                self.operation(start, middle, 5, operator, span);
             }
         }
 
-        is_positive
+        (is_positive, source_start)
     }
 
-    fn parse_factor(&mut self, level: usize, lexer: &mut symbol::io::PL0Lexer) -> bool {
+    // Returns whether the factor is positive and its span in the source, its sign included
+    fn parse_factor(&mut self, level: usize, lexer: &mut symbol::io::PL0Lexer) -> (bool, Range<usize>) {
         // Handle factor
         let mut is_positive = true;
         {
             lexer.next();
         }
+        let source_start = lexer.current_index().start;
 
         if lexer.previous() == symbol::Symbol::Minus {
             is_positive = false;
         } else if *lexer.current() == symbol::Symbol::Minus {
             is_positive = false;
             lexer.next();
+        } else if *lexer.current() == symbol::Symbol::Plus {
+            lexer.next();
         }
         match &lexer.current() {
             symbol::Symbol::Ident => {
-                let mut should_continue = true;
                 // Get the name
-                let index = self.find_variable(lexer.current_content(), self.table_pointer);
+                let index = self.lookup(lexer);
 
-                if index == 0 {
-                    should_continue = false;
-                }
-
-                if should_continue {
+                if index != 0 {
                     match self.name_table[index - 1].kind {
                         nametab::NameTableObject::Constant => {
-                            self.code_pointer += 1;
-                            self.code.push(self.gen(vm::Fct::Lit, 0,
-                                self.name_table[index - 1].val as usize));
+                            self.emit(self.gen(vm::Fct::Lit, 0,
+                                self.name_table[index - 1].val as usize), lexer.current_index());
                         },
                         nametab::NameTableObject::Variable => {
-                            self.code_pointer += 1;
-                            self.code.push(self.gen(vm::Fct::Lod,
+                            self.emit(self.gen(vm::Fct::Lod,
                                 level - self.name_table[index - 1].level,
-                                self.name_table[index - 1].adr));
+                                self.name_table[index - 1].adr), lexer.current_index());
                         },
                         _ => {
                             // Error, should not be a procedur
+                            self.error(lexer, "Procedure cannot be used in expression");
                         },
                     }
                 }
             },
             symbol::Symbol::Number => {
                 // Number
-                self.code_pointer += 1;
-                // parse i64 as usize
-                self.code.push(self.gen(vm::Fct::Lit, 0,
-                    lexer.current_content().parse::<usize>().unwrap()));
+                match self.number(lexer) {
+                    Some(number) => {
+                        // parse i64 as usize
+                        self.emit(self.gen(vm::Fct::Lit, 0, number as usize), lexer.current_index());
+                    },
+                    None => {
+                        self.error(lexer, "Number is too large");
+                    },
+                }
             },
             symbol::Symbol::Lparen => {
                 // Left parent
                 self.parse_expression(level, lexer);
 
-                {
-                    lexer.next();
-                }
-
                 if *lexer.current() != symbol::Symbol::Rparen {
-                    panic!("Expression factor should be Rparen )");
+                    self.error(lexer, "Expression factor should be Rparen )");
+                    lexer.keep_once();
                 }
             },
             _ => {
-                // Nothing to do
-                // jump out
+                self.error(lexer, "Expression expected");
+                lexer.keep_once();
             },
         }
-        is_positive
+        // The factor ends with the current symbol, the next one is read by the caller
+        let end = match lexer.current() {
+            symbol::Symbol::Ident | symbol::Symbol::Number | symbol::Symbol::Rparen => lexer.current_index().end,
+            _ => lexer.previous_end(),
+        };
+        (is_positive, source_start..end.max(source_start))
     }
 
-    fn parse_condition(&mut self, level: usize, lexer: &mut symbol::io::PL0Lexer) {
-        let should_continue = true;
-
+    // Returns the span of the condition
+    fn parse_condition(&mut self, level: usize, lexer: &mut symbol::io::PL0Lexer) -> Range<usize> {
+        let start = self.code_pointer;
         {
             lexer.next();
         }
+        let span_start = lexer.current_index().start;
 
         if *lexer.current() == symbol::Symbol::Oddsym {
             self.parse_expression(level, lexer);
-            self.code_pointer += 1;
-            self.code.push(self.gen(vm::Fct::Opr, 0, 6));
+            let span = span_start..lexer.previous_end();
+            match self.literal(start..self.code_pointer) {
+                Some(value) => self.replace_with_literal(start, value % 2, span),
+                None => self.emit(self.gen(vm::Fct::Opr, 0, 6), span),
+            }
         } else {
             {
                 // Keep the parser
                 lexer.keep_once();
             }
             self.parse_expression(level, lexer);
+            let middle = self.code_pointer;
+            let operator = lexer.current_index();
             match *lexer.current() {
                 symbol::Symbol::Eql => {
                     self.parse_expression(level, lexer);
-                    self.code_pointer += 1;
-                    self.code.push(self.gen(vm::Fct::Opr, 0, 8));
+                    self.operation(start, middle, 8, operator, span_start..lexer.previous_end());
                 },
                 symbol::Symbol::Neq => {
                     self.parse_expression(level, lexer);
-                    self.code_pointer += 1;
-                    self.code.push(self.gen(vm::Fct::Opr, 0, 9));
+                    self.operation(start, middle, 9, operator, span_start..lexer.previous_end());
                 },
                 symbol::Symbol::Lss => {
                     self.parse_expression(level, lexer);
-                    self.code_pointer += 1;
-                    self.code.push(self.gen(vm::Fct::Opr, 0, 10));
+                    self.operation(start, middle, 10, operator, span_start..lexer.previous_end());
                 },
                 symbol::Symbol::Geq => {
                     self.parse_expression(level, lexer);
-                    self.code_pointer += 1;
-                    self.code.push(self.gen(vm::Fct::Opr, 0, 11));
+                    self.operation(start, middle, 11, operator, span_start..lexer.previous_end());
                 },
                 symbol::Symbol::Gtr => {
                     self.parse_expression(level, lexer);
-                    self.code_pointer += 1;
-                    self.code.push(self.gen(vm::Fct::Opr, 0, 12));
+                    self.operation(start, middle, 12, operator, span_start..lexer.previous_end());
                 },
                 symbol::Symbol::Leq => {
                     self.parse_expression(level, lexer);
-                    self.code_pointer += 1;
-                    self.code.push(self.gen(vm::Fct::Opr, 0, 13));
+                    self.operation(start, middle, 13, operator, span_start..lexer.previous_end());
                 },
                 _ => {
-                    // I will not handle it
+                    self.error(lexer, "Relational operator expected");
                 },
             }
         }
+        span_start..lexer.previous_end().max(span_start)
+    }
+
+    // Value of the current number, none if it does not fit in a word
+    fn number(&self, lexer: &symbol::io::PL0Lexer) -> Option<i64> {
+        lexer.current_content().parse::<i64>().ok().filter(|number| self.arithmetic.contains(*number))
+    }
+
+    // Value of the code emitted in range, if it is a single literal
+    fn literal(&self, range: Range<usize>) -> Option<i64> {
+        if !self.constant_folding || range.len() != 1 {
+            return None;
+        }
+        match self.code.get(range.start) {
+            Some(instruction) if instruction.f == vm::Fct::Lit => Some(instruction.a as i64),
+            _ => None,
+        }
+    }
+
+    // Replace the code emitted from start by a single literal of the source at span
+    fn replace_with_literal(&mut self, start: usize, value: i64, span: Range<usize>) {
+        self.truncate(start);
+        self.emit(self.gen(vm::Fct::Lit, 0, value as usize), span);
+    }
+
+    // Negate the operand emitted from start, span is where errors are
+    // reported and source the negation in the source
+    fn negate(&mut self, start: usize, span: Range<usize>, source: Range<usize>) {
+        match self.literal(start..self.code_pointer) {
+            Some(value) => match self.arithmetic.negate(value) {
+                Some(value) => self.replace_with_literal(start, value, source),
+                None => {
+                    self.error_at(span, "Constant expression overflows");
+                    self.replace_with_literal(start, 0, source);
+                },
+            },
+            None => self.emit(self.gen(vm::Fct::Opr, 0, 1), source),
+        }
+    }
+
+    // Emit the operation on the operands emitted from start and from middle,
+    // two literals are evaluated at compile time instead. Errors are reported
+    // at span, source is the operation in the source
+    fn operation(&mut self, start: usize, middle: usize, opr: usize, span: Range<usize>, source: Range<usize>) {
+        let (left, right) = match (self.literal(start..middle), self.literal(middle..self.code_pointer)) {
+            (Some(left), Some(right)) => (left, right),
+            _ => {
+                self.emit(self.gen(vm::Fct::Opr, 0, opr), source);
+                return;
+            },
+        };
+
+        let value = match opr {
+            5 if right == 0 => {
+                self.error_at(span.clone(), "Division by zero in constant expression");
+                Some(0)
+            },
+            2..=5 => self.arithmetic.apply(opr, left, right),
+            8 => Some((left == right) as i64),
+            9 => Some((left != right) as i64),
+            10 => Some((left < right) as i64),
+            11 => Some((left >= right) as i64),
+            12 => Some((left > right) as i64),
+            13 => Some((left <= right) as i64),
+            _ => None,
+        };
+        match value {
+            Some(value) => self.replace_with_literal(start, value, source),
+            None => {
+                self.error_at(span, "Constant expression overflows");
+                self.replace_with_literal(start, 0, source);
+            },
+        }
+    }
+
+    // Emit the jump taken when the condition emitted from start is false,
+    // a constant condition is not tested at run time
+    fn condition_jump(&mut self, start: usize, condition: Range<usize>) -> Option<usize> {
+        let mut f = vm::Fct::Jpc;
+        if let Some(value) = self.literal(start..self.code_pointer) {
+            self.truncate(start);
+            if value != 0 {
+                // Always true, nothing to skip
+                return None;
+            }
+            f = vm::Fct::Jmp;
+        }
+        self.emit(self.gen(f, 0, 0), condition);
+        Some(self.code_pointer - 1)
     }
 
     pub fn get_vm_code(&self) -> &Vec<vm::Instruction> {
         &self.code
     }
+
+    pub fn get_name_table(&self) -> &Vec<nametab::NameTableItem> {
+        &self.name_table
+    }
+
+    pub fn get_line_table(&self) -> &Vec<(usize, usize)> {
+        &self.line_table
+    }
+
+    pub fn get_spans(&self) -> &Vec<Range<usize>> {
+        &self.spans
+    }
+
+    pub fn get_errors(&self) -> &Vec<error::CompileError> {
+        &self.errors
+    }
+
+    pub fn get_references(&self) -> &Vec<(usize, Range<usize>)> {
+        &self.references
+    }
+
+    pub fn get_procedure_extents(&self) -> &Vec<(usize, Range<usize>)> {
+        &self.procedure_extents
+    }
 }
 
 #[cfg(test)]
@@ -847,6 +1233,7 @@ mod tests {
         let mut lex: symbol::io::PL0Lexer =
             symbol::io::PL0Lexer::create_from_content("8 * 9");
         let mut generator = codegen::CodeGenerator::new();
+        generator.set_constant_folding(false);
 
         generator.parse_term(0, &mut lex);
 
@@ -868,6 +1255,7 @@ mod tests {
         let mut lex: symbol::io::PL0Lexer =
             symbol::io::PL0Lexer::create_from_content("8 * 9 * 10");
         let mut generator = codegen::CodeGenerator::new();
+        generator.set_constant_folding(false);
 
         generator.parse_term(0, &mut lex);
 
@@ -895,6 +1283,7 @@ mod tests {
         let mut lex: symbol::io::PL0Lexer =
             symbol::io::PL0Lexer::create_from_content("18 / 9");
         let mut generator = codegen::CodeGenerator::new();
+        generator.set_constant_folding(false);
 
         generator.parse_term(0, &mut lex);
 
@@ -916,6 +1305,7 @@ mod tests {
         let mut lex: symbol::io::PL0Lexer =
             symbol::io::PL0Lexer::create_from_content("18 / 9 / 2");
         let mut generator = codegen::CodeGenerator::new();
+        generator.set_constant_folding(false);
 
         generator.parse_term(0, &mut lex);
 
@@ -943,6 +1333,7 @@ mod tests {
         let mut lex: symbol::io::PL0Lexer =
             symbol::io::PL0Lexer::create_from_content("18 / 9 + 2");
         let mut generator = codegen::CodeGenerator::new();
+        generator.set_constant_folding(false);
 
         generator.parse_expression(0, &mut lex);
 
@@ -987,6 +1378,7 @@ mod tests {
         let mut lex: symbol::io::PL0Lexer =
             symbol::io::PL0Lexer::create_from_content("18 / 9 - 2");
         let mut generator = codegen::CodeGenerator::new();
+        generator.set_constant_folding(false);
 
         generator.parse_expression(0, &mut lex);
 
@@ -1015,6 +1407,7 @@ mod tests {
         let mut lex: symbol::io::PL0Lexer =
             symbol::io::PL0Lexer::create_from_content("- 18 / 9 - 2");
         let mut generator = codegen::CodeGenerator::new();
+        generator.set_constant_folding(false);
 
         generator.parse_expression(0, &mut lex);
 
@@ -1046,6 +1439,7 @@ mod tests {
         let mut lex: symbol::io::PL0Lexer =
             symbol::io::PL0Lexer::create_from_content("odd 1 + 2");
         let mut generator = codegen::CodeGenerator::new();
+        generator.set_constant_folding(false);
 
         generator.parse_condition(0, &mut lex);
 
@@ -1070,6 +1464,7 @@ mod tests {
         let mut lex: symbol::io::PL0Lexer =
             symbol::io::PL0Lexer::create_from_content("3 = 1 + 2");
         let mut generator = codegen::CodeGenerator::new();
+        generator.set_constant_folding(false);
 
         generator.parse_condition(0, &mut lex);
 
@@ -1097,6 +1492,7 @@ mod tests {
         let mut lex: symbol::io::PL0Lexer =
             symbol::io::PL0Lexer::create_from_content("3 != 1 + 2");
         let mut generator = codegen::CodeGenerator::new();
+        generator.set_constant_folding(false);
 
         generator.parse_condition(0, &mut lex);
 
@@ -1124,6 +1520,7 @@ mod tests {
         let mut lex: symbol::io::PL0Lexer =
             symbol::io::PL0Lexer::create_from_content("0 < 1 + 2");
         let mut generator = codegen::CodeGenerator::new();
+        generator.set_constant_folding(false);
 
         generator.parse_condition(0, &mut lex);
 
@@ -1151,6 +1548,7 @@ mod tests {
         let mut lex: symbol::io::PL0Lexer =
             symbol::io::PL0Lexer::create_from_content("6 >= 1 + 2");
         let mut generator = codegen::CodeGenerator::new();
+        generator.set_constant_folding(false);
 
         generator.parse_condition(0, &mut lex);
 
@@ -1178,6 +1576,7 @@ mod tests {
         let mut lex: symbol::io::PL0Lexer =
             symbol::io::PL0Lexer::create_from_content("4 > 1 + 2");
         let mut generator = codegen::CodeGenerator::new();
+        generator.set_constant_folding(false);
 
         generator.parse_condition(0, &mut lex);
 
@@ -1205,6 +1604,7 @@ mod tests {
         let mut lex: symbol::io::PL0Lexer =
             symbol::io::PL0Lexer::create_from_content("5 <= 1 + 2");
         let mut generator = codegen::CodeGenerator::new();
+        generator.set_constant_folding(false);
 
         generator.parse_condition(0, &mut lex);
 
@@ -1275,4 +1675,160 @@ mod tests {
         assert_eq!(generator.code[4].l, 0);
         assert_eq!(generator.code[4].a, 15);
     }
+
+    /* test constant subexpressions are folded */
+    #[test]
+    fn test_folded_expression() {
+        let mut lex: symbol::io::PL0Lexer =
+            symbol::io::PL0Lexer::create_from_content("2 * 3 + a * -(4 - 1)");
+        let mut generator = codegen::CodeGenerator::new();
+        generator.add_into_name_table("a", 5, nametab::NameTableObject::Constant, 0, 0);
+
+        generator.parse_expression(0, &mut lex);
+
+        assert_eq!(generator.code_pointer, 1);
+        assert_eq!(generator.code[0].f, vm::Fct::Lit);
+        assert_eq!(generator.code[0].a as i64, -9);
+    }
+
+    /* test folding stops at a variable */
+    #[test]
+    fn test_partially_folded_expression() {
+        let mut lex: symbol::io::PL0Lexer =
+            symbol::io::PL0Lexer::create_from_content("x + 2 * 3");
+        let mut generator = codegen::CodeGenerator::new();
+        generator.add_into_name_table("x", 0, nametab::NameTableObject::Variable, 0, 0);
+
+        generator.parse_expression(0, &mut lex);
+
+        assert_eq!(generator.code_pointer, 3);
+        assert_eq!(generator.code[0].f, vm::Fct::Lod);
+        assert_eq!(generator.code[1].f, vm::Fct::Lit);
+        assert_eq!(generator.code[1].a, 6);
+        assert_eq!(generator.code[2].f, vm::Fct::Opr);
+        assert_eq!(generator.code[2].a, 2);
+    }
+
+    /* test constant conditions need no test at run time */
+    #[test]
+    fn test_folded_conditions() {
+        let mut lex: symbol::io::PL0Lexer =
+            symbol::io::PL0Lexer::create_from_content("begin if 1 = 1 then write(1); while odd 2 do write(2) end");
+        let mut generator = codegen::CodeGenerator::new();
+
+        lex.next();
+        generator.parse_statement(0, &mut lex);
+
+        assert!(generator.errors.is_empty());
+        assert_eq!(generator.code_pointer, 8);
+        assert_eq!(generator.code[0].f, vm::Fct::Lit);
+        assert_eq!(generator.code[3].f, vm::Fct::Jmp);
+        assert_eq!(generator.code[3].a, 8);
+        assert_eq!(generator.code[7].f, vm::Fct::Jmp);
+        assert_eq!(generator.code[7].a, 3);
+    }
+
+    /* test the optimized program keeps its addresses consistent */
+    #[test]
+    fn test_optimize() {
+        let mut lex: symbol::io::PL0Lexer = symbol::io::PL0Lexer::create_from_content(
+            "var x; procedure p; x := x + 0; begin x := 1; x := x; call p; write(x) end.");
+        let mut generator = codegen::CodeGenerator::new();
+        generator.build_block(0, &mut lex);
+        generator.optimize();
+
+        let code: Vec<String> = generator.code.iter().map(|instruction| instruction.to_string()).collect();
+        assert_eq!(code, vec![
+            "Jmp  0 2", "Opr  0 0",
+            "Inte 0 2", "Lit  0 1", "Sto  0 0", "Cal  0 1", "Lod  0 0", "Opr  0 14", "Opr  0 15", "Opr  0 0",
+        ]);
+        assert_eq!(generator.name_table[0].adr, 2);
+        assert_eq!(generator.name_table[2].adr, 1);
+        assert_eq!(generator.line_table, vec![(1, 1), (3, 1), (5, 1), (6, 1)]);
+    }
+
+    /* test uncalled procedures and jumped over statements are removed */
+    #[test]
+    fn test_eliminate_dead_code() {
+        let mut lex: symbol::io::PL0Lexer = symbol::io::PL0Lexer::create_from_content(
+            "var x;\nprocedure p; write(1);\nprocedure q; call p;\nbegin\nx := 1;\nif 1 = 0 then write(x);\nwrite(2)\nend.");
+        let mut generator = codegen::CodeGenerator::new();
+        generator.build_block(0, &mut lex);
+        let removed = generator.eliminate_dead_code();
+
+        assert_eq!(removed.procedures, vec!["p", "q"]);
+        let code: Vec<String> = generator.code.iter().map(|instruction| instruction.to_string()).collect();
+        assert_eq!(code, vec![
+            "Jmp  0 1",
+            "Inte 0 3", "Lit  0 1", "Sto  0 0", "Jmp  0 5", "Lit  0 2", "Opr  0 14", "Opr  0 15", "Opr  0 0",
+        ]);
+        assert_eq!(removed.instructions, 13);
+        assert_eq!(generator.name_table[0].adr, 1);
+        assert_eq!(generator.name_table[2].adr, nametab::REMOVED);
+        assert_eq!(generator.name_table[3].adr, nametab::REMOVED);
+        assert_eq!(generator.line_table, vec![(2, 5), (4, 6), (5, 7)]);
+    }
+
+    /* test instructions get the span of the construct that produced them */
+    #[test]
+    fn test_spans() {
+        let content = "var x; begin while x < 2 do x := -x * (x + 1); write(x) end.";
+        let mut lex: symbol::io::PL0Lexer = symbol::io::PL0Lexer::create_from_content(content);
+        let mut generator = codegen::CodeGenerator::new();
+        generator.build_block(0, &mut lex);
+
+        let body = "begin while x < 2 do x := -x * (x + 1); write(x) end";
+        let spans: Vec<&str> = generator.spans.iter().map(|span| &content[span.clone()]).collect();
+        assert_eq!(spans.len(), generator.code.len());
+        assert_eq!(spans, vec![
+            body, body,
+            "x", "2", "x < 2", "x < 2",
+            "x", "x", "1", "x + 1", "-x * (x + 1)", "-x * (x + 1)", "x := -x * (x + 1)",
+            "while x < 2 do x := -x * (x + 1)",
+            "x", "x", "write(x)",
+            body,
+        ]);
+
+        generator.optimize();
+        assert_eq!(generator.spans.len(), generator.code.len());
+        assert_eq!(&content[generator.spans[generator.code.len() - 2].clone()], "write(x)");
+    }
+
+    /* test overflow and division by zero are compile errors */
+    #[test]
+    fn test_folding_errors() {
+        let mut lex: symbol::io::PL0Lexer =
+            symbol::io::PL0Lexer::create_from_content("write(1 / (2 - 2), 9223372036854775807 + 1)");
+        let mut generator = codegen::CodeGenerator::new();
+
+        lex.next();
+        generator.parse_statement(0, &mut lex);
+
+        assert_eq!(generator.errors.len(), 2);
+        assert_eq!(generator.errors[0].message, "Division by zero in constant expression");
+        assert_eq!(generator.errors[0].span, 8..9);
+        assert_eq!(generator.errors[1].message, "Constant expression overflows");
+        assert_eq!(generator.errors[1].span, 39..40);
+    }
+
+    /* test folding follows the word size and the overflow behavior */
+    #[test]
+    fn test_folding_arithmetic() {
+        let fold = |content: &str, overflow: vm::Overflow, bits: u32| {
+            let mut lex: symbol::io::PL0Lexer = symbol::io::PL0Lexer::create_from_content(content);
+            let mut generator = codegen::CodeGenerator::new();
+            generator.set_arithmetic(vm::Arithmetic::new(overflow, bits).unwrap());
+            lex.next();
+            generator.parse_statement(0, &mut lex);
+            let messages: Vec<String> = generator.errors.iter().map(|error| error.message.clone()).collect();
+            (generator.code[0].a as i64, messages)
+        };
+
+        assert_eq!(fold("write(32767 + 1)", vm::Overflow::Checked, 16), (0, vec![String::from("Constant expression overflows")]));
+        assert_eq!(fold("write(32767 + 1)", vm::Overflow::Checked, 32), (32768, vec![]));
+        assert_eq!(fold("write(32767 + 1)", vm::Overflow::Wrapping, 16), (-32768, vec![]));
+        assert_eq!(fold("write((-300) * 300)", vm::Overflow::Saturating, 16), (-32768, vec![]));
+        assert_eq!(fold("write(-(-2147483647 - 1))", vm::Overflow::Wrapping, 32), (-2147483648, vec![]));
+        assert_eq!(fold("write(32768)", vm::Overflow::Wrapping, 16).1, vec![String::from("Number is too large")]);
+    }
 }
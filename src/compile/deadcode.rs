@@ -0,0 +1,105 @@
+use crate::vm;
+use crate::compile::peephole;
+
+
+/* what the dead code elimination took out of a program */
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Removed {
+    pub procedures: Vec<String>,    // never called from the main procedure, in declaration order
+    pub instructions: usize,        // all removed instructions, procedures included
+}
+
+// Remove every instruction the program can never execute, returns the new
+// code, the new position of every old one (one more for the end of the code)
+// and which old instructions were reachable
+pub fn eliminate(code: &[vm::Instruction]) -> (Vec<vm::Instruction>, Vec<usize>, Vec<bool>) {
+    let reached = reachable(code);
+    let (kept, relocation) = peephole::remove(code, &reached);
+    (kept, relocation, reached)
+}
+
+/*
+ * Execution starts with the jump at 0 into the main procedure, a procedure
+ * body is reached only through a Cal to its address. Code after an
+ * unconditional jump or a return is reached only if something jumps to it,
+ * so a procedure called from nowhere, or only from dead code, is dead too.
+ */
+pub fn reachable(code: &[vm::Instruction]) -> Vec<bool> {
+    let mut reached = vec![false; code.len()];
+    let mut pending = vec![0];
+    while let Some(pc) = pending.pop() {
+        if pc >= code.len() || reached[pc] {
+            continue;
+        }
+        reached[pc] = true;
+
+        let instruction = code[pc];
+        match instruction.f {
+            vm::Fct::Jmp => pending.push(instruction.a),
+            vm::Fct::Jpc | vm::Fct::Cal => {
+                pending.push(instruction.a);
+                pending.push(pc + 1);
+            },
+            vm::Fct::Opr if instruction.a == 0 => {},
+            vm::Fct::Hlt => {},
+            _ => pending.push(pc + 1),
+        }
+    }
+    reached
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::vm;
+    use crate::compile::deadcode;
+
+    fn instruction(f: vm::Fct, l: usize, a: usize) -> vm::Instruction {
+        vm::Instruction { f, l, a }
+    }
+
+    /* test procedures never called and code after jumps are removed */
+    #[test]
+    fn test_eliminate() {
+        let code = vec![
+            instruction(vm::Fct::Jmp, 0, 7),
+            instruction(vm::Fct::Jmp, 0, 2),
+            instruction(vm::Fct::Inte, 0, 3),   // called
+            instruction(vm::Fct::Opr, 0, 0),
+            instruction(vm::Fct::Jmp, 0, 5),
+            instruction(vm::Fct::Inte, 0, 3),   // never called
+            instruction(vm::Fct::Opr, 0, 0),
+            instruction(vm::Fct::Inte, 0, 3),
+            instruction(vm::Fct::Cal, 0, 2),
+            instruction(vm::Fct::Jmp, 0, 12),
+            instruction(vm::Fct::Lit, 0, 1),    // jumped over
+            instruction(vm::Fct::Opr, 0, 14),
+            instruction(vm::Fct::Opr, 0, 0),
+        ];
+        let (code, relocation, reached) = deadcode::eliminate(&code);
+
+        let listing: Vec<String> = code.iter().map(|instruction| instruction.to_string()).collect();
+        assert_eq!(listing, vec![
+            "Jmp  0 3", "Inte 0 3", "Opr  0 0", "Inte 0 3", "Cal  0 1", "Jmp  0 6", "Opr  0 0",
+        ]);
+        assert_eq!(relocation, vec![0, 1, 1, 2, 3, 3, 3, 3, 4, 5, 6, 6, 6, 7]);
+        assert_eq!(reached.iter().filter(|reached| !**reached).count(), 6);
+    }
+
+    /* test a procedure only called by a dead one is dead too */
+    #[test]
+    fn test_dead_caller() {
+        let code = vec![
+            instruction(vm::Fct::Jmp, 0, 5),
+            instruction(vm::Fct::Inte, 0, 3),   // p, only called by q
+            instruction(vm::Fct::Opr, 0, 0),
+            instruction(vm::Fct::Inte, 0, 3),   // q, never called
+            instruction(vm::Fct::Cal, 0, 1),
+            instruction(vm::Fct::Inte, 0, 3),
+            instruction(vm::Fct::Jpc, 0, 7),
+            instruction(vm::Fct::Opr, 0, 0),
+        ];
+        let reached = deadcode::reachable(&code);
+
+        assert_eq!(reached, vec![true, false, false, false, false, true, true, true]);
+    }
+}
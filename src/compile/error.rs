@@ -0,0 +1,15 @@
+use std::fmt;
+use std::ops::Range;
+
+/* error found while compiling, located in source */
+#[derive(Clone, Debug, PartialEq)]
+pub struct CompileError {
+    pub span: Range<usize>,
+    pub message: String,
+}
+
+impl fmt::Display for CompileError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
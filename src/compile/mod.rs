@@ -1,2 +1,7 @@
 pub mod codegen;
 pub mod nametab;
+pub mod error;
+pub mod peephole;
+pub mod deadcode;
+pub mod cfg;
+pub mod callgraph;
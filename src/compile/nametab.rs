@@ -1,12 +1,17 @@
+use std::ops::Range;
 
 /* types in name table */
-#[derive(PartialEq)]
+#[derive(PartialEq, Copy, Clone, Debug)]
 pub enum NameTableObject {
     Constant,
     Variable,
     Procedur,
 }
 
+// Address of a procedure removed as dead code, no instruction is at it
+pub const REMOVED: usize = usize::MAX;
+
+#[derive(Clone, Debug)]
 pub struct NameTableItem {
     pub name: String,
     pub kind: NameTableObject,
@@ -14,4 +19,23 @@ pub struct NameTableItem {
     pub level: usize,
     pub adr: usize,
     pub size: usize,
+    pub span: Range<usize>,     // where the name is declared
+}
+
+// Items declared by the procedure at index procedure, its nested procedures' own declarations left out
+pub fn declarations(name_table: &[NameTableItem], procedure: usize) -> Vec<&NameTableItem> {
+    // The main procedure shares level 0 with its declarations
+    let level = if procedure == 0 { 0 } else { name_table[procedure].level + 1 };
+
+    let mut items = Vec::new();
+    for item in name_table.iter().skip(procedure + 1) {
+        if item.level < level {
+            // Declarations of the procedure are over
+            break;
+        }
+        if item.level == level {
+            items.push(item);
+        }
+    }
+    items
 }
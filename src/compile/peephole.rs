@@ -0,0 +1,192 @@
+use std::collections::HashSet;
+
+use crate::vm;
+
+
+// Optimize the generated code until nothing changes, returns the new code and
+// the new position of every old one (one more for the end of the code)
+pub fn optimize(code: &[vm::Instruction]) -> (Vec<vm::Instruction>, Vec<usize>) {
+    let mut code = code.to_vec();
+    let mut relocation: Vec<usize> = (0..=code.len()).collect();
+
+    loop {
+        let threaded = thread_jumps(&mut code);
+        let keep = removable(&code);
+        if !threaded && keep.iter().all(|kept| *kept) {
+            break;
+        }
+
+        let (kept, moved) = remove(&code, &keep);
+        code = kept;
+        for position in relocation.iter_mut() {
+            *position = moved[*position];
+        }
+    }
+    (code, relocation)
+}
+
+// Drop the instructions that are not kept and fix the jump and call targets,
+// returns the new code and where each old position went (a removed
+// instruction goes to the next kept one, one more for the end of the code)
+pub fn remove(code: &[vm::Instruction], keep: &[bool]) -> (Vec<vm::Instruction>, Vec<usize>) {
+    let mut moved = Vec::with_capacity(code.len() + 1);
+    let mut position = 0;
+    for kept in keep {
+        moved.push(position);
+        if *kept {
+            position += 1;
+        }
+    }
+    moved.push(position);
+
+    let code = code.iter().zip(keep.iter())
+        .filter(|(_, kept)| **kept)
+        .map(|(instruction, _)| match instruction.f {
+            vm::Fct::Jmp | vm::Fct::Jpc | vm::Fct::Cal => vm::Instruction {
+                f: instruction.f,
+                l: instruction.l,
+                a: moved[instruction.a],
+            },
+            _ => *instruction,
+        })
+        .collect();
+    (code, moved)
+}
+
+// Jump directly to the end of a chain of jumps, returns whether a target changed
+fn thread_jumps(code: &mut [vm::Instruction]) -> bool {
+    let mut changed = false;
+    for pc in 0..code.len() {
+        if code[pc].f != vm::Fct::Jmp && code[pc].f != vm::Fct::Jpc {
+            continue;
+        }
+        let mut target = code[pc].a;
+        let mut steps = 0;
+        while steps < code.len() {
+            match code.get(target) {
+                Some(next) if next.f == vm::Fct::Jmp && next.a != target => {
+                    target = next.a;
+                    steps += 1;
+                },
+                _ => break,
+            }
+        }
+        // Leave a loop made of jumps only as it is
+        if steps < code.len() && target != code[pc].a {
+            code[pc].a = target;
+            changed = true;
+        }
+    }
+    changed
+}
+
+// Instructions to keep, the entry jump at 0 always stays since
+// the vm stops when it comes back to 0
+fn removable(code: &[vm::Instruction]) -> Vec<bool> {
+    let targets: HashSet<usize> = code.iter()
+        .filter(|instruction| matches!(instruction.f, vm::Fct::Jmp | vm::Fct::Jpc | vm::Fct::Cal))
+        .map(|instruction| instruction.a)
+        .collect();
+
+    let mut keep = vec![true; code.len()];
+    let mut pc = 1;
+    while pc < code.len() {
+        let instruction = code[pc];
+        // A pair can go only if nothing jumps in between
+        let next = code.get(pc + 1).filter(|_| !targets.contains(&(pc + 1)));
+
+        match (instruction.f, next) {
+            (vm::Fct::Jmp, _) if instruction.a == pc + 1 => {
+                keep[pc] = false;
+            },
+            (vm::Fct::Inte, _) if instruction.a == 0 => {
+                keep[pc] = false;
+            },
+            (vm::Fct::Lit, Some(next)) if instruction.a == 0 && next.f == vm::Fct::Opr && next.a == 2 => {
+                // Adding 0
+                keep[pc] = false;
+                keep[pc + 1] = false;
+                pc += 1;
+            },
+            (vm::Fct::Lod, Some(next)) if next.f == vm::Fct::Sto && next.l == instruction.l && next.a == instruction.a => {
+                // Storing a variable into itself
+                keep[pc] = false;
+                keep[pc + 1] = false;
+                pc += 1;
+            },
+            _ => {},
+        }
+        pc += 1;
+    }
+    keep
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::vm;
+    use crate::compile::peephole;
+
+    fn instruction(f: vm::Fct, l: usize, a: usize) -> vm::Instruction {
+        vm::Instruction { f, l, a }
+    }
+
+    fn listing(code: &[vm::Instruction]) -> Vec<String> {
+        code.iter().map(|instruction| instruction.to_string()).collect()
+    }
+
+    /* test removed instructions and relocated targets */
+    #[test]
+    fn test_patterns() {
+        let code = vec![
+            instruction(vm::Fct::Jmp, 0, 1),
+            instruction(vm::Fct::Inte, 0, 4),
+            instruction(vm::Fct::Lod, 0, 3),
+            instruction(vm::Fct::Lit, 0, 0),
+            instruction(vm::Fct::Opr, 0, 2),
+            instruction(vm::Fct::Sto, 0, 3),
+            instruction(vm::Fct::Lod, 1, 3),
+            instruction(vm::Fct::Sto, 1, 3),
+            instruction(vm::Fct::Jmp, 0, 9),
+            instruction(vm::Fct::Inte, 0, 0),
+            instruction(vm::Fct::Opr, 0, 0),
+        ];
+        let (optimized, relocation) = peephole::optimize(&code);
+
+        assert_eq!(listing(&optimized), vec!["Jmp  0 1", "Inte 0 4", "Opr  0 0"]);
+        assert_eq!(relocation, vec![0, 1, 2, 2, 2, 2, 2, 2, 2, 2, 2, 3]);
+    }
+
+    /* test jumps to jumps go to the final target */
+    #[test]
+    fn test_thread_jumps() {
+        let code = vec![
+            instruction(vm::Fct::Jmp, 0, 1),
+            instruction(vm::Fct::Lod, 0, 3),
+            instruction(vm::Fct::Jpc, 0, 5),
+            instruction(vm::Fct::Lit, 0, 1),
+            instruction(vm::Fct::Jmp, 0, 1),
+            instruction(vm::Fct::Jmp, 0, 7),
+            instruction(vm::Fct::Opr, 0, 1),
+            instruction(vm::Fct::Opr, 0, 0),
+        ];
+        let (optimized, _) = peephole::optimize(&code);
+
+        assert_eq!(optimized[2].to_string(), "Jpc  0 7");
+        assert_eq!(optimized.len(), 8);
+    }
+
+    /* test a pair is kept when something jumps into it */
+    #[test]
+    fn test_jump_into_pair() {
+        let code = vec![
+            instruction(vm::Fct::Jmp, 0, 1),
+            instruction(vm::Fct::Jpc, 0, 3),
+            instruction(vm::Fct::Lod, 0, 3),
+            instruction(vm::Fct::Sto, 0, 3),
+            instruction(vm::Fct::Opr, 0, 0),
+        ];
+        let (optimized, _) = peephole::optimize(&code);
+
+        assert_eq!(optimized.len(), 5);
+    }
+}
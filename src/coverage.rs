@@ -0,0 +1,267 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::ops::Range;
+
+use crate::vm;
+use crate::bytecode;
+use crate::compile::nametab;
+
+
+/* executions recorded by the vm, a Jpc counts both how often it ran and jumped */
+#[derive(Clone, Debug, PartialEq)]
+pub struct Coverage {
+    pub counts: Vec<u64>,
+    pub jumps: Vec<u64>,
+}
+
+impl Coverage {
+    pub fn new(size: usize) -> Coverage {
+        Coverage { counts: vec![0; size], jumps: vec![0; size] }
+    }
+
+    // Record the instruction about to be executed at pc, top is the top of the stack
+    pub fn record(&mut self, pc: usize, instruction: vm::Instruction, top: Option<i64>) {
+        if pc >= self.counts.len() {
+            self.counts.resize(pc + 1, 0);
+            self.jumps.resize(pc + 1, 0);
+        }
+        self.counts[pc] += 1;
+        if instruction.f == vm::Fct::Jpc && top == Some(0) {
+            self.jumps[pc] += 1;
+        }
+    }
+}
+
+/* both sides of a Jpc, the condition holds when it falls through */
+#[derive(Clone, Debug, PartialEq)]
+pub struct Branch {
+    pub pc: usize,
+    pub line: usize,
+    pub span: Range<usize>,     // of the condition
+    pub executed: u64,
+    pub holds: u64,
+    pub fails: u64,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Report {
+    pub lines: BTreeMap<usize, u64>,            // executions of every line with code
+    pub branches: Vec<Branch>,
+    pub procedures: Vec<(String, usize, u64)>,  // (name, line, calls)
+}
+
+impl Report {
+    pub fn lines_hit(&self) -> usize {
+        self.lines.values().filter(|count| **count > 0).count()
+    }
+
+    // Sides of the branches taken at least once, out of two per branch
+    pub fn branches_taken(&self) -> usize {
+        self.branches.iter().map(|branch| (branch.holds > 0) as usize + (branch.fails > 0) as usize).sum()
+    }
+}
+
+/*
+ * Line and branch coverage of a run. A line counts as executed as often
+ * as its most executed instruction, the entry and the return of a
+ * procedure are on the line its statement begins.
+ */
+pub fn analyze(coverage: &Coverage, code: &[vm::Instruction], name_table: &[nametab::NameTableItem],
+    debug_info: &bytecode::DebugInfo) -> Report {
+    let count = |pc: usize| coverage.counts.get(pc).copied().unwrap_or(0);
+
+    let mut lines = BTreeMap::new();
+    let mut branches = Vec::new();
+    for (pc, instruction) in code.iter().enumerate() {
+        let span = match debug_info.span(pc) {
+            Some(span) => span,
+            None => continue,
+        };
+        let line = debug_info.line_of(span.start);
+        let executions = lines.entry(line).or_insert(0);
+        *executions = count(pc).max(*executions);
+
+        if instruction.f == vm::Fct::Jpc {
+            let fails = coverage.jumps.get(pc).copied().unwrap_or(0);
+            branches.push(Branch { pc, line, span, executed: count(pc), holds: count(pc) - fails, fails });
+        }
+    }
+
+    let procedures = name_table.iter()
+        .filter(|item| item.kind == nametab::NameTableObject::Procedur && item.adr < code.len())
+        .map(|item| {
+            // The main procedure is declared nowhere, it starts with its statement
+            let line = match item.span.is_empty() {
+                false => debug_info.line_of(item.span.start),
+                true => debug_info.line(item.adr).unwrap_or(1),
+            };
+            (item.name.clone(), line, count(item.adr))
+        })
+        .collect();
+    Report { lines, branches, procedures }
+}
+
+/*
+ * Tracefile for lcov and genhtml, a Jpc is a block of two branches, the
+ * first where the condition holds. A branch never reached is "-".
+ */
+pub fn lcov(report: &Report, source_file: &str) -> String {
+    let mut output = format!("TN:\nSF:{}\n", source_file);
+    for (name, line, _) in &report.procedures {
+        writeln!(output, "FN:{},{}", line, name).unwrap();
+    }
+    for (name, _, calls) in &report.procedures {
+        writeln!(output, "FNDA:{},{}", calls, name).unwrap();
+    }
+    writeln!(output, "FNF:{}", report.procedures.len()).unwrap();
+    writeln!(output, "FNH:{}", report.procedures.iter().filter(|(_, _, calls)| *calls > 0).count()).unwrap();
+
+    for (block, branch) in report.branches.iter().enumerate() {
+        for (side, taken) in [branch.holds, branch.fails].iter().enumerate() {
+            match branch.executed {
+                0 => writeln!(output, "BRDA:{},{},{},-", branch.line, block, side).unwrap(),
+                _ => writeln!(output, "BRDA:{},{},{},{}", branch.line, block, side, taken).unwrap(),
+            }
+        }
+    }
+    writeln!(output, "BRF:{}", report.branches.len() * 2).unwrap();
+    writeln!(output, "BRH:{}", report.branches_taken()).unwrap();
+
+    for (line, count) in &report.lines {
+        writeln!(output, "DA:{},{}", line, count).unwrap();
+    }
+    writeln!(output, "LF:{}", report.lines.len()).unwrap();
+    writeln!(output, "LH:{}", report.lines_hit()).unwrap();
+    output.push_str("end_of_record\n");
+    output
+}
+
+/*
+ * Source listing annotated like gcov, every line after how often it was
+ * executed, - for a line without code and ##### for one never executed,
+ * then the sides of its branches.
+ */
+pub fn listing(report: &Report, content: &str) -> String {
+    let mut output = String::new();
+    for (index, text) in content.lines().enumerate() {
+        let line = index + 1;
+        let count = match report.lines.get(&line) {
+            None => String::from("-"),
+            Some(0) => String::from("#####"),
+            Some(count) => count.to_string(),
+        };
+        writeln!(output, "{:>9}:{:>5}:{}", count, line, text).unwrap();
+
+        for branch in report.branches.iter().filter(|branch| branch.line == line) {
+            let condition = &content[branch.span.clone()];
+            match branch.executed {
+                0 => writeln!(output, "branch {} never executed", condition).unwrap(),
+                _ => writeln!(output, "branch {} held {} failed {}", condition, branch.holds, branch.fails).unwrap(),
+            }
+        }
+    }
+
+    let percent = |hit: usize, found: usize| if found == 0 { 100.0 } else { hit as f64 * 100.0 / found as f64 };
+    writeln!(output, "\nlines executed: {} of {} ({:.2}%)", report.lines_hit(), report.lines.len(),
+        percent(report.lines_hit(), report.lines.len())).unwrap();
+    writeln!(output, "branches taken: {} of {} ({:.2}%)", report.branches_taken(), report.branches.len() * 2,
+        percent(report.branches_taken(), report.branches.len() * 2)).unwrap();
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::bytecode;
+    use crate::compile::codegen;
+    use crate::coverage;
+    use crate::symbol;
+
+    fn report(content: &str, input: &str) -> coverage::Report {
+        let mut lexer = symbol::io::PL0Lexer::create_from_content(content);
+        let mut generator = codegen::CodeGenerator::new();
+        assert!(generator.build_block(0, &mut lexer));
+
+        let module = bytecode::Module::compiled(&generator, "test.pl0", content);
+        let mut machine = module.load();
+        machine.redirect(Box::new(std::io::Cursor::new(input.to_string())), Box::new(std::io::sink()));
+        machine.enable_coverage();
+        machine.execute();
+        coverage::analyze(machine.coverage().unwrap(), &module.code, &module.name_table, &module.debug_info)
+    }
+
+    const PROGRAM: &str = "var x, y;
+procedure p;
+  y := 1;
+begin
+  read(x);
+  if x > 0 then
+    call p;
+  while x > 0 do
+    x := x - 1
+end.";
+
+    /* test lines and both sides of the conditions are counted */
+    #[test]
+    fn test_analyze() {
+        let report = report(PROGRAM, "0\n");
+
+        assert_eq!(report.lines.iter().map(|(line, count)| (*line, *count)).collect::<Vec<_>>(),
+            vec![(3, 0), (4, 1), (5, 1), (6, 1), (7, 0), (8, 1), (9, 0)]);
+        assert_eq!(report.branches.len(), 2);
+        assert_eq!((report.branches[0].line, report.branches[0].holds, report.branches[0].fails), (6, 0, 1));
+        assert_eq!(report.procedures, vec![(String::from("_main"), 4, 1), (String::from("p"), 2, 0)]);
+
+        let report = self::report(PROGRAM, "2\n");
+        assert_eq!(report.lines_hit(), 7);
+        assert_eq!((report.branches[1].executed, report.branches[1].holds, report.branches[1].fails), (3, 2, 1));
+        assert_eq!(report.branches_taken(), 3);
+    }
+
+    /* test the lcov records and the annotated listing */
+    #[test]
+    fn test_output() {
+        let report = report(PROGRAM, "0\n");
+
+        assert_eq!(coverage::lcov(&report, "test.pl0"), "TN:
+SF:test.pl0
+FN:4,_main
+FN:2,p
+FNDA:1,_main
+FNDA:0,p
+FNF:2
+FNH:1
+BRDA:6,0,0,0
+BRDA:6,0,1,1
+BRDA:8,1,0,0
+BRDA:8,1,1,1
+BRF:4
+BRH:2
+DA:3,0
+DA:4,1
+DA:5,1
+DA:6,1
+DA:7,0
+DA:8,1
+DA:9,0
+LF:7
+LH:4
+end_of_record
+");
+        assert_eq!(coverage::listing(&report, PROGRAM), "        -:    1:var x, y;
+        -:    2:procedure p;
+    #####:    3:  y := 1;
+        1:    4:begin
+        1:    5:  read(x);
+        1:    6:  if x > 0 then
+branch x > 0 held 0 failed 1
+    #####:    7:    call p;
+        1:    8:  while x > 0 do
+branch x > 0 held 0 failed 1
+    #####:    9:    x := x - 1
+        -:   10:end.
+
+lines executed: 4 of 7 (57.14%)
+branches taken: 2 of 4 (50.00%)
+");
+    }
+}
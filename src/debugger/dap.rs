@@ -0,0 +1,533 @@
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use serde_json::{ json, Value };
+
+use crate::rpc;
+use crate::symbol;
+use crate::vm;
+use crate::bytecode;
+use crate::compile::codegen;
+use crate::debugger;
+
+const THREAD_ID: i64 = 1;
+
+// Debug Adapter Protocol server, requests are handled one after another:
+// the program runs inside continue/step requests until it stops again.
+pub struct DapServer<R: io::BufRead, W: io::Write> {
+    reader: R,
+    writer: W,
+    seq: i64,
+
+    debugger: Option<debugger::Debugger>,
+    output: vm::SharedBuffer,
+    program: String,
+    stop_on_entry: bool,
+    pending_breakpoints: HashMap<String, Vec<usize>>,  // lines by source path, set before the launch
+    line_offset: i64,   // 0 if the client counts lines from 1, 1 otherwise
+    column_offset: i64, // the same for columns
+}
+
+impl<R: io::BufRead, W: io::Write> DapServer<R, W> {
+    pub fn new(reader: R, writer: W) -> DapServer<R, W> {
+        DapServer {
+            reader,
+            writer,
+            seq: 0,
+            debugger: None,
+            output: vm::SharedBuffer::default(),
+            program: String::new(),
+            stop_on_entry: false,
+            pending_breakpoints: HashMap::new(),
+            line_offset: 0,
+            column_offset: 0,
+        }
+    }
+
+    pub fn run(&mut self) -> io::Result<()> {
+        loop {
+            let message = match rpc::read_message(&mut self.reader) {
+                Ok(Some(message)) => message,
+                Ok(None) => break,
+                // Its body has been read, the next message follows
+                Err(error) if error.kind() == io::ErrorKind::InvalidData => {
+                    self.respond(0, "", false, Value::Null, Some(format!("Malformed message: {}", error)))?;
+                    continue;
+                },
+                Err(error) => return Err(error),
+            };
+            if message["type"] != "request" {
+                continue;
+            }
+            let command = message["command"].as_str().unwrap_or("").to_string();
+            let arguments = message["arguments"].clone();
+            let request_seq = message["seq"].as_i64().unwrap_or(0);
+
+            match self.handle(&command, &arguments) {
+                Ok(body) => {
+                    self.respond(request_seq, &command, true, body, None)?;
+                },
+                Err(error) => {
+                    self.respond(request_seq, &command, false, Value::Null, Some(error))?;
+                },
+            }
+
+            // Some requests are followed by events
+            match command.as_str() {
+                "launch" if self.debugger.is_some() => {
+                    self.event("initialized", Value::Null)?;
+                },
+                "configurationDone" => {
+                    let reason = match self.debugger.as_mut() {
+                        Some(debugger) if self.stop_on_entry => debugger.start_on_entry(),
+                        Some(debugger) => debugger.resume(debugger::StepMode::Continue),
+                        None => continue,
+                    };
+                    self.report_stop(reason)?;
+                },
+                "continue" => self.resume(debugger::StepMode::Continue)?,
+                "next" => self.resume(debugger::StepMode::Over)?,
+                "stepIn" => self.resume(debugger::StepMode::In)?,
+                "stepOut" => self.resume(debugger::StepMode::Out)?,
+                "disconnect" | "terminate" => {
+                    break;
+                },
+                _ => {},
+            }
+        }
+        Ok(())
+    }
+
+    fn handle(&mut self, command: &str, arguments: &Value) -> Result<Value, String> {
+        match command {
+            "initialize" => {
+                if arguments["linesStartAt1"] == false {
+                    self.line_offset = 1;
+                }
+                if arguments["columnsStartAt1"] == false {
+                    self.column_offset = 1;
+                }
+                Ok(json!({
+                    "supportsConfigurationDoneRequest": true,
+                    "supportsTerminateRequest": true,
+                }))
+            },
+            "launch" => {
+                self.launch(arguments)?;
+                Ok(Value::Null)
+            },
+            "setBreakpoints" => {
+                let path = arguments["source"]["path"].as_str().unwrap_or("").to_string();
+                // Lines counted from 1, none for a line before the first one
+                let lines: Vec<Option<usize>> = match arguments["breakpoints"].as_array() {
+                    Some(breakpoints) => breakpoints.iter()
+                        .map(|breakpoint| breakpoint["line"].as_i64()
+                            .map(|line| line + self.line_offset)
+                            .filter(|line| *line >= 1)
+                            .map(|line| line as usize))
+                        .collect(),
+                    None => Vec::new(),
+                };
+                let valid: Vec<usize> = lines.iter().flatten().copied().collect();
+
+                let bound = match self.debugger.as_mut() {
+                    // The breakpoints of the program stay as they are
+                    Some(_) if !same_file(&path, &self.program) => {
+                        let breakpoints: Vec<Value> = lines.iter()
+                            .map(|_| json!({ "verified": false, "message": "Not the launched program" }))
+                            .collect();
+                        return Ok(json!({ "breakpoints": breakpoints }));
+                    },
+                    Some(debugger) => debugger.set_breakpoints(&valid),
+                    None => {
+                        let bound = valid.iter().map(|line| Some(*line)).collect();
+                        self.pending_breakpoints.insert(path, valid);
+                        bound
+                    },
+                };
+                let mut bound = bound.into_iter();
+                let breakpoints: Vec<Value> = lines.iter().map(|line| match line.and_then(|_| bound.next()) {
+                    None => json!({ "verified": false, "message": "Invalid line" }),
+                    Some(Some(line)) => json!({ "verified": true, "line": line as i64 - self.line_offset }),
+                    Some(None) => json!({ "verified": false, "message": "No statement at or after this line" }),
+                }).collect();
+                Ok(json!({ "breakpoints": breakpoints }))
+            },
+            "configurationDone" | "disconnect" | "terminate" => {
+                Ok(Value::Null)
+            },
+            "threads" => {
+                Ok(json!({ "threads": [{ "id": THREAD_ID, "name": "main" }] }))
+            },
+            "stackTrace" => {
+                let debugger = self.debugger.as_ref().ok_or("No program launched")?;
+                let frames: Vec<Value> = debugger.frames().iter().enumerate().map(|(id, frame)| {
+                    json!({
+                        "id": id,
+                        "name": frame.name,
+                        "line": frame.line.map(|line| line as i64 - self.line_offset).unwrap_or(0),
+                        "column": frame.column.unwrap_or(1) as i64 - self.column_offset,
+                        "source": self.source(),
+                    })
+                }).collect();
+                Ok(json!({ "stackFrames": frames, "totalFrames": frames.len() }))
+            },
+            "scopes" => {
+                let frame_id = arguments["frameId"].as_u64().unwrap_or(0) as usize;
+                // Each frame owns two variable references: locals and globals
+                Ok(json!({ "scopes": [
+                    { "name": "Locals", "variablesReference": frame_id * 2 + 1, "expensive": false },
+                    { "name": "Globals", "variablesReference": frame_id * 2 + 2, "expensive": false },
+                ] }))
+            },
+            "variables" => {
+                let debugger = self.debugger.as_ref().ok_or("No program launched")?;
+                let reference = arguments["variablesReference"].as_u64().unwrap_or(0) as usize;
+                if reference == 0 {
+                    return Err(String::from("Invalid variables reference"));
+                }
+                let variables = if reference.is_multiple_of(2) {
+                    debugger.globals()
+                } else {
+                    match debugger.frames().get((reference - 1) / 2) {
+                        Some(frame) => debugger.locals(frame),
+                        None => Vec::new(),
+                    }
+                };
+                let variables: Vec<Value> = variables.iter().map(|variable| json!({
+                    "name": variable.name,
+                    "value": variable.value.to_string(),
+                    "type": format!("{:?}", variable.kind),
+                    "variablesReference": 0,
+                })).collect();
+                Ok(json!({ "variables": variables }))
+            },
+            "continue" => {
+                self.debugger.as_ref().ok_or("No program launched")?;
+                Ok(json!({ "allThreadsContinued": true }))
+            },
+            "next" | "stepIn" | "stepOut" => {
+                self.debugger.as_ref().ok_or("No program launched")?;
+                Ok(Value::Null)
+            },
+            _ => Err(format!("Unsupported request {}", command)),
+        }
+    }
+
+    fn launch(&mut self, arguments: &Value) -> Result<(), String> {
+        let program = arguments["program"].as_str().ok_or("Missing program to launch")?;
+        let contents = fs::read(program)
+            .map_err(|err| format!("Cannot read {}: {}", program, err))?;
+
+        // A bytecode file carries its source, which the client is shown
+        let (module, source_path) = match bytecode::is_bytecode(&contents) {
+            true => {
+                let module = bytecode::read(&contents)?;
+                let file = module.debug_info.file.clone();
+                (module, file)
+            },
+            false => {
+                let contents = String::from_utf8_lossy(&contents);
+                let mut lex: symbol::io::PL0Lexer = symbol::io::PL0Lexer::create_from_content(&contents);
+                let mut generator = codegen::CodeGenerator::new();
+                generator.set_arithmetic(arithmetic(arguments)?);
+                if !generator.build_block(0, &mut lex) {
+                    return Err(format!("Parsing Failed {:?}", lex.current()));
+                }
+                (bytecode::Module::compiled(&generator, program, &contents), program.to_string())
+            },
+        };
+
+        // Program input is given in the launch configuration
+        let input = arguments["input"].as_str().unwrap_or("").to_string();
+        let mut pl0_vm = module.load();
+        pl0_vm.redirect(Box::new(io::Cursor::new(input.into_bytes())), Box::new(self.output.clone()));
+
+        let mut debugger = debugger::Debugger::load(pl0_vm, module.name_table, module.line_table);
+        let pending = self.pending_breakpoints.iter().find(|(path, _)| same_file(path, &source_path));
+        if let Some((_, lines)) = pending {
+            debugger.set_breakpoints(lines);
+        }
+
+        self.program = source_path;
+        self.stop_on_entry = arguments["stopOnEntry"] == true;
+        self.debugger = Some(debugger);
+        Ok(())
+    }
+
+    fn source(&self) -> Value {
+        let name = Path::new(&self.program).file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_default();
+        json!({ "name": name, "path": self.program })
+    }
+
+    fn resume(&mut self, mode: debugger::StepMode) -> io::Result<()> {
+        let reason = match self.debugger.as_mut() {
+            Some(debugger) => debugger.resume(mode),
+            None => return Ok(()),
+        };
+        self.report_stop(reason)
+    }
+
+    fn report_stop(&mut self, reason: debugger::StopReason) -> io::Result<()> {
+        let output = self.output.take();
+        if !output.is_empty() {
+            self.event("output", json!({ "category": "stdout", "output": output }))?;
+        }
+
+        match reason {
+            debugger::StopReason::Terminated => {
+                let failed = self.debugger.as_ref().is_some_and(|debugger| debugger.vm().error().is_some());
+                self.event("exited", json!({ "exitCode": failed as i64 }))?;
+                self.event("terminated", Value::Null)
+            },
+            debugger::StopReason::Exception => {
+                let text = self.debugger.as_ref().and_then(|debugger| debugger.vm().error())
+                    .map(|error| error.to_string()).unwrap_or_default();
+                self.event("stopped", json!({
+                    "reason": "exception",
+                    "description": "Runtime error",
+                    "text": text,
+                    "threadId": THREAD_ID,
+                    "allThreadsStopped": true,
+                }))
+            },
+            _ => {
+                let reason = match reason {
+                    debugger::StopReason::Entry => "entry",
+                    debugger::StopReason::Breakpoint => "breakpoint",
+                    _ => "step",
+                };
+                self.event("stopped", json!({
+                    "reason": reason,
+                    "threadId": THREAD_ID,
+                    "allThreadsStopped": true,
+                }))
+            },
+        }
+    }
+
+    fn respond(&mut self, request_seq: i64, command: &str, success: bool,
+               body: Value, message: Option<String>) -> io::Result<()> {
+        self.seq += 1;
+        let mut response = json!({
+            "seq": self.seq,
+            "type": "response",
+            "request_seq": request_seq,
+            "success": success,
+            "command": command,
+        });
+        if !body.is_null() {
+            response["body"] = body;
+        }
+        if let Some(message) = message {
+            response["message"] = Value::String(message);
+        }
+        rpc::write_message(&mut self.writer, &response)
+    }
+
+    fn event(&mut self, event: &str, body: Value) -> io::Result<()> {
+        self.seq += 1;
+        let mut message = json!({
+            "seq": self.seq,
+            "type": "event",
+            "event": event,
+        });
+        if !body.is_null() {
+            message["body"] = body;
+        }
+        rpc::write_message(&mut self.writer, &message)
+    }
+}
+
+// Whether two paths name the same file, compared as written if one does not exist
+fn same_file(first: &str, second: &str) -> bool {
+    match (fs::canonicalize(first), fs::canonicalize(second)) {
+        (Ok(first), Ok(second)) => first == second,
+        _ => Path::new(first) == Path::new(second),
+    }
+}
+
+// Arithmetic of the optional overflow and bits of the launch configuration
+fn arithmetic(arguments: &Value) -> Result<vm::Arithmetic, String> {
+    let overflow = match arguments["overflow"].as_str() {
+        None | Some("checked") => vm::Overflow::Checked,
+        Some("wrapping") => vm::Overflow::Wrapping,
+        Some("saturating") => vm::Overflow::Saturating,
+        Some(overflow) => return Err(format!("Unknown overflow behavior {}", overflow)),
+    };
+    let bits = arguments["bits"].as_u64().unwrap_or(64);
+    u32::try_from(bits).ok().and_then(|bits| vm::Arithmetic::new(overflow, bits))
+        .ok_or(format!("Unsupported word size {}", bits))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use serde_json::{ json, Value };
+
+    use crate::rpc;
+    use crate::debugger::dap;
+
+    fn session(requests: &[Value]) -> Vec<Value> {
+        let mut input: Vec<u8> = Vec::new();
+        for (seq, request) in requests.iter().enumerate() {
+            let mut request = request.clone();
+            request["seq"] = json!(seq + 1);
+            request["type"] = json!("request");
+            rpc::write_message(&mut input, &request).unwrap();
+        }
+
+        let mut output: Vec<u8> = Vec::new();
+        dap::DapServer::new(Cursor::new(input), &mut output).run().unwrap();
+
+        let mut reader = Cursor::new(output);
+        let mut messages = Vec::new();
+        while let Some(message) = rpc::read_message(&mut reader).unwrap() {
+            messages.push(message);
+        }
+        messages
+    }
+
+    /* test a launch, breakpoint, inspect and continue session */
+    #[test]
+    fn test_breakpoint_session() {
+        let messages = session(&[
+            json!({ "command": "initialize", "arguments": { "adapterID": "pl0" } }),
+            json!({ "command": "launch", "arguments": { "program": "sample/sample1.pl0" } }),
+            json!({ "command": "setBreakpoints", "arguments": {
+                "source": { "path": "sample/sample1.pl0" },
+                "breakpoints": [{ "line": 8 }],
+            } }),
+            json!({ "command": "configurationDone" }),
+            json!({ "command": "stackTrace", "arguments": { "threadId": 1 } }),
+            json!({ "command": "variables", "arguments": { "variablesReference": 1 } }),
+            json!({ "command": "continue", "arguments": { "threadId": 1 } }),
+        ]);
+
+        let find = |kind: &str, name: &str| -> Vec<Value> {
+            messages.iter().filter(|message| message["type"] == kind
+                && (message["command"] == name || message["event"] == name)).cloned().collect()
+        };
+
+        assert_eq!(find("response", "launch")[0]["success"], true);
+        assert_eq!(find("event", "initialized").len(), 1);
+        assert_eq!(find("response", "setBreakpoints")[0]["body"]["breakpoints"][0]["line"], 8);
+        assert_eq!(find("event", "stopped")[0]["body"]["reason"], "breakpoint");
+
+        let frames = &find("response", "stackTrace")[0]["body"]["stackFrames"];
+        assert_eq!(frames[0]["name"], "average");
+        assert_eq!(frames[0]["line"], 8);
+        assert_eq!(frames[0]["column"], 12);
+        assert_eq!(frames[1]["name"], "_main");
+        assert_eq!((frames[1]["line"].as_i64(), frames[1]["column"].as_i64()), (Some(13), Some(4)));
+
+        let variables = &find("response", "variables")[0]["body"]["variables"];
+        assert_eq!(variables[0]["name"], "temp");
+        assert_eq!(variables[0]["value"], "10");
+
+        let output = find("event", "output");
+        assert_eq!(output[0]["body"]["output"], "10 \n");
+        assert_eq!(output[1]["body"]["output"], "6 \n");
+        assert_eq!(find("event", "terminated").len(), 1);
+    }
+
+    /* test a malformed message and a pause get error responses and the session goes on */
+    #[test]
+    fn test_invalid_requests() {
+        let mut input: Vec<u8> = Vec::new();
+        input.extend_from_slice(b"Content-Length: 9\r\n\r\n{\"seq\": 1");
+        rpc::write_message(&mut input, &json!({ "seq": 2, "type": "request", "command": "pause" })).unwrap();
+        rpc::write_message(&mut input, &json!({ "seq": 3, "type": "request", "command": "threads" })).unwrap();
+
+        let mut output: Vec<u8> = Vec::new();
+        dap::DapServer::new(Cursor::new(input), &mut output).run().unwrap();
+        let mut reader = Cursor::new(output);
+        let mut messages = Vec::new();
+        while let Some(message) = rpc::read_message(&mut reader).unwrap() {
+            messages.push(message);
+        }
+
+        assert_eq!(messages.len(), 3);
+        assert_eq!((messages[0]["request_seq"].as_i64(), messages[0]["success"].as_bool()), (Some(0), Some(false)));
+        assert!(messages[0]["message"].as_str().unwrap().starts_with("Malformed message"));
+        assert_eq!((messages[1]["command"].as_str(), messages[1]["success"].as_bool()), (Some("pause"), Some(false)));
+        assert_eq!((messages[2]["command"].as_str(), messages[2]["success"].as_bool()), (Some("threads"), Some(true)));
+    }
+
+    /* test breakpoints before the first line or in another source are not verified */
+    #[test]
+    fn test_invalid_breakpoints() {
+        let messages = session(&[
+            json!({ "command": "initialize", "arguments": { "linesStartAt1": false } }),
+            json!({ "command": "launch", "arguments": { "program": "sample/sample1.pl0" } }),
+            json!({ "command": "setBreakpoints", "arguments": {
+                "source": { "path": "sample/sample1.pl0" },
+                "breakpoints": [{ "line": -1 }, { "line": 7 }],
+            } }),
+            json!({ "command": "setBreakpoints", "arguments": {
+                "source": { "path": "sample/other.pl0" },
+                "breakpoints": [{ "line": 7 }],
+            } }),
+            json!({ "command": "configurationDone" }),
+        ]);
+
+        let responses: Vec<&Value> = messages.iter().filter(|message| message["command"] == "setBreakpoints").collect();
+        let breakpoints = &responses[0]["body"]["breakpoints"];
+        assert_eq!(breakpoints[0]["verified"], false);
+        assert_eq!((breakpoints[1]["verified"].as_bool(), breakpoints[1]["line"].as_i64()), (Some(true), Some(7)));
+        assert_eq!(responses[1]["body"]["breakpoints"][0]["verified"], false);
+
+        // The other source left the breakpoint of the program in place
+        let stopped = messages.iter().find(|message| message["event"] == "stopped").unwrap();
+        assert_eq!(stopped["body"]["reason"], "breakpoint");
+    }
+
+    /* test launching a missing program fails */
+    #[test]
+    fn test_launch_missing_program() {
+        let messages = session(&[
+            json!({ "command": "initialize" }),
+            json!({ "command": "launch", "arguments": { "program": "sample/missing.pl0" } }),
+        ]);
+
+        assert_eq!(messages[1]["command"], "launch");
+        assert_eq!(messages[1]["success"], false);
+        assert_eq!(messages.len(), 2);
+    }
+
+    /* test a runtime error stops as an exception with the arithmetic of the launch */
+    #[test]
+    fn test_runtime_error() {
+        let program = std::env::temp_dir().join(format!("pl0rust-dap-{}.pl0", std::process::id()));
+        std::fs::write(&program, "var x;\nbegin\n  x := 32767;\n  x := x + 1;\n  write(x)\nend.").unwrap();
+        let launch = |arguments: Value| session(&[
+            json!({ "command": "initialize" }),
+            json!({ "command": "launch", "arguments": arguments }),
+            json!({ "command": "configurationDone" }),
+            json!({ "command": "stackTrace", "arguments": { "threadId": 1 } }),
+            json!({ "command": "continue", "arguments": { "threadId": 1 } }),
+        ]);
+        let path = program.to_str().unwrap();
+
+        let messages = launch(json!({ "program": path, "bits": 16 }));
+        let stopped = messages.iter().find(|message| message["event"] == "stopped").unwrap();
+        assert_eq!(stopped["body"]["reason"], "exception");
+        assert_eq!(stopped["body"]["text"], "arithmetic overflow, the result does not fit in 16 bits");
+        let frames = messages.iter().find(|message| message["command"] == "stackTrace").unwrap();
+        assert_eq!((frames["body"]["stackFrames"][0]["line"].as_i64(), frames["body"]["stackFrames"][0]["column"].as_i64()), (Some(4), Some(8)));
+        let exited = messages.iter().find(|message| message["event"] == "exited").unwrap();
+        assert_eq!(exited["body"]["exitCode"], 1);
+
+        let messages = launch(json!({ "program": path, "bits": 16, "overflow": "wrapping" }));
+        assert!(messages.iter().any(|message| message["event"] == "output" && message["body"]["output"] == "-32768 \n"));
+        let messages = launch(json!({ "program": path, "bits": 12 }));
+        assert_eq!(messages[1]["success"], false);
+        std::fs::remove_file(&program).unwrap();
+    }
+}
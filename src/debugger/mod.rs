@@ -0,0 +1,377 @@
+pub mod dap;
+
+use std::collections::HashSet;
+
+use crate::vm;
+use crate::compile::nametab;
+
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum StepMode {
+    Continue,
+    Over,       // Next statement in the same or an outer procedure
+    In,         // Next statement anywhere
+    Out,        // Return to the caller
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum StopReason {
+    Entry,
+    Breakpoint,
+    Step,
+    Exception,  // a runtime error, the program cannot go on
+    Terminated,
+}
+
+/* a procedure activation on the vm stack */
+#[derive(Clone, Debug)]
+pub struct Frame {
+    pub procedure: Option<usize>,   // index in name table
+    pub name: String,
+    pub pc: usize,
+    pub bp: usize,
+    pub line: Option<usize>,
+    pub column: Option<usize>,      // with the debug info of the vm
+}
+
+#[derive(Clone, Debug)]
+pub struct Variable {
+    pub name: String,
+    pub kind: nametab::NameTableObject,
+    pub value: i64,
+}
+
+pub struct Debugger {
+    vm: vm::PL0VirtualMachine,
+    name_table: Vec<nametab::NameTableItem>,
+    line_table: Vec<(usize, usize)>,
+
+    breakpoints: HashSet<usize>,    // code pointers
+    started: bool,
+    terminated: bool,
+}
+
+impl Debugger {
+    pub fn load(vm: vm::PL0VirtualMachine, name_table: Vec<nametab::NameTableItem>,
+                line_table: Vec<(usize, usize)>) -> Debugger {
+        Debugger {
+            vm,
+            name_table,
+            line_table,
+            breakpoints: HashSet::new(),
+            started: false,
+            terminated: false,
+        }
+    }
+
+    pub fn vm(&self) -> &vm::PL0VirtualMachine {
+        &self.vm
+    }
+
+    pub fn is_terminated(&self) -> bool {
+        self.terminated
+    }
+
+    // Replace all breakpoints, returns the line each one is bound to
+    pub fn set_breakpoints(&mut self, lines: &[usize]) -> Vec<Option<usize>> {
+        self.breakpoints.clear();
+
+        let mut bound = Vec::new();
+        for line in lines {
+            // Bind to the first statement on this line or after it
+            let target = self.line_table.iter()
+                .filter(|(_, l)| *l >= *line)
+                .min_by_key(|(pc, l)| (*l, *pc));
+            match target {
+                Some((pc, l)) => {
+                    self.breakpoints.insert(*pc);
+                    bound.push(Some(*l));
+                },
+                None => {
+                    bound.push(None);
+                },
+            }
+        }
+        bound
+    }
+
+    pub fn resume(&mut self, mode: StepMode) -> StopReason {
+        if self.vm.error().is_some() {
+            self.vm.flush();
+            self.terminated = true;
+        }
+        if self.terminated {
+            return StopReason::Terminated;
+        }
+        if !self.started {
+            self.vm.prepare();
+            self.started = true;
+        }
+
+        let start_pc = self.vm.pc();
+        let start_depth = self.depth();
+
+        loop {
+            self.vm.single_step_execute();
+            if self.vm.error().is_some() {
+                self.vm.flush();
+                return StopReason::Exception;
+            }
+            if self.vm.pc() == 0 {
+                // Returned from the main procedure
+                self.vm.flush();
+                self.terminated = true;
+                return StopReason::Terminated;
+            }
+
+            let pc = self.vm.pc();
+            if self.breakpoints.contains(&pc) {
+                return StopReason::Breakpoint;
+            }
+
+            let is_statement = self.is_statement(pc);
+            let depth = self.depth();
+            let should_stop = match mode {
+                StepMode::Continue => false,
+                StepMode::Over => is_statement && pc != start_pc && depth <= start_depth,
+                StepMode::In => is_statement && pc != start_pc,
+                StepMode::Out => depth < start_depth,
+            };
+            if should_stop {
+                return StopReason::Step;
+            }
+        }
+    }
+
+    // Stop at the first statement of the main procedure
+    pub fn start_on_entry(&mut self) -> StopReason {
+        match self.resume(StepMode::In) {
+            StopReason::Step => StopReason::Entry,
+            reason => reason,
+        }
+    }
+
+    fn is_statement(&self, pc: usize) -> bool {
+        self.line_table.iter().any(|(p, _)| *p == pc)
+    }
+
+    // Number of activation records on the stack
+    fn depth(&self) -> usize {
+        let stack = self.vm.stack();
+        let mut bp = self.vm.bp();
+        let mut depth = 1;
+        while bp > 3 {
+            bp = stack[bp - 2] as usize;
+            depth += 1;
+        }
+        depth
+    }
+
+    // Source line of the statement containing pc
+    pub fn line_at(&self, pc: usize) -> Option<usize> {
+        self.line_table.iter()
+            .filter(|(p, _)| *p <= pc)
+            .max_by_key(|(p, _)| *p)
+            .map(|(_, line)| *line)
+    }
+
+    // Column where the construct of the instruction at pc begins, if it is on the line of its statement
+    fn column_at(&self, pc: usize, line: Option<usize>) -> Option<usize> {
+        let debug_info = self.vm.debug_info()?;
+        let span = debug_info.span(pc)?;
+        match Some(debug_info.line_of(span.start)) == line {
+            true => Some(debug_info.column_of(span.start)),
+            false => None,
+        }
+    }
+
+    // Procedure whose body contains pc
+    pub fn procedure_at(&self, pc: usize) -> Option<usize> {
+        self.name_table.iter().enumerate()
+            .filter(|(_, item)| item.kind == nametab::NameTableObject::Procedur && item.adr <= pc)
+            .max_by_key(|(_, item)| item.adr)
+            .map(|(index, _)| index)
+    }
+
+    // Walk the dynamic links, the innermost frame first
+    pub fn frames(&self) -> Vec<Frame> {
+        let stack = self.vm.stack();
+        let mut frames = Vec::new();
+        if !self.started || self.terminated {
+            return frames;
+        }
+
+        let mut pc = self.vm.pc();
+        let mut bp = self.vm.bp();
+        loop {
+            let procedure = self.procedure_at(pc);
+            let line = self.line_at(pc);
+            frames.push(Frame {
+                procedure,
+                name: procedure.map(|index| self.name_table[index].name.clone())
+                    .unwrap_or_else(|| String::from("?")),
+                pc,
+                bp,
+                line,
+                column: self.column_at(pc, line),
+            });
+            if bp <= 3 {
+                break;
+            }
+            // The caller is positioned on its Cal instruction
+            pc = stack[bp - 1] as usize - 1;
+            bp = stack[bp - 2] as usize;
+        }
+        frames
+    }
+
+    // Constants and variables declared by the procedure of the frame
+    pub fn locals(&self, frame: &Frame) -> Vec<Variable> {
+        match frame.procedure {
+            Some(procedure) => self.declarations(procedure, frame.bp),
+            None => Vec::new(),
+        }
+    }
+
+    // Constants and variables of the main procedure
+    pub fn globals(&self) -> Vec<Variable> {
+        self.declarations(0, 3)
+    }
+
+    fn declarations(&self, procedure: usize, bp: usize) -> Vec<Variable> {
+        let stack = self.vm.stack();
+        let mut variables = Vec::new();
+        for item in nametab::declarations(&self.name_table, procedure) {
+            match item.kind {
+                nametab::NameTableObject::Constant => {
+                    variables.push(Variable {
+                        name: item.name.clone(),
+                        kind: item.kind,
+                        value: item.val,
+                    });
+                },
+                nametab::NameTableObject::Variable => {
+                    variables.push(Variable {
+                        name: item.name.clone(),
+                        kind: item.kind,
+                        value: stack.get(bp + item.adr).copied().unwrap_or(0),
+                    });
+                },
+                nametab::NameTableObject::Procedur => {},
+            }
+        }
+        variables
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::compile::codegen;
+    use crate::debugger;
+    use crate::symbol;
+    use crate::vm;
+
+    fn load(content: &str) -> (debugger::Debugger, vm::SharedBuffer) {
+        let mut lex: symbol::io::PL0Lexer = symbol::io::PL0Lexer::create_from_content(content);
+        let mut generator = codegen::CodeGenerator::new();
+        generator.build_block(0, &mut lex);
+
+        let output = vm::SharedBuffer::default();
+        let mut pl0_vm = vm::PL0VirtualMachine::load(generator.get_vm_code().to_vec());
+        pl0_vm.redirect(Box::new(std::io::empty()), Box::new(output.clone()));
+
+        (debugger::Debugger::load(pl0_vm, generator.get_name_table().to_vec(),
+            generator.get_line_table().to_vec()), output)
+    }
+
+    const PROGRAM: &str = "var x;
+procedure inc;
+begin
+    x := x + 1
+end;
+begin
+    x := 1;
+    call inc;
+    write(x)
+end.";
+
+    /* test stopping at a breakpoint inside a procedure */
+    #[test]
+    fn test_breakpoint_in_procedure() {
+        let (mut debugger, output) = load(PROGRAM);
+
+        assert_eq!(debugger.set_breakpoints(&[4]), vec![Some(4)]);
+        assert_eq!(debugger.resume(debugger::StepMode::Continue), debugger::StopReason::Breakpoint);
+
+        let frames = debugger.frames();
+        assert_eq!(frames.len(), 2);
+        assert_eq!(frames[0].name, "inc");
+        assert_eq!(frames[0].line, Some(4));
+        assert_eq!(frames[1].name, "_main");
+        assert_eq!(frames[1].line, Some(8));
+
+        let globals = debugger.globals();
+        assert_eq!(globals.len(), 1);
+        assert_eq!(globals[0].name, "x");
+        assert_eq!(globals[0].value, 1);
+
+        assert_eq!(debugger.resume(debugger::StepMode::Continue), debugger::StopReason::Terminated);
+        assert_eq!(output.take(), "2 \n");
+    }
+
+    /* test breakpoint on a line without statement moves forward */
+    #[test]
+    fn test_breakpoint_moved_to_next_statement() {
+        let (mut debugger, _) = load(PROGRAM);
+
+        assert_eq!(debugger.set_breakpoints(&[6, 20]), vec![Some(7), None]);
+    }
+
+    /* test stepping over a call */
+    #[test]
+    fn test_step_over_call() {
+        let (mut debugger, _) = load(PROGRAM);
+
+        assert_eq!(debugger.start_on_entry(), debugger::StopReason::Entry);
+        assert_eq!(debugger.frames()[0].line, Some(7));
+        assert_eq!(debugger.resume(debugger::StepMode::Over), debugger::StopReason::Step);
+        assert_eq!(debugger.frames()[0].line, Some(8));
+        assert_eq!(debugger.resume(debugger::StepMode::Over), debugger::StopReason::Step);
+        assert_eq!(debugger.frames()[0].line, Some(9));
+        assert_eq!(debugger.globals()[0].value, 2);
+    }
+
+    /* test stepping into and out of a call */
+    #[test]
+    fn test_step_in_and_out() {
+        let (mut debugger, _) = load(PROGRAM);
+
+        debugger.set_breakpoints(&[8]);
+        assert_eq!(debugger.resume(debugger::StepMode::Continue), debugger::StopReason::Breakpoint);
+        assert_eq!(debugger.resume(debugger::StepMode::In), debugger::StopReason::Step);
+        assert_eq!(debugger.frames()[0].name, "inc");
+        assert_eq!(debugger.resume(debugger::StepMode::Out), debugger::StopReason::Step);
+        assert_eq!(debugger.frames().len(), 1);
+    }
+
+    /* test a runtime error stops in the faulting frame and ends the program */
+    #[test]
+    fn test_runtime_error() {
+        let (mut debugger, output) = load("var x;
+procedure div;
+begin
+    write(10 / x)
+end;
+begin
+    write(1);
+    call div
+end.");
+
+        assert_eq!(debugger.resume(debugger::StepMode::Continue), debugger::StopReason::Exception);
+        assert_eq!(debugger.vm().error(), Some(&vm::RuntimeError::DivisionByZero));
+        let frames = debugger.frames();
+        assert_eq!((frames[0].name.as_str(), frames[0].line), ("div", Some(4)));
+        assert_eq!(output.take(), "1 \n");
+        assert_eq!(debugger.resume(debugger::StepMode::Continue), debugger::StopReason::Terminated);
+    }
+}
@@ -0,0 +1,328 @@
+use std::ops::Range;
+
+use crate::ast;
+use crate::ast::parser;
+use crate::compile::error;
+
+const INDENT: &str = "    ";
+
+// Re-emit a program in the canonical layout, comments are kept
+pub fn format(content: &str) -> Result<String, error::CompileError> {
+    let program = parser::parse(content)?;
+
+    let mut formatter = Formatter {
+        source: content,
+        comments: &program.comments,
+        next_comment: 0,
+        lines: Vec::new(),
+        last_end: 0,
+    };
+    formatter.block(&program.block, 0);
+    formatter.append(".");
+    formatter.comments_before(content.len(), 0);
+
+    let mut output = formatter.lines.join("\n");
+    output.push('\n');
+    Ok(output)
+}
+
+/*
+ * Output is built line by line. Comments are emitted before the first node
+ * that follows them, on the line of the previous node if they were on the
+ * same line in the source, on their own line otherwise. A new line is always
+ * started after comments, so punctuation never ends up inside them.
+ */
+struct Formatter<'a> {
+    source: &'a str,
+    comments: &'a [Range<usize>],
+    next_comment: usize,
+
+    lines: Vec<String>,
+    last_end: usize,    // source offset of the end of the last emitted node
+}
+
+impl Formatter<'_> {
+    fn block(&mut self, block: &ast::Block, indent: usize) {
+        for declaration in &block.declarations {
+            self.open(declaration.span.start, indent);
+            match &declaration.kind {
+                ast::DeclarationKind::Const(constants) => {
+                    let constants: Vec<String> = constants.iter()
+                        .map(|constant| format!("{} = {}", constant.name.name, constant.value))
+                        .collect();
+                    self.line(indent, format!("const {};", constants.join(", ")));
+                },
+                ast::DeclarationKind::Var(variables) => {
+                    let variables: Vec<&str> = variables.iter().map(|variable| variable.name.as_str()).collect();
+                    self.line(indent, format!("var {};", variables.join(", ")));
+                },
+                ast::DeclarationKind::Procedure(procedure) => {
+                    self.line(indent, format!("procedure {};", procedure.name.name));
+                    self.last_end = procedure.name.span.end;
+                    self.block(&procedure.block, indent + 1);
+                    self.append(";");
+                },
+            }
+            self.last_end = declaration.span.end;
+        }
+
+        self.statement(&block.statement, indent);
+    }
+
+    fn statement(&mut self, statement: &ast::Statement, indent: usize) {
+        if statement.kind == ast::StatementKind::Empty {
+            return;
+        }
+        self.open(statement.span.start, indent);
+
+        match &statement.kind {
+            ast::StatementKind::Assign(name, expression) => {
+                self.line(indent, format!("{} := {}", name.name, self.expression(expression)));
+            },
+            ast::StatementKind::Call(name) => {
+                self.line(indent, format!("call {}", name.name));
+            },
+            ast::StatementKind::Read(names) => {
+                let names: Vec<&str> = names.iter().map(|name| name.name.as_str()).collect();
+                self.line(indent, format!("read({})", names.join(", ")));
+            },
+            ast::StatementKind::Write(expressions) => {
+                let expressions: Vec<String> = expressions.iter()
+                    .map(|expression| self.expression(expression))
+                    .collect();
+                self.line(indent, format!("write({})", expressions.join(", ")));
+            },
+            ast::StatementKind::Begin(statements) => {
+                self.line(indent, String::from("begin"));
+                self.last_end = statement.span.start + "begin".len();
+                self.sequence(statements, statement.span.end - "end".len(), indent);
+            },
+            ast::StatementKind::If(condition, body) => {
+                self.line(indent, format!("if {} then", self.condition(condition)));
+                self.last_end = condition.span.end;
+                self.body(body, indent);
+            },
+            ast::StatementKind::While(condition, body) => {
+                self.line(indent, format!("while {} do", self.condition(condition)));
+                self.last_end = condition.span.end;
+                self.body(body, indent);
+            },
+            ast::StatementKind::Empty => {},
+        }
+        self.last_end = statement.span.end;
+    }
+
+    // Statements between begin and the end keyword at end
+    fn sequence(&mut self, statements: &[ast::Statement], end: usize, indent: usize) {
+        // Empty statements only come from superfluous semicolons
+        let statements: Vec<&ast::Statement> = statements.iter()
+            .filter(|statement| statement.kind != ast::StatementKind::Empty)
+            .collect();
+        for (index, statement) in statements.iter().enumerate() {
+            if index > 0 {
+                self.append(";");
+            }
+            self.statement(statement, indent + 1);
+        }
+
+        self.comments_before(end, indent + 1);
+        self.line(indent, String::from("end"));
+    }
+
+    // Statement controlled by if or while
+    fn body(&mut self, body: &ast::Statement, indent: usize) {
+        let commented = self.comments.get(self.next_comment)
+            .is_some_and(|comment| comment.start < body.span.start);
+
+        match &body.kind {
+            ast::StatementKind::Begin(statements) if !commented => {
+                // begin stays on the line of the condition
+                self.append(" begin");
+                self.last_end = body.span.start + "begin".len();
+                self.sequence(statements, body.span.end - "end".len(), indent);
+                self.last_end = body.span.end;
+            },
+            ast::StatementKind::Begin(_) => {
+                self.statement(body, indent);
+            },
+            _ => {
+                self.statement(body, indent + 1);
+            },
+        }
+    }
+
+    fn condition(&self, condition: &ast::Condition) -> String {
+        match &condition.kind {
+            ast::ConditionKind::Odd(expression) => format!("odd {}", self.expression(expression)),
+            ast::ConditionKind::Compare(left, relation, right) => {
+                format!("{} {} {}", self.expression(left), relation.symbol(), self.expression(right))
+            },
+        }
+    }
+
+    fn expression(&self, expression: &ast::Expression) -> String {
+        match &expression.kind {
+            ast::ExpressionKind::Number(number) => number.to_string(),
+            ast::ExpressionKind::Name(name) => name.name.clone(),
+            ast::ExpressionKind::Negate(operand) => format!("-{}", self.expression(operand)),
+            ast::ExpressionKind::Binary(left, operator, right) => {
+                format!("{} {} {}", self.expression(left), operator.symbol(), self.expression(right))
+            },
+            ast::ExpressionKind::Paren(inner) => format!("({})", self.expression(inner)),
+        }
+    }
+
+    // Prepare a new line for the node beginning at start
+    fn open(&mut self, start: usize, indent: usize) {
+        self.comments_before(start, indent);
+        self.blank_line(start);
+    }
+
+    fn comments_before(&mut self, offset: usize, indent: usize) {
+        while let Some(comment) = self.comments.get(self.next_comment) {
+            if comment.start >= offset {
+                break;
+            }
+            let text = &self.source[comment.clone()];
+            let trailing = comment.start < self.last_end || !self.source[self.last_end..comment.start].contains('\n');
+            match self.lines.last_mut() {
+                Some(last) if trailing => {
+                    last.push(' ');
+                    last.push_str(text);
+                },
+                _ => {
+                    self.blank_line(comment.start);
+                    self.line(indent, text.to_string());
+                },
+            }
+            self.last_end = self.last_end.max(comment.end);
+            self.next_comment += 1;
+        }
+    }
+
+    // Keep one empty line where the source has at least one
+    fn blank_line(&mut self, start: usize) {
+        if start <= self.last_end || self.lines.last().is_none_or(|last| last.is_empty()) {
+            return;
+        }
+        let between: Vec<&str> = self.source[self.last_end..start].split('\n').collect();
+        if between.len() > 2 && between[1..between.len() - 1].iter().any(|part| part.trim().is_empty()) {
+            self.lines.push(String::new());
+        }
+    }
+
+    fn line(&mut self, indent: usize, text: String) {
+        self.lines.push(INDENT.repeat(indent) + &text);
+    }
+
+    fn append(&mut self, text: &str) {
+        match self.lines.last_mut() {
+            Some(last) => last.push_str(text),
+            None => self.lines.push(text.trim_start().to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use crate::formatter;
+
+    /* test the canonical layout */
+    #[test]
+    fn test_layout() {
+        let source = "const a=10; var result;
+procedure average;
+var temp;
+begin
+   temp:=a*(2+1);
+   if odd temp then result:=-temp/2
+end;
+
+begin
+   call average; while result<0 do begin result:=result+1; write(result) end
+end.";
+        let expected = "const a = 10;
+var result;
+procedure average;
+    var temp;
+    begin
+        temp := a * (2 + 1);
+        if odd temp then
+            result := -temp / 2
+    end;
+
+begin
+    call average;
+    while result < 0 do begin
+        result := result + 1;
+        write(result)
+    end
+end.
+";
+        assert_eq!(formatter::format(source).unwrap(), expected);
+
+        // Keywords are lowercase, other spellings are identifiers
+        assert_eq!(formatter::format("var BEGIN; BEGIN:=1.").unwrap(), "var BEGIN;\nBEGIN := 1.\n");
+    }
+
+    /* test signs are accepted where the compiler accepts them */
+    #[test]
+    fn test_signs() {
+        let source = "write(-(-2), - 2 * -3, 1 + -2, +x - 1).";
+        let expected = "write(-(-2), -2 * -3, 1 + -2, x - 1).\n";
+        assert_eq!(formatter::format(source).unwrap(), expected);
+
+        for source in ["write(- -2).", "write(1 - -2).", "write(- -x * 3).", "write(1 - +2).", "write(+-2)."] {
+            let error = formatter::format(source).unwrap_err();
+            assert_eq!(error.message, "Expression expected", "{}", source);
+        }
+    }
+
+    /* test comments are kept in place */
+    #[test]
+    fn test_comments() {
+        let source = "{ header }
+var x; { counter }
+begin
+  (* first *)
+  x := 1;  { set }
+  write(x)
+  { last }
+end. { done }";
+        let expected = "{ header }
+var x; { counter }
+begin
+    (* first *)
+    x := 1; { set }
+    write(x)
+    { last }
+end. { done }
+";
+        assert_eq!(formatter::format(source).unwrap(), expected);
+    }
+
+    /* test formatting twice changes nothing */
+    #[test]
+    fn test_idempotent() {
+        for entry in fs::read_dir("sample").unwrap() {
+            let content = fs::read_to_string(entry.unwrap().path()).unwrap();
+            let once = formatter::format(&content).unwrap();
+            assert_eq!(formatter::format(&once).unwrap(), once);
+        }
+
+        let source = "procedure p;;\nbegin if 1 = 1 then { why } begin end; ; while 0 != 0 do end.";
+        let once = formatter::format(source).unwrap();
+        assert_eq!(once, "procedure p;;\nbegin\n    if 1 = 1 then { why }\n    begin\n    end;\n    while 0 != 0 do\nend.\n");
+        assert_eq!(formatter::format(&once).unwrap(), once);
+    }
+
+    /* test a syntax error is reported instead of formatting */
+    #[test]
+    fn test_syntax_error() {
+        let error = formatter::format("begin x := end.").unwrap_err();
+        assert_eq!(error.message, "Expression expected");
+        assert_eq!(error.span, 11..14);
+    }
+}
@@ -1,4 +1,15 @@
-
 pub mod symbol;
 pub mod vm;
 pub mod compile;
+pub mod ast;
+pub mod formatter;
+pub mod lint;
+pub mod rpc;
+pub mod debugger;
+pub mod lsp;
+pub mod repl;
+pub mod backend;
+pub mod profiler;
+pub mod coverage;
+pub mod bytecode;
+pub mod backtrace;
@@ -0,0 +1,501 @@
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::ops::Range;
+
+use crate::ast;
+use crate::compile::nametab;
+
+
+/* warnings found on a correct program, each one can be allowed or denied */
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Lint {
+    UnusedVariable,
+    UnusedConstant,
+    UnusedProcedure,
+    ReadBeforeAssignment,
+    Shadowing,
+    ConstantCondition,
+    UnreachableStatement,
+    EmptyBlock,
+}
+
+impl Lint {
+    pub const ALL: [Lint; 8] = [
+        Lint::UnusedVariable,
+        Lint::UnusedConstant,
+        Lint::UnusedProcedure,
+        Lint::ReadBeforeAssignment,
+        Lint::Shadowing,
+        Lint::ConstantCondition,
+        Lint::UnreachableStatement,
+        Lint::EmptyBlock,
+    ];
+
+    pub fn code(&self) -> &'static str {
+        match self {
+            Lint::UnusedVariable => "W001",
+            Lint::UnusedConstant => "W002",
+            Lint::UnusedProcedure => "W003",
+            Lint::ReadBeforeAssignment => "W004",
+            Lint::Shadowing => "W005",
+            Lint::ConstantCondition => "W006",
+            Lint::UnreachableStatement => "W007",
+            Lint::EmptyBlock => "W008",
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Lint::UnusedVariable => "unused-variable",
+            Lint::UnusedConstant => "unused-constant",
+            Lint::UnusedProcedure => "unused-procedure",
+            Lint::ReadBeforeAssignment => "read-before-assignment",
+            Lint::Shadowing => "shadowing",
+            Lint::ConstantCondition => "constant-condition",
+            Lint::UnreachableStatement => "unreachable-statement",
+            Lint::EmptyBlock => "empty-block",
+        }
+    }
+
+    // Find a lint by its code or its name
+    pub fn find(code: &str) -> Option<Lint> {
+        Lint::ALL.iter().copied()
+            .find(|lint| lint.code().eq_ignore_ascii_case(code) || lint.name() == code)
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Level {
+    Allow,
+    Warn,
+    Deny,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Warning {
+    pub lint: Lint,
+    pub span: Range<usize>,
+    pub message: String,
+}
+
+/* level of each lint, all of them warn by default */
+#[derive(Clone, Debug, Default)]
+pub struct Config {
+    levels: HashMap<Lint, Level>,
+}
+
+impl Config {
+    pub fn set(&mut self, lint: Lint, level: Level) {
+        self.levels.insert(lint, level);
+    }
+
+    pub fn set_all(&mut self, level: Level) {
+        for lint in Lint::ALL.iter() {
+            self.levels.insert(*lint, level);
+        }
+    }
+
+    pub fn level(&self, lint: Lint) -> Level {
+        self.levels.get(&lint).copied().unwrap_or(Level::Warn)
+    }
+}
+
+// Every warning of the program, in source order
+pub fn check(program: &ast::Program) -> Vec<Warning> {
+    let mut linter = Linter {
+        symbols: Vec::new(),
+        scopes: Vec::new(),
+        warnings: Vec::new(),
+    };
+    linter.block(&program.block);
+    linter.warnings.sort_by_key(|warning| warning.span.start);
+    linter.warnings
+}
+
+struct Symbol {
+    name: String,
+    kind: nametab::NameTableObject,
+    span: Range<usize>,
+    value: i64,
+    body: Range<usize>,     // block of a procedure
+
+    read: bool,
+    written: bool,
+    called: bool,   // from outside of its own body
+}
+
+// State of the statements of one block
+struct Flow {
+    nested: usize,                  // symbols from here on are declared inside the block
+    assigned: HashSet<usize>,       // local variables assigned on every path
+    reported: HashSet<usize>,
+}
+
+struct Linter {
+    symbols: Vec<Symbol>,
+    scopes: Vec<Vec<usize>>,    // symbols declared by each open block
+    warnings: Vec<Warning>,
+}
+
+impl Linter {
+    fn block(&mut self, block: &ast::Block) {
+        let nested = self.symbols.len();
+        self.scopes.push(Vec::new());
+
+        for declaration in &block.declarations {
+            match &declaration.kind {
+                ast::DeclarationKind::Const(constants) => {
+                    for constant in constants {
+                        self.declare(&constant.name, nametab::NameTableObject::Constant, constant.value, 0..0);
+                    }
+                },
+                ast::DeclarationKind::Var(variables) => {
+                    for variable in variables {
+                        self.declare(variable, nametab::NameTableObject::Variable, 0, 0..0);
+                    }
+                },
+                ast::DeclarationKind::Procedure(procedure) => {
+                    self.declare(&procedure.name, nametab::NameTableObject::Procedur, 0, procedure.block.span.clone());
+                    self.block(&procedure.block);
+                },
+            }
+        }
+
+        let mut flow = Flow {
+            nested,
+            assigned: HashSet::new(),
+            reported: HashSet::new(),
+        };
+        self.statement(&block.statement, &mut flow, true);
+
+        let scope = self.scopes.pop().unwrap_or_default();
+        for index in scope {
+            let symbol = &self.symbols[index];
+            let unused = match symbol.kind {
+                nametab::NameTableObject::Constant if !symbol.read => {
+                    Some((Lint::UnusedConstant, format!("Constant {} is never used", symbol.name)))
+                },
+                nametab::NameTableObject::Variable if !symbol.read && symbol.written => {
+                    Some((Lint::UnusedVariable, format!("Variable {} is assigned but never used", symbol.name)))
+                },
+                nametab::NameTableObject::Variable if !symbol.read => {
+                    Some((Lint::UnusedVariable, format!("Variable {} is never used", symbol.name)))
+                },
+                nametab::NameTableObject::Procedur if !symbol.called => {
+                    Some((Lint::UnusedProcedure, format!("Procedure {} is never called", symbol.name)))
+                },
+                _ => None,
+            };
+            if let Some((lint, message)) = unused {
+                let span = symbol.span.clone();
+                self.warn(lint, span, message);
+            }
+        }
+    }
+
+    fn declare(&mut self, name: &ast::Ident, kind: nametab::NameTableObject, value: i64, body: Range<usize>) {
+        let in_block = self.scopes.last()
+            .is_some_and(|scope| scope.iter().any(|index| self.symbols[*index].name == name.name));
+        if in_block {
+            self.warn(Lint::Shadowing, name.span.clone(), format!("{} is already declared in this block", name.name));
+        } else if self.lookup(&name.name).is_some() {
+            self.warn(Lint::Shadowing, name.span.clone(), format!("{} shadows a declaration of an outer block", name.name));
+        }
+
+        self.symbols.push(Symbol {
+            name: name.name.clone(),
+            kind,
+            span: name.span.clone(),
+            value,
+            body,
+            read: false,
+            written: false,
+            called: false,
+        });
+        let index = self.symbols.len() - 1;
+        if let Some(scope) = self.scopes.last_mut() {
+            scope.push(index);
+        }
+    }
+
+    // The visible declaration of a name, the latest and innermost first
+    fn lookup(&self, name: &str) -> Option<usize> {
+        self.scopes.iter().rev()
+            .flat_map(|scope| scope.iter().rev())
+            .copied()
+            .find(|index| self.symbols[*index].name == name)
+    }
+
+    // Variable declared by the block being checked
+    fn is_local(&self, index: usize) -> bool {
+        self.symbols[index].kind == nametab::NameTableObject::Variable
+            && self.scopes.last().is_some_and(|scope| scope.contains(&index))
+    }
+
+    // Returns whether the statement can complete, so the next one is reachable
+    fn statement(&mut self, statement: &ast::Statement, flow: &mut Flow, reachable: bool) -> bool {
+        match &statement.kind {
+            ast::StatementKind::Assign(name, expression) => {
+                self.expression(expression, flow, reachable);
+                if let Some(index) = self.lookup(&name.name) {
+                    self.symbols[index].written = true;
+                    flow.assigned.insert(index);
+                }
+                true
+            },
+            ast::StatementKind::Call(name) => {
+                if let Some(index) = self.lookup(&name.name) {
+                    if !self.symbols[index].body.contains(&statement.span.start) {
+                        self.symbols[index].called = true;
+                    }
+                    if index >= flow.nested {
+                        // A nested procedure may assign any variable of this block
+                        let locals: Vec<usize> = self.scopes.last().cloned().unwrap_or_default();
+                        for local in locals {
+                            if self.is_local(local) {
+                                flow.assigned.insert(local);
+                            }
+                        }
+                    }
+                }
+                true
+            },
+            ast::StatementKind::Read(names) => {
+                for name in names {
+                    if let Some(index) = self.lookup(&name.name) {
+                        self.symbols[index].written = true;
+                        flow.assigned.insert(index);
+                    }
+                }
+                true
+            },
+            ast::StatementKind::Write(expressions) => {
+                for expression in expressions {
+                    self.expression(expression, flow, reachable);
+                }
+                true
+            },
+            ast::StatementKind::Begin(statements) => {
+                if statements.iter().all(|statement| statement.kind == ast::StatementKind::Empty) {
+                    self.warn(Lint::EmptyBlock, statement.span.clone(), String::from("Empty begin end block"));
+                }
+
+                let mut completes = true;
+                let mut reported = false;
+                for statement in statements {
+                    if !completes && reachable && !reported && statement.kind != ast::StatementKind::Empty {
+                        self.warn(Lint::UnreachableStatement, statement.span.clone(), String::from("Unreachable statement"));
+                        reported = true;
+                    }
+                    let next = self.statement(statement, flow, reachable && completes);
+                    completes = completes && next;
+                }
+                completes
+            },
+            ast::StatementKind::If(condition, body) => {
+                let value = self.condition(condition, flow, reachable);
+                if value == Some(false) && reachable && body.kind != ast::StatementKind::Empty {
+                    self.warn(Lint::UnreachableStatement, body.span.clone(), String::from("Unreachable statement"));
+                }
+
+                let assigned = flow.assigned.clone();
+                let completes = self.statement(body, flow, reachable && value != Some(false));
+                if value != Some(true) {
+                    // The body may be skipped
+                    flow.assigned = assigned;
+                }
+                value != Some(true) || completes
+            },
+            ast::StatementKind::While(condition, body) => {
+                let value = self.condition(condition, flow, reachable);
+                if value == Some(false) && reachable && body.kind != ast::StatementKind::Empty {
+                    self.warn(Lint::UnreachableStatement, body.span.clone(), String::from("Unreachable statement"));
+                }
+
+                let assigned = flow.assigned.clone();
+                self.statement(body, flow, reachable && value != Some(false));
+                flow.assigned = assigned;
+                // Nothing leaves a loop whose condition stays true
+                value != Some(true)
+            },
+            ast::StatementKind::Empty => true,
+        }
+    }
+
+    // Value of a constant condition
+    fn condition(&mut self, condition: &ast::Condition, flow: &mut Flow, reachable: bool) -> Option<bool> {
+        let (value, constant) = match &condition.kind {
+            ast::ConditionKind::Odd(expression) => {
+                self.expression(expression, flow, reachable);
+                (self.evaluate(expression).map(|value| value % 2 != 0), self.is_constant(expression))
+            },
+            ast::ConditionKind::Compare(left, relation, right) => {
+                self.expression(left, flow, reachable);
+                self.expression(right, flow, reachable);
+                let value = match (self.evaluate(left), self.evaluate(right)) {
+                    (Some(left), Some(right)) => Some(match relation {
+                        ast::Relation::Eq => left == right,
+                        ast::Relation::Ne => left != right,
+                        ast::Relation::Lt => left < right,
+                        ast::Relation::Le => left <= right,
+                        ast::Relation::Gt => left > right,
+                        ast::Relation::Ge => left >= right,
+                    }),
+                    _ => None,
+                };
+                (value, self.is_constant(left) && self.is_constant(right))
+            },
+        };
+
+        if constant && reachable {
+            let message = match value {
+                Some(true) => "Condition is always true",
+                Some(false) => "Condition is always false",
+                None => "Condition is constant",
+            };
+            self.warn(Lint::ConstantCondition, condition.span.clone(), String::from(message));
+        }
+        if constant { value } else { None }
+    }
+
+    fn expression(&mut self, expression: &ast::Expression, flow: &mut Flow, reachable: bool) {
+        match &expression.kind {
+            ast::ExpressionKind::Number(_) => {},
+            ast::ExpressionKind::Name(name) => {
+                let index = match self.lookup(&name.name) {
+                    Some(index) => index,
+                    None => return,
+                };
+                self.symbols[index].read = true;
+                if reachable && self.is_local(index) && !flow.assigned.contains(&index) && flow.reported.insert(index) {
+                    self.warn(Lint::ReadBeforeAssignment, name.span.clone(),
+                        format!("Variable {} may be read before it is assigned", name.name));
+                }
+            },
+            ast::ExpressionKind::Negate(operand) | ast::ExpressionKind::Paren(operand) => {
+                self.expression(operand, flow, reachable);
+            },
+            ast::ExpressionKind::Binary(left, _, right) => {
+                self.expression(left, flow, reachable);
+                self.expression(right, flow, reachable);
+            },
+        }
+    }
+
+    // Whether the expression only uses numbers and constants
+    fn is_constant(&self, expression: &ast::Expression) -> bool {
+        match &expression.kind {
+            ast::ExpressionKind::Number(_) => true,
+            ast::ExpressionKind::Name(name) => self.lookup(&name.name)
+                .is_some_and(|index| self.symbols[index].kind == nametab::NameTableObject::Constant),
+            ast::ExpressionKind::Negate(operand) | ast::ExpressionKind::Paren(operand) => self.is_constant(operand),
+            ast::ExpressionKind::Binary(left, _, right) => self.is_constant(left) && self.is_constant(right),
+        }
+    }
+
+    // Value of a constant expression, None on overflow or division by zero
+    fn evaluate(&self, expression: &ast::Expression) -> Option<i64> {
+        match &expression.kind {
+            ast::ExpressionKind::Number(number) => Some(*number),
+            ast::ExpressionKind::Name(name) => {
+                let symbol = &self.symbols[self.lookup(&name.name)?];
+                if symbol.kind != nametab::NameTableObject::Constant {
+                    return None;
+                }
+                Some(symbol.value)
+            },
+            ast::ExpressionKind::Negate(operand) => self.evaluate(operand)?.checked_neg(),
+            ast::ExpressionKind::Paren(operand) => self.evaluate(operand),
+            ast::ExpressionKind::Binary(left, operator, right) => {
+                let (left, right) = (self.evaluate(left)?, self.evaluate(right)?);
+                match operator {
+                    ast::BinaryOperator::Add => left.checked_add(right),
+                    ast::BinaryOperator::Sub => left.checked_sub(right),
+                    ast::BinaryOperator::Mul => left.checked_mul(right),
+                    ast::BinaryOperator::Div => left.checked_div(right),
+                }
+            },
+        }
+    }
+
+    fn warn(&mut self, lint: Lint, span: Range<usize>, message: String) {
+        self.warnings.push(Warning { lint, span, message });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ast::parser;
+    use crate::lint;
+
+    fn check(content: &str) -> Vec<(lint::Lint, String)> {
+        let program = parser::parse(content).unwrap();
+        lint::check(&program).into_iter()
+            .map(|warning| (warning.lint, warning.message))
+            .collect()
+    }
+
+    /* test a clean program has no warning */
+    #[test]
+    fn test_no_warning() {
+        let warnings = check("const a = 10; var x;
+procedure p; var y; begin y := a; x := y end;
+begin call p; write(x) end.");
+        assert_eq!(warnings, vec![]);
+    }
+
+    /* test unused declarations */
+    #[test]
+    fn test_unused() {
+        let warnings = check("const a = 1; var x, y; procedure p; call p; begin x := 1; read(y); write(y) end.");
+        assert_eq!(warnings, vec![
+            (lint::Lint::UnusedConstant, String::from("Constant a is never used")),
+            (lint::Lint::UnusedVariable, String::from("Variable x is assigned but never used")),
+            (lint::Lint::UnusedProcedure, String::from("Procedure p is never called")),
+        ]);
+    }
+
+    /* test reads before any assignment */
+    #[test]
+    fn test_read_before_assignment() {
+        let warnings = check("var x, y, z; procedure set; z := 1;
+begin if odd y then x := 1; write(x); write(x); y := 2; call set; write(z) end.");
+        assert_eq!(warnings, vec![
+            (lint::Lint::ReadBeforeAssignment, String::from("Variable y may be read before it is assigned")),
+            (lint::Lint::ReadBeforeAssignment, String::from("Variable x may be read before it is assigned")),
+        ]);
+    }
+
+    /* test shadowing, constant conditions, unreachable statements and empty blocks */
+    #[test]
+    fn test_control_flow() {
+        let warnings = check("const a = 1; var x;
+procedure p; var x; begin x := 1; write(x) end;
+begin
+    call p; x := 0;
+    if a > 2 then write(x);
+    while x < 10 do begin end;
+    while 1 = 1 do x := x + 1;
+    write(x)
+end.");
+        let lints: Vec<lint::Lint> = warnings.iter().map(|(lint, _)| *lint).collect();
+        assert_eq!(lints, vec![
+            lint::Lint::Shadowing,
+            lint::Lint::ConstantCondition,
+            lint::Lint::UnreachableStatement,
+            lint::Lint::EmptyBlock,
+            lint::Lint::ConstantCondition,
+            lint::Lint::UnreachableStatement,
+        ]);
+        assert_eq!(warnings[1].1, "Condition is always false");
+        assert_eq!(warnings[4].1, "Condition is always true");
+    }
+
+    /* test lints are found by code or name */
+    #[test]
+    fn test_find() {
+        assert_eq!(lint::Lint::find("W004"), Some(lint::Lint::ReadBeforeAssignment));
+        assert_eq!(lint::Lint::find("empty-block"), Some(lint::Lint::EmptyBlock));
+        assert_eq!(lint::Lint::find("W999"), None);
+    }
+}
@@ -0,0 +1,263 @@
+pub mod server;
+
+use std::ops::Range;
+
+use logos::Logos;
+
+use crate::symbol;
+use crate::ast;
+use crate::lint;
+use crate::compile::codegen;
+use crate::compile::error;
+use crate::compile::nametab;
+
+
+/* semantic token types, in the order of the legend sent to the client */
+pub const TOKEN_TYPES: [&str; 5] = ["keyword", "variable", "function", "number", "operator"];
+pub const TOKEN_MODIFIERS: [&str; 2] = ["declaration", "readonly"];
+
+// Everything the language server knows about one version of a document
+pub struct Analysis {
+    content: String,
+    lines: symbol::lines::LineIndex,
+
+    errors: Vec<error::CompileError>,
+    warnings: Vec<lint::Warning>,
+    name_table: Vec<nametab::NameTableItem>,
+    references: Vec<(usize, Range<usize>)>,
+    procedure_extents: Vec<(usize, Range<usize>)>,
+}
+
+impl Analysis {
+    pub fn new(content: &str) -> Analysis {
+        let mut lex: symbol::io::PL0Lexer = symbol::io::PL0Lexer::create_from_content(content);
+        let mut generator = codegen::CodeGenerator::new();
+        generator.build_block(0, &mut lex);
+
+        // Warnings only for a correct program
+        let warnings = match ast::parser::parse(content) {
+            Ok(program) if generator.get_errors().is_empty() => lint::check(&program),
+            _ => Vec::new(),
+        };
+
+        Analysis {
+            content: content.to_string(),
+            lines: symbol::lines::LineIndex::new(content),
+            errors: generator.get_errors().to_vec(),
+            warnings,
+            name_table: generator.get_name_table().to_vec(),
+            references: generator.get_references().to_vec(),
+            procedure_extents: generator.get_procedure_extents().to_vec(),
+        }
+    }
+
+    pub fn errors(&self) -> &[error::CompileError] {
+        &self.errors
+    }
+
+    pub fn warnings(&self) -> &[lint::Warning] {
+        &self.warnings
+    }
+
+    pub fn name_table(&self) -> &[nametab::NameTableItem] {
+        &self.name_table
+    }
+
+    // (line, character) of a byte offset, both from 0, characters in UTF-16 units
+    pub fn position(&self, offset: usize) -> (usize, usize) {
+        self.lines.utf16_position(&self.content, offset)
+    }
+
+    // Byte offset of a (line, character) position
+    pub fn offset(&self, line: usize, character: usize) -> usize {
+        self.lines.utf16_offset(&self.content, line, character)
+    }
+
+    // Name table index of the identifier at offset, declaration or use
+    pub fn symbol_at(&self, offset: usize) -> Option<usize> {
+        let contains = |span: &Range<usize>| span.start <= offset && offset <= span.end && span.start < span.end;
+
+        self.name_table.iter().position(|item| contains(&item.span))
+            .or_else(|| self.references.iter()
+                .find(|(_, span)| contains(span))
+                .map(|(index, _)| *index))
+    }
+
+    pub fn definition(&self, index: usize) -> Option<Range<usize>> {
+        let span = &self.name_table.get(index)?.span;
+        if span.start == span.end {
+            // The anonymous main procedure
+            return None;
+        }
+        Some(span.clone())
+    }
+
+    pub fn references(&self, index: usize, include_declaration: bool) -> Vec<Range<usize>> {
+        let mut spans = Vec::new();
+        if include_declaration {
+            if let Some(span) = self.definition(index) {
+                spans.push(span);
+            }
+        }
+        for (reference, span) in &self.references {
+            if *reference == index {
+                spans.push(span.clone());
+            }
+        }
+        spans
+    }
+
+    pub fn hover(&self, index: usize) -> String {
+        let item = &self.name_table[index];
+        match item.kind {
+            nametab::NameTableObject::Constant => {
+                format!("const {} = {}\n\nlevel {}", item.name, item.val, item.level)
+            },
+            nametab::NameTableObject::Variable => {
+                format!("var {}\n\nlevel {}, address {}", item.name, item.level, item.adr)
+            },
+            nametab::NameTableObject::Procedur => {
+                format!("procedure {}\n\nlevel {}, address {}, size {}", item.name, item.level, item.adr, item.size)
+            },
+        }
+    }
+
+    // (name table index, whole declaration, name) of every procedure
+    pub fn procedures(&self) -> Vec<(usize, Range<usize>, Range<usize>)> {
+        let mut procedures: Vec<(usize, Range<usize>, Range<usize>)> = self.procedure_extents.iter()
+            .map(|(index, extent)| (*index, extent.clone(), self.name_table[*index].span.clone()))
+            .collect();
+        procedures.sort_by_key(|(_, extent, _)| extent.start);
+        procedures
+    }
+
+    // (span, token type, token modifiers) for every classified symbol
+    pub fn semantic_tokens(&self) -> Vec<(Range<usize>, usize, usize)> {
+        let mut tokens = Vec::new();
+        let mut lexer = symbol::Symbol::lexer(&self.content);
+
+        while let Some(sym) = lexer.next() {
+            let span = lexer.span();
+            let classified = match sym {
+                symbol::Symbol::Ident => {
+                    let declared = self.name_table.iter().position(|item| item.span == span);
+                    let index = declared.or_else(|| self.references.iter()
+                        .find(|(_, reference)| *reference == span)
+                        .map(|(index, _)| *index));
+                    let mut modifiers = if declared.is_some() { 1 } else { 0 };
+                    match index.map(|index| self.name_table[index].kind) {
+                        Some(nametab::NameTableObject::Procedur) => Some((2, modifiers)),
+                        Some(nametab::NameTableObject::Constant) => {
+                            modifiers |= 2;
+                            Some((1, modifiers))
+                        },
+                        _ => Some((1, modifiers)),
+                    }
+                },
+                symbol::Symbol::Number => Some((3, 0)),
+                symbol::Symbol::Plus | symbol::Symbol::Minus | symbol::Symbol::Times
+                | symbol::Symbol::Slash | symbol::Symbol::Eql | symbol::Symbol::Neq
+                | symbol::Symbol::Lss | symbol::Symbol::Leq | symbol::Symbol::Gtr
+                | symbol::Symbol::Geq | symbol::Symbol::Becomes => Some((4, 0)),
+                symbol::Symbol::Oddsym | symbol::Symbol::Beginsym | symbol::Symbol::Endsym
+                | symbol::Symbol::Ifsym | symbol::Symbol::Thensyn | symbol::Symbol::Whilesym
+                | symbol::Symbol::Writesym | symbol::Symbol::Readsym | symbol::Symbol::Dosym
+                | symbol::Symbol::Callsym | symbol::Symbol::Constsym | symbol::Symbol::Varsym
+                | symbol::Symbol::Procsym => Some((0, 0)),
+                _ => None,
+            };
+            if let Some((token_type, modifiers)) = classified {
+                tokens.push((span, token_type, modifiers));
+            }
+        }
+        tokens
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::lsp;
+
+    const PROGRAM: &str = "const a=10; var result;
+procedure average;
+var temp;
+begin
+   temp:=a;
+   result:=temp
+end;
+
+begin
+   call average;
+   write(result)
+end.";
+
+    /* test no diagnostics for a correct program */
+    #[test]
+    fn test_no_error() {
+        let analysis = lsp::Analysis::new(PROGRAM);
+        assert!(analysis.errors().is_empty());
+    }
+
+    /* test warnings of a correct program */
+    #[test]
+    fn test_warnings() {
+        let analysis = lsp::Analysis::new("var x, y;\nbegin x := 1; write(x) end.");
+
+        assert_eq!(analysis.warnings().len(), 1);
+        assert_eq!(analysis.warnings()[0].message, "Variable y is never used");
+        assert_eq!(analysis.position(analysis.warnings()[0].span.start), (0, 7));
+    }
+
+    /* test diagnostics are located */
+    #[test]
+    fn test_undeclared_error() {
+        let analysis = lsp::Analysis::new("var x;\nbegin\n  y := 1\nend.");
+
+        assert_eq!(analysis.errors().len(), 1);
+        assert_eq!(analysis.errors()[0].message, "Undeclared identifier y");
+        assert_eq!(analysis.position(analysis.errors()[0].span.start), (2, 2));
+    }
+
+    /* test definition and references of a variable */
+    #[test]
+    fn test_definition_and_references() {
+        let analysis = lsp::Analysis::new(PROGRAM);
+
+        // "result" in write(result)
+        let offset = analysis.offset(10, 10);
+        let index = analysis.symbol_at(offset).unwrap();
+        let definition = analysis.definition(index).unwrap();
+        assert_eq!(analysis.position(definition.start), (0, 16));
+
+        let references = analysis.references(index, true);
+        assert_eq!(references.len(), 3);
+        assert_eq!(analysis.position(references[1].start), (5, 3));
+
+        assert_eq!(analysis.hover(index), "var result\n\nlevel 0, address 1");
+    }
+
+    /* test procedures are listed with their extent */
+    #[test]
+    fn test_procedures() {
+        let analysis = lsp::Analysis::new(PROGRAM);
+
+        let procedures = analysis.procedures();
+        assert_eq!(procedures.len(), 1);
+        assert_eq!(analysis.name_table()[procedures[0].0].name, "average");
+        assert_eq!(analysis.position(procedures[0].1.start), (1, 0));
+        assert_eq!(analysis.position(procedures[0].1.end), (6, 4));
+    }
+
+    /* test semantic tokens of a declaration */
+    #[test]
+    fn test_semantic_tokens() {
+        let analysis = lsp::Analysis::new("const a=10;\nbegin write(a) end.");
+        let tokens = analysis.semantic_tokens();
+
+        assert_eq!(tokens[0], (0..5, 0, 0));    // const
+        assert_eq!(tokens[1], (6..7, 1, 3));    // a, declaration and readonly
+        assert_eq!(tokens[2], (7..8, 4, 0));    // =
+        assert_eq!(tokens[3], (8..10, 3, 0));   // 10
+        assert_eq!(tokens[6], (24..25, 1, 2));  // a, readonly
+    }
+}
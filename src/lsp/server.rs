@@ -0,0 +1,361 @@
+use std::collections::HashMap;
+use std::io;
+use std::ops::Range;
+
+use serde_json::{ json, Value };
+
+use crate::rpc;
+use crate::lsp;
+use crate::lint;
+
+// Language server over stdin/stdout, documents are fully synchronized
+pub struct LspServer<R: io::BufRead, W: io::Write> {
+    reader: R,
+    writer: W,
+
+    documents: HashMap<String, lsp::Analysis>,
+    shutdown: bool,
+}
+
+impl<R: io::BufRead, W: io::Write> LspServer<R, W> {
+    pub fn new(reader: R, writer: W) -> LspServer<R, W> {
+        LspServer {
+            reader,
+            writer,
+            documents: HashMap::new(),
+            shutdown: false,
+        }
+    }
+
+    pub fn run(&mut self) -> io::Result<()> {
+        loop {
+            let message = match rpc::read_message(&mut self.reader) {
+                Ok(Some(message)) => message,
+                Ok(None) => break,
+                // Its body has been read, the next message follows
+                Err(error) if error.kind() == io::ErrorKind::InvalidData => {
+                    let response = json!({
+                        "jsonrpc": "2.0",
+                        "id": Value::Null,
+                        "error": { "code": -32700, "message": format!("Parse error: {}", error) },
+                    });
+                    rpc::write_message(&mut self.writer, &response)?;
+                    continue;
+                },
+                Err(error) => return Err(error),
+            };
+            let method = message["method"].as_str().unwrap_or("").to_string();
+            let params = &message["params"];
+
+            match message.get("id") {
+                Some(id) => {
+                    // Request, always answered
+                    let id = id.clone();
+                    let response = match self.request(&method, params) {
+                        Ok(result) => json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+                        Err((code, error)) => json!({
+                            "jsonrpc": "2.0",
+                            "id": id,
+                            "error": { "code": code, "message": error },
+                        }),
+                    };
+                    rpc::write_message(&mut self.writer, &response)?;
+                },
+                None => {
+                    // Notification
+                    if method == "exit" {
+                        break;
+                    }
+                    self.notification(&method, params)?;
+                },
+            }
+        }
+        Ok(())
+    }
+
+    fn request(&mut self, method: &str, params: &Value) -> Result<Value, (i64, String)> {
+        if self.shutdown && method != "shutdown" {
+            return Err((-32600, String::from("Server is shut down")));
+        }
+
+        match method {
+            "initialize" => Ok(json!({
+                "capabilities": {
+                    "textDocumentSync": 1,
+                    "definitionProvider": true,
+                    "referencesProvider": true,
+                    "hoverProvider": true,
+                    "documentSymbolProvider": true,
+                    "semanticTokensProvider": {
+                        "legend": {
+                            "tokenTypes": lsp::TOKEN_TYPES,
+                            "tokenModifiers": lsp::TOKEN_MODIFIERS,
+                        },
+                        "full": true,
+                    },
+                },
+                "serverInfo": { "name": "pl0rust" },
+            })),
+            "shutdown" => {
+                self.shutdown = true;
+                Ok(Value::Null)
+            },
+            "textDocument/definition" => {
+                let (uri, analysis, index) = match self.symbol_at(params) {
+                    Some(found) => found,
+                    None => return Ok(Value::Null),
+                };
+                Ok(match analysis.definition(index) {
+                    Some(span) => location(uri, analysis, &span),
+                    None => Value::Null,
+                })
+            },
+            "textDocument/references" => {
+                let (uri, analysis, index) = match self.symbol_at(params) {
+                    Some(found) => found,
+                    None => return Ok(Value::Null),
+                };
+                let include_declaration = params["context"]["includeDeclaration"] == true;
+                let locations: Vec<Value> = analysis.references(index, include_declaration).iter()
+                    .map(|span| location(uri, analysis, span))
+                    .collect();
+                Ok(Value::from(locations))
+            },
+            "textDocument/hover" => {
+                let (_, analysis, index) = match self.symbol_at(params) {
+                    Some(found) => found,
+                    None => return Ok(Value::Null),
+                };
+                Ok(json!({ "contents": { "kind": "markdown", "value": analysis.hover(index) } }))
+            },
+            "textDocument/documentSymbol" => {
+                let analysis = match self.document(params) {
+                    Some((_, analysis)) => analysis,
+                    None => return Ok(Value::Null),
+                };
+                let symbols: Vec<Value> = analysis.procedures().iter().map(|(index, extent, name)| json!({
+                    "name": analysis.name_table()[*index].name,
+                    "kind": 12,     // Function
+                    "range": range(analysis, extent),
+                    "selectionRange": range(analysis, name),
+                })).collect();
+                Ok(Value::from(symbols))
+            },
+            "textDocument/semanticTokens/full" => {
+                let analysis = match self.document(params) {
+                    Some((_, analysis)) => analysis,
+                    None => return Ok(Value::Null),
+                };
+                // Relative encoding: line delta, start delta, length, type, modifiers
+                let mut data: Vec<usize> = Vec::new();
+                let mut previous = (0, 0);
+                for (span, token_type, modifiers) in analysis.semantic_tokens() {
+                    let (line, character) = analysis.position(span.start);
+                    let (_, end) = analysis.position(span.end);
+                    let delta_start = if line == previous.0 { character - previous.1 } else { character };
+                    data.extend_from_slice(&[line - previous.0, delta_start, end - character, token_type, modifiers]);
+                    previous = (line, character);
+                }
+                Ok(json!({ "data": data }))
+            },
+            _ => Err((-32601, format!("Unsupported method {}", method))),
+        }
+    }
+
+    fn notification(&mut self, method: &str, params: &Value) -> io::Result<()> {
+        let uri = params["textDocument"]["uri"].as_str().unwrap_or("").to_string();
+        match method {
+            "textDocument/didOpen" => {
+                let text = params["textDocument"]["text"].as_str().unwrap_or("");
+                self.update(uri, text)
+            },
+            "textDocument/didChange" => {
+                // Full synchronization, the last change holds the whole text
+                let changes = params["contentChanges"].as_array();
+                match changes.and_then(|changes| changes.last()) {
+                    Some(change) => {
+                        let text = change["text"].as_str().unwrap_or("").to_string();
+                        self.update(uri, &text)
+                    },
+                    None => Ok(()),
+                }
+            },
+            "textDocument/didClose" => {
+                self.documents.remove(&uri);
+                self.publish(&uri, Vec::new())
+            },
+            _ => Ok(()),
+        }
+    }
+
+    fn update(&mut self, uri: String, text: &str) -> io::Result<()> {
+        let analysis = lsp::Analysis::new(text);
+        let mut diagnostics: Vec<Value> = analysis.errors().iter().map(|error| json!({
+            "range": range(&analysis, &error.span),
+            "severity": 1,
+            "source": "pl0rust",
+            "message": error.message,
+        })).collect();
+        for warning in analysis.warnings() {
+            let mut diagnostic = json!({
+                "range": range(&analysis, &warning.span),
+                "severity": 2,
+                "code": warning.lint.code(),
+                "source": "pl0rust",
+                "message": warning.message,
+            });
+            if matches!(warning.lint, lint::Lint::UnusedVariable | lint::Lint::UnusedConstant
+                | lint::Lint::UnusedProcedure | lint::Lint::UnreachableStatement) {
+                // Editors fade out unnecessary code
+                diagnostic["tags"] = json!([1]);
+            }
+            diagnostics.push(diagnostic);
+        }
+        self.documents.insert(uri.clone(), analysis);
+        self.publish(&uri, diagnostics)
+    }
+
+    fn publish(&mut self, uri: &str, diagnostics: Vec<Value>) -> io::Result<()> {
+        rpc::write_message(&mut self.writer, &json!({
+            "jsonrpc": "2.0",
+            "method": "textDocument/publishDiagnostics",
+            "params": { "uri": uri, "diagnostics": diagnostics },
+        }))
+    }
+
+    fn document(&self, params: &Value) -> Option<(&str, &lsp::Analysis)> {
+        let uri = params["textDocument"]["uri"].as_str()?;
+        self.documents.get_key_value(uri).map(|(uri, analysis)| (uri.as_str(), analysis))
+    }
+
+    fn symbol_at(&self, params: &Value) -> Option<(&str, &lsp::Analysis, usize)> {
+        let (uri, analysis) = self.document(params)?;
+        let line = params["position"]["line"].as_u64()? as usize;
+        let character = params["position"]["character"].as_u64()? as usize;
+        let index = analysis.symbol_at(analysis.offset(line, character))?;
+        Some((uri, analysis, index))
+    }
+}
+
+fn range(analysis: &lsp::Analysis, span: &Range<usize>) -> Value {
+    let (start_line, start_character) = analysis.position(span.start);
+    let (end_line, end_character) = analysis.position(span.end);
+    json!({
+        "start": { "line": start_line, "character": start_character },
+        "end": { "line": end_line, "character": end_character },
+    })
+}
+
+fn location(uri: &str, analysis: &lsp::Analysis, span: &Range<usize>) -> Value {
+    json!({ "uri": uri, "range": range(analysis, span) })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use serde_json::{ json, Value };
+
+    use crate::rpc;
+    use crate::lsp::server;
+
+    fn session(messages: &[Value]) -> Vec<Value> {
+        let mut input: Vec<u8> = Vec::new();
+        for message in messages {
+            rpc::write_message(&mut input, message).unwrap();
+        }
+
+        let mut output: Vec<u8> = Vec::new();
+        server::LspServer::new(Cursor::new(input), &mut output).run().unwrap();
+
+        let mut reader = Cursor::new(output);
+        let mut responses = Vec::new();
+        while let Some(response) = rpc::read_message(&mut reader).unwrap() {
+            responses.push(response);
+        }
+        responses
+    }
+
+    fn open(text: &str) -> Value {
+        json!({
+            "jsonrpc": "2.0",
+            "method": "textDocument/didOpen",
+            "params": { "textDocument": { "uri": "file:///a.pl0", "languageId": "pl0", "version": 1, "text": text } },
+        })
+    }
+
+    /* test a message which is not JSON gets a parse error and the server goes on */
+    #[test]
+    fn test_parse_error() {
+        let mut input: Vec<u8> = Vec::new();
+        input.extend_from_slice(b"Content-Length: 7\r\n\r\ngarbage");
+        rpc::write_message(&mut input, &json!({ "jsonrpc": "2.0", "id": 1, "method": "initialize", "params": {} })).unwrap();
+
+        let mut output: Vec<u8> = Vec::new();
+        server::LspServer::new(Cursor::new(input), &mut output).run().unwrap();
+        let mut reader = Cursor::new(output);
+        let mut responses = Vec::new();
+        while let Some(response) = rpc::read_message(&mut reader).unwrap() {
+            responses.push(response);
+        }
+
+        assert_eq!(responses.len(), 2);
+        assert_eq!(responses[0]["id"], Value::Null);
+        assert_eq!(responses[0]["error"]["code"], -32700);
+        assert_eq!(responses[1]["id"], 1);
+        assert_eq!(responses[1]["result"]["capabilities"]["hoverProvider"], true);
+    }
+
+    /* test diagnostics published when a document changes */
+    #[test]
+    fn test_diagnostics_on_change() {
+        let responses = session(&[
+            json!({ "jsonrpc": "2.0", "id": 1, "method": "initialize", "params": {} }),
+            open("var x;\nbegin x := 1; write(x) end."),
+            json!({
+                "jsonrpc": "2.0",
+                "method": "textDocument/didChange",
+                "params": {
+                    "textDocument": { "uri": "file:///a.pl0", "version": 2 },
+                    "contentChanges": [{ "text": "var x;\nbegin y := 1 end." }],
+                },
+            }),
+            json!({ "jsonrpc": "2.0", "method": "exit" }),
+        ]);
+
+        assert_eq!(responses[0]["id"], 1);
+        assert_eq!(responses[0]["result"]["capabilities"]["hoverProvider"], true);
+        assert_eq!(responses[1]["params"]["diagnostics"], json!([]));
+
+        let diagnostics = &responses[2]["params"]["diagnostics"];
+        assert_eq!(diagnostics[0]["message"], "Undeclared identifier y");
+        assert_eq!(diagnostics[0]["range"]["start"], json!({ "line": 1, "character": 6 }));
+    }
+
+    /* test warnings published with their code */
+    #[test]
+    fn test_warning_diagnostics() {
+        let responses = session(&[open("var x;\nbegin x := 1 end.")]);
+
+        let diagnostics = &responses[0]["params"]["diagnostics"];
+        assert_eq!(diagnostics[0]["severity"], 2);
+        assert_eq!(diagnostics[0]["code"], "W001");
+        assert_eq!(diagnostics[0]["message"], "Variable x is assigned but never used");
+    }
+
+    /* test go to definition and hover */
+    #[test]
+    fn test_definition_and_hover() {
+        let position = json!({ "textDocument": { "uri": "file:///a.pl0" }, "position": { "line": 1, "character": 6 } });
+        let responses = session(&[
+            open("var x;\nbegin x := 1 end."),
+            json!({ "jsonrpc": "2.0", "id": 2, "method": "textDocument/definition", "params": position }),
+            json!({ "jsonrpc": "2.0", "id": 3, "method": "textDocument/hover", "params": position }),
+            json!({ "jsonrpc": "2.0", "id": 4, "method": "textDocument/unknown", "params": {} }),
+        ]);
+
+        assert_eq!(responses[1]["result"]["range"]["start"], json!({ "line": 0, "character": 4 }));
+        assert_eq!(responses[2]["result"]["contents"]["value"], "var x\n\nlevel 0, address 0");
+        assert_eq!(responses[3]["error"]["code"], -32601);
+    }
+}
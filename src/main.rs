@@ -1,10 +1,23 @@
-use std::io;
 use std::fs;
 use std::env;
+use std::io;
+use std::io::Write;
+use std::path;
+use std::process;
 
-mod symbol;
-mod vm;
-mod compile;
+use pl0rust::symbol;
+use pl0rust::vm;
+use pl0rust::compile;
+use pl0rust::ast;
+use pl0rust::lint;
+use pl0rust::backend;
+use pl0rust::debugger;
+use pl0rust::lsp;
+use pl0rust::repl;
+use pl0rust::profiler;
+use pl0rust::coverage;
+use pl0rust::bytecode;
+use pl0rust::backtrace;
 
 
 fn main() {
@@ -13,23 +26,759 @@ fn main() {
         // Help info
         panic!("Please pass pl/0 file name as the first command-line argument.");
     }
-    let input_file_name = &args[1];
+
+    if args[1] == "dap" {
+        // Debug adapter speaking on stdin/stdout
+        let stdin = io::stdin();
+        let mut server = debugger::dap::DapServer::new(stdin.lock(), io::stdout());
+        server.run().expect("Debug adapter connection failed");
+        return;
+    }
+
+    if args[1] == "repl" {
+        let mut repl = repl::Repl::new(Box::new(io::BufReader::new(io::stdin())),
+            Box::new(io::stdout()), Box::new(io::stdout()));
+        repl.run().expect("Failed to run the repl");
+        return;
+    }
+
+    if args[1] == "lsp" {
+        // Language server speaking on stdin/stdout
+        let stdin = io::stdin();
+        let mut server = lsp::server::LspServer::new(stdin.lock(), io::stdout());
+        server.run().expect("Language server connection failed");
+        return;
+    }
+    if args[1] == "lint" {
+        process::exit(run_lint(&args[2..]));
+    }
+    if args[1] == "emit" {
+        process::exit(run_emit(&args[2..]));
+    }
+    if args[1] == "build" {
+        process::exit(run_build(&args[2..]));
+    }
+    if args[1] == "cfg" {
+        process::exit(run_cfg(&args[2..]));
+    }
+    if args[1] == "callgraph" {
+        process::exit(run_callgraph(&args[2..]));
+    }
+    if args[1] == "ast" {
+        process::exit(run_ast(&args[2..]));
+    }
+    if args[1] == "profile" {
+        process::exit(run_profile(&args[2..]));
+    }
+    if args[1] == "coverage" {
+        process::exit(run_coverage(&args[2..]));
+    }
+    if args[1] == "compile" {
+        process::exit(run_compile(&args[2..]));
+    }
+    if args[1] == "trace" {
+        process::exit(run_trace(&args[2..]));
+    }
+    // Optimize with -O before the file name
+    let (positional, limits) = match limit_options(args[1..].iter().collect()) {
+        Some(parsed) => parsed,
+        None => process::exit(2),
+    };
+    let (positional, arithmetic) = match arithmetic_options(positional) {
+        Some(parsed) => parsed,
+        None => process::exit(2),
+    };
+    let optimize = positional.first().is_some_and(|arg| arg.as_str() == "-O");
+    let input_file_name = match positional.as_slice() {
+        [input_file_name] if !optimize => input_file_name.as_str(),
+        [_, input_file_name] if optimize => input_file_name.as_str(),
+        _ => {
+            eprintln!("Usage: pl0rust [-O] [--max-steps <count>] [--max-stack <cells>] [--overflow <mode>] [--bits <size>] <file>");
+            process::exit(2);
+        },
+    };
 
     // input_file_name = "sample/sample1.pl0".to_string();
     println!("Reading {:?}", input_file_name);
 
-    let contents = fs::read_to_string(input_file_name)
+    let contents = fs::read(input_file_name)
         .expect("Something went wrong reading the file");
+    // A compiled program runs as it is
+    if bytecode::is_bytecode(&contents) {
+        let module = match bytecode::read(&contents) {
+            Ok(module) => module,
+            Err(error) => {
+                println!("{}: error: {}", input_file_name, error);
+                process::exit(1);
+            },
+        };
+        process::exit(run_module(&module, &limits));
+    }
+    let contents = String::from_utf8_lossy(&contents);
 
-    let mut lex: symbol::io::PL0Lexer = symbol::io::PL0Lexer::create_from_content(&contents);
+    let mut generator = match compile_source(&contents, arithmetic) {
+        Some(generator) => generator,
+        None => process::exit(1),
+    };
+    println!("Parsing finished");
 
-    let mut generator = compile::codegen::CodeGenerator::new();
+    if optimize {
+        let removed = generator.eliminate_dead_code();
+        for procedure in &removed.procedures {
+            println!("Removed procedure {}, it is never called", procedure);
+        }
+        if removed.instructions > 0 {
+            println!("Removed {} unreachable instructions", removed.instructions);
+        }
+
+        let before = generator.get_vm_code().len();
+        generator.optimize();
+        println!("Optimized {} instructions into {}", before, generator.get_vm_code().len());
+    }
 
-    generator.build_block(0, &mut lex);
+    let module = bytecode::Module::compiled(&generator, input_file_name, &contents);
+    process::exit(run_module(&module, &limits));
+}
 
-    let mut pl0_vm_1: vm::PL0VirtualMachine = 
-        vm::PL0VirtualMachine::load(generator.get_vm_code().to_vec());
+// Run the program, a runtime error is reported on stderr with the stack of the program
+fn run_module(module: &bytecode::Module, limits: &Limits) -> i32 {
+    let mut pl0_vm_1: vm::PL0VirtualMachine = module.load();
+    limits.apply(&mut pl0_vm_1);
     pl0_vm_1.execute();
 
+    if report_runtime_error(&pl0_vm_1, module) {
+        return 1;
+    }
     println!("Execution terminated");
+    0
+}
+
+// pl0rust lint [--allow|--warn|--deny <code|name|all>]... <file>
+fn run_lint(args: &[String]) -> i32 {
+    let mut config = lint::Config::default();
+    let mut input_file_name = None;
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let level = match arg.as_str() {
+            "--allow" | "-A" => lint::Level::Allow,
+            "--warn" | "-W" => lint::Level::Warn,
+            "--deny" | "-D" => lint::Level::Deny,
+            _ => {
+                input_file_name = Some(arg);
+                continue;
+            },
+        };
+        let code = match args.next() {
+            Some(code) => code,
+            None => {
+                eprintln!("{} expects a warning code", arg);
+                return 2;
+            },
+        };
+        if code == "all" {
+            config.set_all(level);
+            continue;
+        }
+        match lint::Lint::find(code) {
+            Some(found) => config.set(found, level),
+            None => {
+                eprintln!("Unknown warning {}", code);
+                return 2;
+            },
+        }
+    }
+
+    let input_file_name = match input_file_name {
+        Some(name) => name,
+        None => {
+            eprintln!("Please pass the pl/0 file to check.");
+            return 2;
+        },
+    };
+    // Hard errors first, warnings only make sense on a correct program
+    let (contents, _) = match compile_file(input_file_name, vm::Arithmetic::default()) {
+        Some(compiled) => compiled,
+        None => return 1,
+    };
+    let program = match ast::parser::parse(&contents) {
+        Ok(program) => program,
+        Err(error) => {
+            report_compile_errors(&contents, &[error]);
+            return 1;
+        },
+    };
+    let lines = symbol::lines::LineIndex::new(&contents);
+
+    let mut denied = 0;
+    for warning in lint::check(&program) {
+        let severity = match config.level(warning.lint) {
+            lint::Level::Allow => continue,
+            lint::Level::Warn => "warning",
+            lint::Level::Deny => {
+                denied += 1;
+                "error"
+            },
+        };
+        println!("line {}: {}[{}]: {} ({})", lines.line_of(warning.span.start), severity,
+            warning.lint.code(), warning.message, warning.lint.name());
+    }
+    if denied > 0 { 1 } else { 0 }
+}
+
+// pl0rust emit <target> <file> [-o <output>]
+fn run_emit(args: &[String]) -> i32 {
+    let (positional, output_file_name) = match output_option(args) {
+        Some(parsed) => parsed,
+        None => return 2,
+    };
+    let (target, input_file_name) = match positional.as_slice() {
+        [target, input_file_name] => (target.as_str(), input_file_name),
+        _ => {
+            eprintln!("Usage: pl0rust emit <c|x86_64|riscv64|wasm|llvm|javascript|python|rust|ir> <file> [-o <output>]");
+            return 2;
+        },
+    };
+    let program = match compile_program(input_file_name) {
+        Some(program) => program,
+        None => return 1,
+    };
+    let output = match target {
+        "c" => backend::c::emit(&program).into_bytes(),
+        "x86_64" => backend::x86_64::emit(&program).into_bytes(),
+        "riscv64" => backend::riscv64::emit(&program).into_bytes(),
+        "wasm" => backend::wasm::emit(&program),
+        "llvm" => backend::llvm::emit(&program).into_bytes(),
+        "javascript" => backend::javascript::emit(&program).into_bytes(),
+        "python" => backend::python::emit(&program).into_bytes(),
+        "rust" => backend::rust::emit(&program).into_bytes(),
+        "ir" => backend::ir::print(&program, &backend::ir::lower(&program)).into_bytes(),
+        _ => {
+            eprintln!("Unknown target {}", target);
+            return 2;
+        },
+    };
+
+    match output_file_name {
+        Some(name) => fs::write(name, output).expect("Something went wrong writing the file"),
+        None => io::stdout().write_all(&output).expect("Something went wrong writing the output"),
+    }
+    0
+}
+
+// pl0rust build <file> [-o <output>], the executable is named after the
+// file without its extension by default
+fn run_build(args: &[String]) -> i32 {
+    let (positional, output_file_name) = match output_option(args) {
+        Some(parsed) => parsed,
+        None => return 2,
+    };
+    let input_file_name = match positional.as_slice() {
+        [input_file_name] => input_file_name,
+        _ => {
+            eprintln!("Usage: pl0rust build <file> [-o <output>]");
+            return 2;
+        },
+    };
+    let output_file_name = match output_file_name {
+        Some(name) => path::PathBuf::from(name),
+        None => path::Path::new(input_file_name).with_extension(""),
+    };
+    let program = match compile_program(input_file_name) {
+        Some(program) => program,
+        None => return 1,
+    };
+
+    fs::write(&output_file_name, backend::elf::write(&program))
+        .expect("Something went wrong writing the file");
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(&output_file_name, fs::Permissions::from_mode(0o755))
+            .expect("Something went wrong making the file executable");
+    }
+    0
+}
+
+// pl0rust cfg <file> [-o <output>], the basic blocks of the vm code as a DOT graph
+fn run_cfg(args: &[String]) -> i32 {
+    let (positional, output_file_name) = match output_option(args) {
+        Some(parsed) => parsed,
+        None => return 2,
+    };
+    let input_file_name = match positional.as_slice() {
+        [input_file_name] => input_file_name,
+        _ => {
+            eprintln!("Usage: pl0rust cfg <file> [-o <output>]");
+            return 2;
+        },
+    };
+    let (_, generator) = match compile_file(input_file_name, vm::Arithmetic::default()) {
+        Some(compiled) => compiled,
+        None => return 1,
+    };
+    let procedures = compile::cfg::build(generator.get_vm_code(), generator.get_name_table());
+    let output = compile::cfg::dot(generator.get_vm_code(), &procedures);
+
+    match output_file_name {
+        Some(name) => fs::write(name, output).expect("Something went wrong writing the file"),
+        None => print!("{}", output),
+    }
+    0
+}
+
+// pl0rust callgraph [--dot] <file> [-o <output>], the calls between procedures and their analysis
+fn run_callgraph(args: &[String]) -> i32 {
+    let (positional, output_file_name) = match output_option(args) {
+        Some(parsed) => parsed,
+        None => return 2,
+    };
+    let (dot, input_file_name) = match positional.as_slice() {
+        [input_file_name] => (false, input_file_name),
+        [option, input_file_name] if option.as_str() == "--dot" => (true, input_file_name),
+        _ => {
+            eprintln!("Usage: pl0rust callgraph [--dot] <file> [-o <output>]");
+            return 2;
+        },
+    };
+    let (_, generator) = match compile_file(input_file_name, vm::Arithmetic::default()) {
+        Some(compiled) => compiled,
+        None => return 1,
+    };
+    let graph = compile::callgraph::build(generator.get_vm_code(), generator.get_name_table());
+    let output = if dot { graph.dot() } else { graph.text() };
+
+    match output_file_name {
+        Some(name) => fs::write(name, output).expect("Something went wrong writing the file"),
+        None => print!("{}", output),
+    }
+    0
+}
+
+// pl0rust ast [--format <json|sexp|dot>] [--parse-tree] <file> [-o <output>]
+fn run_ast(args: &[String]) -> i32 {
+    let (positional, output_file_name) = match output_option(args) {
+        Some(parsed) => parsed,
+        None => return 2,
+    };
+    let mut format = "sexp";
+    let mut parse_tree = false;
+    let mut input_file_name = None;
+
+    let mut positional = positional.into_iter();
+    while let Some(arg) = positional.next() {
+        match arg.as_str() {
+            "--format" => match positional.next() {
+                Some(name) => format = name.as_str(),
+                None => {
+                    eprintln!("--format expects json, sexp or dot");
+                    return 2;
+                },
+            },
+            "--parse-tree" => parse_tree = true,
+            _ if input_file_name.is_none() => input_file_name = Some(arg),
+            _ => {
+                eprintln!("Usage: pl0rust ast [--format <json|sexp|dot>] [--parse-tree] <file> [-o <output>]");
+                return 2;
+            },
+        }
+    }
+    let input_file_name = match input_file_name {
+        Some(name) => name,
+        None => {
+            eprintln!("Usage: pl0rust ast [--format <json|sexp|dot>] [--parse-tree] <file> [-o <output>]");
+            return 2;
+        },
+    };
+    let contents = fs::read_to_string(input_file_name)
+        .expect("Something went wrong reading the file");
+
+    let program = match ast::parser::parse(&contents) {
+        Ok(program) => program,
+        Err(error) => {
+            report_compile_errors(&contents, &[error]);
+            return 1;
+        },
+    };
+    let tree = ast::dump::tree(&program, &contents, parse_tree);
+    let output = match format {
+        "json" => serde_json::to_string_pretty(&ast::dump::json(&tree)).unwrap() + "\n",
+        "sexp" => ast::dump::sexp(&tree),
+        "dot" => ast::dump::dot(&tree),
+        _ => {
+            eprintln!("Unknown format {}", format);
+            return 2;
+        },
+    };
+
+    match output_file_name {
+        Some(name) => fs::write(name, output).expect("Something went wrong writing the file"),
+        None => print!("{}", output),
+    }
+    0
+}
+
+// pl0rust profile [--folded] [--top <count>] [limits] [arithmetic] <file> [-o <output>], runs the
+// program and writes the profile to the output or to stderr after it
+fn run_profile(args: &[String]) -> i32 {
+    const USAGE: &str = "Usage: pl0rust profile [--folded] [--top <count>] [--max-steps <count>] [--max-stack <cells>] [--overflow <mode>] [--bits <size>] <file> [-o <output>]";
+    let (positional, output_file_name) = match output_option(args) {
+        Some(parsed) => parsed,
+        None => return 2,
+    };
+    let (positional, limits) = match limit_options(positional) {
+        Some(parsed) => parsed,
+        None => return 2,
+    };
+    let (positional, arithmetic) = match arithmetic_options(positional) {
+        Some(parsed) => parsed,
+        None => return 2,
+    };
+    let mut folded = false;
+    let mut hot = 10;
+    let mut input_file_name = None;
+
+    let mut positional = positional.into_iter();
+    while let Some(arg) = positional.next() {
+        match arg.as_str() {
+            "--folded" => folded = true,
+            "--top" => match positional.next().and_then(|count| count.parse().ok()) {
+                Some(count) => hot = count,
+                None => {
+                    eprintln!("--top expects a number of instructions");
+                    return 2;
+                },
+            },
+            _ if input_file_name.is_none() => input_file_name = Some(arg),
+            _ => {
+                eprintln!("{}", USAGE);
+                return 2;
+            },
+        }
+    }
+    let input_file_name = match input_file_name {
+        Some(name) => name,
+        None => {
+            eprintln!("{}", USAGE);
+            return 2;
+        },
+    };
+    let module = match load_module(input_file_name, false, arithmetic) {
+        Some(module) => module,
+        None => return 1,
+    };
+    let mut machine = module.load();
+    limits.apply(&mut machine);
+    machine.enable_profile();
+    machine.execute();
+    let failed = report_runtime_error(&machine, &module);
+
+    let profile = machine.profile().expect("Profiling was enabled");
+    let output = if folded {
+        profiler::folded(profile, &module.code, &module.name_table)
+    } else {
+        profiler::report(profile, &module.code, &module.name_table, &module.line_table, &module.debug_info.source, hot)
+    };
+
+    match output_file_name {
+        Some(name) => fs::write(name, output).expect("Something went wrong writing the file"),
+        None => eprint!("{}", output),
+    }
+    if failed { 1 } else { 0 }
+}
+
+// pl0rust coverage [--lcov] [limits] [arithmetic] <file> [-o <output>], runs the program and
+// writes the annotated source or the lcov tracefile to the output or to stderr after it
+fn run_coverage(args: &[String]) -> i32 {
+    let (positional, output_file_name) = match output_option(args) {
+        Some(parsed) => parsed,
+        None => return 2,
+    };
+    let (positional, limits) = match limit_options(positional) {
+        Some(parsed) => parsed,
+        None => return 2,
+    };
+    let (positional, arithmetic) = match arithmetic_options(positional) {
+        Some(parsed) => parsed,
+        None => return 2,
+    };
+    let (lcov, input_file_name) = match positional.as_slice() {
+        [input_file_name] => (false, input_file_name),
+        [option, input_file_name] if option.as_str() == "--lcov" => (true, input_file_name),
+        _ => {
+            eprintln!("Usage: pl0rust coverage [--lcov] [--max-steps <count>] [--max-stack <cells>] [--overflow <mode>] [--bits <size>] <file> [-o <output>]");
+            return 2;
+        },
+    };
+    let module = match load_module(input_file_name, false, arithmetic) {
+        Some(module) => module,
+        None => return 1,
+    };
+    let mut machine = module.load();
+    limits.apply(&mut machine);
+    machine.enable_coverage();
+    machine.execute();
+    let failed = report_runtime_error(&machine, &module);
+
+    let debug_info = &module.debug_info;
+    let report = coverage::analyze(machine.coverage().expect("Coverage was enabled"), &module.code,
+        &module.name_table, debug_info);
+    let output = if lcov {
+        coverage::lcov(&report, &debug_info.file)
+    } else {
+        coverage::listing(&report, &debug_info.source)
+    };
+
+    match output_file_name {
+        Some(name) => fs::write(name, output).expect("Something went wrong writing the file"),
+        None => eprint!("{}", output),
+    }
+    if failed { 1 } else { 0 }
+}
+
+// pl0rust compile [-O] [arithmetic] <file> [-o <output>], the bytecode file is named
+// after the file with the extension pl0b by default
+fn run_compile(args: &[String]) -> i32 {
+    let (positional, output_file_name) = match output_option(args) {
+        Some(parsed) => parsed,
+        None => return 2,
+    };
+    let (positional, arithmetic) = match arithmetic_options(positional) {
+        Some(parsed) => parsed,
+        None => return 2,
+    };
+    let (optimize, input_file_name) = match positional.as_slice() {
+        [input_file_name] => (false, input_file_name),
+        [option, input_file_name] if option.as_str() == "-O" => (true, input_file_name),
+        _ => {
+            eprintln!("Usage: pl0rust compile [-O] [--overflow <mode>] [--bits <size>] <file> [-o <output>]");
+            return 2;
+        },
+    };
+    let output_file_name = match output_file_name {
+        Some(name) => path::PathBuf::from(name),
+        None => path::Path::new(input_file_name).with_extension("pl0b"),
+    };
+    let module = match load_module(input_file_name, optimize, arithmetic) {
+        Some(module) => module,
+        None => return 1,
+    };
+
+    fs::write(&output_file_name, bytecode::write(&module))
+        .expect("Something went wrong writing the file");
+    0
+}
+
+// pl0rust trace [limits] [arithmetic] <file> [-o <output>], runs the program writing every
+// instruction executed to the output or to stderr
+fn run_trace(args: &[String]) -> i32 {
+    let (positional, output_file_name) = match output_option(args) {
+        Some(parsed) => parsed,
+        None => return 2,
+    };
+    let (positional, limits) = match limit_options(positional) {
+        Some(parsed) => parsed,
+        None => return 2,
+    };
+    let (positional, arithmetic) = match arithmetic_options(positional) {
+        Some(parsed) => parsed,
+        None => return 2,
+    };
+    let input_file_name = match positional.as_slice() {
+        [input_file_name] => input_file_name,
+        _ => {
+            eprintln!("Usage: pl0rust trace [--max-steps <count>] [--max-stack <cells>] [--overflow <mode>] [--bits <size>] <file> [-o <output>]");
+            return 2;
+        },
+    };
+    let module = match load_module(input_file_name, false, arithmetic) {
+        Some(module) => module,
+        None => return 1,
+    };
+    let trace: Box<dyn Write> = match output_file_name {
+        Some(name) => Box::new(io::BufWriter::new(fs::File::create(name)
+            .expect("Something went wrong creating the file"))),
+        None => Box::new(io::stderr()),
+    };
+
+    let mut machine = module.load();
+    limits.apply(&mut machine);
+    machine.enable_trace(trace);
+    machine.execute();
+    if report_runtime_error(&machine, &module) { 1 } else { 0 }
+}
+
+// Program of a bytecode file or compiled from a source for the arithmetic,
+// or None once the errors are reported. A bytecode file keeps its own arithmetic
+fn load_module(input_file_name: &str, optimize: bool, arithmetic: vm::Arithmetic) -> Option<bytecode::Module> {
+    let contents = fs::read(input_file_name)
+        .expect("Something went wrong reading the file");
+    if bytecode::is_bytecode(&contents) {
+        return match bytecode::read(&contents) {
+            Ok(module) => Some(module),
+            Err(error) => {
+                eprintln!("{}: error: {}", input_file_name, error);
+                None
+            },
+        };
+    }
+    let contents = String::from_utf8_lossy(&contents);
+
+    let mut generator = compile_source(&contents, arithmetic)?;
+    if optimize {
+        generator.eliminate_dead_code();
+        generator.optimize();
+    }
+    Some(bytecode::Module::compiled(&generator, input_file_name, &contents))
+}
+
+// Limits of the vm given with --max-steps and --max-stack
+#[derive(Default)]
+struct Limits {
+    steps: Option<u64>,
+    stack: Option<usize>,
+}
+
+impl Limits {
+    fn apply(&self, machine: &mut vm::PL0VirtualMachine) {
+        machine.set_step_limit(self.steps);
+        if let Some(stack) = self.stack {
+            machine.set_stack_limit(stack);
+        }
+    }
+}
+
+// Arguments left once the limits of the vm are taken out
+fn limit_options(args: Vec<&String>) -> Option<(Vec<&String>, Limits)> {
+    let mut limits = Limits::default();
+    let mut positional = Vec::new();
+
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--max-steps" => match args.next().and_then(|count| count.parse().ok()) {
+                Some(count) => limits.steps = Some(count),
+                None => {
+                    eprintln!("--max-steps expects a number of instructions");
+                    return None;
+                },
+            },
+            "--max-stack" => match args.next().and_then(|cells| cells.parse().ok()) {
+                Some(cells) => limits.stack = Some(cells),
+                None => {
+                    eprintln!("--max-stack expects a number of cells");
+                    return None;
+                },
+            },
+            _ => positional.push(arg),
+        }
+    }
+    Some((positional, limits))
+}
+
+// Arguments left once the arithmetic given with --overflow and --bits is taken out
+fn arithmetic_options(args: Vec<&String>) -> Option<(Vec<&String>, vm::Arithmetic)> {
+    let mut arithmetic = vm::Arithmetic::default();
+    let mut positional = Vec::new();
+
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--overflow" => match args.next().map(|mode| mode.as_str()) {
+                Some("checked") => arithmetic.overflow = vm::Overflow::Checked,
+                Some("wrapping") => arithmetic.overflow = vm::Overflow::Wrapping,
+                Some("saturating") => arithmetic.overflow = vm::Overflow::Saturating,
+                _ => {
+                    eprintln!("--overflow expects checked, wrapping or saturating");
+                    return None;
+                },
+            },
+            "--bits" => match args.next().and_then(|bits| bits.parse().ok()).filter(|bits| vm::Arithmetic::WORD_SIZES.contains(bits)) {
+                Some(bits) => arithmetic.bits = bits,
+                None => {
+                    eprintln!("--bits expects 16, 32 or 64");
+                    return None;
+                },
+            },
+            _ => positional.push(arg),
+        }
+    }
+    Some((positional, arithmetic))
+}
+
+// Report the runtime error which stopped the vm on stderr, whether there was one
+fn report_runtime_error(machine: &vm::PL0VirtualMachine, module: &bytecode::Module) -> bool {
+    match backtrace::capture(machine, &module.name_table, &module.line_table) {
+        Some(report) => {
+            eprint!("{}", report.render());
+            true
+        },
+        None => false,
+    }
+}
+
+// Positional arguments and the file name given with -o
+fn output_option(args: &[String]) -> Option<(Vec<&String>, Option<&String>)> {
+    let mut output_file_name = None;
+    let mut positional = Vec::new();
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        if arg == "-o" {
+            match args.next() {
+                Some(name) => output_file_name = Some(name),
+                None => {
+                    eprintln!("-o expects a file name");
+                    return None;
+                },
+            }
+        } else {
+            positional.push(arg);
+        }
+    }
+    Some((positional, output_file_name))
+}
+
+// Program for the backends, or None once the errors are reported
+fn compile_program(input_file_name: &str) -> Option<backend::Program> {
+    let (contents, _) = compile_file(input_file_name, vm::Arithmetic::default())?;
+
+    match ast::parser::parse(&contents).and_then(|program| backend::lower(&program)) {
+        Ok(program) => Some(program),
+        Err(error) => {
+            report_compile_errors(&contents, &[error]);
+            None
+        },
+    }
+}
+
+// Source of a file and the code generator with its program compiled for the arithmetic,
+// or None once the errors are reported
+fn compile_file(input_file_name: &str, arithmetic: vm::Arithmetic) -> Option<(String, compile::codegen::CodeGenerator)> {
+    let contents = fs::read(input_file_name)
+        .expect("Something went wrong reading the file");
+    let contents = String::from_utf8_lossy(&contents).into_owned();
+
+    let generator = compile_source(&contents, arithmetic)?;
+    Some((contents, generator))
+}
+
+// Code generator with the program of the source compiled for the arithmetic, or None once the errors are reported
+fn compile_source(contents: &str, arithmetic: vm::Arithmetic) -> Option<compile::codegen::CodeGenerator> {
+    let mut lex: symbol::io::PL0Lexer = symbol::io::PL0Lexer::create_from_content(contents);
+    let mut generator = compile::codegen::CodeGenerator::new();
+    generator.set_arithmetic(arithmetic);
+    if !generator.build_block(0, &mut lex) {
+        report_compile_errors(contents, generator.get_errors());
+        return None;
+    }
+    Some(generator)
+}
+
+// Compile errors on stderr with the line they are on
+fn report_compile_errors(contents: &str, errors: &[compile::error::CompileError]) {
+    let lines = symbol::lines::LineIndex::new(contents);
+    for error in errors {
+        eprintln!("line {}: error: {}", lines.line_of(error.span.start), error);
+    }
 }
@@ -0,0 +1,310 @@
+use std::collections::HashMap;
+use std::fmt::Write;
+
+use crate::vm;
+use crate::compile::cfg;
+use crate::compile::nametab;
+
+
+/*
+ * Counts of a profiled run. Every call stack seen is kept once as a node
+ * below the stack of its caller, the instructions are counted on the
+ * stack they run in, so a procedure's own time is on the stacks ending
+ * with it. A call goes down with Cal and comes back with Opr 0.
+ */
+#[derive(Clone, Debug, PartialEq)]
+pub struct Profile {
+    pub counts: Vec<u64>,       // executions of every pc
+    pub stacks: Vec<Stack>,     // the first is the main procedure
+    current: usize,
+    children: HashMap<(usize, usize), usize>,   // (caller stack, called address) to the stack
+}
+
+/* call stack, the address called below the stack of its caller */
+#[derive(Clone, Debug, PartialEq)]
+pub struct Stack {
+    pub caller: Option<usize>,
+    pub address: usize,
+    pub calls: u64,
+    pub instructions: u64,
+}
+
+impl Profile {
+    pub fn new(size: usize) -> Profile {
+        Profile {
+            counts: vec![0; size],
+            // Execution starts with the jump at 0 into the main procedure
+            stacks: vec![Stack { caller: None, address: 0, calls: 1, instructions: 0 }],
+            current: 0,
+            children: HashMap::new(),
+        }
+    }
+
+    // Count the instruction about to be executed at pc
+    pub fn record(&mut self, pc: usize, instruction: vm::Instruction) {
+        if pc >= self.counts.len() {
+            self.counts.resize(pc + 1, 0);
+        }
+        self.counts[pc] += 1;
+        self.stacks[self.current].instructions += 1;
+
+        match instruction.f {
+            vm::Fct::Cal => {
+                let stacks = &mut self.stacks;
+                let current = self.current;
+                let stack = *self.children.entry((current, instruction.a)).or_insert_with(|| {
+                    stacks.push(Stack { caller: Some(current), address: instruction.a, calls: 0, instructions: 0 });
+                    stacks.len() - 1
+                });
+                self.stacks[stack].calls += 1;
+                self.current = stack;
+            },
+            vm::Fct::Opr if instruction.a == 0 => {
+                if let Some(caller) = self.stacks[self.current].caller {
+                    self.current = caller;
+                }
+            },
+            _ => {},
+        }
+    }
+
+    pub fn total(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+}
+
+/* instructions spent in a procedure, inclusive counts those of its callees */
+#[derive(Clone, Debug, PartialEq)]
+pub struct ProcedureProfile {
+    pub name: String,
+    pub calls: u64,
+    pub inclusive: u64,
+    pub exclusive: u64,
+}
+
+/*
+ * Counts per procedure, in the order of the name table. A recursive
+ * procedure's inclusive count is taken from its outermost activations
+ * only, so instructions are never counted twice for the same procedure.
+ */
+pub fn procedures(profile: &Profile, code: &[vm::Instruction], name_table: &[nametab::NameTableItem]) -> Vec<ProcedureProfile> {
+    let blocks = cfg::build(code, name_table);
+    let mut procedures: Vec<ProcedureProfile> = blocks.iter()
+        .map(|procedure| ProcedureProfile { name: procedure.name.clone(), calls: 0, inclusive: 0, exclusive: 0 })
+        .collect();
+    let indexes = stack_procedures(profile, code, &blocks);
+
+    let mut totals: Vec<u64> = profile.stacks.iter().map(|stack| stack.instructions).collect();
+    let mut children: Vec<Vec<usize>> = vec![Vec::new(); profile.stacks.len()];
+    // Callers are created before their callees
+    for (index, stack) in profile.stacks.iter().enumerate().rev() {
+        if let Some(caller) = stack.caller {
+            totals[caller] += totals[index];
+            children[caller].push(index);
+        }
+        if let Some(procedure) = indexes[index] {
+            procedures[procedure].calls += stack.calls;
+            procedures[procedure].exclusive += stack.instructions;
+        }
+    }
+
+    // Walk down the stacks keeping how often each procedure is active,
+    // without recursing as deep as the program did
+    let mut active = vec![0; procedures.len()];
+    let mut pending = vec![(0, true)];
+    while let Some((index, entering)) = pending.pop() {
+        let procedure = match indexes[index] {
+            Some(procedure) => procedure,
+            None => {
+                if entering {
+                    pending.extend(children[index].iter().map(|child| (*child, true)));
+                }
+                continue;
+            },
+        };
+        if entering {
+            if active[procedure] == 0 {
+                procedures[procedure].inclusive += totals[index];
+            }
+            active[procedure] += 1;
+            pending.push((index, false));
+            pending.extend(children[index].iter().map(|child| (*child, true)));
+        } else {
+            active[procedure] -= 1;
+        }
+    }
+    procedures
+}
+
+// Procedure of every stack, a call compiled before the callee went to its leading jump
+fn stack_procedures(profile: &Profile, code: &[vm::Instruction], blocks: &[cfg::Procedure]) -> Vec<Option<usize>> {
+    profile.stacks.iter()
+        .map(|stack| {
+            let entry = cfg::callee(code, stack.address);
+            blocks.iter().position(|procedure| procedure.entry == entry)
+        })
+        .collect()
+}
+
+/*
+ * Folded stacks for flamegraph tools, one line per call stack with the
+ * procedure names from the main procedure down separated by semicolons,
+ * then the instructions executed in it.
+ */
+pub fn folded(profile: &Profile, code: &[vm::Instruction], name_table: &[nametab::NameTableItem]) -> String {
+    let blocks = cfg::build(code, name_table);
+    let indexes = stack_procedures(profile, code, &blocks);
+    let names: Vec<String> = indexes.iter().zip(&profile.stacks)
+        .map(|(index, stack)| match index {
+            Some(index) => blocks[*index].name.clone(),
+            None => format!("@{}", stack.address),
+        })
+        .collect();
+
+    let mut output = String::new();
+    for (index, stack) in profile.stacks.iter().enumerate() {
+        if stack.instructions == 0 {
+            continue;
+        }
+        let mut path = vec![names[index].as_str()];
+        let mut caller = stack.caller;
+        while let Some(current) = caller {
+            path.push(names[current].as_str());
+            caller = profile.stacks[current].caller;
+        }
+        path.reverse();
+        writeln!(output, "{} {}", path.join(";"), stack.instructions).unwrap();
+    }
+    output
+}
+
+// Source line of an instruction, the line of the statement it belongs to
+pub fn line_of(pc: usize, procedures: &[cfg::Procedure], line_table: &[(usize, usize)]) -> Option<usize> {
+    let procedure = procedures.iter().find(|procedure| {
+        procedure.blocks.first().is_some_and(|first| first.start <= pc)
+            && procedure.blocks.last().is_some_and(|last| pc < last.end)
+    })?;
+    let statement = line_table.partition_point(|(start, _)| *start <= pc);
+    match statement.checked_sub(1).map(|index| line_table[index]) {
+        Some((start, line)) if start >= procedure.entry => Some(line),
+        // Before the first statement, like the Inte of the procedure
+        _ => line_table.iter().find(|(start, _)| *start >= procedure.entry).map(|(_, line)| *line),
+    }
+}
+
+/*
+ * Text report of a profiled run, the counts of every procedure and the
+ * most executed instructions with the source line they come from.
+ */
+pub fn report(profile: &Profile, code: &[vm::Instruction], name_table: &[nametab::NameTableItem],
+    line_table: &[(usize, usize)], content: &str, hot: usize) -> String {
+    let total = profile.total();
+    let percent = |count: u64| if total == 0 { 0.0 } else { count as f64 * 100.0 / total as f64 };
+
+    let mut output = String::new();
+    writeln!(output, "instructions executed: {}\n", total).unwrap();
+    writeln!(output, "{:<16} {:>8} {:>12} {:>7} {:>12} {:>7}", "procedure", "calls", "inclusive", "%", "exclusive", "%").unwrap();
+    for procedure in procedures(profile, code, name_table) {
+        writeln!(output, "{:<16} {:>8} {:>12} {:>7.2} {:>12} {:>7.2}", procedure.name, procedure.calls,
+            procedure.inclusive, percent(procedure.inclusive), procedure.exclusive, percent(procedure.exclusive)).unwrap();
+    }
+
+    let blocks = cfg::build(code, name_table);
+    let lines: Vec<&str> = content.lines().collect();
+    let mut pcs: Vec<usize> = (0..profile.counts.len().min(code.len()))
+        .filter(|pc| profile.counts[*pc] > 0)
+        .collect();
+    // Most executed first, then in the order of the code
+    pcs.sort_by(|a, b| profile.counts[*b].cmp(&profile.counts[*a]).then(a.cmp(b)));
+
+    writeln!(output, "\nhot instructions\n{:>6} {:>12} {:>7} {:>6}  {:<12} source", "pc", "count", "%", "line", "instruction").unwrap();
+    for pc in pcs.into_iter().take(hot) {
+        let count = profile.counts[pc];
+        let (line, source) = match line_of(pc, &blocks, line_table) {
+            Some(line) => (line.to_string(), lines.get(line - 1).map_or("", |text| text.trim())),
+            None => (String::from("-"), ""),
+        };
+        writeln!(output, "{:>6} {:>12} {:>7.2} {:>6}  {:<12} {}", pc, count, percent(count), line,
+            code[pc].to_string(), source).unwrap();
+    }
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::compile::codegen;
+    use crate::profiler;
+    use crate::symbol;
+    use crate::vm;
+
+    fn profile(content: &str) -> (codegen::CodeGenerator, profiler::Profile) {
+        let mut lexer = symbol::io::PL0Lexer::create_from_content(content);
+        let mut generator = codegen::CodeGenerator::new();
+        assert!(generator.build_block(0, &mut lexer));
+
+        let mut machine = vm::PL0VirtualMachine::load(generator.get_vm_code().to_vec());
+        machine.redirect(Box::new(std::io::empty()), Box::new(std::io::sink()));
+        machine.enable_profile();
+        machine.execute();
+        let profile = machine.profile().unwrap().clone();
+        (generator, profile)
+    }
+
+    /* test the instructions of callees count in the inclusive time of callers */
+    #[test]
+    fn test_procedures() {
+        let (generator, profile) = profile("var n;
+procedure q; n := n + 1;
+procedure p; begin call q; call q end;
+begin n := 0; while n < 4 do call p end.");
+        let code = generator.get_vm_code();
+        let procedures = profiler::procedures(&profile, code, generator.get_name_table());
+
+        // q: Inte, Lod, Lit, Opr, Sto, Opr 0
+        assert_eq!(procedures[1].name, "q");
+        assert_eq!((procedures[1].calls, procedures[1].inclusive, procedures[1].exclusive), (4, 6 * 4, 6 * 4));
+        // p: Inte, Cal, Cal, Opr 0
+        assert_eq!(procedures[2].name, "p");
+        assert_eq!((procedures[2].calls, procedures[2].inclusive, procedures[2].exclusive), (2, 2 * (4 + 12), 2 * 4));
+        assert_eq!(procedures[0].calls, 1);
+        assert_eq!(procedures[0].inclusive, profile.total());
+        assert_eq!(procedures.iter().map(|procedure| procedure.exclusive).sum::<u64>(), profile.total());
+    }
+
+    /* test recursion is counted once in the inclusive time and folds by stack */
+    #[test]
+    fn test_recursion() {
+        let (generator, profile) = profile("var n;
+procedure r; if n > 0 then begin n := n - 1; call r end;
+begin n := 2; call r end.");
+        let code = generator.get_vm_code();
+        let procedures = profiler::procedures(&profile, code, generator.get_name_table());
+
+        assert_eq!(procedures[1].calls, 3);
+        assert_eq!(procedures[1].inclusive, procedures[1].exclusive);
+        assert_eq!(profiler::folded(&profile, code, generator.get_name_table()), "_main 6\n_main;r 11\n_main;r;r 11\n_main;r;r;r 6\n");
+    }
+
+    /* test hot instructions are the loop body, mapped to its line */
+    #[test]
+    fn test_report() {
+        let content = "var i;
+begin
+  i := 0;
+  while i < 100 do
+    i := i + 1
+end.";
+        let (generator, profile) = profile(content);
+        let output = profiler::report(&profile, generator.get_vm_code(), generator.get_name_table(),
+            generator.get_line_table(), content, 3);
+
+        assert!(output.starts_with("instructions executed: 909\n"));
+        assert!(output.contains("_main                   1          909  100.00          909  100.00\n"));
+        let hot: Vec<&str> = output.lines().skip_while(|line| *line != "hot instructions").skip(2).collect();
+        assert_eq!(hot, [
+            "     4          101   11.11      4  Lod  0 0     while i < 100 do",
+            "     5          101   11.11      4  Lit  0 100   while i < 100 do",
+            "     6          101   11.11      4  Opr  0 10    while i < 100 do",
+        ]);
+    }
+}
@@ -0,0 +1,278 @@
+use std::cell::RefCell;
+use std::io;
+use std::io::Write;
+use std::panic;
+use std::rc::Rc;
+
+use logos::Logos;
+
+use crate::symbol;
+use crate::vm;
+use crate::compile::codegen;
+use crate::compile::nametab;
+
+const PROMPT: &str = "pl0> ";
+const CONTINUATION_PROMPT: &str = "...> ";
+
+const HELP: &str = "Declare with const/var/procedure or type a statement, an empty line submits an incomplete input.
+:code   list the vm code
+:table  list the name table
+:stack  show the vm stack
+:help   show this message
+:quit   leave";
+
+// Input shared between the repl and the read instruction of the vm
+#[derive(Clone)]
+struct SharedReader(Rc<RefCell<Box<dyn io::BufRead>>>);
+
+impl io::Read for SharedReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.borrow_mut().read(buf)
+    }
+}
+
+pub struct Repl {
+    generator: codegen::CodeGenerator,
+    pl0_vm: vm::PL0VirtualMachine,
+
+    input: SharedReader,
+    output: Box<dyn io::Write>,
+}
+
+impl Repl {
+    pub fn new(input: Box<dyn io::BufRead>, output: Box<dyn io::Write>, vm_output: Box<dyn io::Write>) -> Repl {
+        let input = SharedReader(Rc::new(RefCell::new(input)));
+
+        let mut pl0_vm = vm::PL0VirtualMachine::load(Vec::new());
+        // Read byte by byte so that the vm never buffers lines meant for the repl
+        pl0_vm.redirect(Box::new(io::BufReader::with_capacity(1, input.clone())), vm_output);
+        pl0_vm.prepare();
+
+        Repl {
+            generator: codegen::CodeGenerator::new(),
+            pl0_vm,
+            input,
+            output,
+        }
+    }
+
+    pub fn run(&mut self) -> io::Result<()> {
+        let mut source = String::new();
+
+        loop {
+            let prompt = if source.is_empty() { PROMPT } else { CONTINUATION_PROMPT };
+            write!(self.output, "{}", prompt)?;
+            self.output.flush()?;
+
+            let mut line = String::new();
+            if self.input.0.borrow_mut().read_line(&mut line)? == 0 {
+                // End of input
+                writeln!(self.output)?;
+                return Ok(());
+            }
+
+            if source.is_empty() {
+                match line.trim() {
+                    "" => continue,
+                    ":quit" | ":q" => return Ok(()),
+                    ":help" => {
+                        writeln!(self.output, "{}", HELP)?;
+                        continue;
+                    },
+                    ":code" => {
+                        self.list_code()?;
+                        continue;
+                    },
+                    ":table" => {
+                        self.list_table()?;
+                        continue;
+                    },
+                    ":stack" => {
+                        self.list_stack()?;
+                        continue;
+                    },
+                    command if command.starts_with(':') => {
+                        writeln!(self.output, "Unknown command {}, try :help", command)?;
+                        continue;
+                    },
+                    _ => {},
+                }
+            }
+
+            // An empty line forces an incomplete input to be submitted
+            let force = line.trim().is_empty();
+            source.push_str(&line);
+            if !force && !is_complete(&source) {
+                continue;
+            }
+
+            self.evaluate(&source)?;
+            source.clear();
+        }
+    }
+
+    pub fn evaluate(&mut self, source: &str) -> io::Result<()> {
+        let mut lex: symbol::io::PL0Lexer = symbol::io::PL0Lexer::create_from_content(source);
+        let entry = match self.generator.build_incremental(&mut lex) {
+            Some(entry) => entry,
+            None => {
+                for error in self.generator.get_errors() {
+                    writeln!(self.output, "line {}: {}", lex.line_of(error.span.start), error)?;
+                }
+                return Ok(());
+            },
+        };
+
+        let code = self.generator.get_vm_code().to_vec();
+        let end = code.len();
+        self.pl0_vm.reload(code);
+
+        // A failing statement must not end the session, report it quietly
+        let pl0_vm = &mut self.pl0_vm;
+        let hook = panic::take_hook();
+        panic::set_hook(Box::new(|_| {}));
+        let result = panic::catch_unwind(panic::AssertUnwindSafe(|| {
+            pl0_vm.run_until(entry, end);
+        }));
+        panic::set_hook(hook);
+
+        let reason = match result {
+            Err(payload) => payload.downcast_ref::<&str>().map(|reason| reason.to_string())
+                .or_else(|| payload.downcast_ref::<String>().cloned())
+                .or_else(|| Some(String::new())),
+            Ok(()) => self.pl0_vm.error().map(|error| error.to_string()),
+        };
+        if let Some(reason) = reason {
+            // Drop the activation records left by the failed statement
+            let globals = self.generator.get_name_table()[0].size;
+            self.pl0_vm.unwind(3, 3 + globals);
+            writeln!(self.output, "Execution aborted: {}", reason)?;
+        }
+        Ok(())
+    }
+
+    fn list_code(&mut self) -> io::Result<()> {
+        for (pc, instruction) in self.generator.get_vm_code().iter().enumerate() {
+            writeln!(self.output, "{:>4}  {}", pc, instruction)?;
+        }
+        Ok(())
+    }
+
+    fn list_table(&mut self) -> io::Result<()> {
+        writeln!(self.output, "{:<12} {:<10} {:>5} {:>8} {:>5}", "name", "kind", "level", "val/adr", "size")?;
+        for item in self.generator.get_name_table().iter().skip(1) {
+            let value = match item.kind {
+                nametab::NameTableObject::Constant => item.val,
+                _ => item.adr as i64,
+            };
+            writeln!(self.output, "{:<12} {:<10} {:>5} {:>8} {:>5}",
+                item.name, format!("{:?}", item.kind), item.level, value, item.size)?;
+        }
+        Ok(())
+    }
+
+    fn list_stack(&mut self) -> io::Result<()> {
+        writeln!(self.output, "bp {} sp {}", self.pl0_vm.bp(), self.pl0_vm.sp())?;
+        for (address, value) in self.pl0_vm.stack().iter().enumerate() {
+            // Name the slots of global variables
+            let name = self.generator.get_name_table().iter()
+                .find(|item| item.kind == nametab::NameTableObject::Variable && item.level == 0
+                    && 3 + item.adr == address)
+                .map(|item| item.name.as_str())
+                .unwrap_or("");
+            writeln!(self.output, "{:>4}  {:>8}  {}", address, value, name)?;
+        }
+        Ok(())
+    }
+}
+
+// Whether the input can be compiled, or more lines are expected
+fn is_complete(source: &str) -> bool {
+    // Errors found at the very end mean the input stops too early
+    let mut lex: symbol::io::PL0Lexer = symbol::io::PL0Lexer::create_from_content(source);
+    let mut generator = codegen::CodeGenerator::new();
+    generator.build_incremental(&mut lex);
+    let end = source.trim_end().len();
+    if generator.get_errors().iter().any(|error| error.span.start >= end) {
+        return false;
+    }
+
+    // Statements may be empty, so look for a dangling symbol as well
+    let last = symbol::Symbol::lexer(source).last().unwrap_or(symbol::Symbol::Nul);
+    !matches!(last, symbol::Symbol::Becomes | symbol::Symbol::Plus | symbol::Symbol::Minus
+        | symbol::Symbol::Times | symbol::Symbol::Slash | symbol::Symbol::Eql | symbol::Symbol::Neq
+        | symbol::Symbol::Lss | symbol::Symbol::Leq | symbol::Symbol::Gtr | symbol::Symbol::Geq
+        | symbol::Symbol::Comma | symbol::Symbol::Oddsym | symbol::Symbol::Ifsym
+        | symbol::Symbol::Thensyn | symbol::Symbol::Whilesym | symbol::Symbol::Dosym
+        | symbol::Symbol::Callsym | symbol::Symbol::Constsym | symbol::Symbol::Varsym
+        | symbol::Symbol::Procsym)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use crate::repl;
+    use crate::vm;
+
+    fn session(input: &str) -> String {
+        let output = vm::SharedBuffer::default();
+        let mut repl = repl::Repl::new(Box::new(Cursor::new(input.to_string().into_bytes())),
+            Box::new(output.clone()), Box::new(output.clone()));
+        repl.run().unwrap();
+        output.take()
+    }
+
+    /* test globals persist between inputs */
+    #[test]
+    fn test_globals_persist() {
+        let output = session("const a = 10;\nvar x;\nx := a * 2\nwrite(x + 1)\n");
+        assert!(output.contains("21 \n"));
+    }
+
+    /* test multi-line input and procedures */
+    #[test]
+    fn test_multi_line_procedure() {
+        let output = session("var x;\nprocedure inc;\nbegin\n  x := x + 1\nend;\ncall inc\ncall inc\nwrite(x)\n");
+        assert!(output.contains("...> "));
+        assert!(output.contains("2 \n"));
+    }
+
+    /* test a wrong input is discarded */
+    #[test]
+    fn test_error_discarded() {
+        let output = session("var x;\nx := y\nwrite(x)\n:table\n");
+        assert!(output.contains("line 1: Undeclared identifier y"));
+        assert!(output.contains("0 \n"));
+        assert!(output.contains("x            Variable       0        0     0"));
+    }
+
+    /* test a runtime error aborts the statement only */
+    #[test]
+    fn test_runtime_error() {
+        let output = session("var x;\nx := 1 / x\nx := 5\nwrite(x)\n");
+        assert!(output.contains("Execution aborted: division by zero\n"));
+        assert!(output.contains("5 \n"));
+    }
+
+    /* test read shares the input */
+    #[test]
+    fn test_read_input() {
+        let output = session("var x;\nread(x)\n42\nwrite(x)\n:stack\n");
+        assert!(output.contains("42 \n"));
+        assert!(output.contains("   3        42  x"));
+    }
+
+    /* test completeness of input */
+    #[test]
+    fn test_is_complete() {
+        assert!(repl::is_complete("x := 1"));
+        assert!(!repl::is_complete("x := "));
+        assert!(!repl::is_complete("begin x := 1"));
+        assert!(!repl::is_complete("procedure p; begin x := 1 end"));
+        assert!(repl::is_complete("procedure p; begin x := 1 end;"));
+        assert!(!repl::is_complete("while x < 1 do"));
+        assert!(!repl::is_complete("procedure p;\n"));
+        assert!(!repl::is_complete("write(1"));
+    }
+}
@@ -0,0 +1,69 @@
+use std::io;
+use std::io::{ BufRead, Write };
+
+use serde_json::Value;
+
+// Base protocol shared by the debug adapter and the language server:
+// every message is a JSON body preceded by a Content-Length header.
+
+pub fn read_message(reader: &mut dyn BufRead) -> io::Result<Option<Value>> {
+    let mut content_length: Option<usize> = None;
+
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header)? == 0 {
+            // End of stream
+            return Ok(None);
+        }
+        let header = header.trim_end();
+        if header.is_empty() {
+            if content_length.is_some() {
+                break;
+            }
+            // Tolerate blank lines between messages
+            continue;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            if name.trim().eq_ignore_ascii_case("Content-Length") {
+                content_length = value.trim().parse::<usize>().ok();
+            }
+        }
+    }
+
+    let mut body = vec![0; content_length.unwrap_or(0)];
+    reader.read_exact(&mut body)?;
+
+    serde_json::from_slice(&body)
+        .map(Some)
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+}
+
+pub fn write_message(writer: &mut dyn Write, message: &Value) -> io::Result<()> {
+    let body = message.to_string();
+    write!(writer, "Content-Length: {}\r\n\r\n{}", body.len(), body)?;
+    writer.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use serde_json::json;
+
+    use crate::rpc;
+
+    #[test]
+    fn test_message_round_trip() {
+        let mut buffer: Vec<u8> = Vec::new();
+        rpc::write_message(&mut buffer, &json!({ "seq": 1, "command": "initialize" })).unwrap();
+        rpc::write_message(&mut buffer, &json!({ "seq": 2, "command": "launch" })).unwrap();
+
+        let mut reader = Cursor::new(buffer);
+        let first = rpc::read_message(&mut reader).unwrap().unwrap();
+        let second = rpc::read_message(&mut reader).unwrap().unwrap();
+
+        assert_eq!(first["command"], "initialize");
+        assert_eq!(second["seq"], 2);
+        assert!(rpc::read_message(&mut reader).unwrap().is_none());
+    }
+}
@@ -9,25 +9,38 @@ pub struct PL0Lexer<'a> {
     current_symbol: symbol::Symbol,
     current_symbol_content: String,
     previous_symbol: symbol::Symbol,
+    previous_end: usize,
     keep_current_once: bool,
+
+    lines: symbol::lines::LineIndex,
+    comments: Vec<Range<usize>>,
 }
 
 impl PL0Lexer<'_> {
-    pub fn create_from_content(content: &str) -> PL0Lexer {
-        let lexer = PL0Lexer {
+    pub fn create_from_content(content: &str) -> PL0Lexer<'_> {
+        PL0Lexer {
             lexer: symbol::Symbol::lexer(content),
             current_symbol: symbol::Symbol::Nul,
             current_symbol_content: String::new(),
             previous_symbol: symbol::Symbol::Nul,
+            previous_end: 0,
             keep_current_once: false,
-        };
-        lexer
+            lines: symbol::lines::LineIndex::new(content),
+            comments: Vec::new(),
+        }
     }
 
+    #[allow(clippy::should_implement_trait)]
     pub fn next(&mut self) -> &symbol::Symbol {
         if !self.keep_current_once {
             self.previous_symbol = self.current_symbol;
+            self.previous_end = self.lexer.span().end;
             self.current_symbol = self.lexer.next().unwrap_or(symbol::Symbol::EOF);
+            while self.current_symbol == symbol::Symbol::Comment {
+                // Comments are kept aside
+                self.comments.push(self.lexer.span());
+                self.current_symbol = self.lexer.next().unwrap_or(symbol::Symbol::EOF);
+            }
             self.current_symbol_content = self.lexer.slice().to_string();
         } else {
             self.keep_current_once = false;
@@ -47,10 +60,30 @@ impl PL0Lexer<'_> {
         self.lexer.span()
     }
 
+    // Line of the current symbol, starting from 1
+    pub fn current_line(&self) -> usize {
+        self.line_of(self.lexer.span().start)
+    }
+
+    // Line containing the byte offset, starting from 1
+    pub fn line_of(&self, offset: usize) -> usize {
+        self.lines.line_of(offset)
+    }
+
+    // Comments skipped so far
+    pub fn comments(&self) -> &Vec<Range<usize>> {
+        &self.comments
+    }
+
     pub fn previous(&self) -> symbol::Symbol {
         self.previous_symbol
     }
 
+    // Where the symbol before the current one ends
+    pub fn previous_end(&self) -> usize {
+        self.previous_end
+    }
+
     pub fn keep_once(&mut self) {
         self.keep_current_once = true;
     }
@@ -0,0 +1,92 @@
+/* where the lines of a source begin, to turn byte offsets into lines and columns */
+#[derive(Clone, Debug, PartialEq)]
+pub struct LineIndex {
+    starts: Vec<usize>,     // byte offset of the beginning of each line
+}
+
+impl LineIndex {
+    // Lines end with \n, a \r before it belongs to the end of line
+    pub fn new(source: &str) -> LineIndex {
+        let mut starts = vec![0];
+        starts.extend(source.match_indices('\n').map(|(index, _)| index + 1));
+        LineIndex { starts }
+    }
+
+    // Line containing the byte offset, starting from 1
+    pub fn line_of(&self, offset: usize) -> usize {
+        match self.starts.binary_search(&offset) {
+            Ok(line) => line + 1,
+            Err(line) => line,
+        }
+    }
+
+    // Byte offset of the beginning of a line, starting from 1
+    pub fn start(&self, line: usize) -> Option<usize> {
+        self.starts.get(line.wrapping_sub(1)).copied()
+    }
+
+    // Text of a line, starting from 1, without its end of line
+    pub fn text<'a>(&self, source: &'a str, line: usize) -> &'a str {
+        match self.start(line) {
+            Some(start) => source[start..].lines().next().unwrap_or(""),
+            None => "",
+        }
+    }
+
+    // Column of the byte offset in its line in characters, starting from 1
+    pub fn column_of(&self, source: &str, offset: usize) -> usize {
+        let start = self.starts[self.line_of(offset) - 1];
+        source.get(start..offset).map_or(1, |before| before.chars().count() + 1)
+    }
+
+    // (line, character) of a byte offset, both from 0, characters in UTF-16 units
+    pub fn utf16_position(&self, source: &str, offset: usize) -> (usize, usize) {
+        let offset = offset.min(source.len());
+        let line = self.line_of(offset) - 1;
+        let character = source[self.starts[line]..offset].encode_utf16().count();
+        (line, character)
+    }
+
+    // Byte offset of a (line, character) position, one past the line is its end
+    pub fn utf16_offset(&self, source: &str, line: usize, character: usize) -> usize {
+        let start = match self.start(line + 1) {
+            Some(start) => start,
+            None => return source.len(),
+        };
+        let text = self.text(source, line + 1);
+        let mut units = 0;
+        for (index, c) in text.char_indices() {
+            if units >= character {
+                return start + index;
+            }
+            units += c.len_utf16();
+        }
+        start + text.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::symbol::lines;
+
+    /* test offsets and positions with CRLF line ends and characters outside the BMP */
+    #[test]
+    fn test_positions() {
+        let source = "var x;\r\n{ \u{1F600} } x := 1.\r\n";
+        let lines = lines::LineIndex::new(source);
+        let assign = source.find("x :=").unwrap();
+
+        assert_eq!(lines.line_of(0), 1);
+        assert_eq!(lines.line_of(assign), 2);
+        assert_eq!(lines.line_of(source.len()), 3);
+        assert_eq!(lines.text(source, 1), "var x;");
+        assert_eq!(lines.text(source, 4), "");
+        assert_eq!(lines.column_of(source, assign), 7);
+
+        assert_eq!(lines.utf16_position(source, assign), (1, 7));
+        assert_eq!(lines.utf16_offset(source, 1, 7), assign);
+        // Past the end of a line stays before its \r\n
+        assert_eq!(lines.utf16_offset(source, 0, 40), source.find('\r').unwrap());
+        assert_eq!(lines.utf16_offset(source, 9, 0), source.len());
+    }
+}
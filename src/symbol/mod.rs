@@ -1,5 +1,5 @@
 
-use logos::Logos;
+use logos::{ Lexer, Logos };
 
 /* legal symbols */
 #[derive(Logos, Debug, PartialEq, Copy, Clone)]
@@ -97,15 +97,35 @@ pub enum Symbol {
     #[token("procedure")]
     Procsym,
 
+    // Pascal style comments, skipped by the parser
+    #[regex(r"\{[^}]*\}")]
+    #[token("(*", block_comment)]
+    Comment,
+
     // Logos requires one token variant to handle errors,
     // it can be named anything you wish.
     #[error]
     // We can also use this variant to define whitespace,
     // or any other matches we wish to skip.
-    #[regex(r"[ \t\n\f]+", logos::skip)]
+    #[regex(r"[ \t\r\n\f]+", logos::skip)]
     Nul,
 
     EOF,
 }
 
 pub mod io;
+pub mod lines;
+
+// Consume a (* *) comment, it is an error if it is never closed
+fn block_comment(lex: &mut Lexer<Symbol>) -> bool {
+    match lex.remainder().find("*)") {
+        Some(end) => {
+            lex.bump(end + 2);
+            true
+        },
+        None => {
+            lex.bump(lex.remainder().len());
+            false
+        },
+    }
+}
@@ -1,7 +1,17 @@
+use std::cell::RefCell;
+use std::fmt;
 use std::io;
+use std::io::Write;
+use std::rc::Rc;
+
+use crate::bytecode;
+use crate::coverage;
+use crate::profiler;
 
 
 pub const STACK_SIZE: usize = 4096;
+// Cells the stack may grow to before a stack overflow is reported
+pub const STACK_LIMIT: usize = 1 << 20;
 
 #[derive(Copy, Clone, Debug, PartialEq)]
 pub enum Fct {
@@ -17,13 +27,150 @@ pub enum Fct {
 }
 
 /* instruction structure */
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, Debug)]
 pub struct Instruction {
     pub f: Fct,         // instruction
     pub l: usize,         // level difference between declaration and reference
     pub a: usize,         // a variant depending on l
 }
 
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:<4} {} {}", format!("{:?}", self.f), self.l, self.a)
+    }
+}
+
+/* what an arithmetic result which does not fit in a word becomes */
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Overflow {
+    Checked,        // a runtime error, or a compile error for a constant expression
+    Wrapping,       // two's complement wrap around
+    Saturating,     // the nearest bound of the word
+}
+
+/*
+ * Integer semantics of the machine, shared by the vm and constant folding:
+ * words of 16, 32 or 64 bits and what happens on overflow. Results are
+ * computed exactly then brought into the word, division truncates toward 0.
+ */
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Arithmetic {
+    pub overflow: Overflow,
+    pub bits: u32,
+}
+
+impl Default for Arithmetic {
+    fn default() -> Self {
+        Arithmetic { overflow: Overflow::Checked, bits: 64 }
+    }
+}
+
+impl Arithmetic {
+    pub const WORD_SIZES: [u32; 3] = [16, 32, 64];
+
+    // None for an unsupported word size
+    pub fn new(overflow: Overflow, bits: u32) -> Option<Arithmetic> {
+        match Arithmetic::WORD_SIZES.contains(&bits) {
+            true => Some(Arithmetic { overflow, bits }),
+            false => None,
+        }
+    }
+
+    pub fn min(&self) -> i64 {
+        i64::MIN >> (64 - self.bits)
+    }
+
+    pub fn max(&self) -> i64 {
+        i64::MAX >> (64 - self.bits)
+    }
+
+    pub fn contains(&self, value: i64) -> bool {
+        self.min() <= value && value <= self.max()
+    }
+
+    // The value brought into a word, none if it overflows in checked mode
+    pub fn fit(&self, value: i128) -> Option<i64> {
+        if self.min() as i128 <= value && value <= self.max() as i128 {
+            return Some(value as i64);
+        }
+        match self.overflow {
+            Overflow::Checked => None,
+            // Keep the low bits and extend their sign
+            Overflow::Wrapping => Some(((value as i64) << (64 - self.bits)) >> (64 - self.bits)),
+            Overflow::Saturating => Some(if value < 0 { self.min() } else { self.max() }),
+        }
+    }
+
+    pub fn negate(&self, value: i64) -> Option<i64> {
+        self.fit(-(value as i128))
+    }
+
+    // Result of the arithmetic operation opr of the vm, the divisor of 5 is not 0
+    pub fn apply(&self, opr: usize, left: i64, right: i64) -> Option<i64> {
+        let (left, right) = (left as i128, right as i128);
+        match opr {
+            2 => self.fit(left + right),
+            3 => self.fit(left - right),
+            4 => self.fit(left * right),
+            5 => self.fit(left / right),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Arithmetic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let overflow = match self.overflow {
+            Overflow::Checked => "checked",
+            Overflow::Wrapping => "wrapping",
+            Overflow::Saturating => "saturating",
+        };
+        write!(f, "{} {} bit", overflow, self.bits)
+    }
+}
+
+/* fault stopping the program, the pc is left on the instruction which caused it */
+#[derive(Clone, Debug, PartialEq)]
+pub enum RuntimeError {
+    DivisionByZero,
+    Overflow(u32),          // bits of the words
+    StackOverflow(usize),   // limit of the stack in cells
+    StepLimit(u64),         // instructions executed
+}
+
+impl fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RuntimeError::DivisionByZero => write!(f, "division by zero"),
+            RuntimeError::Overflow(bits) => write!(f, "arithmetic overflow, the result does not fit in {} bits", bits),
+            RuntimeError::StackOverflow(limit) => write!(f, "stack overflow, the stack is limited to {} cells", limit),
+            RuntimeError::StepLimit(limit) => write!(f, "step limit reached after {} instructions", limit),
+        }
+    }
+}
+
+// Output sink which can be drained while the vm keeps writing into it
+#[derive(Clone, Default)]
+pub struct SharedBuffer(Rc<RefCell<Vec<u8>>>);
+
+impl SharedBuffer {
+    pub fn take(&self) -> String {
+        let content = self.0.replace(Vec::new());
+        String::from_utf8_lossy(&content).to_string()
+    }
+}
+
+impl io::Write for SharedBuffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
 pub struct PL0VirtualMachine {
     pc: usize,  // program counter
     bp: usize,  // base address pointer
@@ -33,11 +180,25 @@ pub struct PL0VirtualMachine {
 
     current_instruction: Instruction,
     instructions: Vec<Instruction>,
+
+    input: Box<dyn io::BufRead>,
+    output: Box<dyn io::Write>,
+
+    profile: Option<profiler::Profile>,
+    coverage: Option<coverage::Coverage>,
+    debug_info: Option<bytecode::DebugInfo>,
+    trace: Option<Box<dyn io::Write>>,
+
+    arithmetic: Arithmetic,
+    stack_limit: usize,
+    step_limit: Option<u64>,
+    steps: u64,
+    error: Option<RuntimeError>,
 }
 
 impl PL0VirtualMachine {
     pub fn load(ins: Vec<Instruction>) -> PL0VirtualMachine {
-        let vm = PL0VirtualMachine {
+        PL0VirtualMachine {
             pc: 0,
             bp: 0,
             sp: 0,
@@ -49,31 +210,222 @@ impl PL0VirtualMachine {
                 a: 0,
             },
             instructions: ins,
-        };
-        vm
+
+            input: Box::new(io::BufReader::new(io::stdin())),
+            output: Box::new(io::stdout()),
+
+            profile: None,
+            coverage: None,
+            debug_info: None,
+            trace: None,
+
+            arithmetic: Arithmetic::default(),
+            stack_limit: STACK_LIMIT,
+            step_limit: None,
+            steps: 0,
+            error: None,
+        }
+    }
+
+    // Replace stdin/stdout used by read/write instructions
+    pub fn redirect(&mut self, input: Box<dyn io::BufRead>, output: Box<dyn io::Write>) {
+        self.input = input;
+        self.output = output;
+    }
+
+    // Count the executions of every pc and the instructions of every call stack from now on
+    pub fn enable_profile(&mut self) {
+        self.profile = Some(profiler::Profile::new(self.instructions.len()));
+    }
+
+    pub fn profile(&self) -> Option<&profiler::Profile> {
+        self.profile.as_ref()
+    }
+
+    // Record which pcs execute and where the conditional jumps go from now on
+    pub fn enable_coverage(&mut self) {
+        self.coverage = Some(coverage::Coverage::new(self.instructions.len()));
+    }
+
+    pub fn coverage(&self) -> Option<&coverage::Coverage> {
+        self.coverage.as_ref()
+    }
+
+    // Source the code was compiled from, for the trace and the runtime errors
+    pub fn set_debug_info(&mut self, debug_info: bytecode::DebugInfo) {
+        self.debug_info = Some(debug_info);
+    }
+
+    pub fn debug_info(&self) -> Option<&bytecode::DebugInfo> {
+        self.debug_info.as_ref()
+    }
+
+    // Write every instruction before it is executed, with the source it comes from
+    pub fn enable_trace(&mut self, output: Box<dyn io::Write>) {
+        self.trace = Some(output);
+    }
+
+    // Word size and overflow behavior of the arithmetic instructions and read
+    pub fn set_arithmetic(&mut self, arithmetic: Arithmetic) {
+        self.arithmetic = arithmetic;
+    }
+
+    pub fn arithmetic(&self) -> Arithmetic {
+        self.arithmetic
+    }
+
+    // Cells the stack may hold, beyond them the program stops with a stack overflow
+    pub fn set_stack_limit(&mut self, limit: usize) {
+        self.stack_limit = limit;
+    }
+
+    // Instructions the program may execute, none for no limit
+    pub fn set_step_limit(&mut self, limit: Option<u64>) {
+        self.step_limit = limit;
+    }
+
+    // Fault which stopped the program, the vm executes nothing more until prepared again
+    pub fn error(&self) -> Option<&RuntimeError> {
+        self.error.as_ref()
     }
 
     pub fn execute(&mut self) {
+        self.prepare();
+
+        loop {
+            self.single_step_execute();     // Single step
+
+            if self.pc == 0 || self.error.is_some() {
+                break;
+            }
+        }
+        self.output.flush().expect("Failed to flush output");
+    }
+
+    // Set up the activation record of the main procedure
+    pub fn prepare(&mut self) {
+        self.stack.clear();
         self.pc = 0;
         self.bp = 3;
         self.sp = 0;
+        self.steps = 0;
+        self.error = None;
 
         self.sp = 3;
         self.stack.push(3);
         self.stack.push(0);
         self.stack.push(0);
+    }
 
-        loop {
-            self.single_step_execute();     // Single step
+    // Execute from start until falling through end, keeping the current activation record
+    pub fn run_until(&mut self, start: usize, end: usize) {
+        self.pc = start;
+        while self.pc != end && self.error.is_none() {
+            self.single_step_execute();
+        }
+        self.output.flush().expect("Failed to flush output");
+    }
 
-            if self.pc == 0 {
-                break;
+    // Drop the stack above sp and return to the activation record at bp, forgetting a fault
+    pub fn unwind(&mut self, bp: usize, sp: usize) {
+        self.stack.truncate(sp);
+        self.sp = sp;
+        self.bp = bp;
+        self.error = None;
+    }
+
+    // Replace the code, the stack is kept
+    pub fn reload(&mut self, ins: Vec<Instruction>) {
+        self.instructions = ins;
+    }
+
+    pub fn pc(&self) -> usize {
+        self.pc
+    }
+
+    pub fn bp(&self) -> usize {
+        self.bp
+    }
+
+    pub fn sp(&self) -> usize {
+        self.sp
+    }
+
+    pub fn stack(&self) -> &[i64] {
+        &self.stack
+    }
+
+    pub fn instructions(&self) -> &[Instruction] {
+        &self.instructions
+    }
+
+    pub fn flush(&mut self) {
+        self.output.flush().expect("Failed to flush output");
+    }
+
+    // pc, instruction, stack pointer and top, then line:column and the source of the instruction
+    fn trace_instruction(&mut self) {
+        let mut line = format!("{:>5}  {:<12} sp {:<4}", self.pc, self.current_instruction.to_string(), self.sp);
+        if let Some(top) = self.stack.last() {
+            line.push_str(&format!(" top {:<8}", top));
+        } else {
+            line.push_str(&format!(" {:<12}", ""));
+        }
+        if let Some(debug_info) = &self.debug_info {
+            if let (Some(span), Some(text)) = (debug_info.span(self.pc), debug_info.text(self.pc)) {
+                // Only the first line of constructs like a while loop
+                line.push_str(&format!(" {}:{}  {}", debug_info.line_of(span.start), debug_info.column_of(span.start),
+                    text.lines().next().unwrap_or("")));
             }
         }
+        if let Some(trace) = &mut self.trace {
+            writeln!(trace, "{}", line.trim_end()).expect("Failed to write trace");
+        }
+    }
+
+    // Fault the instruction at pc would cause, checked before anything is changed
+    fn check(&self, instruction: Instruction) -> Option<RuntimeError> {
+        if self.step_limit.is_some_and(|limit| self.steps >= limit) {
+            return Some(RuntimeError::StepLimit(self.steps));
+        }
+        let growth = match (instruction.f, instruction.a) {
+            (Fct::Opr, 5) if self.stack.last() == Some(&0) => return Some(RuntimeError::DivisionByZero),
+            (Fct::Opr, 1) if self.arithmetic.negate(self.stack[self.sp - 1]).is_none() => {
+                return Some(RuntimeError::Overflow(self.arithmetic.bits));
+            },
+            (Fct::Opr, opr @ 2..=5) if self.arithmetic.apply(opr, self.stack[self.sp - 2], self.stack[self.sp - 1]).is_none() => {
+                return Some(RuntimeError::Overflow(self.arithmetic.bits));
+            },
+            (Fct::Lit, _) | (Fct::Lod, _) | (Fct::Opr, 16) => 1,
+            (Fct::Cal, _) => 3,
+            (Fct::Inte, size) => size,
+            _ => 0,
+        };
+        match self.stack.len().saturating_add(growth) > self.stack_limit {
+            true => Some(RuntimeError::StackOverflow(self.stack_limit)),
+            false => None,
+        }
     }
 
     pub fn single_step_execute(&mut self) {
+        if self.error.is_some() {
+            return;
+        }
         self.current_instruction = self.instructions[self.pc];
+        if let Some(error) = self.check(self.current_instruction) {
+            self.error = Some(error);
+            return;
+        }
+        self.steps += 1;
+        if let Some(profile) = &mut self.profile {
+            profile.record(self.pc, self.current_instruction);
+        }
+        if let Some(coverage) = &mut self.coverage {
+            coverage.record(self.pc, self.current_instruction, self.stack.last().copied());
+        }
+        if self.trace.is_some() {
+            self.trace_instruction();
+        }
 
         // Debug purpose
         // println!("\n{} {:?} {} at level {}", self.pc, self.current_instruction.f, self.current_instruction.a, self.current_instruction.l);
@@ -105,34 +457,34 @@ impl PL0VirtualMachine {
                     },
                     1 => {
                         // Inverse the number on the top of stack
-                        self.stack[self.sp - 1] = - self.stack[self.sp - 1];
+                        self.stack[self.sp - 1] = self.arithmetic.negate(self.stack[self.sp - 1]).unwrap();
                     },
                     2 => {
                         // Sum
                         self.sp -= 1;
-                        self.stack[self.sp - 1] = self.stack[self.sp - 1] + self.stack[self.sp];
+                        self.stack[self.sp - 1] = self.arithmetic.apply(2, self.stack[self.sp - 1], self.stack[self.sp]).unwrap();
                         self.stack.pop();
                     },
                     3 => {
                         // Difference
                         self.sp -= 1;
-                        self.stack[self.sp - 1] = self.stack[self.sp - 1] - self.stack[self.sp];
+                        self.stack[self.sp - 1] = self.arithmetic.apply(3, self.stack[self.sp - 1], self.stack[self.sp]).unwrap();
                         self.stack.pop();
                     },
                     4 => {
                         // Multiplication
                         self.sp -= 1;
-                        self.stack[self.sp - 1] = self.stack[self.sp - 1] * self.stack[self.sp];
+                        self.stack[self.sp - 1] = self.arithmetic.apply(4, self.stack[self.sp - 1], self.stack[self.sp]).unwrap();
                         self.stack.pop();
                     },
                     5 => {
                         // Division
                         self.sp -= 1;
-                        self.stack[self.sp - 1] = self.stack[self.sp - 1] / self.stack[self.sp];
+                        self.stack[self.sp - 1] = self.arithmetic.apply(5, self.stack[self.sp - 1], self.stack[self.sp]).unwrap();
                         self.stack.pop();
                     },
                     6 => {
-                        self.stack[self.sp - 1] = self.stack[self.sp - 1] % 2;
+                        self.stack[self.sp - 1] %= 2;
                     },
                     8 => {
                         // Equal
@@ -171,26 +523,35 @@ impl PL0VirtualMachine {
                         self.stack.pop();
                     },
                     14 => {
-                        print!("{} ", self.stack[self.sp - 1]);
+                        write!(self.output, "{} ", self.stack[self.sp - 1])
+                            .expect("Failed to write output");
                         self.sp -= 1;
                         self.stack.pop();
                     },
                     15 => {
-                        print!("\n");
+                        writeln!(self.output).expect("Failed to write output");
                     },
                     16 => {
                         let mut input_number: String = String::new();
-                        print!("?");
-                        io::stdin().read_line(&mut input_number)
+                        write!(self.output, "?").expect("Failed to write output");
+                        self.output.flush().expect("Failed to flush output");
+                        self.input.read_line(&mut input_number)
                             .expect("Failed to read line");
 
-                        match input_number.trim().parse() {
-                            Ok(num) => {
-                                self.stack.push(num);
-                                self.sp += 1;
+                        match input_number.trim().parse::<i128>() {
+                            Ok(num) => match self.arithmetic.fit(num) {
+                                Some(num) => {
+                                    self.stack.push(num);
+                                    self.sp += 1;
+                                },
+                                None => {
+                                    // The input is gone, the read is where the program stopped
+                                    self.pc -= 1;
+                                    self.error = Some(RuntimeError::Overflow(self.arithmetic.bits));
+                                },
                             },
                             Err(err) => {
-                                println!("{}", err);
+                                writeln!(self.output, "{}", err).expect("Failed to write output");
                             }
                         };
                         
@@ -263,7 +624,7 @@ impl PL0VirtualMachine {
 }
 
 
-fn base(l: usize, s: &Vec<i64>, b: usize) -> usize {
+pub fn base(l: usize, s: &[i64], b: usize) -> usize {
     let mut level = l;
     let mut base_address: usize = b;
 
@@ -304,4 +665,76 @@ mod tests {
         let mut pl0_vm_1: vm::PL0VirtualMachine = vm::PL0VirtualMachine::load(instructions_2);
         pl0_vm_1.execute();
     }
+
+    /* test results are brought into the word by the overflow behavior */
+    #[test]
+    fn test_arithmetic() {
+        let checked = vm::Arithmetic::new(vm::Overflow::Checked, 16).unwrap();
+        let wrapping = vm::Arithmetic::new(vm::Overflow::Wrapping, 16).unwrap();
+        let saturating = vm::Arithmetic::new(vm::Overflow::Saturating, 32).unwrap();
+        let wide = vm::Arithmetic::new(vm::Overflow::Wrapping, 64).unwrap();
+
+        assert_eq!((checked.min(), checked.max()), (-32768, 32767));
+        assert_eq!(checked.apply(2, 32767, 1), None);
+        assert_eq!(checked.apply(5, -32768, -1), None);
+        assert_eq!(checked.apply(5, -7, 2), Some(-3));
+        assert_eq!(wrapping.apply(2, 32767, 1), Some(-32768));
+        assert_eq!(wrapping.apply(4, 300, 300), Some(90000 - 65536));
+        assert_eq!(wrapping.negate(-32768), Some(-32768));
+        assert_eq!(saturating.apply(3, i32::MIN as i64, 1), Some(i32::MIN as i64));
+        assert_eq!(saturating.apply(4, 1 << 20, 1 << 20), Some(i32::MAX as i64));
+        assert_eq!(wide.apply(2, i64::MAX, 1), Some(i64::MIN));
+        assert_eq!(vm::Arithmetic::default().apply(4, i64::MAX, 2), None);
+        assert_eq!(vm::Arithmetic::new(vm::Overflow::Checked, 8), None);
+    }
+
+    /* test an overflow stops the program in checked mode */
+    #[test]
+    fn test_overflow() {
+        // 16 bit: 32767 + 1, then write
+        let instructions = vec![
+            vm::Instruction{ f: vm::Fct::Jmp, a: 1, l: 0 },
+            vm::Instruction{ f: vm::Fct::Inte, a: 3, l: 0 },
+            vm::Instruction{ f: vm::Fct::Lit, a: 32767, l: 0 },
+            vm::Instruction{ f: vm::Fct::Lit, a: 1, l: 0 },
+            vm::Instruction{ f: vm::Fct::Opr, a: 2, l: 0 },
+            vm::Instruction{ f: vm::Fct::Opr, a: 14, l: 0 },
+            vm::Instruction{ f: vm::Fct::Opr, a: 0, l: 0 },
+        ];
+        let run = |overflow: vm::Overflow| {
+            let output = vm::SharedBuffer::default();
+            let mut machine = vm::PL0VirtualMachine::load(instructions.clone());
+            machine.redirect(Box::new(std::io::empty()), Box::new(output.clone()));
+            machine.set_arithmetic(vm::Arithmetic::new(overflow, 16).unwrap());
+            machine.execute();
+            (machine.error().cloned(), machine.pc(), output.take())
+        };
+
+        assert_eq!(run(vm::Overflow::Checked), (Some(vm::RuntimeError::Overflow(16)), 4, String::new()));
+        assert_eq!(run(vm::Overflow::Wrapping), (None, 0, String::from("-32768 ")));
+        assert_eq!(run(vm::Overflow::Saturating), (None, 0, String::from("32767 ")));
+    }
+
+    /* test a number read must fit in a word */
+    #[test]
+    fn test_read_overflow() {
+        let instructions = vec![
+            vm::Instruction{ f: vm::Fct::Jmp, a: 1, l: 0 },
+            vm::Instruction{ f: vm::Fct::Inte, a: 3, l: 0 },
+            vm::Instruction{ f: vm::Fct::Opr, a: 16, l: 0 },
+            vm::Instruction{ f: vm::Fct::Opr, a: 14, l: 0 },
+            vm::Instruction{ f: vm::Fct::Opr, a: 0, l: 0 },
+        ];
+        let run = |overflow: vm::Overflow| {
+            let output = vm::SharedBuffer::default();
+            let mut machine = vm::PL0VirtualMachine::load(instructions.clone());
+            machine.redirect(Box::new(std::io::Cursor::new("40000\n")), Box::new(output.clone()));
+            machine.set_arithmetic(vm::Arithmetic::new(overflow, 16).unwrap());
+            machine.execute();
+            (machine.error().cloned(), output.take())
+        };
+
+        assert_eq!(run(vm::Overflow::Checked), (Some(vm::RuntimeError::Overflow(16)), String::from("?")));
+        assert_eq!(run(vm::Overflow::Saturating), (None, String::from("?32767 ")));
+    }
 }
@@ -2,21 +2,21 @@ extern crate pl0rust;
 
 #[test]
 fn test_add_into_name_table1() {
-    let mut generator = pl0rust::compile::codegen::CodeGenerator::new();
+    let _generator = pl0rust::compile::codegen::CodeGenerator::new();
 }
 
 #[test]
 fn test_find_variable_no_duplicated_name2() {
-    let mut generator = pl0rust::compile::codegen::CodeGenerator::new();
+    let _generator = pl0rust::compile::codegen::CodeGenerator::new();
 }
 
 #[test]
 fn test_find_variable_with_duplicated_name3() {
-    let mut generator = pl0rust::compile::codegen::CodeGenerator::new();
+    let _generator = pl0rust::compile::codegen::CodeGenerator::new();
 }
 
 #[test]
 fn test_find_variable_not_found4() {
-    let mut generator = pl0rust::compile::codegen::CodeGenerator::new();
+    let _generator = pl0rust::compile::codegen::CodeGenerator::new();
 
 }
\ No newline at end of file